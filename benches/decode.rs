@@ -0,0 +1,75 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use socks_parser::{v4, v5, Wire};
+
+type Error<'i> = nom::error::Error<&'i [u8]>;
+
+fn v5_requests(count: usize) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for i in 0..count {
+        let addr = match i % 3 {
+            0 => v5::AddressType::IPv4(Ipv4Addr::new(192, 168, 0, (i % 256) as u8)),
+            1 => v5::AddressType::IPv6(Ipv6Addr::LOCALHOST),
+            _ => v5::AddressType::DomainName("www.example.com".into()),
+        };
+        v5::Request {
+            command: v5::Command::Connect,
+            rsv: 0,
+            addr,
+            port: 443,
+        }
+        .encode_into(&mut buffer);
+    }
+    buffer
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+
+    let buffer = v5_requests(1);
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    group.bench_function("v5::Request", |b| {
+        b.iter(|| v5::Request::decode::<Error>(black_box(&buffer[..])).unwrap())
+    });
+
+    let mut buffer = Vec::new();
+    v4::Request {
+        command: v4::Command::Connect,
+        addr: v4::AddressType::DomainName("www.example.com".into()),
+        port: 80,
+        secret: Some("user".into()),
+    }
+    .encode_into(&mut buffer);
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    group.bench_function("v4::Request", |b| {
+        b.iter(|| v4::Request::decode::<Error>(black_box(&buffer[..])).unwrap())
+    });
+
+    let buffer = v5_requests(1024);
+    group.throughput(Throughput::Bytes(buffer.len() as u64));
+    group.bench_function("v5::Request::decode_many", |b| {
+        b.iter(|| v5::Request::decode_many::<Error>(black_box(&buffer[..])).unwrap())
+    });
+
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let req = v5::Request {
+        command: v5::Command::Connect,
+        rsv: 0,
+        addr: v5::AddressType::IPv6(Ipv6Addr::LOCALHOST),
+        port: 443,
+    };
+    let mut buffer = Vec::with_capacity(64);
+    c.bench_function("encode/v5::Request", |b| {
+        b.iter(|| {
+            buffer.clear();
+            black_box(&req).encode_into(&mut buffer);
+        })
+    });
+}
+
+criterion_group!(benches, bench_decode, bench_encode);
+criterion_main!(benches);
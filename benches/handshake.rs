@@ -0,0 +1,86 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use socks_parser::{
+    recorder::{Direction, Record, Transcript},
+    testing, ConnectionRequest, Destination,
+};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+
+/// Counts allocations, to report how many a handshake costs.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const GREETING: &[u8] = b"SSH-2.0-OpenSSH_9.6\r\n";
+
+/// Destination greeting clients as soon as it is reached, like SSH or SMTP servers do.
+async fn greeting_destination(req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+    let (remote, mut target) = tokio::io::duplex(1024);
+    target.write_all(GREETING).await?;
+    Ok((remote, req.destination))
+}
+
+fn v5_connect() -> Transcript {
+    let request = [5, 1, 0, 1, 192, 0, 2, 1, 0, 22];
+    Transcript {
+        records: [&[5, 1, 0][..], &request]
+            .into_iter()
+            .map(|data| Record {
+                elapsed: Duration::ZERO,
+                direction: Direction::Sent,
+                data: data.to_vec(),
+            })
+            .collect(),
+    }
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let server = testing::server();
+    let client = v5_connect();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let (transcript, result) = runtime.block_on(server.replay(&client, greeting_destination));
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    result.unwrap();
+    // The relay writes the greeting later unless it went out along with the reply.
+    let mut writes = transcript.sent().count();
+    if !transcript.sent().last().unwrap().ends_with(GREETING) {
+        writes += 1;
+    }
+    eprintln!("handshake/v5 connect: {writes} writes until greeted, {allocations} allocations");
+
+    c.bench_function("handshake/v5 connect", |b| {
+        b.iter(|| {
+            runtime
+                .block_on(server.replay(black_box(&client), greeting_destination))
+                .1
+                .unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, bench_handshake);
+criterion_main!(benches);
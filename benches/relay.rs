@@ -0,0 +1,82 @@
+use std::{future::Future, io};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use socks_parser::{handlers, relay};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Bytes sent each way through the relay per iteration.
+const PAYLOAD: usize = 4 << 20;
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let connecting = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connecting, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+/// Relays [`PAYLOAD`] bytes each way between loopback TCP connections with `pipe`.
+async fn round_trip<P, F>(pipe: P)
+where
+    P: FnOnce(TcpStream, TcpStream) -> F,
+    F: Future<Output = io::Result<(u64, u64)>> + Send + 'static,
+{
+    let (mut client, local) = tcp_pair().await;
+    let (remote, mut target) = tcp_pair().await;
+    let piped = tokio::spawn(pipe(local, remote));
+
+    let echo = tokio::spawn(async move {
+        let (mut r, mut w) = target.split();
+        tokio::io::copy(&mut r, &mut w).await
+    });
+    let (mut r, mut w) = client.split();
+    let upload = vec![7; PAYLOAD];
+    let mut download = vec![0; PAYLOAD];
+    let (sent, received) = tokio::join!(
+        async {
+            w.write_all(&upload).await?;
+            w.shutdown().await
+        },
+        r.read_exact(&mut download),
+    );
+    sent.unwrap();
+    received.unwrap();
+    echo.await.unwrap().unwrap();
+    drop(client);
+    piped.await.unwrap().unwrap();
+}
+
+fn bench_relay(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Bytes(2 * PAYLOAD as u64));
+    group.sample_size(20);
+
+    group.bench_function("copy_bidirectional", |b| {
+        b.iter(|| runtime.block_on(round_trip(handlers::relay)))
+    });
+    group.bench_function("splice", |b| {
+        b.iter(|| runtime.block_on(round_trip(relay::pipe_zero_copy)))
+    });
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    {
+        let uring = std::sync::Arc::new(relay::UringRelay::new(1).unwrap());
+        group.bench_function("io_uring", |b| {
+            b.iter(|| {
+                let uring = std::sync::Arc::clone(&uring);
+                runtime.block_on(round_trip(move |local, remote| async move {
+                    uring.pipe(local, remote).await
+                }))
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_relay);
+criterion_main!(benches);
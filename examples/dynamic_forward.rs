@@ -0,0 +1,113 @@
+//! Dynamic port forwarding, as `ssh -D 1080 HOST` does: every connection accepted on
+//! 127.0.0.1:1080 goes through a channel opened with `ssh -W` to HOST.
+//!
+//! ```text
+//! cargo run --example dynamic_forward -- user@jump.example.com
+//! curl --socks5-hostname 127.0.0.1:1080 http://intranet.example/
+//! ```
+
+use std::{
+    io,
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use socks_parser::{
+    auth::BoxFuture,
+    handlers::{self, Dialer},
+    Destination, Server,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Join, ReadBuf},
+    net::TcpListener,
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+/// Channel to a destination, relayed by an `ssh -W` process through its standard streams.
+struct SshChannel {
+    // Killed once the channel is dropped.
+    _child: Child,
+    io: Join<ChildStdout, ChildStdin>,
+}
+
+impl AsyncRead for SshChannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshChannel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Opens channels through `host`.
+struct SshDialer {
+    host: String,
+}
+
+impl Dialer for SshDialer {
+    type Stream = SshChannel;
+
+    fn dial<'a>(&'a self, destination: &'a Destination) -> BoxFuture<'a, io::Result<SshChannel>> {
+        Box::pin(async move {
+            let mut child = Command::new("ssh")
+                .arg("-W")
+                .arg(destination.to_string())
+                .arg(&self.host)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()?;
+            let stdout = child.stdout.take().expect("piped stdout");
+            let stdin = child.stdin.take().expect("piped stdin");
+            Ok(SshChannel {
+                _child: child,
+                io: tokio::io::join(stdout, stdin),
+            })
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let host = std::env::args().nth(1).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Usage: dynamic_forward [USER@]HOST",
+        )
+    })?;
+    let listener = TcpListener::bind(("127.0.0.1", 1080)).await?;
+    log::info!("Forwarding 127.0.0.1:1080 through {host}");
+    Server::new(listener)
+        .run(
+            handlers::local_dynamic_forward(SshDialer { host }),
+            handlers::relay,
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! SOCKS server reachable without TCP loopback exposure: on a Unix socket, or on a named pipe
+//! on Windows.
+//!
+//! ```text
+//! cargo run --example local_transport -- /run/user/1000/socks.sock
+//! curl --proxy socks5h://localhost --unix-socket /run/user/1000/socks.sock http://example.com/
+//! ```
+
+use std::io;
+
+use socks_parser::{handlers, ConnectionRequest, Destination, Server};
+use tokio::net::TcpStream;
+
+async fn handle_request(c: ConnectionRequest) -> io::Result<(TcpStream, Destination)> {
+    let port = c.destination.port;
+    let stream = match c.destination.addr.to_socket_addr(port) {
+        Some(addr) => TcpStream::connect(addr).await?,
+        None => TcpStream::connect((c.destination.addr.to_string(), port)).await?,
+    };
+    let local = stream.local_addr()?;
+    log::info!("{} -> {}", c.destination, stream.peer_addr()?);
+    Ok((stream, local.into()))
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    let server = Server::unbound();
+
+    #[cfg(unix)]
+    {
+        let path = std::env::args()
+            .nth(1)
+            .unwrap_or_else(|| "socks.sock".to_owned());
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        log::info!("Listening on {path}");
+        server
+            .serve(listener, handle_request, handlers::relay)
+            .await?;
+    }
+
+    #[cfg(windows)]
+    {
+        let name = std::env::args()
+            .nth(1)
+            .unwrap_or_else(|| r"\\.\pipe\socks".to_owned());
+        let listener = socks_parser::NamedPipeListener::bind(&name)?;
+        log::info!("Listening on {name}");
+        server
+            .serve(listener, handle_request, handlers::relay)
+            .await?;
+    }
+
+    Ok(())
+}
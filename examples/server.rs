@@ -4,16 +4,10 @@ use socks_parser::{ConnectionRequest, Destination, Server};
 use tokio::net::{TcpListener, TcpStream};
 
 async fn hanle_request(c: ConnectionRequest) -> io::Result<(TcpStream, Destination)> {
-    let stream = match &c.destination.addr {
-        socks_parser::v5::AddressType::IPv4(ip4) => {
-            TcpStream::connect((*ip4, c.destination.port)).await?
-        }
-        socks_parser::v5::AddressType::DomainName(n) => {
-            TcpStream::connect((n.as_str(), c.destination.port)).await?
-        }
-        socks_parser::v5::AddressType::IPv6(ip6) => {
-            TcpStream::connect((*ip6, c.destination.port)).await?
-        }
+    let port = c.destination.port;
+    let stream = match c.destination.addr.to_socket_addr(port) {
+        Some(addr) => TcpStream::connect(addr).await?,
+        None => TcpStream::connect((c.destination.addr.to_string(), port)).await?,
     };
 
     let addr = stream.peer_addr()?;
@@ -26,9 +20,8 @@ async fn hanle_request(c: ConnectionRequest) -> io::Result<(TcpStream, Destinati
     Ok((stream, addr.into()))
 }
 
-async fn handle_stream(mut local: TcpStream, mut remote: TcpStream) -> io::Result<()> {
-    tokio::io::copy_bidirectional(&mut local, &mut remote).await?;
-    Ok(())
+async fn handle_stream(mut local: TcpStream, mut remote: TcpStream) -> io::Result<(u64, u64)> {
+    tokio::io::copy_bidirectional(&mut local, &mut remote).await
 }
 
 #[tokio::main]
@@ -0,0 +1,91 @@
+//! Tunnel switching to TLS once the SOCKS handshake is done: the server wraps the client stream
+//! with a rustls acceptor before relaying, and the client wraps the tunnel with a connector.
+//!
+//! Runs both ends along with an echo destination, using a self-signed certificate.
+//!
+//! ```text
+//! cargo run --example tls_tunnel
+//! ```
+
+use std::{io, sync::Arc};
+
+use socks_parser::{handlers, resolver::SystemResolver, Client, ConnectOptions, Server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+fn tls_configs() -> Result<(ServerConfig, ClientConfig), Box<dyn std::error::Error>> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+    let cert = certified.cert.der().clone();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+    let provider = Arc::new(ring::default_provider());
+    let server = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], key)?;
+    let mut roots = RootCertStore::empty();
+    roots.add(cert)?;
+    let client = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok((server, client))
+}
+
+async fn echo(listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let (mut r, mut w) = stream.split();
+            tokio::io::copy(&mut r, &mut w).await
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (server_config, client_config) = tls_configs()?;
+
+    let destination = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let destination_addr = destination.local_addr()?;
+    tokio::spawn(echo(destination));
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let proxy = listener.local_addr()?;
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+    tokio::spawn(Server::new(listener).run(
+        handlers::connect_with(SystemResolver, ConnectOptions::default()),
+        handlers::transform_client(
+            move |stream: TcpStream| acceptor.accept(stream),
+            handlers::relay,
+        ),
+    ));
+
+    let connector = TlsConnector::from(Arc::new(client_config));
+    let server_name: ServerName<'static> = "localhost".try_into()?;
+    let tls = move |stream: TcpStream| connector.connect(server_name.clone(), stream);
+    let mut stream = Client::new(TcpStream::connect(proxy).await?)
+        .connect_transformed(destination_addr, &tls)
+        .await?;
+    let (_, session) = stream.get_ref();
+    println!(
+        "Tunnel to {destination_addr} through {proxy} uses {:?}",
+        session.protocol_version(),
+    );
+
+    stream.write_all(b"hello").await?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).await?;
+    println!("Echoed {:?}", String::from_utf8_lossy(&reply));
+    Ok(())
+}
@@ -0,0 +1,392 @@
+//! Destination allow/deny lists.
+//!
+//! Rules are written one per line, the first matching rule wins:
+//!
+//! ```text
+//! # comment
+//! allow *.example.com:443
+//! deny 10.0.0.0/8
+//! deny [fd00::]/8
+//! allow 192.168.1.10:22-80
+//! default deny
+//! ```
+//!
+//! Hosts are either `*`, a domain name (`*.` matches every subdomain), an IP address or a CIDR
+//! block. Destinations matching no rule are allowed unless a `default deny` line is present.
+//!
+//! Servers exposed to untrusted clients usually keep them away from the private networks of
+//! their host, see [`block_private_ranges`].
+
+use std::{
+    fs, io,
+    net::IpAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{v5::AddressType, Destination};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Access control consulted by the server before handing a request to its handler.
+pub trait Acl: Send + Sync {
+    fn check(&self, destination: &Destination) -> Action;
+}
+
+impl<A: Acl + ?Sized> Acl for Arc<A> {
+    fn check(&self, destination: &Destination) -> Action {
+        (**self).check(destination)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HostPattern {
+    Any,
+    Domain(String),
+    Subdomains(String),
+    Network(IpAddr, u8),
+}
+
+impl HostPattern {
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        if s == "*" {
+            return Some(Self::Any);
+        }
+        if let Some(domain) = s.strip_prefix("*.") {
+            return Some(Self::Subdomains(domain.to_ascii_lowercase()));
+        }
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix.parse().ok()?)),
+            None => (s, None),
+        };
+        let ip = ip
+            .strip_prefix('[')
+            .and_then(|i| i.strip_suffix(']'))
+            .unwrap_or(ip);
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => {
+                let max = if ip.is_ipv4() { 32 } else { 128 };
+                let prefix = prefix.unwrap_or(max);
+                (prefix <= max).then_some(Self::Network(ip, prefix))
+            }
+            Err(_) if prefix.is_none() => Some(Self::Domain(s.to_ascii_lowercase())),
+            Err(_) => None,
+        }
+    }
+
+    /// Whether `addr` matches, IPv4-mapped IPv6 addresses and domain names spelling an IP
+    /// address counting as the IPv4 or IPv6 address they connect to.
+    pub(crate) fn matches(&self, addr: &AddressType) -> bool {
+        match (self, addr) {
+            (Self::Any, _) => true,
+            (Self::Network(net, prefix), addr) => match (net, ip_of(addr)) {
+                (IpAddr::V4(net), Some(AddressType::IPv4(ip))) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                    u32::from(*net) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(net), Some(AddressType::IPv6(ip))) => {
+                    let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                    u128::from(*net) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            },
+            (Self::Domain(d), AddressType::DomainName(n)) => n.eq_ignore_ascii_case(d),
+            (Self::Subdomains(d), AddressType::DomainName(n)) => {
+                let n = n.to_ascii_lowercase();
+                n.strip_suffix(d.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Normalized IP address of `addr`, parsed from domain names spelling one.
+fn ip_of(addr: &AddressType) -> Option<AddressType> {
+    let addr = match addr {
+        AddressType::DomainName(name) => {
+            name.trim_matches(['[', ']']).parse::<IpAddr>().ok()?.into()
+        }
+        AddressType::IPv4(_) | AddressType::IPv6(_) => addr.clone(),
+        #[cfg(feature = "extensions")]
+        AddressType::UnixPath(_) | AddressType::Other { .. } => return None,
+    };
+    Some(addr.normalized())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    action: Action,
+    host: HostPattern,
+    ports: RangeInclusive<u16>,
+}
+
+/// Parsed list of rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclRules {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+impl Default for AclRules {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: Action::Allow,
+        }
+    }
+}
+
+fn parse_ports(s: &str) -> Option<RangeInclusive<u16>> {
+    match s.split_once('-') {
+        Some((start, end)) => Some(start.parse().ok()?..=end.parse().ok()?),
+        None => {
+            let port = s.parse().ok()?;
+            Some(port..=port)
+        }
+    }
+}
+
+/// Parses `host[:ports]`, every port matching if none is given.
+pub(crate) fn parse_target(s: &str) -> Option<(HostPattern, RangeInclusive<u16>)> {
+    // Ports follow the last colon, unless it belongs to a bare IPv6 address.
+    let (host, ports) = match s.rsplit_once(':') {
+        Some((host, ports))
+            if !host.contains(':') || host.starts_with('[') && host.contains(']') =>
+        {
+            (host, parse_ports(ports)?)
+        }
+        _ => (s, 0..=u16::MAX),
+    };
+    Some((HostPattern::parse(host)?, ports))
+}
+
+impl AclRules {
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut acl = Self::default();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid ACL rule at line {}: {line:?}", lineno + 1),
+                )
+            };
+            let (keyword, target) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let action = match keyword {
+                "allow" => Action::Allow,
+                "deny" => Action::Deny,
+                "default" => {
+                    acl.default = match target.trim() {
+                        "allow" => Action::Allow,
+                        "deny" => Action::Deny,
+                        _ => return Err(invalid()),
+                    };
+                    continue;
+                }
+                _ => return Err(invalid()),
+            };
+            let (host, ports) = parse_target(target.trim()).ok_or_else(invalid)?;
+            acl.rules.push(Rule {
+                action,
+                host,
+                ports,
+            });
+        }
+        Ok(acl)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+}
+
+impl Acl for AclRules {
+    fn check(&self, destination: &Destination) -> Action {
+        self.rules
+            .iter()
+            .find(|r| r.ports.contains(&destination.port) && r.host.matches(&destination.addr))
+            .map_or(self.default, |r| r.action)
+    }
+}
+
+/// Destinations of [`block_private_ranges`].
+const PRIVATE_RANGES: &str = "
+# Loopback and \"this\" network
+deny 127.0.0.0/8
+deny 0.0.0.0/8
+deny [::1]/128
+deny [::]/128
+deny localhost
+deny *.localhost
+# RFC 1918 and shared address space (RFC 6598)
+deny 10.0.0.0/8
+deny 172.16.0.0/12
+deny 192.168.0.0/16
+deny 100.64.0.0/10
+# Link-local, including the metadata services of cloud providers
+deny 169.254.0.0/16
+deny [fe80::]/10
+deny metadata.google.internal
+# Unique local addresses, and IPv4 addresses mapped to IPv6 ones
+deny [fc00::]/7
+deny [::ffff:0:0]/96
+";
+
+static PRIVATE: LazyLock<AclRules> =
+    LazyLock::new(|| AclRules::parse(PRIVATE_RANGES).expect("private ranges are valid rules"));
+
+/// Rules denying the loopback, private (RFC 1918, unique local), shared and link-local ranges,
+/// including the cloud metadata services, allowing everything else.
+///
+/// Such rules only see the destinations as clients name them: a name resolving to a private
+/// address passes, unless the server re-checks resolved addresses as well, see
+/// [`Server::allow_private_destinations`](crate::Server::allow_private_destinations).
+pub fn block_private_ranges() -> AclRules {
+    PRIVATE.clone()
+}
+
+/// Whether `destination` is denied by [`block_private_ranges`], domain names spelling an IP
+/// address counting as that address.
+#[cfg(feature = "async")]
+pub(crate) fn is_private(destination: &Destination) -> bool {
+    PRIVATE.check(destination) == Action::Deny
+}
+
+/// Fails with `PermissionDenied` if `addr`, which a destination resolved to, is denied by
+/// [`block_private_ranges`].
+#[cfg(feature = "async")]
+pub(crate) fn check_resolved(
+    destination: &Destination,
+    addr: std::net::SocketAddr,
+) -> io::Result<()> {
+    if is_private(&Destination::from(addr)) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{destination} resolves to private address {}", addr.ip()),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct PollState {
+    last_poll: Instant,
+    modified: Option<SystemTime>,
+    /// Whether the file is being checked already.
+    polling: bool,
+}
+
+/// Rules file reloaded whenever it changes on disk.
+///
+/// The file modification time is polled at most once per interval, when a destination is
+/// checked. Within a Tokio runtime, the file is checked and reloaded on a blocking thread, the
+/// new rules applying to the checks which follow. If a reload fails, the previous rules are
+/// kept.
+#[derive(Debug)]
+pub struct FileWatcherAcl {
+    watched: Arc<WatchedFile>,
+    interval: Duration,
+}
+
+#[derive(Debug)]
+struct WatchedFile {
+    path: PathBuf,
+    rules: RwLock<AclRules>,
+    state: Mutex<PollState>,
+}
+
+impl FileWatcherAcl {
+    pub fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        Self::with_interval(path, Duration::from_secs(5))
+    }
+
+    pub fn with_interval(path: impl Into<PathBuf>, interval: Duration) -> io::Result<Self> {
+        let path = path.into();
+        let modified = fs::metadata(&path)?.modified().ok();
+        let rules = AclRules::load(&path)?;
+        Ok(Self {
+            watched: Arc::new(WatchedFile {
+                path,
+                rules: RwLock::new(rules),
+                state: Mutex::new(PollState {
+                    last_poll: Instant::now(),
+                    modified,
+                    polling: false,
+                }),
+            }),
+            interval,
+        })
+    }
+
+    /// Reloads the rules right away.
+    pub fn reload(&self) -> io::Result<()> {
+        self.watched.reload()
+    }
+
+    fn poll(&self) {
+        {
+            let mut state = self.watched.lock_state();
+            if state.polling || state.last_poll.elapsed() < self.interval {
+                return;
+            }
+            state.last_poll = Instant::now();
+            state.polling = true;
+        }
+        #[cfg(feature = "async")]
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let watched = Arc::clone(&self.watched);
+            runtime.spawn_blocking(move || watched.refresh());
+            return;
+        }
+        self.watched.refresh();
+    }
+}
+
+impl WatchedFile {
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, PollState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn reload(&self) -> io::Result<()> {
+        let rules = AclRules::load(&self.path)?;
+        *self.rules.write().unwrap_or_else(|e| e.into_inner()) = rules;
+        log::info!("Reloaded ACL from {}", self.path.display());
+        Ok(())
+    }
+
+    /// Reloads the rules if the file was modified since the last poll.
+    fn refresh(&self) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        let changed = {
+            let mut state = self.lock_state();
+            std::mem::replace(&mut state.modified, modified) != modified
+        };
+        if changed {
+            if let Err(e) = self.reload() {
+                log::error!("Could not reload ACL from {}: {e}", self.path.display());
+            }
+        }
+        self.lock_state().polling = false;
+    }
+}
+
+impl Acl for FileWatcherAcl {
+    fn check(&self, destination: &Destination) -> Action {
+        self.poll();
+        self.watched
+            .rules
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .check(destination)
+    }
+}
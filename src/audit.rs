@@ -0,0 +1,299 @@
+//! Audit trail of what clients did, for operators who must keep one.
+//!
+//! Register a sink with [`Server::with_audit_sink`](crate::Server::with_audit_sink): it gets an
+//! [`AuditEvent`] for every authentication attempt, request allowed or denied by the server
+//! policy (ACL, link-local restriction), client using a disabled SOCKS version, and relayed
+//! connection once closed along with its [`SessionSummary`]. [Honeypots](crate::honeypot)
+//! report what their clients sent as well.
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{auth::BoxFuture, stats::SessionSummary, Destination, Version};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    pub time: SystemTime,
+    pub peer: SocketAddr,
+    pub kind: AuditKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditKind {
+    AuthSucceeded {
+        username: String,
+    },
+    /// Wrong credentials, or no acceptable authentication method when `username` is `None`.
+    AuthFailed {
+        username: Option<String>,
+    },
+    RequestAllowed {
+        destination: Destination,
+        /// Name of the authenticated user, if any.
+        username: Option<String>,
+    },
+    RequestDenied {
+        destination: Destination,
+        reason: String,
+    },
+    /// The client speaks a version disabled with
+    /// [`Server::with_versions`](crate::Server::with_versions).
+    VersionRejected {
+        version: Version,
+    },
+    /// The stream handler relaying the connection returned.
+    ConnectionClosed {
+        summary: SessionSummary,
+    },
+    /// What a client sent through a [`Honeypot`](crate::honeypot::Honeypot) tunnel, up to the
+    /// capture limit.
+    HoneypotPayload {
+        destination: Destination,
+        payload: Vec<u8>,
+    },
+}
+
+impl AuditKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::AuthSucceeded { .. } => "auth_succeeded",
+            Self::AuthFailed { .. } => "auth_failed",
+            Self::RequestAllowed { .. } => "request_allowed",
+            Self::RequestDenied { .. } => "request_denied",
+            Self::VersionRejected { .. } => "version_rejected",
+            Self::ConnectionClosed { .. } => "connection_closed",
+            Self::HoneypotPayload { .. } => "honeypot_payload",
+        }
+    }
+}
+
+/// Destination of audit events.
+///
+/// Failures are logged by the server, which keeps serving clients.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, event: AuditEvent) -> BoxFuture<'_, io::Result<()>>;
+}
+
+impl<A: AuditSink + ?Sized> AuditSink for Arc<A> {
+    fn record(&self, event: AuditEvent) -> BoxFuture<'_, io::Result<()>> {
+        (**self).record(event)
+    }
+}
+
+/// Writes one JSON object per event and per line, such as
+/// `{"timestamp_ms":…,"peer":"192.0.2.1:41000","event":"auth_failed","username":"alice"}`.
+///
+/// Lines are small and written with a single call, blocking the current thread meanwhile.
+pub struct JsonlSink<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonlSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl JsonlSink<File> {
+    /// Appends to the file at `path`, creating it if needed.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+/// Appends `s` as a JSON string.
+fn push_json_string(line: &mut String, s: &str) {
+    line.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+fn push_json_field(line: &mut String, name: &str, value: Option<&str>) {
+    let _ = write!(line, r#","{name}":"#);
+    match value {
+        Some(value) => push_json_string(line, value),
+        None => line.push_str("null"),
+    }
+}
+
+impl AuditEvent {
+    fn to_json(&self) -> String {
+        let timestamp = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let mut line = format!(
+            r#"{{"timestamp_ms":{timestamp},"peer":"{}","event":"{}""#,
+            self.peer,
+            self.kind.name()
+        );
+        match self.kind {
+            AuditKind::AuthSucceeded { ref username } => {
+                push_json_field(&mut line, "username", Some(username));
+            }
+            AuditKind::AuthFailed { ref username } => {
+                push_json_field(&mut line, "username", username.as_deref());
+            }
+            AuditKind::RequestAllowed {
+                ref destination,
+                ref username,
+            } => {
+                push_json_field(&mut line, "destination", Some(&destination.to_string()));
+                push_json_field(&mut line, "username", username.as_deref());
+            }
+            AuditKind::RequestDenied {
+                ref destination,
+                ref reason,
+            } => {
+                push_json_field(&mut line, "destination", Some(&destination.to_string()));
+                push_json_field(&mut line, "reason", Some(reason));
+            }
+            AuditKind::VersionRejected { version } => {
+                let _ = write!(line, r#","version":{}"#, version as u8);
+            }
+            AuditKind::ConnectionClosed { ref summary } => {
+                push_json_field(
+                    &mut line,
+                    "destination",
+                    Some(&summary.destination.to_string()),
+                );
+                let _ = write!(
+                    line,
+                    r#","bytes_up":{},"bytes_down":{},"duration_ms":{}"#,
+                    summary.bytes_up,
+                    summary.bytes_down,
+                    summary.duration.as_millis()
+                );
+                push_json_field(
+                    &mut line,
+                    "close_reason",
+                    Some(&summary.close_reason.to_string()),
+                );
+            }
+            AuditKind::HoneypotPayload {
+                ref destination,
+                ref payload,
+            } => {
+                push_json_field(&mut line, "destination", Some(&destination.to_string()));
+                push_json_field(
+                    &mut line,
+                    "payload",
+                    Some(&String::from_utf8_lossy(payload)),
+                );
+            }
+        }
+        line.push_str("}\n");
+        line
+    }
+}
+
+impl<W: Write + Send> AuditSink for JsonlSink<W> {
+    fn record(&self, event: AuditEvent) -> BoxFuture<'_, io::Result<()>> {
+        let line = event.to_json();
+        Box::pin(async move {
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            writer.write_all(line.as_bytes())?;
+            writer.flush()
+        })
+    }
+}
+
+/// Emits events at the info level with the `socks_parser::audit` target.
+#[cfg(feature = "tracing")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TracingSink;
+
+#[cfg(feature = "tracing")]
+impl AuditSink for TracingSink {
+    fn record(&self, event: AuditEvent) -> BoxFuture<'_, io::Result<()>> {
+        const TARGET: &str = "socks_parser::audit";
+
+        let peer = event.peer;
+        let name = event.kind.name();
+        match event.kind {
+            AuditKind::AuthSucceeded { username } => {
+                tracing::info!(target: TARGET, %peer, event = name, username = username.as_str());
+            }
+            AuditKind::AuthFailed { username } => {
+                tracing::info!(target: TARGET, %peer, event = name, username = username.as_deref());
+            }
+            AuditKind::RequestAllowed {
+                destination,
+                username,
+            } => {
+                tracing::info!(
+                    target: TARGET,
+                    %peer,
+                    event = name,
+                    %destination,
+                    username = username.as_deref(),
+                );
+            }
+            AuditKind::RequestDenied {
+                destination,
+                reason,
+            } => {
+                tracing::info!(
+                    target: TARGET,
+                    %peer,
+                    event = name,
+                    %destination,
+                    reason = reason.as_str(),
+                );
+            }
+            AuditKind::VersionRejected { version } => {
+                tracing::info!(target: TARGET, %peer, event = name, version = version as u8);
+            }
+            AuditKind::ConnectionClosed { summary } => {
+                tracing::info!(
+                    target: TARGET,
+                    %peer,
+                    event = name,
+                    destination = %summary.destination,
+                    bytes_up = summary.bytes_up,
+                    bytes_down = summary.bytes_down,
+                    duration_ms = summary.duration.as_millis() as u64,
+                    close_reason = %summary.close_reason,
+                );
+            }
+            AuditKind::HoneypotPayload {
+                destination,
+                payload,
+            } => {
+                tracing::info!(
+                    target: TARGET,
+                    %peer,
+                    event = name,
+                    %destination,
+                    payload = %String::from_utf8_lossy(&payload),
+                );
+            }
+        }
+        Box::pin(async { Ok(()) })
+    }
+}
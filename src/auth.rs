@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap, fmt, fs, future::Future, io, net::SocketAddr, path::Path, pin::Pin,
+    sync::Arc,
+};
+
+use crate::{
+    v5::{AuthenticationMethod, Hello},
+    Redacted,
+};
+
+/// Boxed future returned by [`Authenticator::verify`], so the trait stays object safe.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Identity of a client which successfully went through authentication.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub username: String,
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.username)
+    }
+}
+
+/// Backend checking SOCKS5 username/password credentials (RFC 1929).
+///
+/// Implement this to plug an external user store (LDAP, database, ...). `Ok(None)` means the
+/// credentials were rejected, errors are reserved for backend failures.
+pub trait Authenticator: Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>>;
+}
+
+impl<A: Authenticator + ?Sized> Authenticator for Arc<A> {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        (**self).verify(username, password)
+    }
+}
+
+/// Policy choosing the authentication method of a SOCKS5 client among those it offers.
+///
+/// The server only implements [`AuthenticationMethod::None`], and
+/// [`AuthenticationMethod::UsernamePassword`] when it has an [`Authenticator`]. Any other choice,
+/// or a method the client did not offer, is answered with
+/// [`AuthenticationMethod::NotAcceptable`].
+pub trait MethodSelector: Send + Sync {
+    fn select<'a>(
+        &'a self,
+        peer: SocketAddr,
+        hello: &'a Hello,
+    ) -> BoxFuture<'a, io::Result<AuthenticationMethod>>;
+}
+
+impl<M: MethodSelector + ?Sized> MethodSelector for Arc<M> {
+    fn select<'a>(
+        &'a self,
+        peer: SocketAddr,
+        hello: &'a Hello,
+    ) -> BoxFuture<'a, io::Result<AuthenticationMethod>> {
+        (**self).select(peer, hello)
+    }
+}
+
+/// In-memory map of usernames to clear-text passwords.
+#[derive(Default, Clone)]
+pub struct StaticUserDb {
+    users: HashMap<String, String>,
+}
+
+/// Lists usernames only, secrets never show up in `Debug` output.
+fn debug_users(
+    name: &str,
+    users: &HashMap<String, String>,
+    f: &mut fmt::Formatter<'_>,
+) -> fmt::Result {
+    f.debug_struct(name)
+        .field(
+            "users",
+            &users
+                .keys()
+                .map(|u| (u, Redacted))
+                .collect::<HashMap<_, _>>(),
+        )
+        .finish()
+}
+
+impl fmt::Debug for StaticUserDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        debug_users("StaticUserDb", &self.users, f)
+    }
+}
+
+impl StaticUserDb {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user(&mut self, username: impl Into<String>, password: impl Into<String>) {
+        self.users.insert(username.into(), password.into());
+    }
+
+    pub fn with_user(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.add_user(username, password);
+        self
+    }
+
+    fn check(&self, username: &str, password: &str) -> Option<Identity> {
+        let expected = self.users.get(username)?;
+        constant_time_eq(expected.as_bytes(), password.as_bytes()).then(|| Identity {
+            username: username.into(),
+        })
+    }
+}
+
+impl<U: Into<String>, P: Into<String>> FromIterator<(U, P)> for StaticUserDb {
+    fn from_iter<T: IntoIterator<Item = (U, P)>>(iter: T) -> Self {
+        Self {
+            users: iter
+                .into_iter()
+                .map(|(u, p)| (u.into(), p.into()))
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for StaticUserDb {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(async move { Ok(self.check(username, password)) })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Users loaded from an Apache `htpasswd` file.
+///
+/// Hashes are checked with the `bcrypt` (`$2a$`, `$2b$`, `$2y$`) and `argon2` (`$argon2...`)
+/// features. Entries using any other scheme never match.
+#[derive(Default, Clone)]
+pub struct HtpasswdFile {
+    users: HashMap<String, String>,
+}
+
+impl fmt::Debug for HtpasswdFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        debug_users("HtpasswdFile", &self.users, f)
+    }
+}
+
+impl HtpasswdFile {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut users = HashMap::new();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line.split_once(':').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid htpasswd entry at line {}", lineno + 1),
+                )
+            })?;
+            users.insert(username.to_owned(), hash.to_owned());
+        }
+        Ok(Self { users })
+    }
+
+    async fn check(&self, username: &str, password: &str) -> io::Result<Option<Identity>> {
+        let Some(hash) = self.users.get(username) else {
+            return Ok(None);
+        };
+        // Slow by design, hashes are checked off the runtime threads.
+        #[cfg(feature = "async")]
+        let matched = {
+            let (hash, password) = (hash.clone(), password.to_owned());
+            tokio::task::spawn_blocking(move || verify_hash(&hash, &password))
+                .await
+                .map_err(io::Error::other)??
+        };
+        #[cfg(not(feature = "async"))]
+        let matched = verify_hash(hash, password)?;
+        Ok(matched.then(|| Identity {
+            username: username.into(),
+        }))
+    }
+}
+
+#[allow(unused_variables)]
+fn verify_hash(hash: &str, password: &str) -> io::Result<bool> {
+    #[cfg(feature = "bcrypt")]
+    if hash.starts_with("$2") {
+        return bcrypt::verify(password, hash)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+
+    #[cfg(feature = "argon2")]
+    if hash.starts_with("$argon2") {
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+        let parsed = PasswordHash::new(hash)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        return Ok(argon2::Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok());
+    }
+
+    log::warn!("Unsupported htpasswd hash scheme");
+    Ok(false)
+}
+
+impl Authenticator for HtpasswdFile {
+    fn verify<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(self.check(username, password))
+    }
+}
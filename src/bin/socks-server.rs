@@ -0,0 +1,135 @@
+//! SOCKS4/SOCKS5 proxy server built on the library.
+
+use std::{io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::Parser;
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+use socks_parser::ListenerOverrides;
+use socks_parser::{
+    acl::FileWatcherAcl,
+    auth::{HtpasswdFile, StaticUserDb},
+    handlers,
+    resolver::{CachingResolver, SystemResolver},
+    ConnectOptions, ConnectionRequest, Server,
+};
+use tokio::net::TcpListener;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Address to listen on.
+    #[arg(short, long, default_value = "127.0.0.1:1080")]
+    listen: SocketAddr,
+
+    /// Also accept connections redirected by iptables `REDIRECT` or `TPROXY` rules on this
+    /// address, relaying them to their original destination.
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    #[arg(long, value_name = "ADDR")]
+    transparent: Option<SocketAddr>,
+
+    /// Require authentication with this `username:password`, can be repeated. SOCKS4 clients,
+    /// which cannot authenticate, are then refused.
+    #[arg(
+        short,
+        long = "user",
+        value_name = "USER:PASSWORD",
+        conflicts_with = "htpasswd"
+    )]
+    users: Vec<String>,
+
+    /// Require authentication against an htpasswd file, refusing SOCKS4 clients.
+    #[arg(long, value_name = "FILE")]
+    htpasswd: Option<PathBuf>,
+
+    /// ACL file, reloaded when modified.
+    #[arg(long, value_name = "FILE")]
+    acl: Option<PathBuf>,
+
+    /// Maximum number of concurrent connections.
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Number of resolved domain names kept in cache, 0 to disable it.
+    #[arg(long, value_name = "NAMES", default_value_t = 1024)]
+    dns_cache: usize,
+
+    /// Set `TCP_NODELAY` on client and destination connections.
+    #[arg(long)]
+    tcp_nodelay: bool,
+
+    /// Enable TCP keepalive on client and destination connections, probing after this many
+    /// idle seconds.
+    #[arg(long, value_name = "SECS")]
+    tcp_keepalive: Option<u64>,
+
+    /// Log level, overridden by `RUST_LOG`.
+    #[arg(long, default_value = "info")]
+    log_level: log::LevelFilter,
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let args = Args::parse();
+    env_logger::Builder::new()
+        .filter_level(args.log_level)
+        .parse_default_env()
+        .init();
+
+    let listener = TcpListener::bind(args.listen).await?;
+    log::info!("Listening on {}", listener.local_addr()?);
+    let keepalive = args.tcp_keepalive.map(Duration::from_secs);
+    let mut server = Server::new(listener).with_tcp_nodelay(args.tcp_nodelay);
+    if let Some(time) = keepalive {
+        server = server.with_tcp_keepalive(time);
+    }
+
+    if let Some(ref path) = args.htpasswd {
+        server = server.with_authenticator(HtpasswdFile::load(path)?);
+    } else if !args.users.is_empty() {
+        let mut users = StaticUserDb::new();
+        for user in &args.users {
+            let (username, password) = user.split_once(':').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Expected USER:PASSWORD, got {user:?}"),
+                )
+            })?;
+            users.add_user(username, password);
+        }
+        server = server.with_authenticator(users);
+    }
+    if let Some(path) = args.acl {
+        server = server.with_acl(FileWatcherAcl::new(path)?);
+    }
+    if let Some(max) = args.max_connections {
+        server = server.with_max_connections(max);
+    }
+
+    let resolver = CachingResolver::new(SystemResolver, args.dns_cache);
+    let options = ConnectOptions {
+        nodelay: args.tcp_nodelay,
+        keepalive,
+        ..ConnectOptions::default()
+    };
+    let connect = handlers::connect_with(resolver, options);
+    let connect = move |req: ConnectionRequest| {
+        let destination = req.destination.clone();
+        let connect = connect.clone();
+        async move {
+            let (stream, bound) = connect(req).await?;
+            log::info!("{destination} -> {}", stream.peer_addr()?);
+            Ok((stream, bound))
+        }
+    };
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    if let Some(addr) = args.transparent {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!(
+            "Accepting redirected connections on {}",
+            listener.local_addr()?
+        );
+        server = server.with_transparent_listener(listener, ListenerOverrides::new());
+        return server.run_all(connect, handlers::relay).await;
+    }
+    server.run(connect, handlers::relay).await
+}
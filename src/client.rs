@@ -1,10 +1,84 @@
-use std::{io, net::SocketAddr};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
 
-use crate::{Version, Wire};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::{
+    error::invalid_data,
+    framing::read_message_exact,
+    proxy::{NoProxy, ProxyUrl, RetryPolicy},
+    stream::StreamTransform,
+    ClientError, DecodeLimits, Destination, EncodeBuffer, ParseError, Redacted, Version, Wire,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+};
 
-fn map_nom_error(e: nom::Err<nom::error::VerboseError<&[u8]>>) -> io::Error {
-    io::Error::new(io::ErrorKind::InvalidData, format!("{e:x?}"))
+/// Hello reply showing the proxy does not speak SOCKS5.
+#[derive(Debug)]
+struct NotSocks5(&'static str);
+
+impl fmt::Display for NotSocks5 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for NotSocks5 {}
+
+fn not_socks5(kind: io::ErrorKind, reason: &'static str) -> io::Error {
+    io::Error::new(kind, NotSocks5(reason))
+}
+
+/// Whether the proxy dropped a connection on which the request was sent along with the hello,
+/// rather than replying to it.
+fn objects_to_pipelining(e: &io::Error) -> bool {
+    is_not_socks5(e)
+        || matches!(
+            e.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+        )
+}
+
+fn is_not_socks5(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<NotSocks5>())
+}
+
+/// Connects with `dialer` to the first address of `proxy` accepting the connection.
+async fn dial_proxy<D: Dialer>(proxy: &ProxyUrl, dialer: &D) -> io::Result<D::Stream> {
+    let mut last_error = None;
+    for addr in tokio::net::lookup_host((proxy.host.as_str(), proxy.port)).await? {
+        match dialer.dial(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::HostUnreachable,
+            format!("No address for {proxy}"),
+        )
+    }))
+}
+
+/// Connects to `destination` without any proxy.
+async fn connect_direct(destination: &Destination) -> io::Result<TcpStream> {
+    match destination.addr {
+        crate::v5::AddressType::DomainName(ref name) => {
+            TcpStream::connect((name.as_str(), destination.port)).await
+        }
+        ref addr => match addr.to_socket_addr(destination.port) {
+            Some(addr) => TcpStream::connect(addr).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot connect directly to {destination}"),
+            )),
+        },
+    }
 }
 
 pub struct Client<S>
@@ -13,30 +87,178 @@ where
 {
     stream: S,
     version: Version,
+    credentials: Vec<Credentials>,
+    limits: DecodeLimits,
+    encoder: EncodeBuffer,
+    optimistic: bool,
+}
+
+/// Authentication offered by the client during a SOCKS5 handshake.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    None,
+    UsernamePassword { username: String, password: String },
 }
 
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::UsernamePassword { username, .. } => f
+                .debug_struct("UsernamePassword")
+                .field("username", username)
+                .field("password", &Redacted)
+                .finish(),
+        }
+    }
+}
+
+impl Credentials {
+    pub fn method(&self) -> crate::v5::AuthenticationMethod {
+        match self {
+            Self::None => crate::v5::AuthenticationMethod::None,
+            Self::UsernamePassword { .. } => crate::v5::AuthenticationMethod::UsernamePassword,
+        }
+    }
+}
+
+/// Destination of a request, as given to [`Client::connect`] and the like.
 pub trait IntoSocksAddr {
     fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16);
 }
 
+/// Destination of a request which may not convert, such as a URL without host.
+///
+/// Requests sent by a [`Client`] go through it, so that destinations which cannot be sent to a
+/// proxy fail right away.
+pub trait TryIntoSocksAddr {
+    fn try_into_socks_addr(self) -> io::Result<(crate::common::v5::AddressType, u16)>;
+}
+
+/// Rejects domain names which are empty, longer than 255 bytes or hold a NUL byte.
+impl<T: IntoSocksAddr> TryIntoSocksAddr for T {
+    fn try_into_socks_addr(self) -> io::Result<(crate::common::v5::AddressType, u16)> {
+        let (addr, port) = self.into_socks_addr();
+        check_encodable(&addr)?;
+        Ok((addr, port))
+    }
+}
+
+/// Fails for addresses whose encoding would not fit their length byte, or which no proxy
+/// could make sense of.
+fn check_encodable(addr: &crate::common::v5::AddressType) -> io::Result<()> {
+    use crate::common::v5::AddressType;
+
+    let reason = match addr {
+        AddressType::DomainName(ref name) if name.is_empty() => "empty",
+        AddressType::DomainName(ref name) if name.len() > u8::MAX as usize => "too long",
+        AddressType::DomainName(ref name) if name.contains('\0') => "NUL byte",
+        #[cfg(feature = "extensions")]
+        AddressType::UnixPath(ref path) if path.as_os_str().len() > u8::MAX as usize => "too long",
+        _ => return Ok(()),
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid destination {addr}: {reason}"),
+    ))
+}
+
 impl IntoSocksAddr for SocketAddr {
     fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
         (self.ip().into(), self.port())
     }
 }
 
+impl IntoSocksAddr for &SocketAddr {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        (*self).into_socks_addr()
+    }
+}
+
+impl IntoSocksAddr for (IpAddr, u16) {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        (self.0.into(), self.1)
+    }
+}
+
+impl IntoSocksAddr for (Ipv4Addr, u16) {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        (crate::common::v5::AddressType::IPv4(self.0), self.1)
+    }
+}
+
+impl IntoSocksAddr for (Ipv6Addr, u16) {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        (crate::common::v5::AddressType::IPv6(self.0), self.1)
+    }
+}
+
+impl IntoSocksAddr for (crate::common::v5::AddressType, u16) {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        self
+    }
+}
+
+impl IntoSocksAddr for crate::Destination {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        (self.addr, self.port)
+    }
+}
+
+/// Fails with `InvalidInput` if the URL has no host, or no port and a scheme without a known
+/// default port.
+#[cfg(feature = "url")]
+impl TryIntoSocksAddr for &url::Url {
+    fn try_into_socks_addr(self) -> io::Result<(crate::common::v5::AddressType, u16)> {
+        let (addr, port) = url_socks_addr(self)?;
+        check_encodable(&addr)?;
+        Ok((addr, port))
+    }
+}
+
+#[cfg(feature = "url")]
+impl TryIntoSocksAddr for url::Url {
+    fn try_into_socks_addr(self) -> io::Result<(crate::common::v5::AddressType, u16)> {
+        (&self).try_into_socks_addr()
+    }
+}
+
+#[cfg(feature = "url")]
+fn url_socks_addr(url: &url::Url) -> io::Result<(crate::common::v5::AddressType, u16)> {
+    use crate::common::v5::AddressType;
+
+    let invalid = |reason| io::Error::new(io::ErrorKind::InvalidInput, reason);
+    let addr = match url.host().ok_or_else(|| invalid("URL without host"))? {
+        url::Host::Domain(d) => AddressType::DomainName(d.into()),
+        url::Host::Ipv4(ip4) => AddressType::IPv4(ip4),
+        url::Host::Ipv6(ip6) => AddressType::IPv6(ip6),
+    };
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| invalid("URL without port"))?;
+    Ok((addr, port))
+}
+
+/// Goes through [`AddressType::domain`](crate::v5::AddressType::domain), names it rejects being
+/// sent as is.
 impl IntoSocksAddr for (String, u16) {
     fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
-        (crate::common::v5::AddressType::DomainName(self.0), self.1)
+        use crate::common::v5::AddressType;
+
+        let addr = AddressType::domain(&self.0).unwrap_or(AddressType::DomainName(self.0));
+        (addr, self.1)
+    }
+}
+
+impl IntoSocksAddr for (&String, u16) {
+    fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
+        (self.0.as_str(), self.1).into_socks_addr()
     }
 }
 
 impl IntoSocksAddr for (&str, u16) {
     fn into_socks_addr(self) -> (crate::common::v5::AddressType, u16) {
-        (
-            crate::common::v5::AddressType::DomainName(self.0.into()),
-            self.1,
-        )
+        (self.0.to_owned(), self.1).into_socks_addr()
     }
 }
 
@@ -49,100 +271,965 @@ where
     }
 
     pub fn new_with_version(stream: S, version: Version) -> Self {
-        Self { stream, version }
+        Self {
+            stream,
+            version,
+            credentials: vec![Credentials::None],
+            limits: DecodeLimits::default(),
+            encoder: EncodeBuffer::new(),
+            optimistic: false,
+        }
     }
 
-    async fn connect_v4(mut self, addr: impl IntoSocksAddr) -> io::Result<S> {
-        use crate::v4::*;
+    /// Bounds applied when decoding server messages.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
 
-        let (addr, port) = addr.into_socks_addr();
-        let addr: AddressType = addr.try_into()?;
+    /// Sets the authentication methods offered to the server, by order of preference.
+    pub fn with_credentials(mut self, credentials: impl IntoIterator<Item = Credentials>) -> Self {
+        self.credentials = credentials.into_iter().collect();
+        self
+    }
 
-        let mut buffer = Vec::new();
-        let req = Request {
-            command: Command::Connect,
+    /// Offers username/password authentication in preference to the other methods.
+    pub fn with_username_password(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.credentials.insert(
+            0,
+            Credentials::UsernamePassword {
+                username: username.into(),
+                password: password.into(),
+            },
+        );
+        self
+    }
+
+    /// Isolates the streams opened with this client from those using another `token`.
+    ///
+    /// Tor builds separate circuits for streams authenticated with distinct credentials, so
+    /// the token is offered as both username and password.
+    #[cfg(feature = "tor")]
+    pub fn with_isolation(self, token: impl Into<String>) -> Self {
+        let token = token.into();
+        self.with_username_password(token.clone(), token)
+    }
+
+    /// Sends the request of [`connect`](Self::connect) right after the SOCKS5 hello, without
+    /// waiting for the proxy to select a method, which saves a round trip.
+    ///
+    /// Only done when no authentication is offered, leaving the proxy no other method to
+    /// select. Proxies reading the hello alone may close the connection on seeing the request
+    /// this early, failing the connection: [`Client::connect_tcp`] then connects again without
+    /// it, see [`ConnectOptions::optimistic_handshake`].
+    pub fn with_optimistic_handshake(mut self, optimistic: bool) -> Self {
+        self.optimistic = optimistic;
+        self
+    }
+
+    /// Whether [`connect`](Self::connect) sends its request along with the hello.
+    fn pipelines_request(&self) -> bool {
+        self.optimistic
+            && self.version == Version::Socks5
+            && self.credentials == [Credentials::None]
+    }
+
+    /// Negotiates the authentication method, sending `pipelined` right after the hello if any.
+    async fn negotiate_v5(
+        &mut self,
+        pipelined: Option<&crate::v5::Request>,
+    ) -> io::Result<crate::v5::AuthenticationMethod> {
+        use crate::v5::*;
+
+        // Several credentials may use the same method, offered once as servers expect.
+        let mut methods = Vec::with_capacity(self.credentials.len());
+        for method in self.credentials.iter().map(Credentials::method) {
+            if !methods.contains(&method) {
+                methods.push(method);
+            }
+        }
+        let hello = Hello { methods };
+        hello.validate()?;
+        let buffer = self.encoder.reset();
+        hello.encode_into(buffer);
+        match pipelined {
+            Some(request) => {
+                log::trace!("Sending {hello:?} along with {request:?}");
+                request.encode_into(buffer);
+            }
+            None => log::trace!("Sending {hello:?}"),
+        }
+        self.stream.write_all(self.encoder.as_slice()).await?;
+
+        // Exactly as many bytes as the reply, leaving whatever the proxy sent next.
+        let mut reply = [0; 2];
+        match self.stream.read_exact(&mut reply).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(not_socks5(e.kind(), "Proxy closed the connection"))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => {
+                return Err(not_socks5(e.kind(), "Proxy reset the connection"))
+            }
+            result => result?,
+        };
+        if reply[0] != Version::Socks5 as u8 {
+            return Err(not_socks5(
+                io::ErrorKind::InvalidData,
+                "Proxy replied with another SOCKS version",
+            ));
+        }
+        let (_, hello_response) =
+            HelloResponse::decode_with_limits::<nom::error::VerboseError<_>>(&reply, &self.limits)
+                .map_err(|e| ClientError::Protocol(ParseError::new(&reply, e)))?;
+        log::trace!("Received {hello_response:?}");
+
+        let credentials = self
+            .credentials
+            .iter()
+            .find(|c| c.method() == hello_response.method)
+            .ok_or(ClientError::MethodNegotiationFailed {
+                offered: hello.methods,
+                selected: hello_response.method,
+            })?;
+
+        match credentials {
+            Credentials::None => {}
+            Credentials::UsernamePassword { username, password } => {
+                let auth = UsernamePassword {
+                    username: username.clone(),
+                    password: password.clone(),
+                };
+                log::trace!("Sending username/password for {username:?}");
+                self.stream.write_all(self.encoder.encode(&auth)).await?;
+
+                let auth_response: UsernamePasswordResponse =
+                    read_message_exact(&mut self.stream, 2, &self.limits)
+                        .await
+                        .map_err(ClientError::protocol)?;
+                log::trace!("Received {auth_response:?}");
+                if !auth_response.success {
+                    return Err(ClientError::AuthenticationRejected.into());
+                }
+            }
+        }
+
+        Ok(hello_response.method)
+    }
+
+    /// Runs the method negotiation and authentication, without sending any request yet.
+    ///
+    /// SOCKS4 has no such phase, so no data is exchanged in that case.
+    pub async fn handshake_only(mut self) -> io::Result<NegotiatedStream<S>> {
+        let method = match self.version {
+            Version::Socks4 => None,
+            Version::Socks5 => Some(self.negotiate_v5(None).await?),
+        };
+        Ok(self.negotiated(method))
+    }
+
+    pub(crate) fn negotiated(
+        self,
+        method: Option<crate::v5::AuthenticationMethod>,
+    ) -> NegotiatedStream<S> {
+        NegotiatedStream {
+            stream: self.stream,
+            version: self.version,
+            method,
+            bound: None,
+            limits: self.limits,
+            encoder: self.encoder,
+        }
+    }
+
+    /// Connects to `addr` sending the request along with the hello, see
+    /// [`with_optimistic_handshake`](Self::with_optimistic_handshake).
+    async fn connect_optimistic(
+        mut self,
+        addr: crate::v5::AddressType,
+        port: u16,
+    ) -> io::Result<S> {
+        let request = crate::v5::Request {
+            command: crate::v5::Command::Connect,
+            rsv: 0,
             addr,
             port,
-            secret: None,
         };
-        req.encode_into(&mut buffer);
-        log::trace!("Sending {req:?}");
-        self.stream.write_all(&buffer[..]).await?;
+        let method = self.negotiate_v5(Some(&request)).await?;
+        let mut negotiated = self.negotiated(Some(method));
+        negotiated.read_reply_v5().await?;
+        Ok(negotiated.into_inner())
+    }
 
-        buffer.clear();
-        let n = self.stream.read_buf(&mut buffer).await?;
-        let (_, response) =
-            Response::decode::<nom::error::VerboseError<_>>(&buffer[..n]).map_err(map_nom_error)?;
-        log::trace!("Received {response:?}");
+    /// Connects to `addr`, retrying with SOCKS4a when the proxy does not speak SOCKS5.
+    ///
+    /// The downgrade happens when the proxy closes the connection or replies with another
+    /// version to the SOCKS5 hello. `dial` then provides the fresh stream to the proxy on which
+    /// the SOCKS4a request is sent, with this client's decode limits.
+    pub async fn connect_or_downgrade<D, F>(
+        self,
+        addr: impl TryIntoSocksAddr,
+        dial: D,
+    ) -> io::Result<S>
+    where
+        D: FnOnce() -> F,
+        F: Future<Output = io::Result<S>>,
+    {
+        let (addr, port) = addr.try_into_socks_addr()?;
+        let destination = Destination { addr, port };
+        if self.version != Version::Socks5 {
+            return self.connect(destination).await;
+        }
+        let limits = self.limits;
+        match self.connect(destination.clone()).await {
+            Err(e) if is_not_socks5(&e) => {
+                log::debug!("Downgrading to SOCKS4a: {e}");
+                Self::new_with_version(dial().await?, Version::Socks4)
+                    .with_decode_limits(limits)
+                    .connect(destination)
+                    .await
+            }
+            result => result,
+        }
+    }
 
-        if response.status == Status::Success {
-            Ok(self.stream)
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("{s:?}", s = response.status),
-            ))
+    /// Asks a Tor proxy to resolve `host`, using the RESOLVE extension.
+    #[cfg(feature = "tor")]
+    pub async fn resolve(self, host: &str) -> io::Result<IpAddr> {
+        let mut negotiated = self.handshake_only().await?;
+        let bound = negotiated
+            .request(crate::v5::Command::TorResolve, (host, 0))
+            .await?;
+        bound
+            .addr
+            .to_socket_addr(0)
+            .map(|addr| addr.ip())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Proxy resolved {host} to {}", bound.addr),
+                )
+            })
+    }
+
+    /// Asks a Tor proxy for the name of `ip`, using the RESOLVE_PTR extension.
+    #[cfg(feature = "tor")]
+    pub async fn resolve_ptr(self, ip: IpAddr) -> io::Result<String> {
+        let mut negotiated = self.handshake_only().await?;
+        let bound = negotiated
+            .request(crate::v5::Command::TorResolvePtr, (ip, 0))
+            .await?;
+        match bound.addr {
+            crate::v5::AddressType::DomainName(name) => Ok(name),
+            addr => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Proxy resolved {ip} to {addr}"),
+            )),
         }
     }
 
-    async fn connect_v5(mut self, addr: impl IntoSocksAddr) -> io::Result<S> {
-        use crate::v5::*;
+    /// Asks the proxy to connect to `addr`, returning the tunnel.
+    ///
+    /// No byte past the proxy replies is read, so data the destination sends right away is
+    /// left in the returned stream.
+    pub async fn connect(self, addr: impl TryIntoSocksAddr) -> io::Result<S> {
+        let (addr, port) = addr.try_into_socks_addr()?;
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "socks_client",
+            version = self.version as u8,
+            destination = %Destination { addr: addr.clone(), port },
+        );
+        let connect = async move {
+            if self.pipelines_request() {
+                return self.connect_optimistic(addr, port).await;
+            }
+            let mut negotiated = self.handshake_only().await?;
+            negotiated
+                .request(crate::v5::Command::Connect, (addr, port))
+                .await?;
+            Ok(negotiated.into_inner())
+        };
+        #[cfg(feature = "tracing")]
+        let connect = tracing::Instrument::instrument(
+            async move {
+                let result = connect.await;
+                match result {
+                    Ok(_) => tracing::debug!("tunnel established"),
+                    Err(ref e) => tracing::debug!(error = %e, "tunnel failed"),
+                }
+                result
+            },
+            span,
+        );
+        connect.await
+    }
+
+    /// Asks the proxy to connect to `addr`, then wraps the tunnel with `transform`, to speak TLS
+    /// or any other protocol the proxy expects once the handshake is done.
+    pub async fn connect_transformed<T>(
+        self,
+        addr: impl TryIntoSocksAddr,
+        transform: &T,
+    ) -> io::Result<T::Output>
+    where
+        T: StreamTransform<S>,
+    {
+        let stream = self.connect(addr).await?;
+        transform.transform(stream).await
+    }
+
+    /// Connects to the proxy at `proxy` with `dialer`, then asks it to connect to `addr`.
+    ///
+    /// Every address `proxy` resolves to is tried in turn, as [`Client::connect_tcp`] does.
+    pub async fn connect_dialed<D>(
+        proxy: impl tokio::net::ToSocketAddrs,
+        addr: impl TryIntoSocksAddr,
+        dialer: &D,
+    ) -> io::Result<S>
+    where
+        D: Dialer<Stream = S>,
+    {
+        let addr = addr.try_into_socks_addr()?;
+        let proxy_addrs = tokio::net::lookup_host(proxy).await?;
+        Self::connect_first(dialer, proxy_addrs, addr, false).await
+    }
+
+    /// Asks the first proxy address `dialer` connects to for `addr`, sending the request along
+    /// with the hello if `optimistic`, and connecting again without it if the proxy objects.
+    async fn connect_first<D>(
+        dialer: &D,
+        proxy_addrs: impl IntoIterator<Item = SocketAddr>,
+        addr: (crate::common::v5::AddressType, u16),
+        optimistic: bool,
+    ) -> io::Result<S>
+    where
+        D: Dialer<Stream = S>,
+    {
+        let mut last_error = None;
+        for proxy_addr in proxy_addrs {
+            match dialer.dial(proxy_addr).await {
+                Ok(stream) if optimistic => {
+                    let client = Self::new(stream).with_optimistic_handshake(true);
+                    match client.connect(addr.clone()).await {
+                        Err(e) if objects_to_pipelining(&e) => {
+                            log::debug!(
+                                "Proxy {proxy_addr} objected to the pipelined request: {e}"
+                            );
+                            let stream = dialer
+                                .dial(proxy_addr)
+                                .await
+                                .map_err(ClientError::ProxyUnreachable)?;
+                            return Self::new(stream).connect(addr).await;
+                        }
+                        result => return result,
+                    }
+                }
+                Ok(stream) => return Self::new(stream).connect(addr).await,
+                Err(e) => {
+                    log::debug!("Could not connect to proxy {proxy_addr}: {e}");
+                    last_error = Some(ClientError::ProxyUnreachable(e).into());
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No usable address for the proxy",
+            )
+        }))
+    }
+
+    /// Opens a connection to `proxy` with `dialer`, configured as [`Client::dial`] does.
+    pub async fn dial_with<D>(proxy: &ProxyUrl, dialer: &D) -> io::Result<Self>
+    where
+        D: Dialer<Stream = S>,
+    {
+        let stream = dial_proxy(proxy, dialer)
+            .await
+            .map_err(ClientError::ProxyUnreachable)?;
+        Ok(Self::for_proxy(stream, proxy))
+    }
+
+    /// Client over `stream`, already connected to `proxy`, configured as [`Client::dial`] does.
+    pub(crate) fn for_proxy(stream: S, proxy: &ProxyUrl) -> Self {
+        let client = Self::new_with_version(stream, proxy.version);
+        match proxy.credentials {
+            Some((ref username, ref password)) => {
+                client.with_username_password(username.as_str(), password.as_str())
+            }
+            None => client,
+        }
+    }
+}
+
+/// Stream on which the SOCKS handshake has been done, ready to send requests.
+pub struct NegotiatedStream<S> {
+    stream: S,
+    version: Version,
+    method: Option<crate::v5::AuthenticationMethod>,
+    bound: Option<Destination>,
+    limits: DecodeLimits,
+    encoder: EncodeBuffer,
+}
 
-        let mut buffer = Vec::new();
-        let hello = Hello {
-            methods: vec![AuthenticationMethod::None],
+impl<S> NegotiatedStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Authentication method selected by a SOCKS5 server.
+    pub fn method(&self) -> Option<crate::v5::AuthenticationMethod> {
+        self.method
+    }
+
+    /// Address reported by the server in reply to the last request.
+    pub fn bound_addr(&self) -> Option<&Destination> {
+        self.bound.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Sends a request and waits for its reply, returning the bound address.
+    ///
+    /// Issuing another request afterwards only works with proxies accepting several requests
+    /// on the same connection.
+    pub async fn request(
+        &mut self,
+        command: crate::v5::Command,
+        addr: impl TryIntoSocksAddr,
+    ) -> io::Result<Destination> {
+        let (addr, port) = addr.try_into_socks_addr()?;
+        let bound = match self.version {
+            Version::Socks4 => self.request_v4(command, addr, port).await?,
+            Version::Socks5 => self.request_v5(command, addr, port).await?,
         };
-        hello.encode_into(&mut buffer);
-        log::trace!("Sending {hello:?}");
-        self.stream.write_all(&buffer[..]).await?;
+        self.bound = Some(bound.clone());
+        Ok(bound)
+    }
 
-        let n = self.stream.read_buf(&mut buffer).await?;
-        let (_, hello_response) =
-            HelloResponse::decode::<nom::error::VerboseError<_>>(&buffer[..n])
-                .map_err(map_nom_error)?;
-        log::trace!("Received {hello_response:?}");
+    async fn request_v4(
+        &mut self,
+        command: crate::v5::Command,
+        addr: crate::v5::AddressType,
+        port: u16,
+    ) -> io::Result<Destination> {
+        use crate::v4::*;
 
-        match hello_response.method {
-            AuthenticationMethod::None => {}
-            // TODO: handle username/password authentication?
-            _ => {
+        let command = match command {
+            crate::v5::Command::Connect => Command::Connect,
+            crate::v5::Command::Bind => Command::Bind,
+            crate::v5::Command::UdpAssociate => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Socks v4 does not support UDP associate",
+                ))
+            }
+            #[cfg(feature = "tor")]
+            crate::v5::Command::TorResolve | crate::v5::Command::TorResolvePtr => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Socks v4 does not support Tor extensions",
+                ))
+            }
+            #[cfg(feature = "mux")]
+            crate::v5::Command::Mux => {
                 return Err(io::Error::new(
                     io::ErrorKind::Unsupported,
-                    "Does not support any authentication method",
+                    "Socks v4 does not support multiplexing",
                 ))
             }
+            #[cfg(feature = "extensions")]
+            crate::v5::Command::Other(code) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Socks v4 does not support command {code:#04x}"),
+                ))
+            }
+        };
+
+        let req = Request {
+            command,
+            addr: addr.try_into()?,
+            port,
+            secret: None,
+        };
+        req.try_encode_into(self.encoder.reset(), Request::DEFAULT_MARKER)?;
+        log::trace!("Sending {req:?}");
+        self.stream.write_all(self.encoder.as_slice()).await?;
+
+        let response: Response = read_message_exact(&mut self.stream, 8, &self.limits)
+            .await
+            .map_err(ClientError::protocol)?;
+        log::trace!("Received {response:?}");
+
+        if response.status.is_success() {
+            Ok(Destination {
+                addr: crate::v5::AddressType::IPv4(response.addr),
+                port: response.port,
+            })
+        } else {
+            Err(ClientError::RequestDenied(response.status.into()).into())
         }
+    }
+
+    async fn request_v5(
+        &mut self,
+        command: crate::v5::Command,
+        addr: crate::v5::AddressType,
+        port: u16,
+    ) -> io::Result<Destination> {
+        use crate::v5::*;
 
-        let (addr, port) = addr.into_socks_addr();
-        buffer.clear();
         let req = Request {
-            command: Command::Connect,
+            command,
+            rsv: 0,
             addr,
             port,
         };
-        req.encode_into(&mut buffer);
         log::trace!("Sending {req:?}");
-        self.stream.write_all(&buffer[..]).await?;
+        self.stream.write_all(self.encoder.encode(&req)).await?;
+        self.read_reply_v5().await
+    }
+
+    async fn read_reply_v5(&mut self) -> io::Result<Destination> {
+        use crate::v5::*;
 
-        let n = self.stream.read_buf(&mut buffer).await?;
-        let (_, response) =
-            Response::decode::<nom::error::VerboseError<_>>(&buffer[..n]).map_err(map_nom_error)?;
+        // Replies with an empty domain name are the shortest.
+        let response: Response = read_message_exact(&mut self.stream, 7, &self.limits)
+            .await
+            .map_err(ClientError::protocol)?;
         log::trace!("Received {response:?}");
 
-        if response.status == Status::Success {
-            Ok(self.stream)
+        if response.status.is_success() {
+            Ok(Destination {
+                addr: response.addr,
+                port: response.port,
+            })
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("{s:?}", s = response.status),
-            ))
+            Err(ClientError::RequestDenied(response.status).into())
+        }
+    }
+}
+
+/// What [`Client::probe`] learnt about a proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeReport {
+    pub version: Version,
+    /// Shortest hello round trip, or time to connect for SOCKS4 proxies which have no hello.
+    pub rtt: Duration,
+    /// Methods accepted by a SOCKS5 proxy, among no authentication, GSSAPI and
+    /// username/password.
+    pub methods: Vec<crate::v5::AuthenticationMethod>,
+}
+
+impl ProbeReport {
+    /// Whether a client configured from `proxy` can authenticate.
+    pub fn usable_by(&self, proxy: &ProxyUrl) -> bool {
+        use crate::v5::AuthenticationMethod;
+
+        self.version == Version::Socks4
+            || self.methods.contains(&AuthenticationMethod::None)
+            || proxy.credentials.is_some()
+                && self
+                    .methods
+                    .contains(&AuthenticationMethod::UsernamePassword)
+    }
+}
+
+/// Socket configuration of outgoing connections, applied by [`Client::connect_tcp`] to the
+/// connection to the proxy and by [`handlers::connect_with`](crate::handlers::connect_with) to
+/// those to destinations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectOptions {
+    /// Sets `TCP_NODELAY`.
+    pub nodelay: bool,
+    /// Enables TCP keepalive, probing after this much idle time.
+    pub keepalive: Option<Duration>,
+    /// Local address to bind to, selecting the egress address on multi-homed hosts.
+    pub local_addr: Option<SocketAddr>,
+    /// Network interface to bind to (`SO_BINDTODEVICE`), only supported on Linux.
+    pub interface: Option<String>,
+    /// Gives up connecting to the proxy after this long.
+    pub connect_timeout: Option<Duration>,
+    /// Sends the SOCKS5 request along with the hello, see
+    /// [`Client::with_optimistic_handshake`], connecting again without it if the proxy closes
+    /// the connection instead of replying. Not used for connections to destinations.
+    pub optimistic_handshake: bool,
+}
+
+impl ConnectOptions {
+    pub(crate) async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.set_tcp_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(ref interface) = self.interface {
+            #[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+            socket.bind_device(Some(interface.as_bytes()))?;
+            #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "fuchsia")))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Cannot bind to interface {interface:?} on this platform"),
+            ));
+        }
+        if let Some(local_addr) = self.local_addr {
+            socket.bind(&local_addr.into())?;
+        }
+
+        let connect = TcpSocket::from_std_stream(socket.into()).connect(addr);
+        match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect).await.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connection to {addr} timed out"),
+                )
+            })?,
+            None => connect.await,
+        }
+    }
+}
+
+/// Transport the convenience APIs of [`Client`] reach proxies over, such as TCP configured by
+/// [`ConnectOptions`], TLS, SSH channels, or a virtual network in tests.
+///
+/// Used by [`Client::connect_dialed`], [`Client::dial_with`],
+/// [`UpstreamPool::with_dialer`](crate::pool::UpstreamPool::with_dialer) and
+/// [`handlers::chain_through`](crate::handlers::chain_through). Unlike
+/// [`handlers::Dialer`](crate::handlers::Dialer), which opens streams to the destinations of
+/// requests, it only ever connects to socket addresses.
+pub trait Dialer: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn dial(&self, addr: SocketAddr) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+impl Dialer for ConnectOptions {
+    type Stream = TcpStream;
+
+    fn dial(&self, addr: SocketAddr) -> impl Future<Output = io::Result<TcpStream>> + Send {
+        self.connect(addr)
+    }
+}
+
+impl<D: Dialer + ?Sized> Dialer for std::sync::Arc<D> {
+    type Stream = D::Stream;
+
+    fn dial(&self, addr: SocketAddr) -> impl Future<Output = io::Result<D::Stream>> + Send {
+        (**self).dial(addr)
+    }
+}
+
+impl<F, Fut, S> Dialer for F
+where
+    F: Fn(SocketAddr) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<S>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Stream = S;
+
+    fn dial(&self, addr: SocketAddr) -> impl Future<Output = io::Result<S>> + Send {
+        self(addr)
+    }
+}
+
+impl Client<TcpStream> {
+    /// Connects to the proxy at `proxy` with `options`, then asks it to connect to `addr`.
+    ///
+    /// Every address `proxy` resolves to is tried in turn.
+    ///
+    /// ```no_run
+    /// # async fn run() -> std::io::Result<()> {
+    /// use std::time::Duration;
+    ///
+    /// use socks_parser::{Client, ConnectOptions};
+    ///
+    /// let options = ConnectOptions {
+    ///     connect_timeout: Some(Duration::from_secs(5)),
+    ///     ..ConnectOptions::default()
+    /// };
+    /// let stream = Client::connect_tcp("127.0.0.1:1080", ("example.com", 443), &options).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn connect_tcp(
+        proxy: impl tokio::net::ToSocketAddrs,
+        addr: impl TryIntoSocksAddr,
+        options: &ConnectOptions,
+    ) -> io::Result<TcpStream> {
+        let addr = addr.try_into_socks_addr()?;
+        let proxy_addrs = tokio::net::lookup_host(proxy).await?.filter(|proxy_addr| {
+            options
+                .local_addr
+                .is_none_or(|local| local.is_ipv4() == proxy_addr.is_ipv4())
+        });
+        Self::connect_first(options, proxy_addrs, addr, options.optimistic_handshake).await
+    }
+
+    /// Dials `proxy` and asks it to connect to `addr`.
+    pub async fn connect_via(
+        proxy: &ProxyUrl,
+        addr: impl TryIntoSocksAddr,
+    ) -> io::Result<TcpStream> {
+        let addr = addr.try_into_socks_addr()?;
+        Self::dial(proxy).await?.connect(addr).await
+    }
+
+    /// Connects to `addr` the way `selector` picks for it, falling back to its next choices
+    /// when one fails.
+    #[cfg(feature = "pac")]
+    pub async fn connect_selected(
+        selector: &crate::pac::ProxySelector,
+        addr: impl TryIntoSocksAddr,
+    ) -> io::Result<TcpStream> {
+        use crate::pac::ProxyChoice;
+
+        let (addr, port) = addr.try_into_socks_addr()?;
+        let destination = Destination { addr, port };
+        let mut last_error = None;
+        for choice in selector.select(&destination) {
+            let result = match choice {
+                ProxyChoice::Proxy(proxy) => Self::connect_via(proxy, destination.clone()).await,
+                ProxyChoice::Direct => connect_direct(&destination).await,
+            };
+            match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    log::debug!("Could not reach {destination} with {choice:?}: {e}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No way to reach {destination}"),
+            )
+        }))
+    }
+
+    /// Connects to `addr` through the proxy set by `ALL_PROXY`, unless it is listed by
+    /// `NO_PROXY` or no proxy is set, as command line tools usually do.
+    pub async fn connect_via_env(addr: impl TryIntoSocksAddr) -> io::Result<TcpStream> {
+        let (addr, port) = addr.try_into_socks_addr()?;
+        let destination = Destination { addr, port };
+        match ProxyUrl::from_env()? {
+            Some(proxy) if !NoProxy::from_env().matches(&destination) => {
+                Self::connect_via(&proxy, destination).await
+            }
+            _ => connect_direct(&destination).await,
         }
     }
 
-    pub async fn connect(self, addr: impl IntoSocksAddr) -> io::Result<S> {
-        match self.version {
-            Version::Socks4 => self.connect_v4(addr).await,
-            Version::Socks5 => self.connect_v5(addr).await,
+    /// Opens a connection to `proxy`, configured with its version and credentials.
+    pub async fn dial(proxy: &ProxyUrl) -> io::Result<Self> {
+        Self::dial_with(proxy, &ConnectOptions::default()).await
+    }
+
+    /// Checks that `proxy` answers, without sending any request.
+    ///
+    /// Every authentication method is offered alone on its own connection, to learn all the
+    /// ones the proxy accepts. The credentials of `proxy`, if any, are checked as well. Fails
+    /// with `TimedOut` if it takes longer than `timeout` overall.
+    pub async fn probe(proxy: &ProxyUrl, timeout: Duration) -> io::Result<ProbeReport> {
+        Self::probe_with(proxy, timeout, &ConnectOptions::default()).await
+    }
+
+    /// Same as [`Client::probe`], connecting to `proxy` with `dialer`.
+    pub async fn probe_with<D: Dialer>(
+        proxy: &ProxyUrl,
+        timeout: Duration,
+        dialer: &D,
+    ) -> io::Result<ProbeReport> {
+        tokio::time::timeout(timeout, Self::probe_methods(proxy, dialer))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Probing {proxy} timed out"),
+                )
+            })?
+    }
+
+    async fn probe_methods<D: Dialer>(proxy: &ProxyUrl, dialer: &D) -> io::Result<ProbeReport> {
+        use crate::v5::{
+            AuthenticationMethod, Hello, HelloResponse, UsernamePassword, UsernamePasswordResponse,
+        };
+
+        let connect = || dial_proxy(proxy, dialer);
+        if proxy.version == Version::Socks4 {
+            let start = Instant::now();
+            connect().await?;
+            return Ok(ProbeReport {
+                version: Version::Socks4,
+                rtt: start.elapsed(),
+                methods: Vec::new(),
+            });
         }
+
+        let limits = DecodeLimits::default();
+        let mut encoder = EncodeBuffer::new();
+        let mut rtt = Duration::MAX;
+        let mut methods = Vec::new();
+        for method in [
+            AuthenticationMethod::None,
+            AuthenticationMethod::Gssapi,
+            AuthenticationMethod::UsernamePassword,
+        ] {
+            let mut stream = connect().await?;
+            let hello = Hello {
+                methods: vec![method],
+            };
+            let start = Instant::now();
+            stream.write_all(encoder.encode(&hello)).await?;
+            let mut reply = [0; 2];
+            stream.read_exact(&mut reply).await?;
+            rtt = rtt.min(start.elapsed());
+            if reply[0] != Version::Socks5 as u8 {
+                return Err(not_socks5(
+                    io::ErrorKind::InvalidData,
+                    "Proxy replied with another SOCKS version",
+                ));
+            }
+            let (_, response) =
+                HelloResponse::decode_with_limits::<nom::error::VerboseError<_>>(&reply, &limits)
+                    .map_err(invalid_data(&reply))?;
+            if response.method != method {
+                continue;
+            }
+            methods.push(method);
+
+            if let (AuthenticationMethod::UsernamePassword, Some((username, password))) =
+                (method, &proxy.credentials)
+            {
+                let auth = UsernamePassword {
+                    username: username.clone(),
+                    password: password.clone(),
+                };
+                stream.write_all(encoder.encode(&auth)).await?;
+                let auth_response: UsernamePasswordResponse =
+                    read_message_exact(&mut stream, 2, &limits).await?;
+                if !auth_response.success {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("{proxy} rejected credentials"),
+                    ));
+                }
+            }
+        }
+        Ok(ProbeReport {
+            version: Version::Socks5,
+            rtt,
+            methods,
+        })
+    }
+
+    /// Tries every proxy in turn until one of them connects to `addr`.
+    ///
+    /// A failing proxy is followed by the next one right away, the whole list being retried
+    /// according to `policy` once every proxy failed. Returns the tunnel along with the proxy
+    /// which established it.
+    pub async fn connect_with_fallback(
+        proxies: &[ProxyUrl],
+        addr: impl TryIntoSocksAddr,
+        policy: RetryPolicy,
+    ) -> io::Result<(TcpStream, &ProxyUrl)> {
+        let (addr, port) = addr.try_into_socks_addr()?;
+        let destination = Destination { addr, port };
+        let mut last_error = None;
+
+        for round in 0..policy.max_rounds {
+            if round > 0 && last_error.is_some() {
+                tokio::time::sleep(policy.backoff(round - 1)).await;
+            }
+            for proxy in proxies {
+                match Self::connect_via(proxy, destination.clone()).await {
+                    Ok(stream) => return Ok((stream, proxy)),
+                    Err(e) => {
+                        log::debug!("Could not connect through {proxy}: {e}");
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        Err(match last_error {
+            Some(e) => io::Error::new(e.kind(), format!("All proxies failed, last error: {e}")),
+            None => io::Error::new(io::ErrorKind::InvalidInput, "No proxy to connect through"),
+        })
+    }
+}
+
+#[cfg(feature = "quic")]
+impl Client<crate::quic::QuicStream> {
+    /// Opens a SOCKS session on a new bidirectional stream of `connection`.
+    pub async fn open_quic(connection: &quinn::Connection) -> io::Result<Self> {
+        let (send, recv) = connection.open_bi().await?;
+        Ok(Self::new(tokio::io::join(recv, send)))
+    }
+
+    /// Asks the server at the other end of `connection` to connect to `addr`.
+    pub async fn connect_quic(
+        connection: &quinn::Connection,
+        addr: impl TryIntoSocksAddr,
+    ) -> io::Result<crate::quic::QuicStream> {
+        Self::open_quic(connection).await?.connect(addr).await
+    }
+
+    /// Sets up a UDP association relayed over the datagrams of `connection`.
+    pub async fn associate_quic(
+        self,
+        connection: quinn::Connection,
+    ) -> io::Result<crate::quic::DatagramAssociation> {
+        let mut negotiated = self.handshake_only().await?;
+        negotiated
+            .request(
+                crate::v5::Command::UdpAssociate,
+                SocketAddr::from(([0, 0, 0, 0], 0)),
+            )
+            .await?;
+        Ok(crate::quic::DatagramAssociation {
+            connection,
+            _control: negotiated.into_inner(),
+        })
+    }
+}
+
+#[cfg(feature = "mux")]
+impl<S> Client<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Negotiates with the proxy then turns the connection into a multiplexed session, on
+    /// which every tunnel skips the handshake, see [`mux`](crate::mux).
+    ///
+    /// Only servers running [`Server::serve_mux`](crate::Server::serve_mux) grant it.
+    pub async fn open_mux(self) -> io::Result<crate::mux::MuxSession> {
+        let limits = self.limits;
+        let mut negotiated = self.handshake_only().await?;
+        negotiated
+            .request(crate::v5::Command::Mux, SocketAddr::from(([0, 0, 0, 0], 0)))
+            .await?;
+        Ok(crate::mux::MuxSession::new(negotiated.into_inner(), limits))
     }
 }
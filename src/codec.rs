@@ -0,0 +1,283 @@
+//! [`tokio_util::codec`] adapters framing SOCKS handshakes.
+//!
+//! Wrap a stream in a [`Framed`](tokio_util::codec::Framed) with [`SocksClientCodec`] or
+//! [`SocksServerCodec`] to send and receive typed handshake messages. Each codec knows which
+//! message comes next from those already exchanged. Once the handshake is over, it decodes
+//! nothing more: data relayed afterwards stays in the read buffer, which
+//! [`Framed::into_parts`](tokio_util::codec::Framed::into_parts) hands back with the stream.
+//!
+//! Username/password is the only sub-negotiation framed, other methods ending the handshake.
+
+use std::{collections::VecDeque, io};
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    framing::decode_message, request::v5::USERNAME_PASSWORD_VERSION, v4, v5, DecodeLimits,
+    ParseMode, Wire,
+};
+
+/// Message sent by a client during the handshake.
+#[derive(Debug)]
+pub enum ClientMessage {
+    V4(v4::Request),
+    Hello(v5::Hello),
+    UsernamePassword(v5::UsernamePassword),
+    Request(v5::Request),
+}
+
+impl ClientMessage {
+    fn encode_into_bytes(&self, buffer: &mut BytesMut) {
+        match self {
+            Self::V4(ref req) => req.encode_into_bytes(buffer),
+            Self::Hello(ref hello) => hello.encode_into_bytes(buffer),
+            Self::UsernamePassword(ref creds) => creds.encode_into_bytes(buffer),
+            Self::Request(ref req) => req.encode_into_bytes(buffer),
+        }
+    }
+}
+
+impl From<v4::Request> for ClientMessage {
+    fn from(value: v4::Request) -> Self {
+        Self::V4(value)
+    }
+}
+
+impl From<v5::Hello> for ClientMessage {
+    fn from(value: v5::Hello) -> Self {
+        Self::Hello(value)
+    }
+}
+
+impl From<v5::UsernamePassword> for ClientMessage {
+    fn from(value: v5::UsernamePassword) -> Self {
+        Self::UsernamePassword(value)
+    }
+}
+
+impl From<v5::Request> for ClientMessage {
+    fn from(value: v5::Request) -> Self {
+        Self::Request(value)
+    }
+}
+
+/// Message sent by a server during the handshake.
+#[derive(Debug)]
+pub enum ServerMessage {
+    V4(v4::Response),
+    Hello(v5::HelloResponse),
+    UsernamePassword(v5::UsernamePasswordResponse),
+    Response(v5::Response),
+}
+
+impl ServerMessage {
+    fn encode_into_bytes(&self, buffer: &mut BytesMut) {
+        match self {
+            Self::V4(ref resp) => resp.encode_into_bytes(buffer),
+            Self::Hello(ref hello) => hello.encode_into_bytes(buffer),
+            Self::UsernamePassword(ref resp) => resp.encode_into_bytes(buffer),
+            Self::Response(ref resp) => resp.encode_into_bytes(buffer),
+        }
+    }
+}
+
+impl From<v4::Response> for ServerMessage {
+    fn from(value: v4::Response) -> Self {
+        Self::V4(value)
+    }
+}
+
+impl From<v5::HelloResponse> for ServerMessage {
+    fn from(value: v5::HelloResponse) -> Self {
+        Self::Hello(value)
+    }
+}
+
+impl From<v5::UsernamePasswordResponse> for ServerMessage {
+    fn from(value: v5::UsernamePasswordResponse) -> Self {
+        Self::UsernamePassword(value)
+    }
+}
+
+impl From<v5::Response> for ServerMessage {
+    fn from(value: v5::Response) -> Self {
+        Self::Response(value)
+    }
+}
+
+/// Decodes the message at the start of `src`, consuming its bytes.
+fn decode_from<M: Wire, T>(
+    src: &mut BytesMut,
+    limits: &DecodeLimits,
+    mode: ParseMode,
+    wrap: fn(M) -> T,
+) -> io::Result<Option<T>> {
+    Ok(
+        decode_message(src, limits, mode)?.map(|(consumed, message)| {
+            src.advance(consumed);
+            wrap(message)
+        }),
+    )
+}
+
+/// Reply a client waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reply {
+    V4,
+    Hello,
+    UsernamePassword,
+    Response,
+}
+
+/// Client side of a handshake: encodes [`ClientMessage`]s and decodes the [`ServerMessage`]s
+/// replying to them.
+///
+/// Messages may be sent without waiting for their replies, such as a hello, credentials and a
+/// request at once.
+#[derive(Debug, Default)]
+pub struct SocksClientCodec {
+    limits: DecodeLimits,
+    expected: VecDeque<Reply>,
+}
+
+impl SocksClientCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bounds applied when decoding server messages.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+impl Encoder<ClientMessage> for SocksClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: ClientMessage, dst: &mut BytesMut) -> io::Result<()> {
+        self.expected.push_back(match item {
+            ClientMessage::V4(_) => Reply::V4,
+            ClientMessage::Hello(_) => Reply::Hello,
+            ClientMessage::UsernamePassword(_) => Reply::UsernamePassword,
+            ClientMessage::Request(_) => Reply::Response,
+        });
+        item.encode_into_bytes(dst);
+        Ok(())
+    }
+}
+
+impl Decoder for SocksClientCodec {
+    type Item = ServerMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<ServerMessage>> {
+        let Some(&reply) = self.expected.front() else {
+            return Ok(None);
+        };
+        let (limits, mode) = (&self.limits, ParseMode::Lenient);
+        let message = match reply {
+            Reply::V4 => decode_from(src, limits, mode, ServerMessage::V4)?,
+            Reply::Hello => decode_from(src, limits, mode, ServerMessage::Hello)?,
+            Reply::UsernamePassword => {
+                decode_from(src, limits, mode, ServerMessage::UsernamePassword)?
+            }
+            Reply::Response => decode_from(src, limits, mode, ServerMessage::Response)?,
+        };
+        if message.is_some() {
+            self.expected.pop_front();
+        }
+        // Credentials sent along the hello get no reply if the server picked another method.
+        if let Some(ServerMessage::Hello(ref hello)) = message {
+            if hello.method != v5::AuthenticationMethod::UsernamePassword
+                && self.expected.front() == Some(&Reply::UsernamePassword)
+            {
+                self.expected.pop_front();
+            }
+        }
+        Ok(message)
+    }
+}
+
+/// Client message a server waits for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Expected {
+    /// A SOCKS4 request or a SOCKS5 hello.
+    #[default]
+    First,
+    /// Credentials or a SOCKS5 request.
+    CredentialsOrRequest,
+    Request,
+    Done,
+}
+
+/// Server side of a handshake: decodes [`ClientMessage`]s and encodes the [`ServerMessage`]s
+/// replying to them.
+///
+/// Messages are told apart by their first byte, so that those pipelined by the client are
+/// decoded without waiting for the replies to be sent.
+#[derive(Debug, Default)]
+pub struct SocksServerCodec {
+    limits: DecodeLimits,
+    mode: ParseMode,
+    expected: Expected,
+}
+
+impl SocksServerCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bounds applied when decoding client messages.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Parses client messages in `mode`, lenient by default.
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl Encoder<ServerMessage> for SocksServerCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: ServerMessage, dst: &mut BytesMut) -> io::Result<()> {
+        item.encode_into_bytes(dst);
+        Ok(())
+    }
+}
+
+impl Decoder for SocksServerCodec {
+    type Item = ClientMessage;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<ClientMessage>> {
+        let Some(&first) = src.first() else {
+            return Ok(None);
+        };
+        let (limits, mode) = (&self.limits, self.mode);
+        let message = match self.expected {
+            Expected::First if first == 4 => decode_from(src, limits, mode, ClientMessage::V4)?,
+            Expected::First => decode_from(src, limits, mode, ClientMessage::Hello)?,
+            Expected::CredentialsOrRequest if first == USERNAME_PASSWORD_VERSION => {
+                decode_from(src, limits, mode, ClientMessage::UsernamePassword)?
+            }
+            Expected::CredentialsOrRequest | Expected::Request => {
+                decode_from(src, limits, mode, ClientMessage::Request)?
+            }
+            Expected::Done => return Ok(None),
+        };
+        if let Some(ref message) = message {
+            self.expected = match message {
+                ClientMessage::Hello(_) => Expected::CredentialsOrRequest,
+                ClientMessage::UsernamePassword(_) => Expected::Request,
+                ClientMessage::V4(_) | ClientMessage::Request(_) => Expected::Done,
+            };
+        }
+        Ok(message)
+    }
+}
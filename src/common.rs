@@ -1,16 +1,16 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use nom::{
-    combinator::map,
-    error::context,
-    number::complete::{be_u16, be_u8},
-    sequence::tuple,
-};
+use nom::{bytes::complete::take, combinator::map_opt, error::context, number::complete::be_u8};
 
 use super::Wire;
 
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 pub enum Version {
     Socks4 = 4,
     Socks5 = 5,
@@ -48,8 +48,8 @@ impl Wire for Ipv4Addr {
     {
         context(
             "IPv4",
-            map(tuple((be_u8, be_u8, be_u8, be_u8)), |(a, b, c, d)| {
-                Self::new(a, b, c, d)
+            map_opt(take(4usize), |b: &[u8]| {
+                <[u8; 4]>::try_from(b).ok().map(Self::from)
             }),
         )(input)
     }
@@ -66,12 +66,9 @@ impl Wire for Ipv6Addr {
     {
         context(
             "IPv6",
-            map(
-                tuple((
-                    be_u16, be_u16, be_u16, be_u16, be_u16, be_u16, be_u16, be_u16,
-                )),
-                |(a, b, c, d, e, f, g, h)| Self::new(a, b, c, d, e, f, g, h),
-            ),
+            map_opt(take(16usize), |b: &[u8]| {
+                <[u8; 16]>::try_from(b).ok().map(Self::from)
+            }),
         )(input)
     }
 }
@@ -49,6 +49,13 @@ impl TryFrom<super::v5::AddressType> for AddressType {
                 io::ErrorKind::Unsupported,
                 "Socks v4 does not support IPv6",
             )),
+            #[cfg(feature = "extensions")]
+            super::v5::AddressType::UnixPath(_) | super::v5::AddressType::Other { .. } => {
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Socks v4 does not support extended address types",
+                ))
+            }
         }
     }
 }
@@ -1,6 +1,11 @@
-use nom::{combinator::map, error::context, number::complete::be_u8};
+use nom::{
+    combinator::map,
+    error::context,
+    number::complete::{be_u16, be_u8},
+    sequence::{preceded, tuple},
+};
 
-use crate::Wire;
+use crate::{DecodeLimits, Wire};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum AuthenticationMethod {
@@ -26,7 +31,7 @@ impl From<u8> for AuthenticationMethod {
 }
 
 impl AuthenticationMethod {
-    fn as_u8(&self) -> u8 {
+    pub fn as_u8(&self) -> u8 {
         match self {
             Self::None => 0,
             Self::Gssapi => 1,
@@ -51,17 +56,49 @@ impl Wire for AuthenticationMethod {
     }
 }
 
-#[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Command {
-    Connect = 1,
-    Bind = 2,
-    UdpAssociate = 3,
+    Connect,
+    Bind,
+    UdpAssociate,
+    /// Tor extension: resolves a domain name, returned as the bound address.
+    #[cfg(feature = "tor")]
+    TorResolve,
+    /// Tor extension: reverse-resolves an IP address, the name being returned as the bound
+    /// address.
+    #[cfg(feature = "tor")]
+    TorResolvePtr,
+    /// Extension of this crate: turns the connection into a multiplexed session, see
+    /// [`mux`](crate::mux).
+    #[cfg(feature = "mux")]
+    Mux,
+    /// Command registered with
+    /// [`extensions::register_command`](crate::extensions::register_command).
+    #[cfg(feature = "extensions")]
+    Other(u8),
+}
+
+impl Command {
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Self::Connect => 1,
+            Self::Bind => 2,
+            Self::UdpAssociate => 3,
+            #[cfg(feature = "tor")]
+            Self::TorResolve => 0xf0,
+            #[cfg(feature = "tor")]
+            Self::TorResolvePtr => 0xf1,
+            #[cfg(feature = "mux")]
+            Self::Mux => 0x80,
+            #[cfg(feature = "extensions")]
+            Self::Other(code) => *code,
+        }
+    }
 }
 
 impl Wire for Command {
     fn encode_into(&self, buffer: &mut Vec<u8>) {
-        buffer.push(*self as u8);
+        buffer.push(self.as_u8());
     }
 
     fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
@@ -73,6 +110,14 @@ impl Wire for Command {
             1 => Ok((rest, Self::Connect)),
             2 => Ok((rest, Self::Bind)),
             3 => Ok((rest, Self::UdpAssociate)),
+            #[cfg(feature = "tor")]
+            0xf0 => Ok((rest, Self::TorResolve)),
+            #[cfg(feature = "tor")]
+            0xf1 => Ok((rest, Self::TorResolvePtr)),
+            #[cfg(feature = "mux")]
+            0x80 => Ok((rest, Self::Mux)),
+            #[cfg(feature = "extensions")]
+            code if crate::extensions::is_registered_command(code) => Ok((rest, Self::Other(code))),
             _ => Err(nom::Err::Failure(nom::error::make_error(
                 buffer,
                 nom::error::ErrorKind::NoneOf,
@@ -83,3 +128,42 @@ impl Wire for Command {
 
 mod address_type;
 pub use address_type::AddressType;
+
+/// Header prepended to every relayed UDP datagram (RFC 1928, section 7).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UdpHeader {
+    /// Fragment number, `0` for standalone datagrams.
+    pub frag: u8,
+    pub addr: AddressType,
+    pub port: u16,
+}
+
+impl Wire for UdpHeader {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&[0, 0, self.frag]);
+        self.addr.encode_into(buffer);
+        buffer.extend_from_slice(&self.port.to_be_bytes()[..]);
+    }
+
+    fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        let (rest, (frag, addr, port)) = context(
+            "UDP header",
+            preceded(be_u16, tuple((be_u8, AddressType::decode, be_u16))),
+        )(buffer)?;
+        Ok((rest, Self { frag, addr, port }))
+    }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<(), &'static str> {
+        self.addr.check_limits(limits)
+    }
+
+    fn check_strict(&self, message: &[u8]) -> Result<(), &'static str> {
+        if message[..2] != [0, 0] {
+            return Err("Reserved bytes must be 0");
+        }
+        self.addr.check_strict(&message[3..])
+    }
+}
@@ -1,8 +1,13 @@
 use std::{
-    fmt,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
 };
 
+#[cfg(feature = "extensions")]
+use std::path::PathBuf;
+
+#[cfg(feature = "extensions")]
+use nom::{bytes::complete::take, combinator::recognize};
 use nom::{
     combinator::{map, map_opt},
     error::context,
@@ -10,13 +15,25 @@ use nom::{
     number::complete::be_u8,
 };
 
-use crate::Wire;
+use crate::{DecodeLimits, Wire};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum AddressType {
     IPv4(Ipv4Addr),
     DomainName(String),
     IPv6(Ipv6Addr),
+    /// Unix socket path, an extension whose type code is set by
+    /// [`extensions::set_unix_path_code`](crate::extensions::set_unix_path_code).
+    #[cfg(feature = "extensions")]
+    UnixPath(PathBuf),
+    /// Address type registered with
+    /// [`extensions::register_address_type`](crate::extensions::register_address_type).
+    #[cfg(feature = "extensions")]
+    Other {
+        code: u8,
+        /// Bytes following the code, including the length byte of length-prefixed formats.
+        payload: Vec<u8>,
+    },
 }
 
 impl Wire for AddressType {
@@ -36,6 +53,31 @@ impl Wire for AddressType {
                 buffer.push(size);
                 buffer.extend_from_slice(name.as_bytes());
             }
+            #[cfg(feature = "extensions")]
+            Self::UnixPath(ref path) => {
+                buffer.push(crate::extensions::unix_path_code());
+                let path = path.as_os_str().as_encoded_bytes();
+                let size: u8 = path.len().try_into().expect("Unix path too long");
+                buffer.push(size);
+                buffer.extend_from_slice(path);
+            }
+            #[cfg(feature = "extensions")]
+            Self::Other { code, ref payload } => {
+                buffer.push(*code);
+                buffer.extend_from_slice(payload);
+            }
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        match self {
+            Self::IPv4(_) => 5,
+            Self::IPv6(_) => 17,
+            Self::DomainName(ref name) => 2 + name.len(),
+            #[cfg(feature = "extensions")]
+            Self::UnixPath(ref path) => 2 + path.as_os_str().as_encoded_bytes().len(),
+            #[cfg(feature = "extensions")]
+            Self::Other { ref payload, .. } => 1 + payload.len(),
         }
     }
 
@@ -56,6 +98,17 @@ impl Wire for AddressType {
                 }),
             )(rest),
             4 => map(Ipv6Addr::decode, Self::IPv6)(rest),
+            #[cfg(feature = "extensions")]
+            code if code == crate::extensions::unix_path_code() => context(
+                "Unix path",
+                map_opt(length_data(be_u8), |b| {
+                    path_from_bytes(b).map(Self::UnixPath)
+                }),
+            )(rest),
+            #[cfg(feature = "extensions")]
+            code if crate::extensions::address_format(code).is_some() => {
+                context("extension address", |input| decode_other(code, input))(rest)
+            }
             _ => Err(nom::Err::Failure(E::add_context(
                 buffer,
                 "Invalid address type",
@@ -63,14 +116,185 @@ impl Wire for AddressType {
             ))),
         }
     }
+
+    fn check_limits(&self, limits: &DecodeLimits) -> Result<(), &'static str> {
+        match self {
+            Self::DomainName(ref name) => limits.check_domain_name(name),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_strict(&self, _message: &[u8]) -> Result<(), &'static str> {
+        match self {
+            Self::DomainName(ref name) if name.is_empty() => Err("Empty domain name"),
+            #[cfg(feature = "extensions")]
+            Self::UnixPath(_) => Err("Unix paths are not standard"),
+            #[cfg(feature = "extensions")]
+            Self::Other { .. } => Err("Private address types are not standard"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AddressType {
+    /// Domain name typed by a user, such as a hostname given on the command line.
+    ///
+    /// With the `idna` feature, Unicode names are converted to their ASCII (punycode) form, the
+    /// only one allowed on the wire. Names which are not ASCII once converted, hold whitespace or
+    /// control characters, are empty or longer than 255 bytes are rejected.
+    pub fn domain(name: &str) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid domain name {name:?}: {reason}"),
+            )
+        };
+
+        #[cfg(feature = "idna")]
+        let name = idna::domain_to_ascii(name).map_err(|e| invalid(&e.to_string()))?;
+        if name.is_empty() {
+            return Err(invalid("empty"));
+        }
+        if !name.is_ascii() {
+            return Err(invalid("not ASCII"));
+        }
+        if name
+            .bytes()
+            .any(|b| b.is_ascii_whitespace() || b.is_ascii_control())
+        {
+            return Err(invalid("whitespace or control character"));
+        }
+        if name.len() > u8::MAX as usize {
+            return Err(invalid("too long"));
+        }
+        Ok(Self::DomainName(name.to_string()))
+    }
+
+    /// IP address, possibly between brackets, or [domain name](Self::domain) typed by a user.
+    pub fn host(host: &str) -> io::Result<Self> {
+        let ip = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        match ip.parse::<IpAddr>() {
+            Ok(ip) => Ok(ip.into()),
+            Err(_) => Self::domain(host),
+        }
+    }
+
+    /// Turns IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) into plain IPv4 ones.
+    pub fn normalized(self) -> Self {
+        match self {
+            Self::IPv6(ip6) => match ip6.to_ipv4_mapped() {
+                Some(ip4) => Self::IPv4(ip4),
+                None => Self::IPv6(ip6),
+            },
+            other => other,
+        }
+    }
+
+    /// IPv4 address, including IPv4-mapped IPv6 ones, as needed by SOCKS4 replies.
+    pub fn to_ipv4(&self) -> Option<Ipv4Addr> {
+        match self {
+            Self::IPv4(ip4) => Some(*ip4),
+            Self::IPv6(ip6) => ip6.to_ipv4_mapped(),
+            _ => None,
+        }
+    }
+
+    /// Whether this is an `169.254.0.0/16` or `fe80::/10` address.
+    pub fn is_link_local(&self) -> bool {
+        match self.clone().normalized() {
+            Self::IPv4(ip4) => ip4.is_link_local(),
+            Self::IPv6(ip6) => ip6.is_unicast_link_local(),
+            Self::DomainName(ref name) => {
+                parse_scoped(name).is_some_and(|(ip6, _)| ip6.is_unicast_link_local())
+            }
+            #[cfg(feature = "extensions")]
+            Self::UnixPath(_) | Self::Other { .. } => false,
+        }
+    }
+
+    /// Socket address, without resolving domain names.
+    ///
+    /// Domain names holding an IPv6 literal keep their numeric scope id (`fe80::1%2`), since the
+    /// wire format has no room for it otherwise.
+    pub fn to_socket_addr(&self, port: u16) -> Option<SocketAddr> {
+        match self.clone().normalized() {
+            Self::IPv4(ip4) => Some((ip4, port).into()),
+            Self::IPv6(ip6) => Some((ip6, port).into()),
+            Self::DomainName(ref name) => match name.parse::<IpAddr>() {
+                Ok(ip) => Some(Self::from(ip).to_socket_addr(port)?),
+                Err(_) => {
+                    let (ip6, scope_id) = parse_scoped(name)?;
+                    Some(SocketAddrV6::new(ip6, port, 0, scope_id).into())
+                }
+            },
+            #[cfg(feature = "extensions")]
+            Self::UnixPath(_) | Self::Other { .. } => None,
+        }
+    }
 }
 
+/// Address of a registered type, from the bytes following its code.
+#[cfg(feature = "extensions")]
+fn decode_other<'i, E>(code: u8, input: &'i [u8]) -> nom::IResult<&'i [u8], AddressType, E>
+where
+    E: nom::error::ParseError<&'i [u8]>,
+{
+    use crate::extensions::AddressFormat;
+
+    let (rest, payload) = match crate::extensions::address_format(code) {
+        Some(AddressFormat::Fixed(len)) => take(len)(input)?,
+        _ => recognize(length_data(be_u8))(input)?,
+    };
+    Ok((
+        rest,
+        AddressType::Other {
+            code,
+            payload: payload.to_vec(),
+        },
+    ))
+}
+
+/// Unix paths are raw bytes on Unix, and must be UTF-8 elsewhere.
+#[cfg(feature = "extensions")]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    #[cfg(unix)]
+    let path = <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes);
+    #[cfg(not(unix))]
+    let path = std::str::from_utf8(bytes).ok()?;
+    Some(path.into())
+}
+
+/// Parses `addr%scope_id`, brackets being optional.
+fn parse_scoped(name: &str) -> Option<(Ipv6Addr, u32)> {
+    let name = name
+        .strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .unwrap_or(name);
+    let (ip6, scope_id) = name.split_once('%')?;
+    Some((ip6.parse().ok()?, scope_id.parse().ok()?))
+}
+
+/// With the `idna` feature, the alternate form (`{:#}`) shows punycode domain names in Unicode.
 impl fmt::Display for AddressType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::IPv4(ref ip4) => fmt::Display::fmt(ip4, f),
             Self::IPv6(ref ip6) => write!(f, "[{}]", ip6),
+            #[cfg(feature = "idna")]
+            Self::DomainName(ref name) if f.alternate() => {
+                f.write_str(&idna::domain_to_unicode(name).0)
+            }
             Self::DomainName(ref name) => f.write_str(name),
+            #[cfg(feature = "extensions")]
+            Self::UnixPath(ref path) => write!(f, "unix:{}", path.display()),
+            #[cfg(feature = "extensions")]
+            Self::Other { code, ref payload } => {
+                write!(f, "ext{code:02x}:")?;
+                payload.iter().try_for_each(|b| write!(f, "{b:02x}"))
+            }
         }
     }
 }
@@ -83,3 +307,23 @@ impl From<IpAddr> for AddressType {
         }
     }
 }
+
+impl TryFrom<AddressType> for IpAddr {
+    type Error = io::Error;
+
+    fn try_from(value: AddressType) -> Result<Self, Self::Error> {
+        match value {
+            AddressType::IPv4(ip4) => Ok(Self::V4(ip4)),
+            AddressType::IPv6(ip6) => Ok(Self::V6(ip6)),
+            AddressType::DomainName(n) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{n:?} is not an IP address"),
+            )),
+            #[cfg(feature = "extensions")]
+            addr @ (AddressType::UnixPath(_) | AddressType::Other { .. }) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{addr} is not an IP address"),
+            )),
+        }
+    }
+}
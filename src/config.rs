@@ -0,0 +1,380 @@
+//! Server configuration loadable from a TOML file or environment variables.
+//!
+//! ```toml
+//! listen = "0.0.0.0:1080"
+//! versions = ["socks5"]
+//! acl = "/etc/socks/acl"
+//! rewrite = "/etc/socks/rewrite"
+//! handshake_timeout_secs = 10
+//! max_connections = 1000
+//! parse_mode = "strict"
+//!
+//! [auth]
+//! type = "users"
+//! users = { alice = "secret" }
+//!
+//! [limits]
+//! max_message_size = 512
+//!
+//! [policies.alice]
+//! acl = "/etc/socks/alice.acl"
+//! max_connections = 8
+//! bandwidth = { upload = 65536, download = 1048576 }
+//! ```
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::net::TcpListener;
+
+use crate::{
+    acl::{Acl, FileWatcherAcl},
+    auth::{Authenticator, HtpasswdFile, StaticUserDb},
+    policy::{UserPolicies, UserPolicy},
+    rewrite::RewriteRules,
+    server::Settings,
+    throttle::BandwidthLimit,
+    DecodeLimits, ParseMode, Redacted, Server, Version,
+};
+
+pub use crate::server::ConfigHandle;
+
+/// Everything needed to build a [`Server`] with [`Server::from_config`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    /// SOCKS versions clients may use, SOCKS4 being refused when `auth` is configured.
+    pub versions: Vec<Version>,
+    pub auth: AuthConfig,
+    /// ACL file, reloaded when modified.
+    pub acl: Option<PathBuf>,
+    /// Restrictions of authenticated users, by username.
+    pub policies: HashMap<String, UserPolicyConfig>,
+    /// [Rewrite rules](crate::rewrite) file. Their proxies are only used by request handlers
+    /// such as [`handlers::follow_rewrites`](crate::handlers::follow_rewrites).
+    pub rewrite: Option<PathBuf>,
+    pub allow_link_local: bool,
+    /// See [`Server::allow_private_destinations`].
+    pub allow_private_destinations: bool,
+    pub handshake_timeout_secs: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub limits: DecodeLimits,
+    pub parse_mode: ParseMode,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: (Ipv4Addr::LOCALHOST, 1080).into(),
+            versions: vec![Version::Socks4, Version::Socks5],
+            auth: AuthConfig::None,
+            acl: None,
+            policies: HashMap::new(),
+            rewrite: None,
+            allow_link_local: true,
+            allow_private_destinations: true,
+            handshake_timeout_secs: None,
+            max_connections: None,
+            limits: DecodeLimits::default(),
+            parse_mode: ParseMode::default(),
+        }
+    }
+}
+
+/// How SOCKS5 clients authenticate.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum AuthConfig {
+    #[default]
+    None,
+    /// Usernames mapped to clear-text passwords.
+    Users {
+        users: HashMap<String, String>,
+    },
+    Htpasswd {
+        path: PathBuf,
+    },
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::Users { users } => f
+                .debug_struct("Users")
+                .field(
+                    "users",
+                    &users
+                        .keys()
+                        .map(|u| (u, Redacted))
+                        .collect::<HashMap<_, _>>(),
+                )
+                .finish(),
+            Self::Htpasswd { path } => f.debug_struct("Htpasswd").field("path", path).finish(),
+        }
+    }
+}
+
+/// Restrictions of one user, see [`UserPolicy`].
+///
+/// Bandwidth is limited by request handlers wrapped with [`Server::throttle_users`], which
+/// picks up policies updated along with the configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserPolicyConfig {
+    /// ACL file checked after the server one, reloaded when modified.
+    pub acl: Option<PathBuf>,
+    pub max_connections: Option<usize>,
+    /// Rates in bytes per second, shared by every tunnel of the user.
+    pub bandwidth: BandwidthLimit,
+}
+
+impl UserPolicyConfig {
+    fn load(&self) -> io::Result<UserPolicy> {
+        let mut policy = UserPolicy::new().with_bandwidth(self.bandwidth);
+        if let Some(ref path) = self.acl {
+            policy = policy.with_acl(FileWatcherAcl::new(path)?);
+        }
+        if let Some(max) = self.max_connections {
+            policy = policy.with_max_connections(max);
+        }
+        Ok(policy)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses the value of the environment variable `name`, if set.
+fn var<T: FromStr>(name: &str) -> io::Result<Option<T>> {
+    match env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid(format!("Invalid value for {name}: {value:?}"))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(invalid(format!("Invalid value for {name}"))),
+    }
+}
+
+impl ServerConfig {
+    pub fn from_toml(content: &str) -> io::Result<Self> {
+        toml::from_str(content).map_err(|e| invalid(e.to_string()))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Default configuration, overridden by the environment as described in
+    /// [`ServerConfig::with_env`].
+    pub fn from_env() -> io::Result<Self> {
+        Self::default().with_env()
+    }
+
+    /// Overrides settings with the environment variables which are set:
+    ///
+    /// - `SOCKS_LISTEN`: address to listen on.
+    /// - `SOCKS_VERSIONS`: comma separated versions, such as `socks4,socks5`.
+    /// - `SOCKS_USERS`: comma separated `username:password` pairs.
+    /// - `SOCKS_HTPASSWD`: htpasswd file, taking precedence over `SOCKS_USERS`.
+    /// - `SOCKS_ACL`: ACL file.
+    /// - `SOCKS_REWRITE`: rewrite rules file.
+    /// - `SOCKS_ALLOW_LINK_LOCAL`: `true` or `false`.
+    /// - `SOCKS_ALLOW_PRIVATE_DESTINATIONS`: `true` or `false`.
+    /// - `SOCKS_HANDSHAKE_TIMEOUT`: in seconds.
+    /// - `SOCKS_MAX_CONNECTIONS`.
+    /// - `SOCKS_PARSE_MODE`: `strict` or `lenient`.
+    pub fn with_env(mut self) -> io::Result<Self> {
+        if let Some(listen) = var("SOCKS_LISTEN")? {
+            self.listen = listen;
+        }
+        if let Some(versions) = var::<String>("SOCKS_VERSIONS")? {
+            self.versions = versions
+                .split(',')
+                .map(|v| match v.trim() {
+                    "socks4" => Ok(Version::Socks4),
+                    "socks5" => Ok(Version::Socks5),
+                    v => Err(invalid(format!("Unknown SOCKS version {v:?}"))),
+                })
+                .collect::<io::Result<_>>()?;
+        }
+        if let Some(users) = var::<String>("SOCKS_USERS")? {
+            let users = users
+                .split(',')
+                .map(|user| {
+                    user.split_once(':')
+                        .map(|(u, p)| (u.to_owned(), p.to_owned()))
+                        .ok_or_else(|| invalid("Expected username:password in SOCKS_USERS".into()))
+                })
+                .collect::<io::Result<_>>()?;
+            self.auth = AuthConfig::Users { users };
+        }
+        if let Some(path) = var("SOCKS_HTPASSWD")? {
+            self.auth = AuthConfig::Htpasswd { path };
+        }
+        if let Some(path) = var("SOCKS_ACL")? {
+            self.acl = Some(path);
+        }
+        if let Some(path) = var("SOCKS_REWRITE")? {
+            self.rewrite = Some(path);
+        }
+        if let Some(allow) = var("SOCKS_ALLOW_LINK_LOCAL")? {
+            self.allow_link_local = allow;
+        }
+        if let Some(allow) = var("SOCKS_ALLOW_PRIVATE_DESTINATIONS")? {
+            self.allow_private_destinations = allow;
+        }
+        if let Some(secs) = var("SOCKS_HANDSHAKE_TIMEOUT")? {
+            self.handshake_timeout_secs = Some(secs);
+        }
+        if let Some(max) = var("SOCKS_MAX_CONNECTIONS")? {
+            self.max_connections = Some(max);
+        }
+        if let Some(mode) = var::<String>("SOCKS_PARSE_MODE")? {
+            self.parse_mode = match mode.trim() {
+                "strict" => ParseMode::Strict,
+                "lenient" => ParseMode::Lenient,
+                m => return Err(invalid(format!("Unknown parse mode {m:?}"))),
+            };
+        }
+        Ok(self)
+    }
+}
+
+impl AuthConfig {
+    fn load(&self) -> io::Result<Option<Arc<dyn Authenticator>>> {
+        Ok(match *self {
+            Self::None => None,
+            Self::Users { ref users } => {
+                let mut db = StaticUserDb::new();
+                for (username, password) in users {
+                    db.add_user(username, password);
+                }
+                Some(Arc::new(db))
+            }
+            Self::Htpasswd { ref path } => Some(Arc::new(HtpasswdFile::load(path)?)),
+        })
+    }
+}
+
+impl ServerConfig {
+    /// Settings a running server can change, with the files they refer to loaded.
+    fn settings(&self) -> io::Result<Settings> {
+        let acl = match self.acl {
+            Some(ref path) => Some(Arc::new(FileWatcherAcl::new(path)?) as Arc<dyn Acl>),
+            None => None,
+        };
+        let user_policies = if self.policies.is_empty() {
+            None
+        } else {
+            let mut policies = UserPolicies::new();
+            for (username, policy) in &self.policies {
+                policies.add_user(username, policy.load()?);
+            }
+            Some(Arc::new(policies))
+        };
+        Ok(Settings {
+            versions: self.versions.clone(),
+            authenticator: self.auth.load()?,
+            acl,
+            user_policies,
+            allow_link_local: self.allow_link_local,
+            allow_private: self.allow_private_destinations,
+            handshake_timeout: self.handshake_timeout_secs.map(Duration::from_secs),
+            limits: self.limits,
+            parse_mode: self.parse_mode,
+        })
+    }
+}
+
+impl Server {
+    /// Binds to the configured address and applies the rest of `config`.
+    ///
+    /// Bandwidth limits of user policies apply to the streams of request handlers wrapped with
+    /// [`throttle_users`](Self::throttle_users):
+    ///
+    /// ```no_run
+    /// # async fn run() -> std::io::Result<()> {
+    /// use socks_parser::{config::ServerConfig, handlers, resolver::SystemResolver};
+    /// use socks_parser::{ConnectOptions, Server};
+    ///
+    /// let server = Server::from_config(&ServerConfig::load("/etc/socks/server.toml")?).await?;
+    /// let connect = handlers::connect_with(SystemResolver, ConnectOptions::default());
+    /// let connect = server.throttle_users(connect);
+    /// server.run(connect, handlers::relay).await
+    /// # }
+    /// ```
+    pub async fn from_config(config: &ServerConfig) -> io::Result<Self> {
+        let Settings {
+            versions,
+            authenticator,
+            acl,
+            user_policies,
+            allow_link_local,
+            allow_private,
+            handshake_timeout,
+            limits,
+            parse_mode,
+        } = config.settings()?;
+        let listener = TcpListener::bind(config.listen).await?;
+        let mut server = Self::new(listener)
+            .with_versions(versions)
+            .with_decode_limits(limits)
+            .with_parse_mode(parse_mode)
+            .allow_link_local(allow_link_local)
+            .allow_private_destinations(allow_private);
+        if let Some(authenticator) = authenticator {
+            server = server.with_authenticator(authenticator);
+        }
+        if let Some(acl) = acl {
+            server = server.with_acl(acl);
+        }
+        if let Some(policies) = user_policies {
+            server = server.with_user_policies(policies);
+        }
+        if let Some(ref path) = config.rewrite {
+            server = server.with_layer(RewriteRules::load(path)?);
+        }
+        if let Some(timeout) = handshake_timeout {
+            server = server.with_handshake_timeout(timeout);
+        }
+        if let Some(max) = config.max_connections {
+            server = server.with_max_connections(max);
+        }
+        Ok(server)
+    }
+
+    /// Same as [`ConfigHandle::update_config`], for a server not running yet or serving
+    /// clients of [`accept`](Self::accept).
+    pub fn update_config(&self, config: &ServerConfig) -> io::Result<()> {
+        self.config_handle().update_config(config)
+    }
+}
+
+impl ConfigHandle {
+    /// Applies `config` to the connections the server accepts from now on, replacing what was
+    /// set by [`Server::from_config`] or the builders of [`Server`]. Connections already
+    /// accepted, and their tunnels, keep running with the settings they started with.
+    ///
+    /// The ACL, authentication, user policies, versions, decoding limits, parse mode, handshake
+    /// timeout and link-local restriction are swapped at once. The listen address, connection
+    /// limit and rewrite rules only change on restart. If a file of `config` cannot be loaded,
+    /// the server keeps its settings.
+    pub fn update_config(&self, config: &ServerConfig) -> io::Result<()> {
+        self.replace(config.settings()?);
+        log::info!("Configuration updated");
+        Ok(())
+    }
+}
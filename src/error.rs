@@ -0,0 +1,285 @@
+use std::{error::Error, fmt, io, num::NonZeroUsize};
+
+use nom::error::{VerboseError, VerboseErrorKind};
+
+use crate::v5::{AuthenticationMethod, Status};
+
+/// Bytes shown on each side of the failing one by [`ParseError`]'s `Display` implementation.
+const EXCERPT_RADIUS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The input ended early, `needed` more bytes being required when known.
+    Incomplete {
+        needed: Option<NonZeroUsize>,
+    },
+    /// A specific character was expected.
+    Char(char),
+    Nom(nom::error::ErrorKind),
+}
+
+/// Decoding failure, located in the input it was decoded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Position of the offending byte in the input.
+    pub offset: usize,
+    /// Contexts the parser went through, outermost first.
+    pub contexts: Vec<&'static str>,
+    pub kind: ParseErrorKind,
+    /// Input bytes around `offset`, starting at `excerpt_start`.
+    excerpt: Vec<u8>,
+    excerpt_start: usize,
+}
+
+impl ParseError {
+    /// Locates `err`, returned when decoding `input`.
+    pub fn new(input: &[u8], err: nom::Err<VerboseError<&[u8]>>) -> Self {
+        let (offset, contexts, kind) = match err {
+            nom::Err::Incomplete(needed) => {
+                let needed = match needed {
+                    nom::Needed::Size(n) => Some(n),
+                    nom::Needed::Unknown => None,
+                };
+                (
+                    input.len(),
+                    Vec::new(),
+                    ParseErrorKind::Incomplete { needed },
+                )
+            }
+            nom::Err::Error(e) | nom::Err::Failure(e) => {
+                // Errors are stacked innermost first.
+                let offset = e
+                    .errors
+                    .first()
+                    .map_or(0, |(rest, _)| input.len().saturating_sub(rest.len()));
+                let mut kind = ParseErrorKind::Nom(nom::error::ErrorKind::Fail);
+                let mut contexts = Vec::new();
+                for (_, error) in e.errors.iter().rev() {
+                    match *error {
+                        VerboseErrorKind::Context(context) => contexts.push(context),
+                        VerboseErrorKind::Char(c) => kind = ParseErrorKind::Char(c),
+                        VerboseErrorKind::Nom(k) => kind = ParseErrorKind::Nom(k),
+                    }
+                }
+                (offset, contexts, kind)
+            }
+        };
+
+        let excerpt_start = offset.saturating_sub(EXCERPT_RADIUS);
+        let excerpt_end = input.len().min(offset + EXCERPT_RADIUS + 1);
+        Self {
+            offset,
+            contexts,
+            kind,
+            excerpt: input[excerpt_start.min(excerpt_end)..excerpt_end].to_vec(),
+            excerpt_start,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::Incomplete { needed: Some(n) } => {
+                write!(f, "Incomplete input, {n} more bytes needed")?
+            }
+            ParseErrorKind::Incomplete { needed: None } => f.write_str("Incomplete input")?,
+            ParseErrorKind::Char(c) => write!(f, "Expected {c:?} at offset {}", self.offset)?,
+            ParseErrorKind::Nom(k) => write!(f, "Invalid data at offset {} ({k:?})", self.offset)?,
+        }
+        if !self.contexts.is_empty() {
+            write!(f, " in {}", self.contexts.join(" > "))?;
+        }
+
+        // Hexdump excerpt, the offending byte between brackets.
+        write!(f, "\n{:04x}:", self.excerpt_start)?;
+        for (i, byte) in self.excerpt.iter().enumerate() {
+            if self.excerpt_start + i == self.offset {
+                write!(f, " [{byte:02x}]")?;
+            } else {
+                write!(f, " {byte:02x}")?;
+            }
+        }
+        if self.excerpt_start + self.excerpt.len() <= self.offset {
+            f.write_str(" []")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(value: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// A client sent more than [`DecodeLimits::max_handshake_size`](crate::DecodeLimits) bytes
+/// without completing its handshake, carried by the I/O error the server closes the connection
+/// with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTooLarge {
+    pub limit: usize,
+}
+
+impl fmt::Display for HandshakeTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handshake larger than {} bytes", self.limit)
+    }
+}
+
+impl Error for HandshakeTooLarge {}
+
+impl From<HandshakeTooLarge> for io::Error {
+    fn from(value: HandshakeTooLarge) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// Why a SOCKS5 hello is unfit for method negotiation, see
+/// [`Hello::validate`](crate::v5::Hello::validate).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HelloError {
+    /// No method is offered, which the one-byte NMETHODS field allows.
+    NoMethods,
+    /// More methods than the NMETHODS field can count.
+    TooManyMethods(usize),
+    /// Method offered more than once.
+    RepeatedMethod(AuthenticationMethod),
+}
+
+impl fmt::Display for HelloError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoMethods => f.write_str("No authentication method offered"),
+            Self::TooManyMethods(count) => {
+                write!(f, "{count} authentication methods offered, at most 255")
+            }
+            Self::RepeatedMethod(method) => {
+                write!(f, "Authentication method {method:?} offered more than once")
+            }
+        }
+    }
+}
+
+impl Error for HelloError {}
+
+impl From<HelloError> for io::Error {
+    fn from(value: HelloError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, value)
+    }
+}
+
+/// Why a client handshake failed, carried by the I/O errors of [`Client`](crate::Client) and
+/// [`sansio::Client`](crate::sansio::Client): see [`ClientError::of`].
+///
+/// Tells a proxy which is down or speaks another protocol from one denying the destination,
+/// which the error kind alone does not.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClientError {
+    /// Connecting to the proxy failed.
+    ProxyUnreachable(io::Error),
+    /// The proxy selected a method which was not offered, typically
+    /// [`NotAcceptable`](crate::v5::AuthenticationMethod::NotAcceptable).
+    MethodNegotiationFailed {
+        offered: Vec<AuthenticationMethod>,
+        selected: AuthenticationMethod,
+    },
+    /// The proxy rejected the credentials.
+    AuthenticationRejected,
+    /// The proxy replied to the request with a failure, SOCKS4 ones being converted.
+    RequestDenied(Status),
+    /// The proxy sent a message which could not be decoded.
+    Protocol(ParseError),
+}
+
+impl ClientError {
+    /// Failure carried by `error`, if it comes from a client handshake.
+    pub fn of(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// Whether trying again, possibly through another proxy, may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::ProxyUnreachable(_) => true,
+            Self::RequestDenied(status) => status.is_retryable(),
+            Self::MethodNegotiationFailed { .. } | Self::AuthenticationRejected => false,
+            Self::Protocol(_) => false,
+        }
+    }
+
+    /// Turns an error holding a [`ParseError`], as decoding a reply fails with, into a
+    /// [`Protocol`](Self::Protocol) one.
+    #[cfg(feature = "async")]
+    pub(crate) fn protocol(error: io::Error) -> io::Error {
+        match error.downcast::<ParseError>() {
+            Ok(e) => Self::Protocol(e).into(),
+            Err(error) => error,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ProxyUnreachable(e) => write!(f, "Proxy unreachable: {e}"),
+            Self::MethodNegotiationFailed { offered, selected } => write!(
+                f,
+                "Server selected an unexpected authentication method: {selected:?}, offered \
+                 {offered:?}"
+            ),
+            Self::AuthenticationRejected => f.write_str("Server rejected credentials"),
+            // Only the status, as callers used to match on it.
+            Self::RequestDenied(status) => write!(f, "{status:?}"),
+            Self::Protocol(e) => write!(f, "Invalid reply from the proxy: {e}"),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::ProxyUnreachable(e) => Some(e),
+            Self::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClientError> for io::Error {
+    fn from(value: ClientError) -> Self {
+        let kind = match value {
+            ClientError::ProxyUnreachable(ref e) => e.kind(),
+            ClientError::MethodNegotiationFailed { .. } => io::ErrorKind::Unsupported,
+            ClientError::AuthenticationRejected => io::ErrorKind::PermissionDenied,
+            ClientError::RequestDenied(status) => status.error_kind(),
+            ClientError::Protocol(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, value)
+    }
+}
+
+/// Turns decoding errors on `input` into `InvalidData` I/O errors.
+#[cfg(feature = "async")]
+pub(crate) fn invalid_data(
+    input: &[u8],
+) -> impl Fn(nom::Err<VerboseError<&[u8]>>) -> io::Error + '_ {
+    move |e| ParseError::new(input, e).into()
+}
+
+/// Whether decoding `input` failed only because it is cut short, parsers being complete ones.
+#[cfg(any(feature = "async", feature = "bytes"))]
+pub(crate) fn is_truncated(err: &nom::Err<VerboseError<&[u8]>>) -> bool {
+    match err {
+        nom::Err::Incomplete(_) => true,
+        nom::Err::Error(e) | nom::Err::Failure(e) => match e.errors.first() {
+            Some((_, VerboseErrorKind::Nom(nom::error::ErrorKind::Eof))) => true,
+            Some((rest, _)) => rest.is_empty(),
+            None => false,
+        },
+    }
+}
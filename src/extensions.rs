@@ -0,0 +1,115 @@
+//! Non-standard SOCKS5 extensions, spoken by some proxies only.
+//!
+//! Extended messages are decoded as long as the feature is enabled, and rejected in
+//! [strict mode](crate::ParseMode::Strict). Experimental address types and commands are only
+//! decoded once registered, as [`AddressType::Other`] and [`Command::Other`]:
+//!
+//! ```
+//! use socks_parser::extensions::{self, AddressFormat};
+//!
+//! extensions::register_address_type(0x81, AddressFormat::Fixed(16)).unwrap();
+//! extensions::register_command(0x82).unwrap();
+//! ```
+//!
+//! [`AddressType::Other`]: crate::v5::AddressType::Other
+//! [`Command::Other`]: crate::v5::Command::Other
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        RwLock,
+    },
+};
+
+/// Address type code of [`AddressType::UnixPath`](crate::v5::AddressType::UnixPath) unless
+/// changed with [`set_unix_path_code`].
+pub const DEFAULT_UNIX_PATH_CODE: u8 = 5;
+
+static UNIX_PATH_CODE: AtomicU8 = AtomicU8::new(DEFAULT_UNIX_PATH_CODE);
+
+/// Address type code of Unix socket paths, for every message encoded or decoded from now on.
+pub fn unix_path_code() -> u8 {
+    UNIX_PATH_CODE.load(Ordering::Relaxed)
+}
+
+fn standard_address_type(code: u8) -> io::Result<()> {
+    if matches!(code, 1 | 3 | 4) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Address type {code} is already standard"),
+        ));
+    }
+    Ok(())
+}
+
+/// Changes the address type code of Unix socket paths, to match tools using another one.
+///
+/// Codes of the standard address types are refused. This code takes precedence over address
+/// types registered with [`register_address_type`].
+pub fn set_unix_path_code(code: u8) -> io::Result<()> {
+    standard_address_type(code)?;
+    UNIX_PATH_CODE.store(code, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Layout of the address following a registered address type code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFormat {
+    /// Always this many bytes.
+    Fixed(usize),
+    /// A length byte, followed by that many bytes.
+    LengthPrefixed,
+}
+
+static ADDRESS_TYPES: RwLock<BTreeMap<u8, AddressFormat>> = RwLock::new(BTreeMap::new());
+static COMMANDS: RwLock<BTreeSet<u8>> = RwLock::new(BTreeSet::new());
+
+/// Decodes addresses of type `code` from now on, registering it again replacing its format.
+///
+/// Codes of the standard address types are refused.
+pub fn register_address_type(code: u8, format: AddressFormat) -> io::Result<()> {
+    standard_address_type(code)?;
+    ADDRESS_TYPES
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(code, format);
+    Ok(())
+}
+
+/// Decodes requests with command `code` from now on.
+///
+/// Codes of the standard commands, of the Tor ones with the `tor` feature and of the MUX one
+/// with the `mux` feature, are refused.
+pub fn register_command(code: u8) -> io::Result<()> {
+    if matches!(code, 1..=3)
+        || cfg!(feature = "tor") && matches!(code, 0xf0 | 0xf1)
+        || cfg!(feature = "mux") && code == 0x80
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Command {code:#04x} is already known"),
+        ));
+    }
+    COMMANDS
+        .write()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(code);
+    Ok(())
+}
+
+pub(crate) fn address_format(code: u8) -> Option<AddressFormat> {
+    ADDRESS_TYPES
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(&code)
+        .copied()
+}
+
+pub(crate) fn is_registered_command(code: u8) -> bool {
+    COMMANDS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(&code)
+}
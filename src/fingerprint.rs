@@ -0,0 +1,210 @@
+//! Identification of SOCKS clients from the way they talk, for abuse detection.
+//!
+//! A [`Fingerprint`] only depends on choices made by the client software: the authentication
+//! methods it offers and their order, the SOCKS4a marker it uses, what it puts in the reserved
+//! byte... Its string form is stable, so it can be logged and aggregated.
+
+use std::fmt;
+
+use crate::{v4, v5, Wire};
+
+/// Handshake traits of a client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Fingerprint {
+    Socks4 {
+        /// Last octet of the `0.0.0.x` address announcing a SOCKS4a domain name.
+        socks4a_marker: Option<u8>,
+        /// Whether the user ID is non-empty.
+        user_id: bool,
+    },
+    Socks5 {
+        /// Authentication methods as offered, repetitions included.
+        methods: Vec<u8>,
+        rsv: u8,
+        /// Address type of the request.
+        address_type: u8,
+        /// Whether more than the hello reached the server before the method reply, when known.
+        pipelined: Option<bool>,
+    },
+}
+
+impl Fingerprint {
+    /// Fingerprints a client from the bytes it sent, such as `Transcript::sent` joined together
+    /// on the client side.
+    ///
+    /// SOCKS5 credentials between the hello and the request are skipped. Returns `None` if
+    /// `data` does not start with a complete SOCKS handshake.
+    pub fn from_client_bytes(data: &[u8]) -> Option<Self> {
+        match *data {
+            [4, ..] => {
+                let (_, request) = v4::Request::decode::<()>(data).ok()?;
+                let socks4a_marker = match request.addr {
+                    v4::AddressType::DomainName(_) => Some(data[7]),
+                    v4::AddressType::IPv4(_) => None,
+                };
+                Some(Self::Socks4 {
+                    socks4a_marker,
+                    user_id: request.secret.is_some(),
+                })
+            }
+            [5, n, ref rest @ ..] if n > 0 && rest.len() >= n.into() => {
+                let (methods, mut rest) = rest.split_at(n.into());
+                if rest.first() == Some(&crate::request::v5::USERNAME_PASSWORD_VERSION) {
+                    (rest, _) = v5::UsernamePassword::decode::<()>(rest).ok()?;
+                }
+                let (_, request) = v5::Request::decode::<()>(rest).ok()?;
+                Some(Self::Socks5 {
+                    methods: methods.to_vec(),
+                    rsv: request.rsv,
+                    address_type: rest[3],
+                    pipelined: None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Fingerprints the client of a handshake recorded by the server, telling whether it waited
+    /// for replies.
+    #[cfg(feature = "async")]
+    pub fn from_transcript(transcript: &crate::recorder::Transcript) -> Option<Self> {
+        use crate::recorder::Direction;
+
+        let client_bytes = transcript.received().flatten().copied().collect::<Vec<_>>();
+        let mut fingerprint = Self::from_client_bytes(&client_bytes)?;
+        if let Self::Socks5 {
+            ref methods,
+            ref mut pipelined,
+            ..
+        } = fingerprint
+        {
+            let hello_len = 2 + methods.len();
+            let before_reply: usize = transcript
+                .records
+                .iter()
+                .take_while(|r| r.direction == Direction::Received)
+                .map(|r| r.data.len())
+                .sum();
+            *pipelined = Some(before_reply > hello_len);
+        }
+        Some(fingerprint)
+    }
+
+    /// Names of the known clients behaving this way, several of them often being alike.
+    pub fn known_clients(&self) -> Vec<&'static str> {
+        SIGNATURES
+            .iter()
+            .filter(|s| s.matches(self))
+            .map(|s| s.client)
+            .collect()
+    }
+}
+
+/// `4:-:-` for SOCKS4, `4a:01:uid` for SOCKS4a, and `5:00,02:00:03` for SOCKS5 with the
+/// offered methods, the reserved byte and the address type, followed by `:p` or `:w` when the
+/// client is known to pipeline its messages or to wait for replies.
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Socks4 {
+                socks4a_marker,
+                user_id,
+            } => {
+                match socks4a_marker {
+                    Some(marker) => write!(f, "4a:{marker:02x}")?,
+                    None => f.write_str("4:-")?,
+                }
+                f.write_str(if *user_id { ":uid" } else { ":-" })
+            }
+            Self::Socks5 {
+                methods,
+                rsv,
+                address_type,
+                pipelined,
+            } => {
+                f.write_str("5:")?;
+                for (i, method) in methods.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{method:02x}")?;
+                }
+                write!(f, ":{rsv:02x}:{address_type:02x}")?;
+                match pipelined {
+                    Some(true) => f.write_str(":p"),
+                    Some(false) => f.write_str(":w"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Traits shared by every handshake of a known client.
+struct Signature {
+    client: &'static str,
+    traits: Traits,
+}
+
+enum Traits {
+    Socks4 { socks4a_marker: Option<u8> },
+    Socks5 { methods: &'static [u8] },
+}
+
+impl Signature {
+    fn matches(&self, fingerprint: &Fingerprint) -> bool {
+        match (&self.traits, fingerprint) {
+            (
+                Traits::Socks4 { socks4a_marker },
+                Fingerprint::Socks4 {
+                    socks4a_marker: marker,
+                    ..
+                },
+            ) => socks4a_marker == marker,
+            (
+                Traits::Socks5 { methods },
+                Fingerprint::Socks5 {
+                    methods: offered,
+                    rsv,
+                    ..
+                },
+            ) => *rsv == 0 && methods == offered,
+            _ => false,
+        }
+    }
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        client: "curl",
+        traits: Traits::Socks5 { methods: &[0] },
+    },
+    Signature {
+        client: "curl",
+        traits: Traits::Socks5 { methods: &[0, 2] },
+    },
+    Signature {
+        client: "curl",
+        traits: Traits::Socks4 {
+            socks4a_marker: Some(1),
+        },
+    },
+    Signature {
+        client: "Firefox",
+        traits: Traits::Socks5 { methods: &[0] },
+    },
+    Signature {
+        client: "ssh (nc -X 5)",
+        traits: Traits::Socks5 { methods: &[0] },
+    },
+    Signature {
+        client: "proxychains",
+        traits: Traits::Socks4 {
+            socks4a_marker: None,
+        },
+    },
+    Signature {
+        client: "proxychains",
+        traits: Traits::Socks5 { methods: &[0, 2] },
+    },
+];
@@ -0,0 +1,195 @@
+//! Reading whole handshake messages from streams delivering them in arbitrary chunks, and
+//! writing them back.
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    ops::Deref,
+    pin::pin,
+    task::Poll,
+};
+
+use nom::error::VerboseError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    error::{invalid_data, is_truncated},
+    DecodeLimits, EncodeBuffer, HandshakeTooLarge, ParseMode, Wire,
+};
+
+/// Bytes received from a client during its handshake, reading no more than
+/// [`DecodeLimits::max_handshake_size`] of them in total.
+pub(crate) struct HandshakeBuffer {
+    bytes: Vec<u8>,
+    /// Bytes of the messages decoded so far, removed from `bytes`.
+    consumed: usize,
+    max: usize,
+}
+
+impl HandshakeBuffer {
+    pub(crate) fn new(limits: &DecodeLimits) -> Self {
+        Self {
+            bytes: Vec::with_capacity(limits.max_handshake_size.min(512)),
+            consumed: 0,
+            max: limits.max_handshake_size,
+        }
+    }
+
+    /// Reads more bytes from `stream`, `0` once it is closed, failing with
+    /// [`HandshakeTooLarge`] if the handshake already spans the maximum.
+    pub(crate) async fn read_from<C: AsyncRead + Unpin>(
+        &mut self,
+        stream: &mut C,
+    ) -> io::Result<usize> {
+        let left = self.max.saturating_sub(self.consumed + self.bytes.len());
+        if left == 0 {
+            return Err(HandshakeTooLarge { limit: self.max }.into());
+        }
+        stream.take(left as u64).read_buf(&mut self.bytes).await
+    }
+
+    /// Removes the first `n` bytes, those of a decoded message.
+    fn consume(&mut self, n: usize) {
+        self.bytes.drain(..n);
+        self.consumed += n;
+    }
+
+    /// Bytes received but not decoded yet.
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Deref for HandshakeBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Decodes the message at the start of `buffer`, returning how many bytes it spans, or `None`
+/// if it is truncated.
+pub(crate) fn decode_message<M: Wire>(
+    buffer: &[u8],
+    limits: &DecodeLimits,
+    mode: ParseMode,
+) -> io::Result<Option<(usize, M)>> {
+    match M::decode_with_limits::<VerboseError<_>>(buffer, limits) {
+        Ok((rest, message)) => {
+            let consumed = buffer.len() - rest.len();
+            if mode == ParseMode::Strict {
+                message
+                    .check_strict(&buffer[..consumed])
+                    .map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))?;
+            }
+            Ok(Some((consumed, message)))
+        }
+        Err(ref e) if is_truncated(e) => Ok(None),
+        Err(e) => Err(invalid_data(buffer)(e)),
+    }
+}
+
+/// Decodes the message at the start of `buffer`, reading more from `stream` until it is
+/// complete. Its bytes are then removed from `buffer`, keeping those which came after it.
+///
+/// Bytes following the message are left alone whatever `mode`, clients being allowed to send
+/// the next one without waiting for a reply.
+pub(crate) async fn read_message<C, M>(
+    stream: &mut C,
+    buffer: &mut HandshakeBuffer,
+    limits: &DecodeLimits,
+    mode: ParseMode,
+) -> io::Result<M>
+where
+    C: AsyncRead + Unpin,
+    M: Wire,
+{
+    loop {
+        if let Some((consumed, message)) = decode_message(buffer, limits, mode)? {
+            buffer.consume(consumed);
+            return Ok(message);
+        }
+        if buffer.read_from(stream).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed in the middle of a message",
+            ));
+        }
+    }
+}
+
+/// Reads a message without consuming any byte past its end, so data following it is left in
+/// `stream`. Messages are at least `min_len` bytes long.
+pub(crate) async fn read_message_exact<C, M>(
+    stream: &mut C,
+    min_len: usize,
+    limits: &DecodeLimits,
+) -> io::Result<M>
+where
+    C: AsyncRead + Unpin,
+    M: Wire,
+{
+    let mut buffer = vec![0; min_len];
+    stream.read_exact(&mut buffer).await?;
+    loop {
+        match M::decode_with_limits::<VerboseError<_>>(&buffer, limits) {
+            Ok((_, message)) => return Ok(message),
+            Err(ref e) if is_truncated(e) => buffer.push(stream.read_u8().await?),
+            Err(e) => return Err(invalid_data(&buffer)(e)),
+        }
+    }
+}
+
+pub(crate) async fn write_message<C, M>(
+    stream: &mut C,
+    encoder: &mut EncodeBuffer,
+    message: &M,
+) -> io::Result<()>
+where
+    C: AsyncWrite + Unpin,
+    M: Wire,
+{
+    stream.write_all(encoder.encode(message)).await
+}
+
+/// Room [`write_reply`] makes for bytes from the destination.
+const PENDING_PAYLOAD_LEN: usize = 4096;
+
+/// Capacity of an [`EncodeBuffer`] never growing while the server replies to an IP
+/// destination.
+pub(crate) const REPLY_BUFFER_LEN: usize = 22 + PENDING_PAYLOAD_LEN;
+
+/// Writes the successful `reply` to a request along with what `remote` already sent, in a
+/// single write, sparing a segment to protocols where the server speaks first.
+///
+/// `remote` is polled once and never waited for. When it reports the end of the stream, the
+/// reply is written alone and the relay gets to see that end on its next read.
+pub(crate) async fn write_reply<C, S, M>(
+    stream: &mut C,
+    encoder: &mut EncodeBuffer,
+    reply: &M,
+    remote: &mut S,
+) -> io::Result<()>
+where
+    C: AsyncWrite + Unpin,
+    S: AsyncRead + Unpin,
+    M: Wire,
+{
+    let buffer = encoder.reset();
+    buffer.reserve_exact(reply.encoded_len() + PENDING_PAYLOAD_LEN);
+    reply.encode_into(buffer);
+
+    let polled = {
+        let mut read = pin!(remote.read_buf(buffer));
+        poll_fn(|cx| Poll::Ready(read.as_mut().poll(cx))).await
+    };
+    if let Poll::Ready(Ok(pending @ 1..)) = polled {
+        log::trace!("Sending {pending} bytes from the destination along with the reply");
+    }
+    stream.write_all(buffer).await?;
+    match polled {
+        Poll::Ready(Err(e)) => Err(e),
+        _ => Ok(()),
+    }
+}
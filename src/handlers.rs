@@ -0,0 +1,554 @@
+//! Ready-made handlers for [`Server::run`](crate::Server::run).
+//!
+//! Request handlers fail with `TimedOut` once the [`ConnectionRequest::deadline`] passes.
+//!
+//! Handlers keeping state may rather implement [`RequestHandler`] with an `async fn`, served by
+//! [`Server::run_handler`](crate::Server::run_handler) without boxing their futures, or
+//! [`DynRequestHandler`] to pick one at runtime. Both signatures rely on `impl Trait` in trait
+//! methods, within the minimum supported Rust version of the crate (1.84, the `rust-version` of
+//! its manifest).
+
+use std::{collections::HashMap, future::Future, io, net::SocketAddr, sync::Arc, time::Instant};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::{
+    auth::BoxFuture,
+    honeypot::{Honeypot, HoneypotStream},
+    policy::UserPolicies,
+    pool::{PooledStream, UpstreamPool},
+    proxy::ProxyUrl,
+    resolver::{self, Resolver, SystemResolver},
+    rewrite::{self, RewriteRules},
+    stream::{AsyncStream, DynStream, StreamTransform},
+    throttle::{BandwidthLimit, Throttled, TokenBucket},
+    Client, ConnectOptions, ConnectionRequest, Destination,
+};
+
+/// Fails with `TimedOut` if `attempt` is still running at `deadline`.
+async fn until<T>(
+    deadline: Option<Instant>,
+    attempt: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), attempt)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Request deadline exceeded"))?,
+        None => attempt.await,
+    }
+}
+
+/// Request handler forwarding every request to another SOCKS proxy.
+///
+/// The address reported to the client is the one bound by `upstream`.
+pub fn chain_to(
+    upstream: ProxyUrl,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(TcpStream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    chain_through(upstream, ConnectOptions::default())
+}
+
+/// Same as [`chain_to`], connecting to `upstream` with `dialer`, over TLS or an SSH channel for
+/// instance.
+pub fn chain_through<D: crate::Dialer + 'static>(
+    upstream: ProxyUrl,
+    dialer: D,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(D::Stream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    let upstream = Arc::new(upstream);
+    let dialer = Arc::new(dialer);
+    move |req| {
+        Box::pin(until(req.deadline, async move {
+            log::debug!("Forwarding {} to {}", req.destination, upstream);
+            let mut negotiated = Client::dial_with(&upstream, &*dialer)
+                .await?
+                .handshake_only()
+                .await?;
+            let bound = negotiated
+                .request(crate::v5::Command::Connect, req.destination)
+                .await?;
+            Ok((negotiated.into_inner(), bound))
+        }))
+    }
+}
+
+/// Request handler forwarding every request through one of the proxies of `pool`.
+#[allow(clippy::type_complexity)]
+pub fn chain_to_pool<D: crate::Dialer + 'static>(
+    pool: Arc<UpstreamPool<D>>,
+) -> impl FnOnce(
+    ConnectionRequest,
+) -> BoxFuture<'static, io::Result<(PooledStream<D::Stream>, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    move |req| Box::pin(async move { until(req.deadline, pool.connect(req.destination)).await })
+}
+
+/// How [`route_by_userid`] reaches a destination.
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// Connects from this host, typically binding to an egress interface or address.
+    Direct(ConnectOptions),
+    /// Forwards the request to another SOCKS proxy.
+    Proxy(ProxyUrl),
+}
+
+/// Connects with `options` to the first address of `destination` accepting the connection,
+/// reporting the local address as bound.
+async fn connect_directly(
+    options: &ConnectOptions,
+    destination: &Destination,
+    addrs: impl IntoIterator<Item = SocketAddr>,
+) -> io::Result<(TcpStream, Destination)> {
+    let mut last_error = None;
+    for addr in addrs {
+        match options.connect(addr).await {
+            Ok(stream) => {
+                let bound = stream.local_addr()?.into();
+                return Ok((stream, bound));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::HostUnreachable,
+            format!("No address for {destination}"),
+        )
+    }))
+}
+
+impl Route {
+    async fn connect(&self, req: ConnectionRequest) -> io::Result<(TcpStream, Destination)> {
+        let destination = req.destination;
+        match self {
+            Self::Direct(options) => {
+                let addrs = resolver::resolve_and_pin(
+                    &SystemResolver,
+                    &destination,
+                    req.acl.as_deref(),
+                    req.allow_private,
+                )
+                .await?;
+                connect_directly(options, &destination, addrs).await
+            }
+            Self::Proxy(upstream) => {
+                let mut negotiated = Client::dial(upstream).await?.handshake_only().await?;
+                let bound = negotiated
+                    .request(crate::v5::Command::Connect, destination)
+                    .await?;
+                Ok((negotiated.into_inner(), bound))
+            }
+        }
+    }
+}
+
+/// Request handler picking the route of SOCKS4 requests from their user id.
+///
+/// Requests without a user id listed in `routes`, including every SOCKS5 request, are denied.
+pub fn route_by_userid(
+    routes: HashMap<String, Route>,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(TcpStream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    let routes = Arc::new(routes);
+    move |req| {
+        Box::pin(async move {
+            let route = req
+                .secret
+                .as_ref()
+                .and_then(|user_id| routes.get(user_id))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("No route for request to {}", req.destination),
+                    )
+                })?;
+            log::debug!("Routing {} through {route:?}", req.destination);
+            until(req.deadline, route.connect(req)).await
+        })
+    }
+}
+
+/// Request handler going through the proxy picked by `rules` for each request, connecting
+/// directly with `options` otherwise.
+///
+/// `rules` must also be a [layer](crate::Server::with_layer) of the server.
+pub fn follow_rewrites(
+    rules: RewriteRules,
+    options: ConnectOptions,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(TcpStream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    move |req| {
+        Box::pin(async move {
+            let route = match req.annotations.get(rewrite::UPSTREAM) {
+                Some(name) => Route::Proxy(rules.proxy(name).cloned().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("Unknown proxy {name:?}"))
+                })?),
+                None => Route::Direct(options),
+            };
+            log::debug!("Routing {} through {route:?}", req.destination);
+            until(req.deadline, route.connect(req)).await
+        })
+    }
+}
+
+/// Request handler connecting directly with `options`, resolving domain names with `resolver`.
+///
+/// `resolver` is shared by every request, typically a
+/// [`CachingResolver`](crate::resolver::CachingResolver) sparing lookups of popular
+/// destinations. Other address types are not supported.
+///
+/// Names are resolved once with [`resolve_and_pin`](crate::resolver::resolve_and_pin), connecting
+/// to the very addresses checked against [`ConnectionRequest::allow_private`] and
+/// [`ConnectionRequest::acl`].
+pub fn connect_with<R: Resolver + 'static>(
+    resolver: R,
+    options: ConnectOptions,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(TcpStream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    let resolver = Arc::new(resolver);
+    let options = Arc::new(options);
+    move |req| {
+        Box::pin(until(req.deadline, async move {
+            let destination = req.destination;
+            let addrs = resolver::resolve_and_pin(
+                &*resolver,
+                &destination,
+                req.acl.as_deref(),
+                req.allow_private,
+            )
+            .await?;
+            connect_directly(&options, &destination, addrs).await
+        }))
+    }
+}
+
+/// Request handler wrapping `handle_request`, whose streams are limited to the bandwidth of the
+/// user who asked for them according to `policies`.
+///
+/// Every tunnel of a user shares their allowance, streams opened for other clients are left
+/// alone.
+pub fn throttle_users<HC, FC, S>(
+    policies: Arc<UserPolicies>,
+    handle_request: HC,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(Throttled<S>, Destination)>>
+       + Send
+       + Clone
+       + 'static
+where
+    HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+    FC: Future<Output = io::Result<(S, Destination)>> + Send + 'static,
+    S: Send + 'static,
+{
+    move |req| {
+        Box::pin(async move {
+            let identity = req.identity.clone();
+            let (stream, bound) = handle_request(req).await?;
+            Ok((policies.throttle(identity.as_ref(), stream), bound))
+        })
+    }
+}
+
+/// Request handler wrapping `handle_request`, boxing the streams it opens into a [`DynStream`].
+///
+/// Handlers opening streams of different types, once boxed, can be picked from by a single
+/// handler:
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// use socks_parser::{handlers, proxy::ProxyUrl, resolver::SystemResolver, v5::AddressType};
+/// use socks_parser::{ConnectOptions, ConnectionRequest, Server};
+///
+/// let direct = handlers::boxed(handlers::connect_with(SystemResolver, ConnectOptions::default()));
+/// let onion = handlers::boxed(handlers::chain_to("socks5h://127.0.0.1:9050".parse()?));
+/// let handle_request = move |req: ConnectionRequest| match req.destination.addr {
+///     AddressType::DomainName(ref name) if name.ends_with(".onion") => onion(req),
+///     _ => direct(req),
+/// };
+/// Server::new(tokio::net::TcpListener::bind("127.0.0.1:1080").await?)
+///     .run(handle_request, handlers::relay)
+///     .await
+/// # }
+/// ```
+pub fn boxed<HC, FC, S>(
+    handle_request: HC,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(DynStream, Destination)>>
+       + Send
+       + Clone
+       + 'static
+where
+    HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+    FC: Future<Output = io::Result<(S, Destination)>> + Send + 'static,
+    S: AsyncStream + 'static,
+{
+    move |req| {
+        Box::pin(async move {
+            let (stream, bound) = handle_request(req).await?;
+            Ok((Box::new(stream) as DynStream, bound))
+        })
+    }
+}
+
+/// Request handler keeping its state across requests.
+///
+/// Implementations may use `async fn handle`, as long as the future is `Send`:
+///
+/// ```
+/// use std::{io, sync::atomic::{AtomicU64, Ordering}};
+///
+/// use socks_parser::{handlers::RequestHandler, ConnectionRequest, Destination};
+/// use tokio::net::TcpStream;
+///
+/// struct Counting {
+///     requests: AtomicU64,
+/// }
+///
+/// impl RequestHandler for Counting {
+///     type Stream = TcpStream;
+///
+///     async fn handle(&self, req: ConnectionRequest) -> io::Result<(TcpStream, Destination)> {
+///         self.requests.fetch_add(1, Ordering::Relaxed);
+///         let stream = TcpStream::connect(req.destination.to_string()).await?;
+///         let bound = stream.local_addr()?.into();
+///         Ok((stream, bound))
+///     }
+/// }
+/// ```
+pub trait RequestHandler: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn handle(
+        &self,
+        req: ConnectionRequest,
+    ) -> impl Future<Output = io::Result<(Self::Stream, Destination)>> + Send;
+}
+
+impl<H: RequestHandler + ?Sized> RequestHandler for Arc<H> {
+    type Stream = H::Stream;
+
+    fn handle(
+        &self,
+        req: ConnectionRequest,
+    ) -> impl Future<Output = io::Result<(Self::Stream, Destination)>> + Send {
+        (**self).handle(req)
+    }
+}
+
+impl<H: RequestHandler + ?Sized> RequestHandler for Box<H> {
+    type Stream = H::Stream;
+
+    fn handle(
+        &self,
+        req: ConnectionRequest,
+    ) -> impl Future<Output = io::Result<(Self::Stream, Destination)>> + Send {
+        (**self).handle(req)
+    }
+}
+
+/// Object safe [`RequestHandler`] returning boxed futures, implemented by every request
+/// handler, to choose between handlers opening streams of the same type at runtime.
+pub trait DynRequestHandler<S>: Send + Sync {
+    fn handle_boxed(&self, req: ConnectionRequest) -> BoxFuture<'_, io::Result<(S, Destination)>>;
+}
+
+impl<H: RequestHandler> DynRequestHandler<H::Stream> for H {
+    fn handle_boxed(
+        &self,
+        req: ConnectionRequest,
+    ) -> BoxFuture<'_, io::Result<(H::Stream, Destination)>> {
+        Box::pin(self.handle(req))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> RequestHandler for dyn DynRequestHandler<S> {
+    type Stream = S;
+
+    fn handle(
+        &self,
+        req: ConnectionRequest,
+    ) -> impl Future<Output = io::Result<(S, Destination)>> + Send {
+        self.handle_boxed(req)
+    }
+}
+
+/// Opens streams to destinations over a transport of its own, such as the `direct-tcpip`
+/// channels of an SSH session.
+pub trait Dialer: Send + Sync {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn dial<'a>(&'a self, destination: &'a Destination) -> BoxFuture<'a, io::Result<Self::Stream>>;
+}
+
+impl<D: Dialer + ?Sized> Dialer for Arc<D> {
+    type Stream = D::Stream;
+
+    fn dial<'a>(&'a self, destination: &'a Destination) -> BoxFuture<'a, io::Result<Self::Stream>> {
+        (**self).dial(destination)
+    }
+}
+
+/// Request handler accepting every request without connecting anywhere, see
+/// [`honeypot`](crate::honeypot).
+pub fn honeypot(
+    honeypot: Honeypot,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(HoneypotStream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    let honeypot = Arc::new(honeypot);
+    move |req| Box::pin(async move { honeypot.handle(req).await })
+}
+
+/// Request handler opening every stream with `dialer`, as `ssh -D` does.
+///
+/// The transport has no local address to report, so clients are told the unspecified address
+/// `0.0.0.0:0` was bound, like OpenSSH does.
+pub fn local_dynamic_forward<D: Dialer + 'static>(
+    dialer: D,
+) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(D::Stream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    let dialer = Arc::new(dialer);
+    move |req| {
+        Box::pin(async move {
+            log::debug!("Dialing {}", req.destination);
+            let stream = until(req.deadline, dialer.dial(&req.destination)).await?;
+            let bound = std::net::SocketAddr::from((std::net::Ipv4Addr::UNSPECIFIED, 0));
+            Ok((stream, bound.into()))
+        })
+    }
+}
+
+/// Request handler connecting to the Unix sockets listed in `allowed`, for clients using the
+/// [`AddressType::UnixPath`](crate::v5::AddressType::UnixPath) extension.
+///
+/// Other destinations are denied, combine it with another handler through
+/// [`EitherStream`](crate::stream::EitherStream) to serve them as well. The reported bound
+/// address is the socket path.
+#[cfg(all(feature = "extensions", unix))]
+pub fn unix_sockets(
+    allowed: impl IntoIterator<Item = std::path::PathBuf>,
+) -> impl FnOnce(
+    ConnectionRequest,
+) -> BoxFuture<'static, io::Result<(tokio::net::UnixStream, Destination)>>
+       + Send
+       + Clone
+       + 'static {
+    let allowed: Arc<std::collections::HashSet<_>> = Arc::new(allowed.into_iter().collect());
+    move |req| {
+        Box::pin(async move {
+            match req.destination.addr {
+                crate::v5::AddressType::UnixPath(ref path) if allowed.contains(path) => {
+                    log::debug!("Connecting to Unix socket {}", path.display());
+                    let stream = until(req.deadline, tokio::net::UnixStream::connect(path)).await?;
+                    Ok((stream, req.destination))
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!(
+                        "Destination {} is not an allowed Unix socket",
+                        req.destination
+                    ),
+                )),
+            }
+        })
+    }
+}
+
+/// Stream handler copying data both ways until either side closes.
+///
+/// See [`relay::pipe_zero_copy`](crate::relay::pipe_zero_copy) to spare the copies between
+/// TCP streams.
+pub async fn relay<L, S>(mut local: L, mut remote: S) -> io::Result<(u64, u64)>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::io::copy_bidirectional(&mut local, &mut remote).await
+}
+
+/// Stream handler relaying TCP streams on the io_uring threads of `relay`, see
+/// [`UringRelay`](crate::relay::UringRelay).
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub fn relay_uring<L, S>(
+    relay: Arc<crate::relay::UringRelay>,
+) -> impl FnOnce(L, S) -> BoxFuture<'static, io::Result<(u64, u64)>> + Send + Clone + 'static
+where
+    L: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    move |local, remote| Box::pin(async move { relay.pipe(local, remote).await })
+}
+
+/// Whether a [`BandwidthLimit`] applies to each connection or to all of them together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitScope {
+    PerConnection,
+    Global,
+}
+
+/// Stream handler relaying like [`relay`], within `limit`.
+pub fn relay_throttled<L, S>(
+    limit: BandwidthLimit,
+    scope: LimitScope,
+) -> impl FnOnce(L, S) -> BoxFuture<'static, io::Result<(u64, u64)>> + Send + Clone + 'static
+where
+    L: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let buckets = move || {
+        (
+            limit.upload.map(|rate| Arc::new(TokenBucket::new(rate))),
+            limit.download.map(|rate| Arc::new(TokenBucket::new(rate))),
+        )
+    };
+    let global = match scope {
+        LimitScope::Global => Some(buckets()),
+        LimitScope::PerConnection => None,
+    };
+    move |local, remote| {
+        let (upload, download) = global.unwrap_or_else(buckets);
+        Box::pin(relay(
+            Throttled::new(local, upload),
+            Throttled::new(remote, download),
+        ))
+    }
+}
+
+/// Stream handler wrapping the client stream with `transform` before handing it to
+/// `handle_stream`, clients speaking plain SOCKS then the protocol of `transform` over the same
+/// connection.
+pub fn transform_client<L, S, T, HS, FS, R>(
+    transform: T,
+    handle_stream: HS,
+) -> impl FnOnce(L, S) -> BoxFuture<'static, io::Result<R>> + Send + Clone + 'static
+where
+    L: Send + 'static,
+    S: Send + 'static,
+    T: StreamTransform<L> + 'static,
+    HS: FnOnce(T::Output, S) -> FS + Send + Clone + 'static,
+    FS: Future<Output = io::Result<R>> + Send,
+{
+    let transform = Arc::new(transform);
+    move |local, remote| {
+        Box::pin(async move {
+            let local = transform.transform(local).await?;
+            handle_stream(local, remote).await
+        })
+    }
+}
@@ -0,0 +1,241 @@
+//! Request handler accepting every request without reaching any destination, for researchers
+//! watching what clients of an open proxy try.
+//!
+//! Clients are told their request succeeded, then get a [`HoneypotStream`] swallowing what
+//! they send and answering as its [`Tarpit`] says. With the `audit` feature, what they sent is
+//! recorded as an [`AuditKind::HoneypotPayload`](crate::audit::AuditKind::HoneypotPayload)
+//! event, the server reporting their authentication attempts and requests to the same sink.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use std::time::Duration;
+//!
+//! use socks_parser::{
+//!     handlers,
+//!     honeypot::{Honeypot, Tarpit},
+//!     Server,
+//! };
+//!
+//! let bound = std::net::SocketAddr::from(([203, 0, 113, 7], 40000));
+//! let honeypot = Honeypot::new(bound).with_tarpit(Tarpit::Drip {
+//!     banner: b"HTTP/1.1 200 OK\r\n".to_vec(),
+//!     interval: Duration::from_secs(10),
+//! });
+//! Server::new(tokio::net::TcpListener::bind("0.0.0.0:1080").await?)
+//!     .run_handler(honeypot, handlers::relay)
+//!     .await
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+#[cfg(feature = "audit")]
+use crate::audit::AuditSink;
+use crate::{handlers::RequestHandler, ConnectionRequest, Destination};
+
+/// What clients of a [`Honeypot`] read from their tunnel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Tarpit {
+    /// Nothing, the tunnel ending right away as if the destination closed the connection.
+    #[default]
+    Eof,
+    /// `banner`, one byte every `interval` over and over, holding clients as long as they wait.
+    /// An empty banner holds them without sending anything.
+    Drip { banner: Vec<u8>, interval: Duration },
+}
+
+/// Request handler accepting every request, see the [module documentation](self).
+#[derive(Clone)]
+pub struct Honeypot {
+    bound: Destination,
+    tarpit: Tarpit,
+    #[cfg(feature = "audit")]
+    audit: Option<(std::sync::Arc<dyn AuditSink>, usize)>,
+}
+
+impl Honeypot {
+    /// Tells clients `bound` is the address used to reach their destination.
+    pub fn new(bound: impl Into<Destination>) -> Self {
+        Self {
+            bound: bound.into(),
+            tarpit: Tarpit::default(),
+            #[cfg(feature = "audit")]
+            audit: None,
+        }
+    }
+
+    /// The tunnel ends right away by default.
+    pub fn with_tarpit(mut self, tarpit: Tarpit) -> Self {
+        self.tarpit = tarpit;
+        self
+    }
+
+    /// Reports up to `capture_limit` bytes sent by each client to `sink`, once its tunnel is
+    /// closed.
+    #[cfg(feature = "audit")]
+    pub fn with_audit_sink(mut self, sink: impl AuditSink + 'static, capture_limit: usize) -> Self {
+        self.audit = Some((std::sync::Arc::new(sink), capture_limit));
+        self
+    }
+}
+
+impl std::fmt::Debug for Honeypot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Honeypot")
+            .field("bound", &self.bound)
+            .field("tarpit", &self.tarpit)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RequestHandler for Honeypot {
+    type Stream = HoneypotStream;
+
+    async fn handle(&self, req: ConnectionRequest) -> io::Result<(HoneypotStream, Destination)> {
+        log::info!(
+            "Honeypot request for {} from {}",
+            req.destination,
+            req.peer
+                .map_or_else(|| "unknown client".into(), |peer| peer.to_string())
+        );
+        let stream = HoneypotStream {
+            tarpit: self.tarpit.clone(),
+            position: 0,
+            sleep: None,
+            #[cfg(feature = "audit")]
+            capture: self.audit.as_ref().map(|(sink, limit)| Capture {
+                sink: std::sync::Arc::clone(sink),
+                limit: *limit,
+                request: req,
+                payload: Vec::new(),
+            }),
+        };
+        Ok((stream, self.bound.clone()))
+    }
+}
+
+/// Tunnel handed out by a [`Honeypot`], connected nowhere.
+///
+/// Writes always succeed, their data being dropped once recorded if asked to.
+pub struct HoneypotStream {
+    tarpit: Tarpit,
+    /// Position of the next byte to send in the banner.
+    position: usize,
+    sleep: Option<Pin<Box<Sleep>>>,
+    #[cfg(feature = "audit")]
+    capture: Option<Capture>,
+}
+
+impl std::fmt::Debug for HoneypotStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HoneypotStream")
+            .field("tarpit", &self.tarpit)
+            .field("position", &self.position)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for HoneypotStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Tarpit::Drip {
+            ref banner,
+            interval,
+        } = this.tarpit
+        else {
+            return Poll::Ready(Ok(()));
+        };
+        let sleep = this
+            .sleep
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(interval)));
+        loop {
+            ready!(sleep.as_mut().poll(cx));
+            sleep.as_mut().reset(tokio::time::Instant::now() + interval);
+            if let Some(&byte) = banner.get(this.position % banner.len().max(1)) {
+                this.position += 1;
+                buf.put_slice(&[byte]);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl AsyncWrite for HoneypotStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        #[cfg(feature = "audit")]
+        if let Some(ref mut capture) = self.get_mut().capture {
+            let room = capture.limit.saturating_sub(capture.payload.len());
+            capture
+                .payload
+                .extend_from_slice(&buf[..room.min(buf.len())]);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// What a client sent, reported once its tunnel is dropped.
+#[cfg(feature = "audit")]
+struct Capture {
+    sink: std::sync::Arc<dyn AuditSink>,
+    limit: usize,
+    request: ConnectionRequest,
+    payload: Vec<u8>,
+}
+
+#[cfg(feature = "audit")]
+impl Drop for HoneypotStream {
+    fn drop(&mut self) {
+        use crate::audit::{AuditEvent, AuditKind};
+
+        let Some(capture) = self.capture.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!("Cannot record honeypot payload outside of a runtime");
+            return;
+        };
+        let event = AuditEvent {
+            time: std::time::SystemTime::now(),
+            peer: capture
+                .request
+                .peer
+                .unwrap_or_else(|| std::net::SocketAddr::from(([0, 0, 0, 0], 0))),
+            kind: AuditKind::HoneypotPayload {
+                destination: capture.request.destination,
+                payload: capture.payload,
+            },
+        };
+        runtime.spawn(async move {
+            if let Err(e) = capture.sink.record(event).await {
+                log::warn!("Could not record audit event: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,203 @@
+use std::{fmt, net::IpAddr};
+
+use base64::Engine;
+
+use nom::{
+    bytes::complete::{is_not, tag, take, take_till1},
+    character::complete::crlf,
+    combinator::{map, map_opt, opt},
+    error::context,
+    multi::many_till,
+    sequence::{delimited, preceded, terminated, tuple},
+};
+
+use crate::{v5::AddressType, Destination, Redacted, Wire};
+
+/// HTTP proxy `CONNECT host:port` request.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+    pub destination: Destination,
+    /// Value of the `Proxy-Authorization` header, if any.
+    pub proxy_authorization: Option<String>,
+}
+
+impl ConnectRequest {
+    /// Username and password of a `Proxy-Authorization: Basic` header.
+    pub fn basic_credentials(&self) -> Option<(String, String)> {
+        let (scheme, encoded) = self.proxy_authorization.as_deref()?.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()?;
+        let (username, password) = std::str::from_utf8(&decoded).ok()?.split_once(':')?;
+        Some((username.to_owned(), password.to_owned()))
+    }
+}
+
+impl fmt::Debug for ConnectRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectRequest")
+            .field("destination", &self.destination)
+            .field(
+                "proxy_authorization",
+                &self.proxy_authorization.as_ref().map(|_| Redacted),
+            )
+            .finish()
+    }
+}
+
+/// Status line answering a [`ConnectRequest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectResponse {
+    pub status: u16,
+}
+
+impl ConnectResponse {
+    pub const ESTABLISHED: Self = Self { status: 200 };
+    pub const BAD_REQUEST: Self = Self { status: 400 };
+    pub const FORBIDDEN: Self = Self { status: 403 };
+    pub const PROXY_AUTHENTICATION_REQUIRED: Self = Self { status: 407 };
+    pub const BAD_GATEWAY: Self = Self { status: 502 };
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "Connection Established",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            407 => "Proxy Authentication Required",
+            502 => "Bad Gateway",
+            504 => "Gateway Timeout",
+            _ => "Unknown",
+        }
+    }
+}
+
+fn parse_authority(authority: &str) -> Option<Destination> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let addr = match host.parse::<IpAddr>() {
+        Ok(ip) => ip.into(),
+        Err(_) if !host.is_empty() && host.len() <= u8::MAX as usize => {
+            AddressType::DomainName(host.to_owned())
+        }
+        Err(_) => return None,
+    };
+    Some(Destination { addr, port })
+}
+
+fn skip_headers<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], (), E>
+where
+    E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+{
+    context(
+        "headers",
+        map(many_till(terminated(is_not("\r\n"), crlf), crlf), |_| ()),
+    )(buffer)
+}
+
+/// Value of the `Proxy-Authorization` header among the remaining headers, the others being
+/// skipped.
+fn proxy_authorization<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Option<String>, E>
+where
+    E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+{
+    context(
+        "headers",
+        map(
+            many_till(terminated(is_not("\r\n"), crlf), crlf),
+            |(lines, _): (Vec<&[u8]>, _)| {
+                lines.into_iter().find_map(|line| {
+                    let colon = line.iter().position(|&b| b == b':')?;
+                    let (name, value) = line.split_at(colon);
+                    name.trim_ascii()
+                        .eq_ignore_ascii_case(b"proxy-authorization")
+                        .then(|| String::from_utf8_lossy(value[1..].trim_ascii()).into_owned())
+                })
+            },
+        ),
+    )(buffer)
+}
+
+impl Wire for ConnectRequest {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        let authority = match self.destination.addr {
+            AddressType::IPv6(ref ip6) => format!("[{ip6}]:{}", self.destination.port),
+            ref addr => format!("{addr}:{}", self.destination.port),
+        };
+        buffer.extend_from_slice(
+            format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n").as_bytes(),
+        );
+        if let Some(ref authorization) = self.proxy_authorization {
+            buffer
+                .extend_from_slice(format!("Proxy-Authorization: {authorization}\r\n").as_bytes());
+        }
+        buffer.extend_from_slice(b"\r\n");
+    }
+
+    fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        context(
+            "HTTP CONNECT request",
+            map(
+                tuple((
+                    delimited(
+                        tag("CONNECT "),
+                        map_opt(take_till1(|b| b == b' '), |b| {
+                            std::str::from_utf8(b).ok().and_then(parse_authority)
+                        }),
+                        tuple((tag(" HTTP/1."), take(1usize), crlf)),
+                    ),
+                    proxy_authorization,
+                )),
+                |(destination, proxy_authorization)| Self {
+                    destination,
+                    proxy_authorization,
+                },
+            ),
+        )(buffer)
+    }
+}
+
+impl Wire for ConnectResponse {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(
+            format!("HTTP/1.1 {} {}\r\n", self.status, self.reason()).as_bytes(),
+        );
+        if self.status == 407 {
+            buffer.extend_from_slice(b"Proxy-Authenticate: Basic realm=\"proxy\"\r\n");
+        }
+        buffer.extend_from_slice(b"\r\n");
+    }
+
+    fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        context(
+            "HTTP CONNECT response",
+            map(
+                terminated(
+                    preceded(
+                        tuple((tag("HTTP/1."), take(1usize), tag(" "))),
+                        terminated(
+                            map_opt(take(3usize), |b| {
+                                std::str::from_utf8(b).ok().and_then(|s| s.parse().ok())
+                            }),
+                            terminated(opt(is_not("\r\n")), crlf),
+                        ),
+                    ),
+                    skip_headers,
+                ),
+                |status| Self { status },
+            ),
+        )(buffer)
+    }
+}
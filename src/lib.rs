@@ -1,18 +1,96 @@
-use std::net::SocketAddr;
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+pub mod acl;
+#[cfg(feature = "audit")]
+pub mod audit;
+pub mod auth;
+#[cfg(feature = "codec")]
+pub mod codec;
 pub mod common;
+#[cfg(feature = "config")]
+pub mod config;
+mod error;
+#[cfg(feature = "extensions")]
+pub mod extensions;
+pub mod fingerprint;
+#[cfg(feature = "async")]
+mod framing;
+#[cfg(feature = "http-connect")]
+pub mod http;
+mod limits;
+#[cfg(feature = "mux")]
+pub mod mux;
+#[cfg(feature = "pac")]
+pub mod pac;
+mod parse;
+#[cfg(feature = "pcap")]
+pub mod pcap;
+pub mod prelude;
+pub mod proxy;
+#[cfg(feature = "quic")]
+pub mod quic;
 mod request;
 mod response;
+pub mod sansio;
+pub mod sniff;
+pub mod stats;
+pub mod udp;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 #[cfg(feature = "async")]
 mod client;
 #[cfg(feature = "async")]
-pub use client::Client;
+pub use client::{
+    Client, ConnectOptions, Credentials, Dialer, IntoSocksAddr, NegotiatedStream, ProbeReport,
+    TryIntoSocksAddr,
+};
+#[cfg(feature = "async")]
+pub mod handlers;
+#[cfg(feature = "async")]
+pub mod honeypot;
+#[cfg(feature = "async")]
+pub mod policy;
+#[cfg(feature = "async")]
+pub mod pool;
+#[cfg(feature = "async")]
+pub mod recorder;
+#[cfg(feature = "async")]
+pub mod relay;
+#[cfg(all(feature = "async", feature = "tor"))]
+pub mod resolve;
+#[cfg(feature = "async")]
+pub mod resolver;
+#[cfg(feature = "async")]
+pub mod rewrite;
 #[cfg(feature = "async")]
 mod server;
-pub use server::Server;
+#[cfg(feature = "async")]
+pub mod stream;
+#[cfg(feature = "async")]
+pub mod testing;
+#[cfg(feature = "async")]
+pub mod throttle;
+#[cfg(all(feature = "async", windows))]
+pub use server::NamedPipeListener;
+#[cfg(feature = "async")]
+pub use server::{
+    BindOptions, ConnectionId, Decision, FailureReplyAddress, HandshakeRequest, Incoming,
+    InspectedRequest, Layer, Listener, ListenerOverrides, Rejection, ReplyWriter, Server,
+    ServerHandshake,
+};
 
 pub use common::Version;
+pub use error::{ClientError, HandshakeTooLarge, HelloError, ParseError, ParseErrorKind};
+pub use limits::{DecodeLimits, ParseMode};
+pub use parse::{parse_request, AnyRequest};
+pub use sniff::{sniff, MaybeSocks};
 
 pub use nom;
 
@@ -24,6 +102,8 @@ pub mod v4 {
     pub use crate::request::v4::Request;
     pub use crate::response::v4::{Response, Status};
 
+    use std::net::Ipv4Addr;
+
     impl From<Request> for super::ConnectionRequest {
         fn from(value: Request) -> Self {
             let addr = match value.addr {
@@ -35,21 +115,26 @@ pub mod v4 {
                     addr,
                     port: value.port,
                 },
+                identity: None,
+                secret: value.secret,
+                peer: None,
+                deadline: None,
+                annotations: Default::default(),
+                allow_private: true,
+                acl: None,
             }
         }
     }
 
     impl From<super::ConnectionResponse> for Response {
         fn from(value: super::ConnectionResponse) -> Self {
-            let addr = match value.connected_to.addr {
-                crate::common::v5::AddressType::IPv4(ip4) => ip4,
-                _ => 0u32.into(),
-            };
+            let addr = value
+                .connected_to
+                .addr
+                .to_ipv4()
+                .unwrap_or(Ipv4Addr::UNSPECIFIED);
             Self {
-                status: match value.status {
-                    crate::v5::Status::Success => Status::Success,
-                    _ => Status::Rejected,
-                },
+                status: value.status.into(),
                 addr,
                 port: value.connected_to.port,
             }
@@ -59,11 +144,13 @@ pub mod v4 {
 
 pub mod v5 {
     pub use crate::common::{
-        v5::{AddressType, AuthenticationMethod, Command},
+        v5::{AddressType, AuthenticationMethod, Command, UdpHeader},
         Version,
     };
-    pub use crate::request::v5::{Hello, Request};
-    pub use crate::response::v5::{Hello as HelloResponse, Response, Status};
+    pub use crate::request::v5::{Hello, Request, UsernamePassword};
+    pub use crate::response::v5::{
+        Hello as HelloResponse, Response, Status, UsernamePassword as UsernamePasswordResponse,
+    };
 
     impl From<Request> for super::ConnectionRequest {
         fn from(value: Request) -> Self {
@@ -72,6 +159,13 @@ pub mod v5 {
                     addr: value.addr,
                     port: value.port,
                 },
+                identity: None,
+                secret: None,
+                peer: None,
+                deadline: None,
+                annotations: Default::default(),
+                allow_private: true,
+                acl: None,
             }
         }
     }
@@ -130,17 +224,195 @@ impl From<SocketAddr> for Destination {
     }
 }
 
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+/// Resolves domain names with the system resolver, which blocks the current thread.
+impl ToSocketAddrs for Destination {
+    type Iter = std::vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        if let Some(addr) = self.addr.to_socket_addr(self.port) {
+            return Ok(vec![addr].into_iter());
+        }
+        match self.addr {
+            v5::AddressType::DomainName(ref n) => (n.as_str(), self.port).to_socket_addrs(),
+            #[cfg(feature = "extensions")]
+            ref addr @ (v5::AddressType::UnixPath(_) | v5::AddressType::Other { .. }) => {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Cannot resolve {addr}"),
+                ))
+            }
+            v5::AddressType::IPv4(_) | v5::AddressType::IPv6(_) => unreachable!(),
+        }
+    }
+}
+
+/// Fails with `InvalidInput` if the destination is not an IP address.
+impl TryFrom<&Destination> for SocketAddr {
+    type Error = io::Error;
+
+    fn try_from(value: &Destination) -> io::Result<Self> {
+        value.addr.to_socket_addr(value.port).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Destination {value} is not an IP address"),
+            )
+        })
+    }
+}
+
+impl Destination {
+    /// `https://` URL for port 443, `http://` otherwise, for HTTP middleboxes to route on.
+    #[cfg(any(feature = "url", feature = "http"))]
+    fn to_http_url(&self) -> io::Result<String> {
+        #[cfg(feature = "extensions")]
+        if let v5::AddressType::UnixPath(_) | v5::AddressType::Other { .. } = self.addr {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Destination {self} has no host"),
+            ));
+        }
+        let scheme = if self.port == 443 { "https" } else { "http" };
+        Ok(format!("{scheme}://{self}/"))
+    }
+}
+
+/// `https://` URL for port 443, `http://` otherwise. Fails with `InvalidInput` if the
+/// destination is not a valid host.
+#[cfg(feature = "url")]
+impl TryFrom<&Destination> for url::Url {
+    type Error = io::Error;
+
+    fn try_from(value: &Destination) -> io::Result<Self> {
+        url::Url::parse(&value.to_http_url()?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// `https://` URI for port 443, `http://` otherwise. Fails with `InvalidInput` if the
+/// destination is not a valid host.
+#[cfg(feature = "http")]
+impl TryFrom<&Destination> for ::http::Uri {
+    type Error = io::Error;
+
+    fn try_from(value: &Destination) -> io::Result<Self> {
+        ::http::Uri::try_from(value.to_http_url()?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
 impl<T: Into<Destination>> From<T> for ConnectionRequest {
     fn from(value: T) -> Self {
         Self {
             destination: value.into(),
+            identity: None,
+            secret: None,
+            peer: None,
+            deadline: None,
+            annotations: BTreeMap::new(),
+            allow_private: true,
+            acl: None,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone)]
 pub struct ConnectionRequest {
     pub destination: Destination,
+    /// Set when the client authenticated itself during the handshake.
+    pub identity: Option<auth::Identity>,
+    /// User id sent by SOCKS4 clients, often used to select how to reach the destination.
+    pub secret: Option<String>,
+    /// Address of the client, set by the server.
+    pub peer: Option<SocketAddr>,
+    /// When the server gives up on the handshake, set if it has a handshake timeout. Handlers
+    /// still running by then are cancelled.
+    pub deadline: Option<Instant>,
+    /// Notes left by [server layers](Server::with_layer) for the request handler.
+    pub annotations: BTreeMap<String, String>,
+    /// Whether the destination may resolve to a private address, cleared by servers which do
+    /// not [allow them](Server::allow_private_destinations). Ready-made handlers then check
+    /// every address they resolve the destination to.
+    pub allow_private: bool,
+    /// Access control of the server, set by servers which have one. Ready-made handlers check
+    /// every address they resolve the destination to against it.
+    pub acl: Option<Arc<dyn acl::Acl>>,
+}
+
+/// Requests are equal if they share the same access control, if any.
+impl PartialEq for ConnectionRequest {
+    fn eq(&self, other: &Self) -> bool {
+        self.destination == other.destination
+            && self.identity == other.identity
+            && self.secret == other.secret
+            && self.peer == other.peer
+            && self.deadline == other.deadline
+            && self.annotations == other.annotations
+            && self.allow_private == other.allow_private
+            && match (&self.acl, &other.acl) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            }
+    }
+}
+
+impl Eq for ConnectionRequest {}
+
+impl ConnectionRequest {
+    /// Time left before [`deadline`](Self::deadline), to bound connection attempts with.
+    pub fn time_left(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+/// Converts the destination, see [`Destination`].
+impl TryFrom<&ConnectionRequest> for SocketAddr {
+    type Error = io::Error;
+
+    fn try_from(value: &ConnectionRequest) -> io::Result<Self> {
+        Self::try_from(&value.destination)
+    }
+}
+
+/// Converts the destination, see [`Destination`].
+#[cfg(feature = "url")]
+impl TryFrom<&ConnectionRequest> for url::Url {
+    type Error = io::Error;
+
+    fn try_from(value: &ConnectionRequest) -> io::Result<Self> {
+        Self::try_from(&value.destination)
+    }
+}
+
+/// Converts the destination, see [`Destination`].
+#[cfg(feature = "http")]
+impl TryFrom<&ConnectionRequest> for ::http::Uri {
+    type Error = io::Error;
+
+    fn try_from(value: &ConnectionRequest) -> io::Result<Self> {
+        Self::try_from(&value.destination)
+    }
+}
+
+impl fmt::Debug for ConnectionRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionRequest")
+            .field("destination", &self.destination)
+            .field("identity", &self.identity)
+            .field("secret", &self.secret.as_ref().map(|_| Redacted))
+            .field("peer", &self.peer)
+            .field("deadline", &self.deadline)
+            .field("annotations", &self.annotations)
+            .field("allow_private", &self.allow_private)
+            .field("acl", &self.acl.is_some())
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -149,9 +421,195 @@ pub struct ConnectionResponse {
     pub status: v5::Status,
 }
 
+/// Stands for a secret in `Debug` output.
+pub(crate) struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
 pub trait Wire: Sized {
     fn encode_into(&self, buffer: &mut Vec<u8>);
+
+    /// Number of bytes [`Wire::encode_into`] appends, to size buffers up front.
+    ///
+    /// Encodes the message by default, messages on the hot path of a handshake override it.
+    fn encoded_len(&self) -> usize {
+        let mut buffer = Vec::new();
+        self.encode_into(&mut buffer);
+        buffer.len()
+    }
+
     fn decode<'i, E>(input: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
     where
         E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>;
+
+    /// Decodes as many consecutive messages as possible from `input`.
+    ///
+    /// Decoding stops on the first recoverable error (typically a truncated trailing message),
+    /// whose bytes are returned as the remaining input. Failures are propagated.
+    fn decode_many<'i, E>(mut input: &'i [u8]) -> nom::IResult<&'i [u8], Vec<Self>, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        let mut items = Vec::new();
+        while !input.is_empty() {
+            match Self::decode::<E>(input) {
+                Ok((rest, item)) => {
+                    if rest.len() == input.len() {
+                        break;
+                    }
+                    items.push(item);
+                    input = rest;
+                }
+                Err(nom::Err::Error(_)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((input, items))
+    }
+
+    /// Checks a decoded value against `limits`, returning the reason of the violation.
+    fn check_limits(&self, _limits: &DecodeLimits) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Checks the start of a message, possibly truncated, against `limits`, so that a count
+    /// announced by its header is rejected without waiting for the bytes it announces.
+    fn check_header_limits(_input: &[u8], _limits: &DecodeLimits) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Checks what [`ParseMode::Strict`] rejects besides trailing bytes, `message` holding the
+    /// bytes `self` was decoded from.
+    fn check_strict(&self, _message: &[u8]) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    /// Same as [`Wire::decode`], also rejecting what `mode` disallows.
+    fn decode_with<'i, E>(input: &'i [u8], mode: ParseMode) -> nom::IResult<&'i [u8], Self, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        let (rest, item) = Self::decode::<E>(input)?;
+        if mode == ParseMode::Strict {
+            item.check_strict(&input[..input.len() - rest.len()])
+                .map_err(|reason| {
+                    nom::Err::Failure(E::add_context(
+                        input,
+                        reason,
+                        nom::error::make_error(input, nom::error::ErrorKind::Verify),
+                    ))
+                })?;
+            if !rest.is_empty() {
+                return Err(nom::Err::Failure(E::add_context(
+                    rest,
+                    "Trailing bytes",
+                    nom::error::make_error(rest, nom::error::ErrorKind::NonEmpty),
+                )));
+            }
+        }
+        Ok((rest, item))
+    }
+
+    /// Same as [`Wire::decode`], but rejects messages exceeding `limits`.
+    fn decode_with_limits<'i, E>(
+        input: &'i [u8],
+        limits: &DecodeLimits,
+    ) -> nom::IResult<&'i [u8], Self, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        let too_large = |reason| {
+            nom::Err::Failure(E::add_context(
+                input,
+                reason,
+                nom::error::make_error(input, nom::error::ErrorKind::TooLarge),
+            ))
+        };
+
+        Self::check_header_limits(input, limits).map_err(too_large)?;
+        let bounded = &input[..input.len().min(limits.max_message_size)];
+        match Self::decode::<E>(bounded) {
+            Ok((rest, item)) => {
+                item.check_limits(limits).map_err(too_large)?;
+                Ok((&input[bounded.len() - rest.len()..], item))
+            }
+            Err(nom::Err::Error(_)) if bounded.len() < input.len() => {
+                Err(too_large("Message too large"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Appends the encoded message to `buffer`, for `tokio_util` codecs and the like.
+    ///
+    /// The storage of `buffer` is handed to [`Wire::encode_into`] and back, without copying
+    /// unless it is shared with other handles.
+    #[cfg(feature = "bytes")]
+    fn encode_into_bytes(&self, buffer: &mut bytes::BytesMut) {
+        let mut vec = Vec::from(std::mem::take(buffer));
+        self.encode_into(&mut vec);
+        *buffer = bytes::Bytes::from(vec).into();
+    }
+
+    /// Decodes the message at the start of `input`, such as [`Bytes`](bytes::Bytes) or
+    /// [`BytesMut`](bytes::BytesMut), and advances it past the message. Returns `None`, leaving
+    /// `input` alone, while the message is truncated.
+    ///
+    /// Domain names and other variable-length fields are still copied into the decoded message.
+    #[cfg(feature = "bytes")]
+    fn decode_buf<B>(input: &mut B) -> Result<Option<Self>, ParseError>
+    where
+        B: bytes::Buf + AsRef<[u8]>,
+    {
+        use nom::error::VerboseError;
+
+        let chunk = input.as_ref();
+        let (consumed, message) = match Self::decode::<VerboseError<&[u8]>>(chunk) {
+            Ok((rest, message)) => (chunk.len() - rest.len(), message),
+            Err(ref e) if error::is_truncated(e) => return Ok(None),
+            Err(e) => return Err(ParseError::new(chunk, e)),
+        };
+        input.advance(consumed);
+        Ok(Some(message))
+    }
+}
+
+/// Storage reused to encode consecutive messages, such as all those of a handshake.
+#[derive(Debug, Default)]
+pub struct EncodeBuffer {
+    buffer: Vec<u8>,
+}
+
+impl EncodeBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffer holding `capacity` bytes before growing.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buffer: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Encodes `message` in place of the previous one.
+    pub fn encode(&mut self, message: &impl Wire) -> &[u8] {
+        message.encode_into(self.reset());
+        &self.buffer
+    }
+
+    /// Empties the buffer and gives access to it, for encoders taking more arguments.
+    pub fn reset(&mut self) -> &mut Vec<u8> {
+        self.buffer.clear();
+        &mut self.buffer
+    }
+
+    /// Bytes encoded since the last reset.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer
+    }
 }
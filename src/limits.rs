@@ -0,0 +1,69 @@
+/// Bounds enforced by [`Wire::decode_with_limits`](crate::Wire::decode_with_limits).
+///
+/// Exceeding any of them is reported as a `nom::Err::Failure` with
+/// `nom::error::ErrorKind::TooLarge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct DecodeLimits {
+    pub max_domain_name_len: usize,
+    pub max_methods: usize,
+    pub max_message_size: usize,
+    /// Bytes a server reads from a client over its whole handshake, before failing with
+    /// [`HandshakeTooLarge`](crate::HandshakeTooLarge). Data pipelined after the final request
+    /// is not held against it, being left to the relay.
+    ///
+    /// Not enforced by [`Wire::decode_with_limits`](crate::Wire::decode_with_limits), which
+    /// decodes a single message.
+    pub max_handshake_size: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_domain_name_len: u8::MAX as usize,
+            max_methods: u8::MAX as usize,
+            max_message_size: 1024,
+            // The largest legal SOCKS5 handshake spans 1032 bytes: a 257 bytes hello, a 513
+            // bytes authentication and a 262 bytes request.
+            max_handshake_size: 2048,
+        }
+    }
+}
+
+impl DecodeLimits {
+    pub(crate) fn check_domain_name(&self, name: &str) -> Result<(), &'static str> {
+        if name.len() > self.max_domain_name_len {
+            Err("Domain name too long")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn check_methods(&self, count: usize) -> Result<(), &'static str> {
+        if count > self.max_methods {
+            Err("Too many authentication methods")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How strictly [`Wire::decode_with`](crate::Wire::decode_with) follows the specifications.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum ParseMode {
+    /// Rejects non-zero reserved bytes, empty domain names and credentials, repeated
+    /// authentication methods and bytes following the message.
+    Strict,
+    /// Accepts what clients and servers commonly send, as [`Wire::decode`](crate::Wire::decode).
+    #[default]
+    Lenient,
+}
@@ -0,0 +1,189 @@
+//! Many tunnels over a single SOCKS connection (`mux` feature, experimental).
+//!
+//! Meant for a client and a server both running this crate, sparing chatty applications a
+//! connection and a handshake per tunnel:
+//!
+//! 1. the client negotiates and authenticates as usual, then sends a request with the `MUX`
+//!    command ([`Command::Mux`], `0x80`) and any destination;
+//! 2. once the server replied with success, the connection carries a
+//!    [yamux](https://github.com/hashicorp/yamux/blob/master/spec.md) session, the client
+//!    opening its streams;
+//! 3. each stream starts with a SOCKS5 CONNECT request, without hello nor authentication as the
+//!    identity of the connection applies, answered with a regular reply before being relayed.
+//!
+//! See [`Client::open_mux`](crate::Client::open_mux) and
+//! [`Server::serve_mux`](crate::Server::serve_mux).
+
+use std::{
+    fmt,
+    future::poll_fn,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot},
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+use crate::{v5::Command, Client, DecodeLimits, TryIntoSocksAddr};
+
+/// Request for a new stream, answered by the task driving the connection.
+type Open = oneshot::Sender<io::Result<yamux::Stream>>;
+
+/// Client side of a multiplexed connection, opened by
+/// [`Client::open_mux`](crate::Client::open_mux).
+///
+/// The connection is closed once the session and every stream opened on it are dropped.
+#[derive(Clone)]
+pub struct MuxSession {
+    opener: mpsc::UnboundedSender<Open>,
+    limits: DecodeLimits,
+}
+
+impl MuxSession {
+    /// Runs the client side of yamux over `stream`, whose `MUX` request was granted.
+    pub(crate) fn new<S>(stream: S, limits: DecodeLimits) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let connection = yamux::Connection::new(
+            stream.compat(),
+            yamux::Config::default(),
+            yamux::Mode::Client,
+        );
+        let (opener, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(e) = drive(connection, requests).await {
+                log::debug!("Multiplexed connection failed: {e}");
+            }
+        });
+        Self { opener, limits }
+    }
+
+    /// Asks the server to connect to `addr` on a new stream, returning the tunnel.
+    pub async fn connect(&self, addr: impl TryIntoSocksAddr) -> io::Result<MuxStream> {
+        let (opened, stream) = oneshot::channel();
+        let closed = || io::Error::new(io::ErrorKind::NotConnected, "Session is closed");
+        self.opener.send(opened).map_err(|_| closed())?;
+        let stream = MuxStream {
+            inner: stream.await.map_err(|_| closed())??.compat(),
+            _session: Some(self.opener.clone()),
+        };
+        let mut negotiated = Client::new(stream)
+            .with_decode_limits(self.limits)
+            .negotiated(None);
+        negotiated.request(Command::Connect, addr).await?;
+        Ok(negotiated.into_inner())
+    }
+}
+
+impl fmt::Debug for MuxSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxSession")
+            .field("closed", &self.opener.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Opens the streams requested through `requests` while serving `connection`, until it is
+/// closed by the server or nothing uses it anymore.
+async fn drive<S>(
+    mut connection: yamux::Connection<Compat<S>>,
+    mut requests: mpsc::UnboundedReceiver<Open>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut pending: Option<Open> = None;
+    let mut closing = false;
+    poll_fn(|cx| loop {
+        if closing {
+            return connection.poll_close(cx).map_err(io::Error::other);
+        }
+        match connection.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(_))) => {
+                log::debug!("Dropping stream opened by the server");
+                continue;
+            }
+            Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => {}
+        }
+        let open = match pending.take() {
+            Some(open) => open,
+            None => match requests.poll_recv(cx) {
+                Poll::Ready(Some(open)) => open,
+                Poll::Ready(None) => {
+                    closing = true;
+                    continue;
+                }
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        match connection.poll_new_outbound(cx) {
+            Poll::Ready(opened) => {
+                let _ = open.send(opened.map_err(io::Error::other));
+            }
+            Poll::Pending => {
+                pending = Some(open);
+                return Poll::Pending;
+            }
+        }
+    })
+    .await
+}
+
+/// Tunnel carried by a stream of a multiplexed connection.
+pub struct MuxStream {
+    inner: Compat<yamux::Stream>,
+    /// Keeps the client connection open as long as the stream is.
+    _session: Option<mpsc::UnboundedSender<Open>>,
+}
+
+impl MuxStream {
+    pub(crate) fn accepted(stream: yamux::Stream) -> Self {
+        Self {
+            inner: stream.compat(),
+            _session: None,
+        }
+    }
+}
+
+impl fmt::Debug for MuxStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MuxStream")
+            .field(self.inner.get_ref())
+            .finish()
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,124 @@
+//! Client-side proxy selection per destination, as proxy auto-config (PAC) files do.
+//!
+//! Rather than evaluating JavaScript, a [`ProxySelector`] reads rules mapping destinations to
+//! what `FindProxyForURL` would return, one per line, the first matching rule wins:
+//!
+//! ```text
+//! # comment
+//! *.corp.example      DIRECT
+//! 10.0.0.0/8          DIRECT
+//! *.example.com:443   SOCKS5 gw.example.com:1080; DIRECT
+//! *                   SOCKS5 proxy.example.com:1080
+//! ```
+//!
+//! Targets are written as [ACL rules](crate::acl) ones. `SOCKS` and `SOCKS4` proxies speak
+//! SOCKS4, `SOCKS5` ones SOCKS5, and are tried in order. Destinations matching no rule are
+//! reached directly.
+
+use std::{fs, io, ops::RangeInclusive, path::Path};
+
+use crate::{
+    acl::{parse_target, HostPattern},
+    proxy::ProxyUrl,
+    Destination,
+};
+
+/// How to reach a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyChoice {
+    Direct,
+    Proxy(ProxyUrl),
+}
+
+impl ProxyChoice {
+    /// Parses a single PAC result entry, such as `SOCKS5 host:port`.
+    fn parse(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid proxy {s:?}, expected DIRECT or SOCKS[4|5] host:port"),
+            )
+        };
+        let mut words = s.split_whitespace();
+        let choice = match (words.next(), words.next()) {
+            (Some(kind), None) if kind.eq_ignore_ascii_case("DIRECT") => Self::Direct,
+            (Some(kind), Some(authority)) => {
+                let scheme = match kind.to_ascii_uppercase().as_str() {
+                    "SOCKS" | "SOCKS4" => "socks4",
+                    "SOCKS5" => "socks5",
+                    _ => return Err(invalid()),
+                };
+                Self::Proxy(format!("{scheme}://{authority}").parse()?)
+            }
+            _ => return Err(invalid()),
+        };
+        match words.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(choice),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    host: HostPattern,
+    ports: RangeInclusive<u16>,
+    choices: Vec<ProxyChoice>,
+}
+
+/// Parsed list of rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxySelector {
+    rules: Vec<Rule>,
+}
+
+impl ProxySelector {
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid proxy rule at line {}: {reason}", lineno + 1),
+                )
+            };
+            let (target, result) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(format!("{line:?}")))?;
+            let (host, ports) = parse_target(target)
+                .ok_or_else(|| invalid(format!("invalid target {target:?}")))?;
+            let choices = result
+                .split(';')
+                .map(str::trim)
+                .filter(|c| !c.is_empty())
+                .map(ProxyChoice::parse)
+                .collect::<io::Result<Vec<_>>>()
+                .map_err(|e| invalid(e.to_string()))?;
+            if choices.is_empty() {
+                return Err(invalid(format!("no proxy for {target:?}")));
+            }
+            rules.push(Rule {
+                host,
+                ports,
+                choices,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Ways to reach `destination`, to try in order.
+    pub fn select(&self, destination: &Destination) -> &[ProxyChoice] {
+        self.rules
+            .iter()
+            .find(|r| r.ports.contains(&destination.port) && r.host.matches(&destination.addr))
+            .map_or(&[ProxyChoice::Direct], |r| &r.choices)
+    }
+}
@@ -0,0 +1,60 @@
+use nom::{combinator::peek, error::context};
+
+use crate::{v4, v5, Version, Wire};
+
+/// Any message a client may send first, whatever its version.
+#[derive(Debug)]
+pub enum AnyRequest {
+    V4(v4::Request),
+    V5Hello(v5::Hello),
+    V5(v5::Request),
+}
+
+impl AnyRequest {
+    pub fn version(&self) -> Version {
+        match self {
+            Self::V4(_) => Version::Socks4,
+            Self::V5Hello(_) | Self::V5(_) => Version::Socks5,
+        }
+    }
+}
+
+impl Wire for AnyRequest {
+    fn encode_into(&self, buffer: &mut Vec<u8>) {
+        match self {
+            Self::V4(ref req) => req.encode_into(buffer),
+            Self::V5Hello(ref hello) => hello.encode_into(buffer),
+            Self::V5(ref req) => req.encode_into(buffer),
+        }
+    }
+
+    /// SOCKS5 hellos and requests share the same first byte: a request is only recognized when it
+    /// spans the whole input, anything else is decoded as a hello.
+    fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        let (_, version) = context("Any request", peek(Version::decode))(buffer)?;
+        match version {
+            Version::Socks4 => {
+                let (rest, req) = v4::Request::decode(buffer)?;
+                Ok((rest, Self::V4(req)))
+            }
+            Version::Socks5 => match v5::Request::decode::<E>(buffer) {
+                Ok(([], req)) => Ok((&[], Self::V5(req))),
+                _ => {
+                    let (rest, hello) = v5::Hello::decode(buffer)?;
+                    Ok((rest, Self::V5Hello(hello)))
+                }
+            },
+        }
+    }
+}
+
+/// Decodes a client message without knowing its version beforehand.
+pub fn parse_request<'i, E>(input: &'i [u8]) -> nom::IResult<&'i [u8], AnyRequest, E>
+where
+    E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+{
+    AnyRequest::decode(input)
+}
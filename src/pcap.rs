@@ -0,0 +1,291 @@
+//! Offline analysis of captured SOCKS sessions.
+//!
+//! [`SessionReassembler`] is fed the TCP payload of each direction of a connection, in order,
+//! as extracted from a pcap/pcapng capture. It reports the decoded handshake messages and where
+//! the tunneled data starts.
+
+use crate::{v4, v5, DecodeLimits, Version, Wire};
+
+type Error<'i> = nom::error::Error<&'i [u8]>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Handshake message seen in a session.
+#[derive(Debug)]
+pub enum Message {
+    V4Request(v4::Request),
+    V4Response(v4::Response),
+    Hello(v5::Hello),
+    HelloResponse(v5::HelloResponse),
+    UsernamePassword(v5::UsernamePassword),
+    UsernamePasswordResponse(v5::UsernamePasswordResponse),
+    V5Request(v5::Request),
+    V5Response(v5::Response),
+}
+
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// `offset` is the position of the message in its direction's byte stream.
+    Message {
+        direction: Direction,
+        offset: u64,
+        message: Message,
+    },
+    /// Tunneled bytes, located by their position in the direction's byte stream.
+    Payload {
+        direction: Direction,
+        offset: u64,
+        len: usize,
+    },
+    /// The stream stopped looking like SOCKS at `offset`; the session is no longer tracked.
+    Invalid { direction: Direction, offset: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    V4Response,
+    Hello,
+    HelloResponse,
+    UsernamePassword,
+    UsernamePasswordResponse,
+    V5Request,
+    V5Response,
+    Tunnel,
+    Closed,
+}
+
+#[derive(Debug, Default)]
+struct HalfStream {
+    buffer: Vec<u8>,
+    /// Stream offset of `buffer[0]`.
+    offset: u64,
+}
+
+impl HalfStream {
+    fn consume(&mut self, n: usize) -> u64 {
+        let offset = self.offset;
+        self.buffer.drain(..n);
+        self.offset += n as u64;
+        offset
+    }
+}
+
+/// Rebuilds the SOCKS handshake of a single TCP connection from its segments.
+#[derive(Debug)]
+pub struct SessionReassembler {
+    state: State,
+    version: Option<Version>,
+    client: HalfStream,
+    server: HalfStream,
+    limits: DecodeLimits,
+}
+
+impl Default for SessionReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+enum Step {
+    Decoded(usize, Message, State),
+    NeedMore,
+    Invalid,
+}
+
+fn step<T: Wire>(
+    buffer: &[u8],
+    limits: &DecodeLimits,
+    message: impl FnOnce(&T) -> State,
+    wrap: impl FnOnce(T) -> Message,
+) -> Step {
+    match T::decode_with_limits::<Error>(buffer, limits) {
+        Ok((rest, value)) => {
+            let next = message(&value);
+            Step::Decoded(buffer.len() - rest.len(), wrap(value), next)
+        }
+        Err(nom::Err::Error(_)) if buffer.len() < limits.max_message_size => Step::NeedMore,
+        Err(_) => Step::Invalid,
+    }
+}
+
+impl SessionReassembler {
+    pub fn new() -> Self {
+        Self::with_limits(DecodeLimits::default())
+    }
+
+    pub fn with_limits(limits: DecodeLimits) -> Self {
+        Self {
+            state: State::Start,
+            version: None,
+            client: HalfStream::default(),
+            server: HalfStream::default(),
+            limits,
+        }
+    }
+
+    /// Whether the handshake is over and the following bytes are tunneled data.
+    pub fn is_tunneling(&self) -> bool {
+        self.state == State::Tunnel
+    }
+
+    /// Appends the next in-order TCP payload of `direction` and returns what it completed.
+    pub fn feed(&mut self, direction: Direction, data: &[u8]) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        if self.state == State::Closed {
+            return events;
+        }
+        self.half(direction).buffer.extend_from_slice(data);
+
+        while let Some(event) = self.advance() {
+            let stop = matches!(event, SessionEvent::Invalid { .. });
+            events.push(event);
+            if stop {
+                break;
+            }
+        }
+        events
+    }
+
+    fn half(&mut self, direction: Direction) -> &mut HalfStream {
+        match direction {
+            Direction::ClientToServer => &mut self.client,
+            Direction::ServerToClient => &mut self.server,
+        }
+    }
+
+    fn advance(&mut self) -> Option<SessionEvent> {
+        use Direction::*;
+
+        let direction = match self.state {
+            State::Closed => return None,
+            State::Tunnel => {
+                let direction = if self.client.buffer.is_empty() {
+                    ServerToClient
+                } else {
+                    ClientToServer
+                };
+                let half = self.half(direction);
+                let len = half.buffer.len();
+                if len == 0 {
+                    return None;
+                }
+                let offset = half.consume(len);
+                return Some(SessionEvent::Payload {
+                    direction,
+                    offset,
+                    len,
+                });
+            }
+            State::Start | State::Hello | State::UsernamePassword | State::V5Request => {
+                ClientToServer
+            }
+            State::V4Response
+            | State::HelloResponse
+            | State::UsernamePasswordResponse
+            | State::V5Response => ServerToClient,
+        };
+
+        let limits = self.limits;
+        let buffer = match direction {
+            ClientToServer => &self.client.buffer,
+            ServerToClient => &self.server.buffer,
+        };
+        if buffer.is_empty() {
+            return None;
+        }
+        let result = match self.state {
+            State::Start => match buffer[0] {
+                4 => {
+                    self.version = Some(Version::Socks4);
+                    step(buffer, &limits, |_| State::V4Response, Message::V4Request)
+                }
+                5 => {
+                    self.version = Some(Version::Socks5);
+                    self.state = State::Hello;
+                    return self.advance();
+                }
+                _ => Step::Invalid,
+            },
+            State::V4Response => step(
+                buffer,
+                &limits,
+                |r: &v4::Response| match r.status {
+                    v4::Status::Success => State::Tunnel,
+                    _ => State::Closed,
+                },
+                Message::V4Response,
+            ),
+            State::Hello => step(buffer, &limits, |_| State::HelloResponse, Message::Hello),
+            State::HelloResponse => step(
+                buffer,
+                &limits,
+                |r: &v5::HelloResponse| match r.method {
+                    v5::AuthenticationMethod::None => State::V5Request,
+                    v5::AuthenticationMethod::UsernamePassword => State::UsernamePassword,
+                    // Other methods encapsulate the rest of the session.
+                    _ => State::Closed,
+                },
+                Message::HelloResponse,
+            ),
+            State::UsernamePassword => step(
+                buffer,
+                &limits,
+                |_| State::UsernamePasswordResponse,
+                Message::UsernamePassword,
+            ),
+            State::UsernamePasswordResponse => step(
+                buffer,
+                &limits,
+                |r: &v5::UsernamePasswordResponse| {
+                    if r.success {
+                        State::V5Request
+                    } else {
+                        State::Closed
+                    }
+                },
+                Message::UsernamePasswordResponse,
+            ),
+            State::V5Request => step(buffer, &limits, |_| State::V5Response, Message::V5Request),
+            State::V5Response => step(
+                buffer,
+                &limits,
+                |r: &v5::Response| match r.status {
+                    v5::Status::Success => State::Tunnel,
+                    _ => State::Closed,
+                },
+                Message::V5Response,
+            ),
+            State::Tunnel | State::Closed => unreachable!(),
+        };
+
+        match result {
+            Step::Decoded(n, message, next) => {
+                self.state = next;
+                let offset = self.half(direction).consume(n);
+                Some(SessionEvent::Message {
+                    direction,
+                    offset,
+                    message,
+                })
+            }
+            Step::NeedMore => None,
+            Step::Invalid => {
+                self.state = State::Closed;
+                Some(SessionEvent::Invalid {
+                    direction,
+                    offset: self.half(direction).offset,
+                })
+            }
+        }
+    }
+
+    /// SOCKS version of the session, once the client spoke.
+    pub fn version(&self) -> Option<Version> {
+        self.version
+    }
+}
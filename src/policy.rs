@@ -0,0 +1,216 @@
+//! Restrictions applying to each authenticated user, on top of those of the server.
+//!
+//! [`Server::with_user_policies`](crate::Server::with_user_policies) checks the destinations
+//! and counts the tunnels of every user, while
+//! [`Server::throttle_users`](crate::Server::throttle_users) limits the bandwidth of the
+//! streams relayed for them.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use std::sync::Arc;
+//!
+//! use socks_parser::{
+//!     acl::AclRules,
+//!     auth::StaticUserDb,
+//!     handlers,
+//!     policy::{UserPolicies, UserPolicy},
+//!     throttle::BandwidthLimit,
+//!     resolver::SystemResolver,
+//!     ConnectOptions, Server,
+//! };
+//!
+//! let guest = UserPolicy::new()
+//!     .with_acl(AclRules::parse("allow *:443\ndefault deny")?)
+//!     .with_max_connections(4)
+//!     .with_bandwidth(BandwidthLimit {
+//!         upload: Some(64 * 1024),
+//!         download: Some(1024 * 1024),
+//!     });
+//! let policies = Arc::new(UserPolicies::new().with_user("guest", guest));
+//! let server = Server::new(tokio::net::TcpListener::bind("127.0.0.1:1080").await?)
+//!     .with_authenticator(StaticUserDb::new().with_user("guest", "guest"))
+//!     .with_user_policies(policies);
+//! let connect = handlers::connect_with(SystemResolver, ConnectOptions::default());
+//! let connect = server.throttle_users(connect);
+//! server.run(connect, handlers::relay).await
+//! # }
+//! ```
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    acl::{Acl, Action},
+    auth::Identity,
+    throttle::{BandwidthLimit, Throttled, TokenBucket},
+    Destination,
+};
+
+/// Restrictions of one user.
+#[derive(Clone, Default)]
+pub struct UserPolicy {
+    acl: Option<Arc<dyn Acl>>,
+    max_connections: Option<usize>,
+    bandwidth: BandwidthLimit,
+}
+
+impl UserPolicy {
+    /// Policy restricting nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Denies the destinations denied by `acl`, which are checked after the server ACL.
+    pub fn with_acl(mut self, acl: impl Acl + 'static) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Rejects requests of the user while `max` of their tunnels are open.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Rates shared by all the tunnels of the user.
+    pub fn with_bandwidth(mut self, limit: BandwidthLimit) -> Self {
+        self.bandwidth = limit;
+        self
+    }
+}
+
+impl fmt::Debug for UserPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserPolicy")
+            .field("acl", &self.acl.is_some())
+            .field("max_connections", &self.max_connections)
+            .field("bandwidth", &self.bandwidth)
+            .finish()
+    }
+}
+
+/// Policy of a user along with what their tunnels share.
+#[derive(Debug)]
+struct UserState {
+    policy: UserPolicy,
+    active: Arc<AtomicUsize>,
+    upload: Option<Arc<TokenBucket>>,
+    download: Option<Arc<TokenBucket>>,
+}
+
+/// Policies of users, keyed by the username they authenticated with.
+///
+/// Users without a policy, and clients which did not authenticate, are only restricted by the
+/// server settings.
+#[derive(Debug, Default)]
+pub struct UserPolicies {
+    users: HashMap<String, UserState>,
+}
+
+impl UserPolicies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_user(&mut self, username: impl Into<String>, policy: UserPolicy) {
+        let state = UserState {
+            active: Arc::default(),
+            upload: policy
+                .bandwidth
+                .upload
+                .map(|rate| Arc::new(TokenBucket::new(rate))),
+            download: policy
+                .bandwidth
+                .download
+                .map(|rate| Arc::new(TokenBucket::new(rate))),
+            policy,
+        };
+        self.users.insert(username.into(), state);
+    }
+
+    pub fn with_user(mut self, username: impl Into<String>, policy: UserPolicy) -> Self {
+        self.add_user(username, policy);
+        self
+    }
+
+    /// Number of tunnels of `username` currently open.
+    pub fn active_connections(&self, username: &str) -> usize {
+        self.users
+            .get(username)
+            .map_or(0, |state| state.active.load(Ordering::Relaxed))
+    }
+
+    fn state<'a>(&'a self, identity: Option<&'a Identity>) -> Option<(&'a str, &'a UserState)> {
+        let username = identity?.username.as_str();
+        self.users.get(username).map(|state| (username, state))
+    }
+
+    /// Fails with `PermissionDenied` if the policy of `identity` denies `destination`.
+    pub(crate) fn admit(
+        &self,
+        identity: Option<&Identity>,
+        destination: &Destination,
+    ) -> io::Result<()> {
+        let Some((username, state)) = self.state(identity) else {
+            return Ok(());
+        };
+        match state.policy.acl {
+            Some(ref acl) if acl.check(destination) == Action::Deny => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Destination {destination} denied for user {username}"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a tunnel of `identity` until the returned lease is dropped, failing with
+    /// `PermissionDenied` if the user already has as many as allowed.
+    pub(crate) fn open(&self, identity: Option<&Identity>) -> io::Result<Option<ConnectionLease>> {
+        let Some((username, state)) = self.state(identity) else {
+            return Ok(None);
+        };
+        let max = state.policy.max_connections.unwrap_or(usize::MAX);
+        state
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .map_err(|n| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("User {username} already has {n} connections"),
+                )
+            })?;
+        Ok(Some(ConnectionLease {
+            active: Arc::clone(&state.active),
+        }))
+    }
+
+    /// Limits `stream`, opened for `identity`, to the bandwidth of the user: reads from the
+    /// destination count as download, writes to it as upload.
+    pub fn throttle<S>(&self, identity: Option<&Identity>, stream: S) -> Throttled<S> {
+        match self.state(identity) {
+            Some((_, state)) => Throttled::new(stream, state.download.clone())
+                .with_write_bucket(state.upload.clone()),
+            None => Throttled::new(stream, None),
+        }
+    }
+}
+
+/// Tunnel counted against the connection limit of a user until dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionLease {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
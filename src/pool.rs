@@ -0,0 +1,453 @@
+//! Pools of connections to upstream proxies.
+//!
+//! An [`UpstreamPool`] picks the proxy of every connection made by
+//! [`handlers::chain_to_pool`](crate::handlers::chain_to_pool). Proxies failing to complete a
+//! handshake are set aside until a health check, or another connection attempt, succeeds.
+//!
+//! A [`ClientPool`] keeps connections to a single proxy open ahead of time, saving a round trip
+//! or more to applications opening many short-lived tunnels through it.
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{
+    proxy::ProxyUrl, v5::Command, Client, ConnectOptions, Destination, Dialer, NegotiatedStream,
+    TryIntoSocksAddr,
+};
+
+/// How an [`UpstreamPool`] picks a proxy among the healthy ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each proxy in turn.
+    #[default]
+    RoundRobin,
+    /// The proxy relaying the fewest connections.
+    LeastConnections,
+    /// The proxy with the lowest round-trip time, as measured by connections and health checks.
+    LatencyAware,
+}
+
+struct Upstream {
+    proxy: ProxyUrl,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    /// Smoothed round-trip time in microseconds, `0` until measured.
+    latency_us: AtomicU64,
+}
+
+impl Upstream {
+    fn record_success(&self, elapsed: Duration) {
+        let sample = u64::try_from(elapsed.as_micros())
+            .unwrap_or(u64::MAX)
+            .max(1);
+        let latency = match self.latency_us.load(Ordering::Relaxed) {
+            0 => sample,
+            previous => (previous.saturating_mul(3) / 4).saturating_add(sample / 4),
+        };
+        self.latency_us.store(latency, Ordering::Relaxed);
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            log::info!("Upstream {} is back", self.proxy);
+        }
+    }
+
+    fn record_failure(&self, e: &io::Error) {
+        if self.healthy.swap(false, Ordering::Relaxed) {
+            log::warn!("Upstream {} is down: {e}", self.proxy);
+        }
+    }
+}
+
+/// State of a proxy of an [`UpstreamPool`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamStatus {
+    pub proxy: ProxyUrl,
+    pub healthy: bool,
+    pub active_connections: usize,
+    /// Smoothed round-trip time, `None` until the proxy was reached once.
+    pub latency: Option<Duration>,
+}
+
+/// Upstream proxies sharing the load of a chaining server.
+///
+/// Every proxy starts healthy. When all of them are down, they are all tried again rather than
+/// failing every request until the next health check.
+///
+/// Proxies are reached over TCP, or with the [`Dialer`] given to [`UpstreamPool::with_dialer`].
+pub struct UpstreamPool<D = ConnectOptions> {
+    upstreams: Arc<[Upstream]>,
+    strategy: Strategy,
+    next: AtomicUsize,
+    dialer: D,
+}
+
+impl UpstreamPool {
+    pub fn new(proxies: impl IntoIterator<Item = ProxyUrl>, strategy: Strategy) -> Self {
+        let upstreams = proxies
+            .into_iter()
+            .map(|proxy| Upstream {
+                proxy,
+                healthy: AtomicBool::new(true),
+                active: AtomicUsize::new(0),
+                latency_us: AtomicU64::new(0),
+            })
+            .collect();
+        Self {
+            upstreams,
+            strategy,
+            next: AtomicUsize::new(0),
+            dialer: ConnectOptions::default(),
+        }
+    }
+}
+
+impl<D> UpstreamPool<D> {
+    /// Connects to the proxies with `dialer`, for connections and health checks alike.
+    pub fn with_dialer<E: Dialer>(self, dialer: E) -> UpstreamPool<E> {
+        UpstreamPool {
+            upstreams: self.upstreams,
+            strategy: self.strategy,
+            next: self.next,
+            dialer,
+        }
+    }
+
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        self.upstreams
+            .iter()
+            .map(|u| UpstreamStatus {
+                proxy: u.proxy.clone(),
+                healthy: u.healthy.load(Ordering::Relaxed),
+                active_connections: u.active.load(Ordering::Relaxed),
+                latency: match u.latency_us.load(Ordering::Relaxed) {
+                    0 => None,
+                    us => Some(Duration::from_micros(us)),
+                },
+            })
+            .collect()
+    }
+
+    /// Indices of the upstreams to try, best first according to the strategy.
+    fn candidates(&self) -> Vec<usize> {
+        let mut candidates: Vec<_> = (0..self.upstreams.len())
+            .filter(|&i| self.upstreams[i].healthy.load(Ordering::Relaxed))
+            .collect();
+        if candidates.is_empty() {
+            candidates.extend(0..self.upstreams.len());
+        }
+        match self.strategy {
+            Strategy::RoundRobin => {
+                if !candidates.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                    candidates.rotate_left(start);
+                }
+            }
+            Strategy::LeastConnections => {
+                candidates.sort_by_key(|&i| self.upstreams[i].active.load(Ordering::Relaxed));
+            }
+            Strategy::LatencyAware => {
+                candidates.sort_by_key(|&i| self.upstreams[i].latency_us.load(Ordering::Relaxed));
+            }
+        }
+        candidates
+    }
+}
+
+impl<D: Dialer + 'static> UpstreamPool<D> {
+    /// Connects to `destination` through the best upstream, falling back to the next ones if it
+    /// cannot be reached.
+    ///
+    /// Errors returned by an upstream once the handshake is over, such as an unreachable
+    /// destination, are not retried.
+    pub async fn connect(
+        self: &Arc<Self>,
+        destination: Destination,
+    ) -> io::Result<(PooledStream<D::Stream>, Destination)> {
+        let mut last_error = None;
+        for index in self.candidates() {
+            let upstream = &self.upstreams[index];
+            let start = Instant::now();
+            let negotiated = async {
+                Client::dial_with(&upstream.proxy, &self.dialer)
+                    .await?
+                    .handshake_only()
+                    .await
+            };
+            let mut negotiated = match negotiated.await {
+                Ok(negotiated) => {
+                    upstream.record_success(start.elapsed());
+                    negotiated
+                }
+                Err(e) => {
+                    upstream.record_failure(&e);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            log::debug!("Forwarding {destination} to {}", upstream.proxy);
+            let bound = negotiated.request(Command::Connect, destination).await?;
+            upstream.active.fetch_add(1, Ordering::Relaxed);
+            let stream = PooledStream {
+                stream: negotiated.into_inner(),
+                upstreams: Arc::clone(&self.upstreams),
+                index,
+            };
+            return Ok((stream, bound));
+        }
+        Err(match last_error {
+            Some(e) => io::Error::new(e.kind(), format!("All upstreams failed, last error: {e}")),
+            None => io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No upstream to connect through",
+            ),
+        })
+    }
+
+    /// Probes every upstream at once with [`Client::probe`], updating their health and latency.
+    pub async fn check_health(self: &Arc<Self>, timeout: Duration) {
+        let mut probes = JoinSet::new();
+        for index in 0..self.upstreams.len() {
+            let pool = Arc::clone(self);
+            probes.spawn(async move {
+                let upstream = &pool.upstreams[index];
+                match Client::probe_with(&upstream.proxy, timeout, &pool.dialer).await {
+                    Ok(report) if report.usable_by(&upstream.proxy) => {
+                        upstream.record_success(report.rtt)
+                    }
+                    Ok(_) => upstream.record_failure(&io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "No acceptable authentication method",
+                    )),
+                    Err(e) => upstream.record_failure(&e),
+                }
+            });
+        }
+        probes.join_all().await;
+    }
+
+    /// Checks the health of the upstreams every `interval`, until the pool is dropped.
+    pub fn spawn_health_checks(
+        self: &Arc<Self>,
+        interval: Duration,
+        timeout: Duration,
+    ) -> JoinHandle<()> {
+        let pool = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    break;
+                };
+                pool.check_health(timeout).await;
+            }
+        })
+    }
+}
+
+/// Connection through an upstream of an [`UpstreamPool`], accounted as active until dropped.
+pub struct PooledStream<S = TcpStream> {
+    stream: S,
+    upstreams: Arc<[Upstream]>,
+    index: usize,
+}
+
+impl<S> PooledStream<S> {
+    pub fn proxy(&self) -> &ProxyUrl {
+        &self.upstreams[self.index].proxy
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> Drop for PooledStream<S> {
+    fn drop(&mut self) {
+        self.upstreams[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PooledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PooledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Idle connection of a [`ClientPool`].
+enum Warm {
+    Connected(TcpStream),
+    Negotiated(NegotiatedStream<TcpStream>),
+}
+
+impl Warm {
+    /// Whether the proxy closed the connection, or sent something unexpected, while it was idle.
+    fn is_stale(&self) -> bool {
+        let stream = match self {
+            Self::Connected(stream) => stream,
+            Self::Negotiated(negotiated) => negotiated.get_ref(),
+        };
+        !matches!(stream.try_read(&mut [0]), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+struct Idle {
+    warm: Warm,
+    since: Instant,
+}
+
+/// Connections to a proxy opened ahead of time, ready to carry a request.
+///
+/// Up to `size` connections are kept idle, opened in the background by [`ClientPool::fill`]
+/// and after each one handed out. Those closed by the proxy, or idle for too long, are
+/// discarded.
+pub struct ClientPool {
+    proxy: ProxyUrl,
+    size: usize,
+    negotiate: bool,
+    max_idle: Duration,
+    idle: Mutex<VecDeque<Idle>>,
+    opening: AtomicUsize,
+}
+
+impl ClientPool {
+    pub fn new(proxy: ProxyUrl, size: usize) -> Self {
+        Self {
+            proxy,
+            size,
+            negotiate: false,
+            max_idle: Duration::from_secs(30),
+            idle: Mutex::new(VecDeque::new()),
+            opening: AtomicUsize::new(0),
+        }
+    }
+
+    /// Also runs the method negotiation and authentication ahead of time, leaving only the
+    /// request to send. Proxies with a short handshake timeout close such connections sooner.
+    pub fn with_pre_negotiation(mut self) -> Self {
+        self.negotiate = true;
+        self
+    }
+
+    /// Discards connections idle for longer than `max_idle`, 30 seconds by default.
+    pub fn with_max_idle(mut self, max_idle: Duration) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    pub fn proxy(&self) -> &ProxyUrl {
+        &self.proxy
+    }
+
+    /// Number of connections ready to be handed out, some of which may have been closed since.
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Opens connections in the background, until `size` of them are idle or being opened.
+    pub fn fill(self: &Arc<Self>) {
+        let pending = self.idle_connections() + self.opening.load(Ordering::Relaxed);
+        for _ in pending..self.size {
+            self.opening.fetch_add(1, Ordering::Relaxed);
+            let pool = Arc::clone(self);
+            tokio::spawn(async move {
+                let warm = pool.open().await;
+                pool.opening.fetch_sub(1, Ordering::Relaxed);
+                match warm {
+                    Ok(warm) => pool
+                        .idle
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push_back(Idle {
+                            warm,
+                            since: Instant::now(),
+                        }),
+                    Err(e) => log::debug!("Could not open a connection to {}: {e}", pool.proxy),
+                }
+            });
+        }
+    }
+
+    async fn open(&self) -> io::Result<Warm> {
+        let stream = TcpStream::connect((self.proxy.host.as_str(), self.proxy.port)).await?;
+        Ok(if self.negotiate {
+            let client = Client::for_proxy(stream, &self.proxy);
+            Warm::Negotiated(client.handshake_only().await?)
+        } else {
+            Warm::Connected(stream)
+        })
+    }
+
+    /// Oldest idle connection still usable.
+    fn take_idle(&self) -> Option<Warm> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some(Idle { warm, since }) = idle.pop_front() {
+            if since.elapsed() <= self.max_idle && !warm.is_stale() {
+                return Some(warm);
+            }
+        }
+        None
+    }
+
+    /// Connection to the proxy done with the method negotiation and authentication, opening a
+    /// new one if none is idle.
+    pub async fn handshake(self: &Arc<Self>) -> io::Result<NegotiatedStream<TcpStream>> {
+        let warm = self.take_idle();
+        self.fill();
+        match warm {
+            Some(Warm::Negotiated(negotiated)) => Ok(negotiated),
+            Some(Warm::Connected(stream)) => {
+                Client::for_proxy(stream, &self.proxy)
+                    .handshake_only()
+                    .await
+            }
+            None => Client::dial(&self.proxy).await?.handshake_only().await,
+        }
+    }
+
+    /// Asks the proxy to connect to `addr` over a pooled connection, returning the tunnel.
+    pub async fn connect(self: &Arc<Self>, addr: impl TryIntoSocksAddr) -> io::Result<TcpStream> {
+        let mut negotiated = self.handshake().await?;
+        negotiated.request(Command::Connect, addr).await?;
+        Ok(negotiated.into_inner())
+    }
+}
@@ -0,0 +1,41 @@
+//! Types and traits most applications need, to import at once.
+//!
+//! ```no_run
+//! use socks_parser::prelude::*;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! async fn handle_request(
+//!     req: ConnectionRequest,
+//! ) -> std::io::Result<(tokio::net::TcpStream, Destination)> {
+//!     if req.destination.addr == AddressType::DomainName("ads.example.com".into()) {
+//!         return Err(Rejection::Status(Status::ConnectionNotAllowed).into());
+//!     }
+//!     let stream = tokio::net::TcpStream::connect(req.destination.to_string()).await?;
+//!     let bound = stream.local_addr()?;
+//!     Ok((stream, bound.into()))
+//! }
+//!
+//! let listener = tokio::net::TcpListener::bind(("127.0.0.1", 1080)).await?;
+//! Server::new(listener)
+//!     .run(handle_request, handlers::relay)
+//!     .await
+//! # }
+//! ```
+
+pub use crate::{
+    acl::Acl,
+    auth::{Authenticator, Identity, MethodSelector},
+    proxy::ProxyUrl,
+    v4,
+    v5::{self, AddressType, Command, Status},
+    ConnectionRequest, ConnectionResponse, DecodeLimits, Destination, ParseError, ParseMode,
+    Version, Wire,
+};
+
+#[cfg(feature = "async")]
+pub use crate::{
+    handlers::{self, Dialer},
+    stats::Relayed,
+    Client, ConnectOptions, Credentials, Decision, IntoSocksAddr, Layer, Listener, Rejection,
+    Server, TryIntoSocksAddr,
+};
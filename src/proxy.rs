@@ -0,0 +1,241 @@
+use std::{
+    collections::hash_map::RandomState,
+    env, fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::IpAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{acl::HostPattern, v5::AddressType, Destination, Version};
+
+/// Location of a SOCKS proxy, written as `socks5://[user:password@]host:port`.
+///
+/// The `socks4`, `socks4a`, `socks5` and `socks5h` schemes are accepted, the port defaults to
+/// 1080.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProxyUrl {
+    pub version: Version,
+    pub host: String,
+    pub port: u16,
+    pub credentials: Option<(String, String)>,
+}
+
+impl FromStr for ProxyUrl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid proxy URL {s:?}: {reason}"),
+            )
+        };
+
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| invalid("missing scheme"))?;
+        let version = match scheme.to_ascii_lowercase().as_str() {
+            "socks4" | "socks4a" => Version::Socks4,
+            "socks5" | "socks5h" => Version::Socks5,
+            _ => return Err(invalid("unsupported scheme")),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, authority) = match rest.rsplit_once('@') {
+            Some((userinfo, authority)) => {
+                let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((username.into(), password.into())), authority)
+            }
+            None => (None, rest),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !authority.ends_with(']') && !host.ends_with(':') => {
+                (host, port.parse().map_err(|_| invalid("invalid port"))?)
+            }
+            _ => (authority, 1080),
+        };
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+
+        Ok(Self {
+            version,
+            host: host.into(),
+            port,
+            credentials,
+        })
+    }
+}
+
+/// Value of the first environment variable of `names` which is set and not empty.
+fn env_var(names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+}
+
+impl ProxyUrl {
+    /// Proxy set by the `ALL_PROXY` or `all_proxy` environment variable, if any.
+    ///
+    /// Proxies of other schemes than SOCKS, such as `http://`, are ignored as they would be by
+    /// curl. Destinations listed by `NO_PROXY` should be reached directly, see
+    /// [`NoProxy::from_env`].
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Some(url) = env_var(&["ALL_PROXY", "all_proxy"]) else {
+            return Ok(None);
+        };
+        let url = url.trim();
+        if let Some((scheme, _)) = url.split_once("://") {
+            if !scheme.to_ascii_lowercase().starts_with("socks") {
+                log::debug!("Ignoring ALL_PROXY, {scheme} proxies are not supported");
+                return Ok(None);
+            }
+        }
+        url.parse().map(Some)
+    }
+}
+
+/// Destinations to reach without a proxy, as listed by the `NO_PROXY` environment variable.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NoProxy {
+    patterns: Vec<HostPattern>,
+}
+
+impl NoProxy {
+    /// Parses a comma separated list of `*`, IP addresses, CIDR blocks and domain names, the
+    /// latter matching their subdomains as well. A leading `.` or `*.` is ignored.
+    ///
+    /// Other entries, such as `localhost:8080` or `<local>`, are logged and skipped, the list
+    /// being shared with tools understanding more of them.
+    pub fn parse(list: &str) -> Self {
+        let mut patterns = Vec::new();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let host = entry
+                .strip_prefix("*.")
+                .or_else(|| entry.strip_prefix('.'))
+                .unwrap_or(entry);
+            let pattern = HostPattern::parse(host).filter(|pattern| match pattern {
+                HostPattern::Domain(domain) => is_host_name(domain),
+                _ => true,
+            });
+            let Some(pattern) = pattern else {
+                log::debug!("Ignoring unsupported NO_PROXY entry {entry:?}");
+                continue;
+            };
+            if let HostPattern::Domain(ref domain) = pattern {
+                patterns.push(HostPattern::Subdomains(domain.clone()));
+            }
+            patterns.push(pattern);
+        }
+        Self { patterns }
+    }
+
+    /// Reads `NO_PROXY`, or `no_proxy`.
+    pub fn from_env() -> Self {
+        env_var(&["NO_PROXY", "no_proxy"])
+            .map(|list| Self::parse(&list))
+            .unwrap_or_default()
+    }
+
+    /// Whether `destination` is listed, domain names being IP addresses counting as such.
+    pub fn matches(&self, destination: &Destination) -> bool {
+        let addr = match destination.addr {
+            AddressType::DomainName(ref name) => name
+                .parse::<IpAddr>()
+                .map_or_else(|_| destination.addr.clone(), Into::into),
+            ref addr => addr.clone().normalized(),
+        };
+        self.patterns.iter().any(|p| p.matches(&addr))
+    }
+}
+
+/// Whether `name` is made of dot separated labels of letters, digits, `-` and `_`.
+fn is_host_name(name: &str) -> bool {
+    name.split('.').all(|label| {
+        !label.is_empty()
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    })
+}
+
+impl fmt::Display for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.version {
+            Version::Socks4 => "socks4",
+            Version::Socks5 => "socks5",
+        };
+        write!(f, "{scheme}://")?;
+        if let Some((ref username, _)) = self.credentials {
+            write!(f, "{username}:***@")?;
+        }
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Debug for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ProxyUrl({self})")
+    }
+}
+
+/// How many times, and how fast, proxies are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of passes over the whole proxy list.
+    pub max_rounds: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Randomizes each delay between half and all of its nominal value.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_rounds: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Only tries each proxy once, without waiting in between.
+    pub fn no_retry() -> Self {
+        Self {
+            max_rounds: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            jitter: false,
+        }
+    }
+
+    /// Delay to wait after the `attempt`-th failed round (starting at 0), before retrying the
+    /// proxies.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let nominal = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        if self.jitter {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(attempt);
+            let ratio = 0.5 + (hasher.finish() % 1000) as f64 / 2000.;
+            nominal.mul_f64(ratio)
+        } else {
+            nominal
+        }
+    }
+}
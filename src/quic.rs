@@ -0,0 +1,57 @@
+//! SOCKS over QUIC (`quic` feature).
+//!
+//! Each bidirectional stream of a QUIC connection carries a regular SOCKS session, see
+//! [`Server::serve_quic`](crate::Server::serve_quic) and
+//! [`Client::connect_quic`](crate::Client::connect_quic). SOCKS5 UDP associations are relayed
+//! over the QUIC datagrams of the connection, each one starting with a [`UdpHeader`].
+
+use std::io;
+
+use crate::{v5::UdpHeader, Destination, TryIntoSocksAddr, Wire};
+
+/// Client side of a SOCKS session running on a QUIC bidirectional stream.
+pub type QuicStream = tokio::io::Join<quinn::RecvStream, quinn::SendStream>;
+
+/// UDP association set up by [`Client::associate_quic`](crate::Client::associate_quic).
+///
+/// The association ends when this value is dropped.
+pub struct DatagramAssociation {
+    pub(crate) connection: quinn::Connection,
+    /// Kept open for the association to last.
+    pub(crate) _control: QuicStream,
+}
+
+impl DatagramAssociation {
+    /// Asks the server to send `payload` to `addr`.
+    pub fn send_to(&self, addr: impl TryIntoSocksAddr, payload: &[u8]) -> io::Result<()> {
+        let (addr, port) = addr.try_into_socks_addr()?;
+        let mut datagram = Vec::with_capacity(payload.len() + 22);
+        UdpHeader {
+            frag: 0,
+            addr,
+            port,
+        }
+        .encode_into(&mut datagram);
+        datagram.extend_from_slice(payload);
+        self.connection
+            .send_datagram(datagram.into())
+            .map_err(io::Error::other)
+    }
+
+    /// Waits for the next datagram relayed by the server, along with its sender.
+    pub async fn recv_from(&self) -> io::Result<(Destination, Vec<u8>)> {
+        loop {
+            let datagram = self.connection.read_datagram().await?;
+            match UdpHeader::decode::<nom::error::Error<_>>(&datagram) {
+                Ok((payload, header)) if header.frag == 0 => {
+                    let from = Destination {
+                        addr: header.addr,
+                        port: header.port,
+                    };
+                    return Ok((from, payload.to_vec()));
+                }
+                _ => log::debug!("Dropping invalid datagram from the server"),
+            }
+        }
+    }
+}
@@ -0,0 +1,397 @@
+//! Recording of SOCKS handshakes, to debug misbehaving peers or replay them in tests.
+//!
+//! Wrap the stream given to [`Client`](crate::Client) in a [`Recorder`], or register a callback
+//! with [`Server::with_recorder`](crate::Server::with_recorder). Transcripts are saved as JSON
+//! lines or in a compact binary format, and a client transcript can be fed back to a server
+//! with [`Server::replay`](crate::Server::replay).
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, BufRead, Read, Write},
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Direction of a record, from the point of view of the recording side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent = 0,
+    Received = 1,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Sent => "sent",
+            Self::Received => "received",
+        }
+    }
+
+    fn reversed(self) -> Self {
+        match self {
+            Self::Sent => Self::Received,
+            Self::Received => Self::Sent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the recording started, with a microsecond precision.
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// Every chunk of data exchanged during a handshake, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub records: Vec<Record>,
+}
+
+fn invalid(lineno: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid transcript record at line {}", lineno + 1),
+    )
+}
+
+impl Transcript {
+    /// Chunks sent by the recording side.
+    pub fn sent(&self) -> impl Iterator<Item = &[u8]> {
+        self.records
+            .iter()
+            .filter(|r| r.direction == Direction::Sent)
+            .map(|r| &r.data[..])
+    }
+
+    /// Chunks received by the recording side.
+    pub fn received(&self) -> impl Iterator<Item = &[u8]> {
+        self.records
+            .iter()
+            .filter(|r| r.direction == Direction::Received)
+            .map(|r| &r.data[..])
+    }
+
+    /// Bytes sent by the recording side, whatever chunks they were written in, to compare
+    /// with the messages of a specification.
+    pub fn sent_bytes(&self) -> Vec<u8> {
+        self.sent().flatten().copied().collect()
+    }
+
+    /// Bytes received by the recording side, see [`sent_bytes`](Self::sent_bytes).
+    pub fn received_bytes(&self) -> Vec<u8> {
+        self.received().flatten().copied().collect()
+    }
+
+    /// Same exchange, seen from the other side.
+    pub fn reversed(&self) -> Self {
+        Self {
+            records: self
+                .records
+                .iter()
+                .map(|r| Record {
+                    direction: r.direction.reversed(),
+                    ..r.clone()
+                })
+                .collect(),
+        }
+    }
+
+    /// Overwrites with `*` the credentials a client sent in the `client` direction: the
+    /// password of a SOCKS5 username/password authentication, or the `Proxy-Authorization`
+    /// header of an HTTP CONNECT request.
+    ///
+    /// Servers mask their transcripts before handing them over, which then only replay
+    /// against an authenticator accepting any password.
+    pub fn mask_credentials(&mut self, client: Direction) {
+        let positions: Vec<(usize, usize)> = self
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.direction == client)
+            .flat_map(|(i, r)| (0..r.data.len()).map(move |j| (i, j)))
+            .collect();
+        let bytes: Vec<u8> = positions
+            .iter()
+            .map(|&(i, j)| self.records[i].data[j])
+            .collect();
+        let selected = self
+            .records
+            .iter()
+            .filter(|r| r.direction == client.reversed())
+            .flat_map(|r| &r.data)
+            .nth(1);
+        let secret = match bytes.first() {
+            Some(5) if selected == Some(&2) => password_range(&bytes),
+            Some(b'C') => proxy_authorization_range(&bytes),
+            _ => None,
+        };
+        for &(i, j) in &positions[secret.unwrap_or_default()] {
+            self.records[i].data[j] = b'*';
+        }
+    }
+
+    /// Writes one `{"elapsed_us":…,"direction":"sent","data":"<hex>"}` object per line.
+    pub fn write_jsonl(&self, mut writer: impl Write) -> io::Result<()> {
+        for record in &self.records {
+            let mut data = String::with_capacity(record.data.len() * 2);
+            for byte in &record.data {
+                let _ = write!(data, "{byte:02x}");
+            }
+            writeln!(
+                writer,
+                r#"{{"elapsed_us":{},"direction":"{}","data":"{data}"}}"#,
+                record.elapsed.as_micros(),
+                record.direction.as_str(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reads a transcript written by [`Transcript::write_jsonl`].
+    pub fn read_jsonl(reader: impl BufRead) -> io::Result<Self> {
+        let mut records = Vec::new();
+        for (lineno, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let field = |name: &str| {
+                let start = line.find(&format!("\"{name}\":"))? + name.len() + 3;
+                let value = &line[start..];
+                let end = value.find([',', '}'])?;
+                Some(value[..end].trim_matches('"'))
+            };
+            let elapsed = field("elapsed_us")
+                .and_then(|us| us.parse().ok())
+                .map(Duration::from_micros)
+                .ok_or_else(|| invalid(lineno))?;
+            let direction = match field("direction") {
+                Some("sent") => Direction::Sent,
+                Some("received") => Direction::Received,
+                _ => return Err(invalid(lineno)),
+            };
+            let hex = field("data").ok_or_else(|| invalid(lineno))?;
+            let data = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect::<Option<_>>()
+                .ok_or_else(|| invalid(lineno))?;
+            records.push(Record {
+                elapsed,
+                direction,
+                data,
+            });
+        }
+        Ok(Self { records })
+    }
+
+    /// Writes each record as its direction (`0` for sent, `1` for received), elapsed
+    /// microseconds (big endian `u64`), data length (big endian `u32`) and data.
+    pub fn write_binary(&self, mut writer: impl Write) -> io::Result<()> {
+        for record in &self.records {
+            let len = u32::try_from(record.data.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Record too large"))?;
+            writer.write_all(&[record.direction as u8])?;
+            writer.write_all(&(record.elapsed.as_micros() as u64).to_be_bytes())?;
+            writer.write_all(&len.to_be_bytes())?;
+            writer.write_all(&record.data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a transcript written by [`Transcript::write_binary`].
+    pub fn read_binary(mut reader: impl Read) -> io::Result<Self> {
+        let mut records = Vec::new();
+        loop {
+            let mut header = [0; 13];
+            match reader.read_exact(&mut header[..1]) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                result => result?,
+            }
+            reader.read_exact(&mut header[1..])?;
+            let direction = match header[0] {
+                0 => Direction::Sent,
+                1 => Direction::Received,
+                _ => return Err(invalid(records.len())),
+            };
+            let elapsed = u64::from_be_bytes(header[1..9].try_into().expect("8 bytes"));
+            let len = u32::from_be_bytes(header[9..].try_into().expect("4 bytes"));
+            let mut data = vec![0; len as usize];
+            reader.read_exact(&mut data)?;
+            records.push(Record {
+                elapsed: Duration::from_micros(elapsed),
+                direction,
+                data,
+            });
+        }
+        Ok(Self { records })
+    }
+}
+
+/// Range of the password in the bytes sent by a SOCKS5 client, its hello followed by a
+/// username/password authentication request.
+fn password_range(bytes: &[u8]) -> Option<Range<usize>> {
+    let auth = 2 + usize::from(*bytes.get(1)?);
+    if *bytes.get(auth)? != 1 {
+        return None;
+    }
+    let password_len_at = auth + 2 + usize::from(*bytes.get(auth + 1)?);
+    let start = password_len_at + 1;
+    let end = start + usize::from(*bytes.get(password_len_at)?);
+    Some(start.min(bytes.len())..end.min(bytes.len()))
+}
+
+/// Range of the value of the `Proxy-Authorization` header in the bytes sent by an HTTP client.
+fn proxy_authorization_range(bytes: &[u8]) -> Option<Range<usize>> {
+    const HEADER: &[u8] = b"\r\nproxy-authorization:";
+
+    let head = bytes
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(bytes, |end| &bytes[..end + 2]);
+    let start = head
+        .windows(HEADER.len())
+        .position(|w| w.eq_ignore_ascii_case(HEADER))?
+        + HEADER.len();
+    let end = head[start..]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map_or(head.len(), |n| start + n);
+    Some(start..end)
+}
+
+/// Stream wrapper recording everything read from and written to `S`.
+///
+/// Credentials are recorded as they are exchanged, see [`Transcript::mask_credentials`].
+#[derive(Debug)]
+pub struct Recorder<S> {
+    inner: S,
+    start: Instant,
+    transcript: Transcript,
+}
+
+impl<S> Recorder<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            start: Instant::now(),
+            transcript: Transcript::default(),
+        }
+    }
+
+    pub fn transcript(&self) -> &Transcript {
+        &self.transcript
+    }
+
+    pub fn into_parts(self) -> (S, Transcript) {
+        (self.inner, self.transcript)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if !data.is_empty() {
+            let elapsed = self.start.elapsed().as_micros() as u64;
+            self.transcript.records.push(Record {
+                elapsed: Duration::from_micros(elapsed),
+                direction,
+                data: data.to_vec(),
+            });
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.record(Direction::Received, &buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.record(Direction::Sent, &buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Stream playing back recorded chunks, one per read, and discarding writes.
+pub(crate) struct Playback {
+    chunks: VecDeque<Vec<u8>>,
+}
+
+impl Playback {
+    pub(crate) fn new<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        Self {
+            chunks: chunks.into_iter().map(<[u8]>::to_vec).collect(),
+        }
+    }
+}
+
+impl AsyncRead for Playback {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(chunk) = this.chunks.front_mut() {
+            let n = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                this.chunks.pop_front();
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Playback {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
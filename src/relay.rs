@@ -0,0 +1,80 @@
+//! Relaying data between clients and their destinations.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub use uring::UringRelay;
+
+/// Stream handler relaying like [`handlers::relay`](crate::handlers::relay), without copying
+/// data to userspace when possible.
+///
+/// When both streams are [`TcpStream`](tokio::net::TcpStream)s on Linux, data moves from one socket to the other
+/// through a pipe with `splice(2)`. Other streams, such as TLS or throttled ones, and other
+/// platforms fall back to [`copy_bidirectional`](tokio::io::copy_bidirectional).
+///
+/// Returns the number of bytes sent from `local` to `remote`, then the other way round.
+pub async fn pipe_zero_copy<L, S>(mut local: L, mut remote: S) -> io::Result<(u64, u64)>
+where
+    L: AsyncRead + AsyncWrite + Unpin + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    #[cfg(target_os = "linux")]
+    if let (Some(local), Some(remote)) = (
+        (&local as &dyn std::any::Any).downcast_ref::<tokio::net::TcpStream>(),
+        (&remote as &dyn std::any::Any).downcast_ref::<tokio::net::TcpStream>(),
+    ) {
+        log::trace!(
+            "Splicing {:?} and {:?}",
+            local.peer_addr(),
+            remote.peer_addr()
+        );
+        return tokio::try_join!(
+            splice::one_way(local, remote),
+            splice::one_way(remote, local)
+        );
+    }
+    tokio::io::copy_bidirectional(&mut local, &mut remote).await
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use std::{io, net::Shutdown};
+
+    use rustix::pipe::{self, PipeFlags, SpliceFlags};
+    use socket2::SockRef;
+    use tokio::{io::Interest, net::TcpStream};
+
+    /// Most bytes moved by a single `splice(2)` call, the default capacity of a pipe.
+    const CHUNK_LEN: usize = 64 * 1024;
+
+    /// Moves data from `from` to `to` until `from` is closed, then shuts `to` down for writing.
+    pub(super) async fn one_way(from: &TcpStream, to: &TcpStream) -> io::Result<u64> {
+        let (pipe_out, pipe_in) = pipe::pipe_with(PipeFlags::NONBLOCK | PipeFlags::CLOEXEC)?;
+        let flags = SpliceFlags::NONBLOCK | SpliceFlags::MOVE;
+        let mut total = 0;
+        loop {
+            let mut pending = from
+                .async_io(Interest::READABLE, || {
+                    Ok(pipe::splice(from, None, &pipe_in, None, CHUNK_LEN, flags)?)
+                })
+                .await?;
+            if pending == 0 {
+                break;
+            }
+            total += pending as u64;
+            while pending > 0 {
+                pending -= to
+                    .async_io(Interest::WRITABLE, || {
+                        Ok(pipe::splice(&pipe_out, None, to, None, pending, flags)?)
+                    })
+                    .await?;
+            }
+        }
+        SockRef::from(to).shutdown(Shutdown::Write)?;
+        Ok(total)
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    any::Any,
+    io,
+    net::Shutdown,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+
+use crate::stream::EitherStream;
+
+/// Bytes read at once from each socket.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// Connection handed over to a relay thread, along with where to send its outcome.
+struct Job {
+    local: std::net::TcpStream,
+    remote: std::net::TcpStream,
+    done: oneshot::Sender<io::Result<(u64, u64)>>,
+}
+
+/// Threads relaying TCP connections with io_uring, each driving its own ring, while
+/// handshakes stay on the Tokio runtime of the server.
+///
+/// Use [`handlers::relay_uring`](crate::handlers::relay_uring) as the stream handler of a
+/// [`Server`](crate::Server). Connections are spread over the threads in turn, and those still
+/// relayed are closed once the last handle to it is dropped.
+pub struct UringRelay {
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+impl UringRelay {
+    /// Starts `threads` relay threads, at least one. Fails if the kernel does not support
+    /// io_uring, or denies it.
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let mut workers = Vec::with_capacity(threads.max(1));
+        for i in 0..threads.max(1) {
+            let (jobs, receiver) = mpsc::unbounded_channel();
+            let (started, ready) = std::sync::mpsc::channel();
+            thread::Builder::new()
+                .name(format!("socks-uring-{i}"))
+                .spawn(move || {
+                    let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                        Ok(runtime) => runtime,
+                        Err(e) => {
+                            let _ = started.send(Err(e));
+                            return;
+                        }
+                    };
+                    let _ = started.send(Ok(()));
+                    runtime.block_on(work(receiver));
+                })?;
+            ready
+                .recv()
+                .map_err(|_| io::Error::other("io_uring relay thread exited while starting"))??;
+            workers.push(jobs);
+        }
+        Ok(Self {
+            workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Relays like [`pipe_zero_copy`](super::pipe_zero_copy), on one of the relay threads when
+    /// both streams are [`TcpStream`]s, with
+    /// [`copy_bidirectional`](tokio::io::copy_bidirectional) otherwise.
+    ///
+    /// Returns the number of bytes sent from `local` to `remote`, then the other way round.
+    pub async fn pipe<L, S>(&self, local: L, remote: S) -> io::Result<(u64, u64)>
+    where
+        L: AsyncRead + AsyncWrite + Unpin + 'static,
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let (local, remote) = match (into_tcp(local), into_tcp(remote)) {
+            (Ok(local), Ok(remote)) => (local, remote),
+            (local, remote) => {
+                let mut local = local.map_or_else(EitherStream::Right, EitherStream::Left);
+                let mut remote = remote.map_or_else(EitherStream::Right, EitherStream::Left);
+                return tokio::io::copy_bidirectional(&mut local, &mut remote).await;
+            }
+        };
+        let (local, remote) = (blocking(local)?, blocking(remote)?);
+        let (done, outcome) = oneshot::channel();
+        let worker = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        self.workers[worker]
+            .send(Job {
+                local,
+                remote,
+                done,
+            })
+            .map_err(|_| io::Error::other("io_uring relay thread stopped"))?;
+        outcome
+            .await
+            .map_err(|_| io::Error::other("io_uring relay thread stopped"))?
+    }
+}
+
+impl std::fmt::Debug for UringRelay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringRelay")
+            .field("threads", &self.workers.len())
+            .finish()
+    }
+}
+
+/// `stream` itself if it is a [`TcpStream`].
+fn into_tcp<T: 'static>(stream: T) -> Result<TcpStream, T> {
+    let mut stream = Some(stream);
+    match (&mut stream as &mut dyn Any).downcast_mut::<Option<TcpStream>>() {
+        Some(tcp) => Ok(tcp.take().expect("stream taken twice")),
+        None => Err(stream.expect("stream taken twice")),
+    }
+}
+
+/// Socket of `stream` for the ring, which waits for operations to complete rather than polling.
+fn blocking(stream: TcpStream) -> io::Result<std::net::TcpStream> {
+    let stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// Runs the relays of the jobs sent to a thread, until its [`UringRelay`] is dropped.
+async fn work(mut jobs: mpsc::UnboundedReceiver<Job>) {
+    while let Some(job) = jobs.recv().await {
+        tokio_uring::spawn(async move {
+            let _ = job.done.send(relay(job.local, job.remote).await);
+        });
+    }
+}
+
+async fn relay(local: std::net::TcpStream, remote: std::net::TcpStream) -> io::Result<(u64, u64)> {
+    let local = tokio_uring::net::TcpStream::from_std(local);
+    let remote = tokio_uring::net::TcpStream::from_std(remote);
+    tokio::try_join!(one_way(&local, &remote), one_way(&remote, &local))
+}
+
+/// Moves data from `from` to `to` until `from` is closed, then shuts `to` down for writing.
+async fn one_way(
+    from: &tokio_uring::net::TcpStream,
+    to: &tokio_uring::net::TcpStream,
+) -> io::Result<u64> {
+    let mut buffer = Vec::with_capacity(CHUNK_LEN);
+    let mut total = 0;
+    loop {
+        buffer.clear();
+        let (read, filled) = from.read(buffer).await;
+        let n = read?;
+        if n == 0 {
+            break;
+        }
+        let (written, drained) = to.write_all(filled).await;
+        written?;
+        buffer = drained;
+        total += n as u64;
+    }
+    to.shutdown(Shutdown::Write)?;
+    Ok(total)
+}
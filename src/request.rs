@@ -1,11 +1,11 @@
 pub mod v4 {
-    use std::net::Ipv4Addr;
+    use std::{fmt, io, net::Ipv4Addr, num::NonZeroU8};
 
     use nom::{
         bytes::complete::{tag, take_while1},
         combinator::{map, opt, verify},
         error::{context, ContextError},
-        number::complete::{be_u16, be_u8},
+        number::complete::be_u16,
         sequence::{preceded, terminated, tuple},
     };
 
@@ -14,10 +14,9 @@ pub mod v4 {
             v4::{AddressType, Command},
             Version,
         },
-        Wire,
+        DecodeLimits, Redacted, Wire,
     };
 
-    #[derive(Debug)]
     pub struct Request {
         pub command: Command,
         pub addr: AddressType,
@@ -25,6 +24,124 @@ pub mod v4 {
         pub secret: Option<String>,
     }
 
+    impl fmt::Debug for Request {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Request")
+                .field("command", &self.command)
+                .field("addr", &self.addr)
+                .field("port", &self.port)
+                .field("secret", &self.secret.as_ref().map(|_| Redacted))
+                .finish()
+        }
+    }
+
+    impl Request {
+        /// Last octet of the `0.0.0.x` address announcing a SOCKS4a domain name, as used by
+        /// [`Wire::encode_into`].
+        pub const DEFAULT_MARKER: NonZeroU8 = NonZeroU8::MIN;
+
+        /// Request for `command` to `host`, an IPv4 address or a domain name (SOCKS4a) checked
+        /// with [`AddressType::host`](crate::v5::AddressType::host).
+        pub fn new(command: Command, host: &str, port: u16) -> io::Result<Self> {
+            let addr = match crate::v5::AddressType::host(host)? {
+                crate::v5::AddressType::IPv4(ip4) => AddressType::IPv4(ip4),
+                crate::v5::AddressType::DomainName(n) => AddressType::DomainName(n),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("SOCKS4 cannot reach {host}"),
+                    ))
+                }
+            };
+            let request = Self {
+                command,
+                addr,
+                port,
+                secret: None,
+            };
+            request.validate()?;
+            Ok(request)
+        }
+
+        /// CONNECT request to `host`, rejecting port `0`.
+        pub fn connect_to(host: &str, port: u16) -> io::Result<Self> {
+            if port == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Cannot connect to port 0",
+                ));
+            }
+            Self::new(Command::Connect, host, port)
+        }
+
+        /// Sends `user_id` along, which must be ASCII without NUL bytes.
+        pub fn with_user_id(mut self, user_id: impl Into<String>) -> io::Result<Self> {
+            self.secret = Some(user_id.into());
+            self.validate()?;
+            Ok(self)
+        }
+
+        #[doc(hidden)]
+        pub fn debug_unredacted(&self) -> String {
+            format!(
+                "Request {{ command: {:?}, addr: {:?}, port: {:?}, secret: {:?} }}",
+                self.command, self.addr, self.port, self.secret
+            )
+        }
+
+        /// Checks the user ID and domain name are ASCII strings without NUL bytes, the domain
+        /// name being non-empty.
+        pub fn validate(&self) -> io::Result<()> {
+            fn check(what: &str, s: &str) -> io::Result<()> {
+                if s.bytes().all(|b| b.is_ascii() && b != 0) {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{what} must be ASCII without NUL bytes"),
+                    ))
+                }
+            }
+
+            if let Some(ref secret) = self.secret {
+                check("User ID", secret)?;
+            }
+            match self.addr {
+                AddressType::DomainName(ref n) if n.is_empty() => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Empty domain name",
+                )),
+                AddressType::DomainName(ref n) => check("Domain name", n),
+                AddressType::IPv4(_) => Ok(()),
+            }
+        }
+
+        /// Encodes the request after [validating](Request::validate) it, announcing domain names
+        /// with the address `0.0.0.marker`.
+        pub fn try_encode_into(&self, buffer: &mut Vec<u8>, marker: NonZeroU8) -> io::Result<()> {
+            self.validate()?;
+            self.encode_with_marker(buffer, marker);
+            Ok(())
+        }
+
+        fn encode_with_marker(&self, buffer: &mut Vec<u8>, marker: NonZeroU8) {
+            Version::Socks4.encode_into(buffer);
+            self.command.encode_into(buffer);
+            buffer.extend_from_slice(&self.port.to_be_bytes()[..]);
+            match self.addr {
+                AddressType::IPv4(ip4) => {
+                    buffer.extend_from_slice(&ip4.octets()[..]);
+                    encode_string(self.secret.as_deref(), buffer);
+                }
+                AddressType::DomainName(ref n) => {
+                    buffer.extend_from_slice(&[0, 0, 0, marker.get()][..]);
+                    encode_string(self.secret.as_deref(), buffer);
+                    encode_string(Some(n.as_str()), buffer);
+                }
+            }
+        }
+    }
+
     fn encode_string(s: Option<&str>, buffer: &mut Vec<u8>) {
         if let Some(s) = s {
             buffer.extend_from_slice(s.as_bytes());
@@ -49,51 +166,37 @@ pub mod v4 {
     }
 
     impl Wire for Request {
+        /// Encodes the request as is, see [`Request::try_encode_into`] to validate it first.
         fn encode_into(&self, buffer: &mut Vec<u8>) {
-            Version::Socks4.encode_into(buffer);
-            self.command.encode_into(buffer);
-            buffer.extend_from_slice(&self.port.to_be_bytes()[..]);
-            match self.addr {
-                AddressType::IPv4(ip4) => {
-                    buffer.extend_from_slice(&ip4.octets()[..]);
-                    encode_string(self.secret.as_deref(), buffer);
-                }
-                AddressType::DomainName(ref n) => {
-                    buffer.extend_from_slice(&[0, 0, 0, 1][..]);
-                    encode_string(self.secret.as_deref(), buffer);
-                    encode_string(Some(n.as_str()), buffer);
-                }
-            }
+            self.encode_with_marker(buffer, Self::DEFAULT_MARKER);
         }
 
         fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
         where
             E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
         {
-            log::trace!("v4::Request::decode({buffer:?})");
-            let (rest, (command, port, (a, b, c, d), secret, name)) = context(
+            log::trace!("v4::Request::decode({} bytes)", buffer.len());
+            let (rest, (command, port, ip4, secret)) = context(
                 "Socks request",
                 preceded(
                     verify(Version::decode, |&v| v == Version::Socks4),
-                    tuple((
-                        Command::decode,
-                        be_u16,
-                        tuple((be_u8, be_u8, be_u8, be_u8)),
-                        decode_string,
-                        opt(decode_string),
-                    )),
+                    tuple((Command::decode, be_u16, Ipv4Addr::decode, decode_string)),
                 ),
             )(buffer)?;
-            let addr = match name {
-                Some(Some(n)) => AddressType::DomainName(n),
-                Some(None) => {
-                    return Err(nom::Err::Failure(ContextError::add_context(
-                        buffer,
-                        "Got empty domain name",
-                        nom::error::make_error(buffer, nom::error::ErrorKind::Verify),
-                    )));
-                }
-                None => AddressType::IPv4(Ipv4Addr::new(a, b, c, d)),
+
+            // SOCKS4a: 0.0.0.x (x != 0) means a domain name follows the user ID.
+            let (rest, addr) = match ip4.octets() {
+                [0, 0, 0, x] if x != 0 => match context("domain name", decode_string)(rest)? {
+                    (rest, Some(n)) => (rest, AddressType::DomainName(n)),
+                    (_, None) => {
+                        return Err(nom::Err::Failure(ContextError::add_context(
+                            buffer,
+                            "Got empty domain name",
+                            nom::error::make_error(buffer, nom::error::ErrorKind::Verify),
+                        )));
+                    }
+                },
+                _ => (rest, AddressType::IPv4(ip4)),
             };
 
             Ok((
@@ -106,14 +209,23 @@ pub mod v4 {
                 },
             ))
         }
+
+        fn check_limits(&self, limits: &DecodeLimits) -> Result<(), &'static str> {
+            match self.addr {
+                AddressType::DomainName(ref n) => limits.check_domain_name(n),
+                AddressType::IPv4(_) => Ok(()),
+            }
+        }
     }
 }
 
 pub mod v5 {
+    use std::{fmt, io};
+
     use nom::{
-        combinator::{map, verify},
+        combinator::{map, map_opt, verify},
         error::context,
-        multi::length_count,
+        multi::{length_count, length_data},
         number::complete::{be_u16, be_u8},
         sequence::{preceded, tuple},
     };
@@ -123,14 +235,57 @@ pub mod v5 {
             v5::{AddressType, AuthenticationMethod, Command},
             Version,
         },
-        Wire,
+        DecodeLimits, HelloError, Redacted, Wire,
     };
 
+    /// Methods offered by a SOCKS5 client, at least one and without duplicates once decoded.
     #[derive(Debug)]
     pub struct Hello {
         pub methods: Vec<AuthenticationMethod>,
     }
 
+    impl Hello {
+        pub fn offers(&self, method: AuthenticationMethod) -> bool {
+            self.methods.contains(&method)
+        }
+
+        /// First method of `priority` offered by the client.
+        pub fn preferred(&self, priority: &[AuthenticationMethod]) -> Option<AuthenticationMethod> {
+            priority
+                .iter()
+                .copied()
+                .find(|&m| m != AuthenticationMethod::NotAcceptable && self.offers(m))
+        }
+
+        /// Checks that the hello offers between 1 and 255 methods, each once, as decoded ones
+        /// do: hellos built by hand may not, and the largest could not even be encoded.
+        pub fn validate(&self) -> Result<(), HelloError> {
+            if self.methods.is_empty() {
+                return Err(HelloError::NoMethods);
+            }
+            if self.methods.len() > u8::MAX as usize {
+                return Err(HelloError::TooManyMethods(self.methods.len()));
+            }
+            for (i, &method) in self.methods.iter().enumerate() {
+                if self.methods[..i].contains(&method) {
+                    return Err(HelloError::RepeatedMethod(method));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Drops repeated methods, keeping the first occurrence.
+    fn dedup(methods: Vec<AuthenticationMethod>) -> Vec<AuthenticationMethod> {
+        let mut unique = Vec::with_capacity(methods.len());
+        for m in methods {
+            if !unique.contains(&m) {
+                unique.push(m);
+            }
+        }
+        unique
+    }
+
     impl Wire for Hello {
         fn encode_into(&self, buffer: &mut Vec<u8>) {
             Version::Socks5.encode_into(buffer);
@@ -154,36 +309,113 @@ pub mod v5 {
                 map(
                     preceded(
                         verify(Version::decode, |&v| v == Version::Socks5),
-                        length_count(be_u8, AuthenticationMethod::decode),
+                        length_count(
+                            context("method count", verify(be_u8, |&n| n > 0)),
+                            AuthenticationMethod::decode,
+                        ),
                     ),
-                    |methods| Self { methods },
+                    |methods| Self {
+                        methods: dedup(methods),
+                    },
                 ),
             )(buffer)
         }
+
+        fn check_header_limits(input: &[u8], limits: &DecodeLimits) -> Result<(), &'static str> {
+            match *input {
+                [version, count, ..] if version == Version::Socks5 as u8 => {
+                    limits.check_methods(count.into())
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn check_limits(&self, limits: &DecodeLimits) -> Result<(), &'static str> {
+            limits.check_methods(self.methods.len())
+        }
+
+        fn check_strict(&self, message: &[u8]) -> Result<(), &'static str> {
+            if message.len() - 2 != self.methods.len() {
+                return Err("Repeated authentication methods");
+            }
+            Ok(())
+        }
     }
 
     #[derive(Debug)]
     pub struct Request {
         pub command: Command,
+        /// Reserved byte as sent by the client, `0` unless it ignores the RFC.
+        pub rsv: u8,
         pub addr: AddressType,
         pub port: u16,
     }
 
+    impl Request {
+        /// Request for `command` to `host`, an IP address or a domain name checked with
+        /// [`AddressType::host`].
+        pub fn new(command: Command, host: &str, port: u16) -> io::Result<Self> {
+            Ok(Self {
+                command,
+                rsv: 0,
+                addr: AddressType::host(host)?,
+                port,
+            })
+        }
+
+        /// CONNECT request to `host`, rejecting port `0`.
+        pub fn connect_to(host: &str, port: u16) -> io::Result<Self> {
+            if port == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Cannot connect to port 0",
+                ));
+            }
+            Self::new(Command::Connect, host, port)
+        }
+
+        /// Checks the request can be encoded: domain names must be non-empty and at most 255
+        /// bytes long.
+        pub fn validate(&self) -> io::Result<()> {
+            match self.addr {
+                AddressType::DomainName(ref n) if n.is_empty() => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Empty domain name",
+                )),
+                AddressType::DomainName(ref n) if n.len() > u8::MAX as usize => Err(
+                    io::Error::new(io::ErrorKind::InvalidInput, "Domain name too long"),
+                ),
+                _ => Ok(()),
+            }
+        }
+
+        /// Encodes the request after [validating](Request::validate) it.
+        pub fn try_encode_into(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+            self.validate()?;
+            self.encode_into(buffer);
+            Ok(())
+        }
+    }
+
     impl Wire for Request {
         fn encode_into(&self, buffer: &mut Vec<u8>) {
             Version::Socks5.encode_into(buffer);
             self.command.encode_into(buffer);
-            buffer.push(0);
+            buffer.push(self.rsv);
             self.addr.encode_into(buffer);
             buffer.extend_from_slice(&self.port.to_be_bytes()[..]);
         }
 
+        fn encoded_len(&self) -> usize {
+            5 + self.addr.encoded_len()
+        }
+
         fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
         where
             E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
         {
-            log::trace!("v5::Request::decode({buffer:?})");
-            let (rest, (command, _zero, addr, port)) = context(
+            log::trace!("v5::Request::decode({} bytes)", buffer.len());
+            let (rest, (command, rsv, addr, port)) = context(
                 "Request",
                 preceded(
                     verify(Version::decode, |&v| v == Version::Socks5),
@@ -194,10 +426,102 @@ pub mod v5 {
                 rest,
                 Self {
                     command,
+                    rsv,
                     addr,
                     port,
                 },
             ))
         }
+
+        fn check_limits(&self, limits: &DecodeLimits) -> Result<(), &'static str> {
+            self.addr.check_limits(limits)
+        }
+
+        fn check_strict(&self, message: &[u8]) -> Result<(), &'static str> {
+            if self.rsv != 0 {
+                return Err("Reserved byte must be 0");
+            }
+            #[cfg(feature = "extensions")]
+            if let Command::Other(_) = self.command {
+                return Err("Private commands are not standard");
+            }
+            self.addr.check_strict(&message[3..])
+        }
+    }
+
+    /// Username/password sub-negotiation request (RFC 1929).
+    pub struct UsernamePassword {
+        pub username: String,
+        pub password: String,
+    }
+
+    impl fmt::Debug for UsernamePassword {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("UsernamePassword")
+                .field("username", &self.username)
+                .field("password", &Redacted)
+                .finish()
+        }
+    }
+
+    impl UsernamePassword {
+        #[doc(hidden)]
+        pub fn debug_unredacted(&self) -> String {
+            format!(
+                "UsernamePassword {{ username: {:?}, password: {:?} }}",
+                self.username, self.password
+            )
+        }
+    }
+
+    pub(crate) const USERNAME_PASSWORD_VERSION: u8 = 1;
+
+    fn encode_short_string(s: &str, buffer: &mut Vec<u8>) {
+        let size: u8 = s.len().try_into().expect("String too long");
+        buffer.push(size);
+        buffer.extend_from_slice(s.as_bytes());
+    }
+
+    fn decode_short_string<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], String, E>
+    where
+        E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+    {
+        map_opt(length_data(be_u8), |b| {
+            std::str::from_utf8(b).ok().map(String::from)
+        })(buffer)
+    }
+
+    impl Wire for UsernamePassword {
+        fn encode_into(&self, buffer: &mut Vec<u8>) {
+            buffer.push(USERNAME_PASSWORD_VERSION);
+            encode_short_string(&self.username, buffer);
+            encode_short_string(&self.password, buffer);
+        }
+
+        fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
+        where
+            E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+        {
+            context(
+                "Username/password request",
+                map(
+                    preceded(
+                        verify(be_u8, |&v| v == USERNAME_PASSWORD_VERSION),
+                        tuple((
+                            context("username", decode_short_string),
+                            context("password", decode_short_string),
+                        )),
+                    ),
+                    |(username, password)| Self { username, password },
+                ),
+            )(buffer)
+        }
+
+        fn check_strict(&self, _message: &[u8]) -> Result<(), &'static str> {
+            if self.username.is_empty() || self.password.is_empty() {
+                return Err("Empty username or password");
+            }
+            Ok(())
+        }
     }
 }
@@ -0,0 +1,43 @@
+//! Server side of the Tor RESOLVE and RESOLVE_PTR extensions, letting clients explicitly
+//! delegate DNS lookups to the proxy.
+
+use std::{io, net::IpAddr, sync::Arc};
+
+use crate::{auth::BoxFuture, resolver::Resolver};
+
+pub use crate::resolver::SystemResolver;
+
+/// Backend answering RESOLVE requests, registered with
+/// [`Server::with_resolver`](crate::Server::with_resolver).
+///
+/// The result is sent to the client as the bound address of the reply.
+pub trait ResolveHandler: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<IpAddr>>;
+
+    /// Reverse lookup, unsupported unless overridden.
+    fn resolve_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<String>> {
+        Box::pin(async move {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Cannot reverse-resolve {ip}"),
+            ))
+        })
+    }
+}
+
+impl<R: ResolveHandler + ?Sized> ResolveHandler for Arc<R> {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<IpAddr>> {
+        (**self).resolve(host)
+    }
+
+    fn resolve_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<String>> {
+        (**self).resolve_ptr(ip)
+    }
+}
+
+/// Answers with the first address found, reverse lookups being unsupported.
+impl ResolveHandler for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<IpAddr>> {
+        Box::pin(async move { Ok(self.lookup(host).await?.addrs[0]) })
+    }
+}
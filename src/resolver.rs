@@ -0,0 +1,268 @@
+//! Name resolution of destinations, to reach those given by name.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    acl::{self, Acl, Action},
+    auth::BoxFuture,
+    v5::AddressType,
+    Destination,
+};
+
+/// Addresses a name resolves to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    pub addrs: Vec<IpAddr>,
+    /// How long the addresses may be cached, when the resolver knows it.
+    pub ttl: Option<Duration>,
+}
+
+/// Backend resolving the domain names of destinations, such as the system resolver or a DNS
+/// client.
+pub trait Resolver: Send + Sync {
+    /// Fails with `HostUnreachable` when `host` has no address.
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>>;
+}
+
+impl<R: Resolver + ?Sized> Resolver for Arc<R> {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        (**self).lookup(host)
+    }
+}
+
+/// Resolves names with the system resolver, which does not tell how long addresses are valid.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async move {
+            let addrs: Vec<_> = tokio::net::lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::HostUnreachable,
+                    format!("No address for {host}"),
+                ));
+            }
+            Ok(Lookup { addrs, ttl: None })
+        })
+    }
+}
+
+/// Addresses to connect to for `destination`: its own when it is an IP address, otherwise those
+/// `resolver` finds for its name, keeping the ones `acl` allows and, unless `allow_private`,
+/// those outside of [`acl::block_private_ranges`].
+///
+/// Connect to these very addresses rather than to the name: resolving it again could yield
+/// another address than the one checked, which is how DNS rebinding gets around an ACL. Fails
+/// with `PermissionDenied` when every address is denied.
+pub async fn resolve_and_pin<R: Resolver + ?Sized>(
+    resolver: &R,
+    destination: &Destination,
+    acl: Option<&dyn Acl>,
+    allow_private: bool,
+) -> io::Result<Vec<SocketAddr>> {
+    let addrs = match destination.addr.to_socket_addr(destination.port) {
+        Some(addr) => vec![addr],
+        None => {
+            let AddressType::DomainName(ref host) = destination.addr else {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("Cannot resolve {destination}"),
+                ));
+            };
+            let lookup = resolver.lookup(host).await?;
+            lookup
+                .addrs
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, destination.port))
+                .collect()
+        }
+    };
+
+    let mut denied = None;
+    let pinned: Vec<_> = addrs
+        .into_iter()
+        .filter(|&addr| match vet(destination, addr, acl, allow_private) {
+            Ok(()) => true,
+            Err(e) => {
+                denied = Some(e);
+                false
+            }
+        })
+        .collect();
+    match denied {
+        Some(e) if pinned.is_empty() => Err(e),
+        _ => Ok(pinned),
+    }
+}
+
+/// Fails with `PermissionDenied` if `destination` may not be reached through `addr`.
+fn vet(
+    destination: &Destination,
+    addr: SocketAddr,
+    acl: Option<&dyn Acl>,
+    allow_private: bool,
+) -> io::Result<()> {
+    if !allow_private {
+        acl::check_resolved(destination, addr)?;
+    }
+    if let Some(acl) = acl {
+        if acl.check(&Destination::from(addr)) == Action::Deny {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{destination} resolves to {}, denied by ACL", addr.ip()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+struct Entry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+    /// Value of [`Cache::clock`] when last used.
+    used: u64,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// Incremented on every use of an entry, to find the least recently used one.
+    clock: u64,
+}
+
+/// Resolver keeping the addresses found by another one until their TTL expires, to spare a
+/// lookup to every request for a popular destination.
+///
+/// Share it across connections, behind an `Arc` or through a handler such as
+/// [`handlers::connect_with`](crate::handlers::connect_with). Failed lookups are not cached.
+/// Once `capacity` names are cached, the least recently used one is evicted.
+pub struct CachingResolver<R> {
+    inner: R,
+    capacity: usize,
+    default_ttl: Duration,
+    max_ttl: Duration,
+    cache: Mutex<Cache>,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Lookups with no known TTL are kept for a minute, see [`Self::with_default_ttl`].
+    pub fn new(inner: R, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity,
+            default_ttl: Duration::from_secs(60),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            cache: Mutex::default(),
+        }
+    }
+
+    /// How long to keep addresses whose TTL is unknown, as with [`SystemResolver`].
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Longest time addresses are kept, whatever their TTL. A day by default.
+    pub fn with_max_ttl(mut self, ttl: Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    /// Number of cached names, including expired ones not evicted yet.
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Cached addresses of `host`, along with the time left before they expire.
+    fn cached(&self, host: &str) -> Option<Lookup> {
+        let mut cache = self.cache.lock().unwrap();
+        cache.clock += 1;
+        let clock = cache.clock;
+        let now = Instant::now();
+        match cache.entries.get_mut(host) {
+            Some(entry) if entry.expires > now => {
+                entry.used = clock;
+                Some(Lookup {
+                    addrs: entry.addrs.clone(),
+                    ttl: Some(entry.expires - now),
+                })
+            }
+            Some(_) => {
+                cache.entries.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, host: &str, lookup: &Lookup) {
+        let ttl = lookup.ttl.unwrap_or(self.default_ttl).min(self.max_ttl);
+        if ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.entries.len() >= self.capacity && !cache.entries.contains_key(host) {
+            let now = Instant::now();
+            let evicted = match cache.entries.iter().find(|(_, e)| e.expires <= now) {
+                Some((name, _)) => Some(name.clone()),
+                None => cache
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.used)
+                    .map(|(name, _)| name.clone()),
+            };
+            if let Some(name) = evicted {
+                cache.entries.remove(&name);
+            }
+        }
+        cache.clock += 1;
+        let entry = Entry {
+            addrs: lookup.addrs.clone(),
+            expires: Instant::now() + ttl,
+            used: cache.clock,
+        };
+        cache.entries.insert(host.to_owned(), entry);
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async move {
+            let host = host.to_ascii_lowercase();
+            if let Some(lookup) = self.cached(&host) {
+                log::trace!("Using cached addresses of {host}");
+                return Ok(lookup);
+            }
+            let lookup = self.inner.lookup(&host).await?;
+            self.insert(&host, &lookup);
+            Ok(lookup)
+        })
+    }
+}
+
+impl<R> fmt::Debug for CachingResolver<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachingResolver")
+            .field("capacity", &self.capacity)
+            .field("default_ttl", &self.default_ttl)
+            .field("max_ttl", &self.max_ttl)
+            .finish_non_exhaustive()
+    }
+}
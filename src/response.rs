@@ -1,5 +1,5 @@
 pub mod v4 {
-    use std::net::Ipv4Addr;
+    use std::{io, net::Ipv4Addr};
 
     use nom::{
         combinator::verify,
@@ -29,12 +29,9 @@ pub mod v4 {
             E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
         {
             let (rest, s) = context("status", be_u8)(buffer)?;
-            match s {
-                0x5a => Ok((rest, Self::Success)),
-                0x5b => Ok((rest, Self::Rejected)),
-                0x5c => Ok((rest, Self::InetdNotAccessible)),
-                0x5d => Ok((rest, Self::InetdNotIdentified)),
-                _ => Err(nom::Err::Failure(nom::error::make_error(
+            match Self::try_from(s) {
+                Ok(status) => Ok((rest, status)),
+                Err(_) => Err(nom::Err::Failure(nom::error::make_error(
                     buffer,
                     nom::error::ErrorKind::NoneOf,
                 ))),
@@ -42,6 +39,65 @@ pub mod v4 {
         }
     }
 
+    impl TryFrom<u8> for Status {
+        type Error = io::Error;
+
+        fn try_from(value: u8) -> io::Result<Self> {
+            match value {
+                0x5a => Ok(Self::Success),
+                0x5b => Ok(Self::Rejected),
+                0x5c => Ok(Self::InetdNotAccessible),
+                0x5d => Ok(Self::InetdNotIdentified),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid SOCKS4 status {value:#04x}"),
+                )),
+            }
+        }
+    }
+
+    impl From<Status> for u8 {
+        fn from(value: Status) -> Self {
+            value as u8
+        }
+    }
+
+    impl Status {
+        pub fn is_success(self) -> bool {
+            self == Self::Success
+        }
+
+        /// Whether the request may succeed if sent again, SOCKS4 rejections telling nothing
+        /// about their cause while identd failures come from the client setup.
+        pub fn is_retryable(self) -> bool {
+            self == Self::Rejected
+        }
+    }
+
+    /// Reply to a SOCKS4 client for the outcome of its request.
+    ///
+    /// SOCKS4 only tells whether a request succeeded, its other codes being about the identd
+    /// check of the user id: every failure is reported as rejected.
+    impl From<crate::v5::Status> for Status {
+        fn from(value: crate::v5::Status) -> Self {
+            use crate::v5::Status as V5;
+
+            match value {
+                V5::Success => Self::Success,
+                V5::GeneralFailure
+                | V5::ConnectionNotAllowed
+                | V5::NetworkUnreachable
+                | V5::HostUnreachalble
+                | V5::ConnectionRefused
+                | V5::TTLExpired
+                | V5::CommandNotSupported
+                | V5::Unassigned(_) => Self::Rejected,
+                #[cfg(feature = "tor")]
+                V5::TorExtended(_) => Self::Rejected,
+            }
+        }
+    }
+
     #[derive(Debug)]
     pub struct Response {
         pub status: Status,
@@ -57,6 +113,10 @@ pub mod v4 {
             self.addr.encode_into(buffer);
         }
 
+        fn encoded_len(&self) -> usize {
+            8
+        }
+
         fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
         where
             E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
@@ -74,6 +134,8 @@ pub mod v4 {
 }
 
 pub mod v5 {
+    use std::io;
+
     use nom::{
         combinator::{map, verify},
         error::context,
@@ -86,7 +148,7 @@ pub mod v5 {
             v5::{AddressType, AuthenticationMethod},
             Version,
         },
-        Wire,
+        DecodeLimits, Wire,
     };
 
     #[derive(Debug)]
@@ -94,6 +156,22 @@ pub mod v5 {
         pub method: AuthenticationMethod,
     }
 
+    impl Hello {
+        /// Tells the client `method` was selected among those it offered.
+        pub fn accept(method: crate::sansio::NegotiatedMethod) -> Self {
+            Self {
+                method: method.method(),
+            }
+        }
+
+        /// Tells the client none of its methods is acceptable.
+        pub fn not_acceptable() -> Self {
+            Self {
+                method: AuthenticationMethod::NotAcceptable,
+            }
+        }
+    }
+
     impl Wire for Hello {
         fn encode_into(&self, buffer: &mut Vec<u8>) {
             Version::Socks5.encode_into(buffer);
@@ -127,9 +205,81 @@ pub mod v5 {
         ConnectionRefused,
         TTLExpired,
         CommandNotSupported,
+        /// Tor extended error code, from `0xf0` to `0xf6`.
+        #[cfg(feature = "tor")]
+        TorExtended(u8),
         Unassigned(u8),
     }
 
+    /// Closest SOCKS5 status of a SOCKS4 one, identd failures being reported as not allowed.
+    impl From<crate::v4::Status> for Status {
+        fn from(value: crate::v4::Status) -> Self {
+            use crate::v4::Status as V4;
+
+            match value {
+                V4::Success => Self::Success,
+                V4::Rejected => Self::GeneralFailure,
+                V4::InetdNotAccessible | V4::InetdNotIdentified => Self::ConnectionNotAllowed,
+            }
+        }
+    }
+
+    impl Status {
+        pub fn is_success(self) -> bool {
+            self == Self::Success
+        }
+
+        /// Kind of the I/O errors standing for this failure.
+        pub(crate) fn error_kind(self) -> io::ErrorKind {
+            match self {
+                Self::ConnectionNotAllowed => io::ErrorKind::PermissionDenied,
+                Self::NetworkUnreachable => io::ErrorKind::NetworkUnreachable,
+                Self::HostUnreachalble => io::ErrorKind::HostUnreachable,
+                Self::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+                Self::TTLExpired => io::ErrorKind::TimedOut,
+                Self::CommandNotSupported => io::ErrorKind::Unsupported,
+                _ => io::ErrorKind::Other,
+            }
+        }
+
+        /// Whether the failure may be transient, the request succeeding if sent again later or
+        /// through another proxy. Denials, refused connections and unknown codes are not.
+        pub fn is_retryable(self) -> bool {
+            match self {
+                Self::GeneralFailure
+                | Self::NetworkUnreachable
+                | Self::HostUnreachalble
+                | Self::TTLExpired => true,
+                // Onion service descriptor not found, introduction or rendezvous failed.
+                #[cfg(feature = "tor")]
+                Self::TorExtended(code) => matches!(code, 0xf0 | 0xf2 | 0xf3),
+                Self::Success
+                | Self::ConnectionNotAllowed
+                | Self::ConnectionRefused
+                | Self::CommandNotSupported
+                | Self::Unassigned(_) => false,
+            }
+        }
+    }
+
+    impl From<Status> for u8 {
+        fn from(value: Status) -> Self {
+            match value {
+                Status::Success => 0,
+                Status::GeneralFailure => 1,
+                Status::ConnectionNotAllowed => 2,
+                Status::NetworkUnreachable => 3,
+                Status::HostUnreachalble => 4,
+                Status::ConnectionRefused => 5,
+                Status::TTLExpired => 6,
+                Status::CommandNotSupported => 7,
+                #[cfg(feature = "tor")]
+                Status::TorExtended(v) => v,
+                Status::Unassigned(v) => v,
+            }
+        }
+    }
+
     impl From<u8> for Status {
         fn from(value: u8) -> Self {
             match value {
@@ -141,6 +291,8 @@ pub mod v5 {
                 5 => Self::ConnectionRefused,
                 6 => Self::TTLExpired,
                 7 => Self::CommandNotSupported,
+                #[cfg(feature = "tor")]
+                0xf0..=0xf6 => Self::TorExtended(value),
                 v => Self::Unassigned(v),
             }
         }
@@ -148,18 +300,7 @@ pub mod v5 {
 
     impl Wire for Status {
         fn encode_into(&self, buffer: &mut Vec<u8>) {
-            let b = match self {
-                Self::Success => 0,
-                Self::GeneralFailure => 1,
-                Self::ConnectionNotAllowed => 2,
-                Self::NetworkUnreachable => 3,
-                Self::HostUnreachalble => 4,
-                Self::ConnectionRefused => 5,
-                Self::TTLExpired => 6,
-                Self::CommandNotSupported => 7,
-                Self::Unassigned(v) => *v,
-            };
-            buffer.push(b);
+            buffer.push((*self).into());
         }
 
         fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
@@ -186,6 +327,10 @@ pub mod v5 {
             buffer.extend_from_slice(&self.port.to_be_bytes()[..]);
         }
 
+        fn encoded_len(&self) -> usize {
+            5 + self.addr.encoded_len()
+        }
+
         fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
         where
             E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
@@ -199,5 +344,49 @@ pub mod v5 {
             )(buffer)?;
             Ok((rest, Self { status, addr, port }))
         }
+
+        fn check_limits(&self, limits: &DecodeLimits) -> Result<(), &'static str> {
+            self.addr.check_limits(limits)
+        }
+
+        fn check_strict(&self, message: &[u8]) -> Result<(), &'static str> {
+            if message[2] != 0 {
+                return Err("Reserved byte must be 0");
+            }
+            self.addr.check_strict(&message[3..])
+        }
+    }
+
+    /// Username/password sub-negotiation response (RFC 1929).
+    #[derive(Debug)]
+    pub struct UsernamePassword {
+        pub success: bool,
+    }
+
+    impl Wire for UsernamePassword {
+        fn encode_into(&self, buffer: &mut Vec<u8>) {
+            buffer.push(crate::request::v5::USERNAME_PASSWORD_VERSION);
+            buffer.push(if self.success { 0 } else { 1 });
+        }
+
+        fn decode<'i, E>(buffer: &'i [u8]) -> nom::IResult<&'i [u8], Self, E>
+        where
+            E: nom::error::ParseError<&'i [u8]> + nom::error::ContextError<&'i [u8]>,
+        {
+            context(
+                "Username/password response",
+                map(
+                    preceded(
+                        verify(be_u8, |&v| {
+                            v == crate::request::v5::USERNAME_PASSWORD_VERSION
+                        }),
+                        be_u8,
+                    ),
+                    |status| Self {
+                        success: status == 0,
+                    },
+                ),
+            )(buffer)
+        }
     }
 }
@@ -0,0 +1,182 @@
+//! Destination rewriting rules, applied by the server before the request handler.
+//!
+//! Rules are written one per line:
+//!
+//! ```text
+//! # comment
+//! proxy corp socks5://gw.corp.example:1080
+//! host *.intranet.example 10.0.0.1
+//! port *:80 8080
+//! via 10.0.0.0/8 corp
+//! block ads.example.com
+//! ```
+//!
+//! `host` and `port` replace the address or port of the destination, `via` routes the request
+//! through a proxy declared with `proxy`, and `block` denies it. Every matching rule applies in
+//! order, to the destination as rewritten by the previous ones, until a `block` rule matches.
+//! Targets are written as [ACL rules](crate::acl) ones.
+//!
+//! [`RewriteRules`] is a server [layer](crate::Server::with_layer). Proxies picked by `via`
+//! rules are only used by [`handlers::follow_rewrites`](crate::handlers::follow_rewrites).
+
+use std::{collections::HashMap, fs, io, net::IpAddr, ops::RangeInclusive, path::Path, sync::Arc};
+
+use crate::{
+    acl::{parse_target, HostPattern},
+    proxy::ProxyUrl,
+    v5::{AddressType, Status},
+    ConnectionRequest, Decision, Destination, Layer, Rejection,
+};
+
+/// Annotation naming the proxy picked by a `via` rule.
+pub const UPSTREAM: &str = "upstream";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Action {
+    Host(AddressType),
+    Port(u16),
+    Via(String),
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    host: HostPattern,
+    ports: RangeInclusive<u16>,
+    action: Action,
+}
+
+/// Parsed list of rules, cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct RewriteRules {
+    rules: Arc<[Rule]>,
+    proxies: Arc<HashMap<String, ProxyUrl>>,
+}
+
+/// What [`RewriteRules::rewrite`] made of a destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rewritten {
+    Forward {
+        destination: Destination,
+        /// Name of the proxy to go through.
+        upstream: Option<String>,
+    },
+    Blocked,
+}
+
+impl RewriteRules {
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut rules = Vec::new();
+        let mut proxies = HashMap::new();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid rewrite rule at line {}: {reason}", lineno + 1),
+                )
+            };
+            let words: Vec<_> = line.split_whitespace().collect();
+            let (keyword, target, argument) = match words[..] {
+                [keyword, target] => (keyword, target, None),
+                [keyword, target, argument] => (keyword, target, Some(argument)),
+                _ => return Err(invalid(&format!("{line:?}"))),
+            };
+            if keyword == "proxy" {
+                let url = argument.ok_or_else(|| invalid("missing proxy URL"))?;
+                proxies.insert(target.to_owned(), url.parse()?);
+                continue;
+            }
+            let action = match (keyword, argument) {
+                ("host", Some(host)) => Action::Host(match host.parse::<IpAddr>() {
+                    Ok(ip) => ip.into(),
+                    Err(_) => AddressType::DomainName(host.to_owned()),
+                }),
+                ("port", Some(port)) => {
+                    Action::Port(port.parse().map_err(|_| invalid("invalid port"))?)
+                }
+                ("via", Some(name)) => Action::Via(name.to_owned()),
+                ("block", None) => Action::Block,
+                _ => return Err(invalid(&format!("{line:?}"))),
+            };
+            let (host, ports) = parse_target(target).ok_or_else(|| invalid("invalid target"))?;
+            rules.push(Rule {
+                host,
+                ports,
+                action,
+            });
+        }
+        for rule in &rules {
+            if let Action::Via(ref name) = rule.action {
+                if !proxies.contains_key(name) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Unknown proxy {name:?} in rewrite rules"),
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            rules: rules.into(),
+            proxies: Arc::new(proxies),
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Proxy declared as `name`.
+    pub fn proxy(&self, name: &str) -> Option<&ProxyUrl> {
+        self.proxies.get(name)
+    }
+
+    pub fn rewrite(&self, mut destination: Destination) -> Rewritten {
+        let mut upstream = None;
+        for rule in self.rules.iter() {
+            if !rule.ports.contains(&destination.port) || !rule.host.matches(&destination.addr) {
+                continue;
+            }
+            match rule.action {
+                Action::Host(ref addr) => destination.addr = addr.clone(),
+                Action::Port(port) => destination.port = port,
+                Action::Via(ref name) => upstream = Some(name.clone()),
+                Action::Block => return Rewritten::Blocked,
+            }
+        }
+        Rewritten::Forward {
+            destination,
+            upstream,
+        }
+    }
+}
+
+/// Rewrites the destination of requests, noting the proxy to go through as the [`UPSTREAM`]
+/// annotation. Blocked requests are denied with `ConnectionNotAllowed`.
+impl Layer for RewriteRules {
+    fn apply(&self, mut request: ConnectionRequest) -> Decision {
+        let original = request.destination.clone();
+        match self.rewrite(request.destination) {
+            Rewritten::Forward {
+                destination,
+                upstream,
+            } => {
+                if destination != original {
+                    log::debug!("Rewrote {original} to {destination}");
+                }
+                request.destination = destination;
+                if let Some(upstream) = upstream {
+                    request.annotations.insert(UPSTREAM.into(), upstream);
+                }
+                Decision::Continue(request)
+            }
+            Rewritten::Blocked => {
+                log::debug!("Blocked {original} by rewrite rules");
+                Decision::Deny(Rejection::Status(Status::ConnectionNotAllowed))
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! SOCKS5 handshakes driven by hand, without any I/O.
+//!
+//! [`Client`] hands out the messages to send and takes the decoded replies, its type following
+//! the state of the handshake: a request cannot be sent before the authentication method is
+//! negotiated, nor credentials before the server asked for them.
+//!
+//! ```
+//! use std::net::SocketAddr;
+//!
+//! use socks_parser::{sansio::{Client, Negotiated}, v5, Destination};
+//!
+//! let client = Client::new().with_username_password("alice", "secret");
+//! let hello = client.hello();
+//! // Send `hello`, then decode the reply of the server.
+//! let reply = v5::HelloResponse { method: v5::AuthenticationMethod::UsernamePassword };
+//! let Negotiated::NeedsAuth(client) = client.on_hello_response(&reply)? else {
+//!     unreachable!();
+//! };
+//! let credentials = client.credentials();
+//! // Send `credentials`, then decode the reply of the server.
+//! let client = client.on_auth_response(&v5::UsernamePasswordResponse { success: true })?;
+//! let destination = Destination::from(SocketAddr::from(([192, 0, 2, 1], 80)));
+//! let (request, pending) = client.request(v5::Command::Connect, destination);
+//! // Send `request`, then decode the reply of the server.
+//! let reply = v5::Response {
+//!     status: v5::Status::Success,
+//!     addr: v5::AddressType::IPv4([198, 51, 100, 7].into()),
+//!     port: 41000,
+//! };
+//! let bound = pending.on_response(reply)?;
+//! # Ok::<_, std::io::Error>(())
+//! ```
+
+use std::io;
+
+use crate::{
+    error::ClientError,
+    v5::{
+        AuthenticationMethod, Command, Hello, HelloResponse, Request, Response, UsernamePassword,
+        UsernamePasswordResponse,
+    },
+    Destination,
+};
+
+/// Authentication method selected by a server among those offered by a client.
+///
+/// Only built from the methods of a client [`Hello`], so the server cannot reply with a method
+/// the client did not offer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NegotiatedMethod(AuthenticationMethod);
+
+impl NegotiatedMethod {
+    /// First method of `priority` offered in `hello`, if any.
+    pub fn select(hello: &Hello, priority: &[AuthenticationMethod]) -> Option<Self> {
+        hello.preferred(priority).map(Self)
+    }
+
+    pub fn method(self) -> AuthenticationMethod {
+        self.0
+    }
+}
+
+/// Client side of a SOCKS5 handshake, in the `State` it reached.
+#[derive(Debug)]
+pub struct Client<State> {
+    state: State,
+}
+
+/// Method negotiation is yet to be done.
+#[derive(Debug, Default)]
+pub struct NeedsHello {
+    credentials: Option<UsernamePassword>,
+}
+
+/// The server asked for the username and password.
+#[derive(Debug)]
+pub struct NeedsAuth {
+    credentials: UsernamePassword,
+}
+
+/// The client may send its request.
+#[derive(Debug)]
+pub struct Ready {
+    method: AuthenticationMethod,
+}
+
+/// Outcome of the method negotiation.
+#[derive(Debug)]
+pub enum Negotiated {
+    NeedsAuth(Client<NeedsAuth>),
+    Ready(Client<Ready>),
+}
+
+impl Client<NeedsHello> {
+    /// Client offering no authentication.
+    pub fn new() -> Self {
+        Self {
+            state: NeedsHello::default(),
+        }
+    }
+
+    /// Offers username/password authentication instead.
+    pub fn with_username_password(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.state.credentials = Some(UsernamePassword {
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    /// First message to send.
+    pub fn hello(&self) -> Hello {
+        let method = match self.state.credentials {
+            Some(_) => AuthenticationMethod::UsernamePassword,
+            None => AuthenticationMethod::None,
+        };
+        Hello {
+            methods: vec![method],
+        }
+    }
+
+    /// Fails with `Unsupported` if the server picked a method that was not offered.
+    pub fn on_hello_response(self, response: &HelloResponse) -> io::Result<Negotiated> {
+        let offered = self.hello().methods;
+        match (response.method, self.state.credentials) {
+            (AuthenticationMethod::None, None) => Ok(Negotiated::Ready(Client {
+                state: Ready {
+                    method: AuthenticationMethod::None,
+                },
+            })),
+            (AuthenticationMethod::UsernamePassword, Some(credentials)) => {
+                Ok(Negotiated::NeedsAuth(Client {
+                    state: NeedsAuth { credentials },
+                }))
+            }
+            (selected, _) => Err(ClientError::MethodNegotiationFailed { offered, selected }.into()),
+        }
+    }
+}
+
+impl Default for Client<NeedsHello> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client<NeedsAuth> {
+    /// Message to send.
+    pub fn credentials(&self) -> &UsernamePassword {
+        &self.state.credentials
+    }
+
+    /// Fails with `PermissionDenied` if the server rejected the credentials.
+    pub fn on_auth_response(
+        self,
+        response: &UsernamePasswordResponse,
+    ) -> io::Result<Client<Ready>> {
+        if !response.success {
+            return Err(ClientError::AuthenticationRejected.into());
+        }
+        Ok(Client {
+            state: Ready {
+                method: AuthenticationMethod::UsernamePassword,
+            },
+        })
+    }
+}
+
+impl Client<Ready> {
+    /// Method the client authenticated with.
+    pub fn method(&self) -> AuthenticationMethod {
+        self.state.method
+    }
+
+    /// Request to send, ending the handshake once replied to.
+    pub fn request(self, command: Command, destination: Destination) -> (Request, PendingRequest) {
+        let request = Request {
+            command,
+            rsv: 0,
+            addr: destination.addr,
+            port: destination.port,
+        };
+        (request, PendingRequest { _private: () })
+    }
+}
+
+/// Request sent, waiting for the reply of the server.
+#[derive(Debug)]
+pub struct PendingRequest {
+    _private: (),
+}
+
+impl PendingRequest {
+    /// Address bound by the server, or the status of the failure formatted in the error.
+    pub fn on_response(self, response: Response) -> io::Result<Destination> {
+        if !response.status.is_success() {
+            return Err(ClientError::RequestDenied(response.status).into());
+        }
+        Ok(Destination {
+            addr: response.addr,
+            port: response.port,
+        })
+    }
+}
@@ -1,26 +1,870 @@
-use std::{future::Future, io};
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::{ConnectionRequest, Destination, Version, Wire};
+#[cfg(feature = "audit")]
+use crate::audit::{AuditEvent, AuditKind, AuditSink};
+#[cfg(feature = "tor")]
+use crate::resolve::ResolveHandler;
+use crate::{
+    acl::{self, Acl, Action},
+    auth::{Authenticator, BoxFuture, Identity, MethodSelector},
+    error::invalid_data,
+    framing::{self, read_message, write_message, write_reply, HandshakeBuffer},
+    handlers::RequestHandler,
+    policy::UserPolicies,
+    recorder::{Direction, Playback, Recorder, Transcript},
+    stats::{CloseReason, Relayed, ServerStats, SessionSummary},
+    stream::DynStream,
+    throttle::Throttled,
+    udp::{ClientSource, UdpRelayOptions},
+    ConnectionRequest, DecodeLimits, Destination, EncodeBuffer, MaybeSocks, ParseMode, Version,
+    Wire,
+};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::{JoinError, JoinSet},
 };
 
-fn map_nom_error(e: nom::Err<nom::error::VerboseError<&[u8]>>) -> io::Error {
-    io::Error::new(io::ErrorKind::InvalidData, format!("{e:x?}"))
+// Connection able to carry UDP datagrams next to the client stream.
+#[cfg(feature = "quic")]
+use quic::Datagrams;
+/// Connection able to carry UDP datagrams next to the client stream, none without QUIC.
+#[cfg(not(feature = "quic"))]
+type Datagrams = std::convert::Infallible;
+
+/// Logs with the `log` macro `$level`, prefixed with the connection served by the current task.
+macro_rules! connection_log {
+    ($level:ident, $($arg:tt)+) => {
+        match crate::ConnectionId::current() {
+            Some(id) => log::$level!("[{id}] {}", format_args!($($arg)+)),
+            None => log::$level!($($arg)+),
+        }
+    };
+}
+
+/// Records `$value` as `$field` on the current connection span.
+macro_rules! record_span {
+    ($field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, tracing::field::display(&$value));
+    };
+}
+
+mod association;
+mod bind;
+mod connection;
+mod handshake;
+mod incoming;
+mod inspect;
+mod layer;
+mod listener;
+#[cfg(feature = "mux")]
+mod mux;
+#[cfg(feature = "quic")]
+mod quic;
+mod rejection;
+#[cfg(feature = "config")]
+mod reload;
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+mod transparent;
+
+use association::{Association, ClientTransport};
+pub use bind::BindOptions;
+use bind::PendingBind;
+pub use connection::ConnectionId;
+pub use handshake::{HandshakeRequest, ReplyWriter, ServerHandshake};
+pub use incoming::Incoming;
+pub use inspect::InspectedRequest;
+pub use layer::{Decision, Layer};
+#[cfg(windows)]
+pub use listener::NamedPipeListener;
+use listener::{ErasedListener, TcpOptions, TunedListener};
+pub use listener::{Listener, ListenerOverrides};
+pub use rejection::{FailureReplyAddress, Rejection};
+#[cfg(feature = "config")]
+pub use reload::ConfigHandle;
+#[cfg(feature = "config")]
+pub(crate) use reload::Settings;
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+use transparent::TransparentListener;
+
+/// Reports `$kind` about the client at `$peer` to the audit sink, if any.
+macro_rules! audit {
+    ($shared:expr, $peer:expr, $kind:expr) => {
+        #[cfg(feature = "audit")]
+        $shared.audit($peer, $kind).await;
+    };
 }
 
 pub struct Server {
-    listener: TcpListener,
+    listener: Option<TcpListener>,
+    stats: Arc<ServerStats>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    method_selector: Option<Arc<dyn MethodSelector>>,
+    acl: Option<Arc<dyn Acl>>,
+    user_policies: Option<Arc<UserPolicies>>,
+    limits: DecodeLimits,
+    parse_mode: ParseMode,
+    max_connections: Option<usize>,
+    allow_link_local: bool,
+    allow_private: bool,
+    recorder: Option<Arc<OnHandshake>>,
+    inspector: Option<Arc<OnRequest>>,
+    on_session_end: Option<Arc<OnSessionEnd>>,
+    versions: Vec<Version>,
+    handshake_timeout: Option<Duration>,
+    layers: Vec<Arc<dyn Layer>>,
+    failure_reply_address: FailureReplyAddress,
+    listeners: Vec<(Box<dyn ErasedListener>, ListenerOverrides)>,
+    tcp_options: TcpOptions,
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    transparent_listeners: Vec<(TcpListener, ListenerOverrides)>,
+    #[cfg(feature = "tor")]
+    resolver: Option<Arc<dyn ResolveHandler>>,
+    #[cfg(feature = "audit")]
+    audit: Option<Arc<dyn AuditSink>>,
+    udp_relay: UdpRelayOptions,
+    bind: Option<BindOptions>,
+    #[cfg(feature = "config")]
+    config: ConfigHandle,
+}
+
+/// Callback receiving the transcript of every handshake.
+type OnHandshake = dyn Fn(SocketAddr, Transcript) + Send + Sync;
+
+/// Callback receiving every request as decoded.
+type OnRequest = dyn Fn(SocketAddr, InspectedRequest<'_>) + Send + Sync;
+
+/// Callback receiving the summary of every relayed tunnel.
+type OnSessionEnd = dyn Fn(SocketAddr, SessionSummary) + Send + Sync;
+
+/// What is left to do with a client once its handshake is over.
+enum Handshaken<S> {
+    /// Relay the stream opened by the request handler.
+    Relay(S),
+    Associate(Association),
+    /// Relay the peer connecting for a BIND request, once it does.
+    Bind(PendingBind),
+    /// The request was answered, as RESOLVE ones are.
+    #[cfg(feature = "tor")]
+    Done,
+}
+
+/// How the session of a client starts.
+enum Opening {
+    /// With a SOCKS handshake.
+    Handshake,
+    /// Redirected to a transparent listener, the client sends no handshake.
+    Redirected(Destination),
+    /// On a stream of a multiplexed connection, with a lone SOCKS5 request on behalf of the
+    /// client authenticated for the whole connection.
+    #[cfg(feature = "mux")]
+    Multiplexed(Option<Identity>),
+}
+
+/// Client connection being served.
+#[derive(Debug, Clone, Copy)]
+struct Session {
+    peer: SocketAddr,
+    /// End of the handshake, if limited.
+    deadline: Option<Instant>,
+}
+
+/// State shared by every connection handled by a running server.
+struct Shared {
+    stats: Arc<ServerStats>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    method_selector: Option<Arc<dyn MethodSelector>>,
+    acl: Option<Arc<dyn Acl>>,
+    user_policies: Option<Arc<UserPolicies>>,
+    limits: DecodeLimits,
+    parse_mode: ParseMode,
+    allow_link_local: bool,
+    allow_private: bool,
+    recorder: Option<Arc<OnHandshake>>,
+    inspector: Option<Arc<OnRequest>>,
+    on_session_end: Option<Arc<OnSessionEnd>>,
+    versions: Vec<Version>,
+    handshake_timeout: Option<Duration>,
+    layers: Vec<Arc<dyn Layer>>,
+    failure_reply_address: FailureReplyAddress,
+    #[cfg(feature = "tor")]
+    resolver: Option<Arc<dyn ResolveHandler>>,
+    #[cfg(feature = "audit")]
+    audit: Option<Arc<dyn AuditSink>>,
+    udp_relay: UdpRelayOptions,
+    bind: Option<BindOptions>,
+}
+
+impl Shared {
+    fn inspect(&self, peer: SocketAddr, request: InspectedRequest<'_>) {
+        if let Some(ref on_request) = self.inspector {
+            on_request(peer, request);
+        }
+    }
+
+    /// Whether clients may speak `version`. SOCKS4 clients cannot authenticate, they are refused
+    /// once an authenticator is set.
+    fn accepts(&self, version: Version) -> bool {
+        self.versions.contains(&version)
+            && !(version == Version::Socks4 && self.authenticator.is_some())
+    }
+
+    /// Fails with `PermissionDenied` if clients may not reach `destination`.
+    fn admit(&self, destination: &Destination) -> io::Result<()> {
+        if !self.allow_link_local && destination.addr.is_link_local() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Link-local destination {destination}"),
+            ));
+        }
+        if !self.allow_private && acl::is_private(destination) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Private destination {destination}"),
+            ));
+        }
+        if let Some(ref acl) = self.acl {
+            if acl.check(destination) == Action::Deny {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Destination {destination} denied by ACL"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reply to a SOCKS5 request served on a socket bound to `bound`, or failing to.
+    fn bound_response(
+        &self,
+        bound: Result<SocketAddr, &io::Error>,
+        requested: Destination,
+    ) -> crate::v5::Response {
+        match bound {
+            Ok(addr) => crate::v5::Response {
+                status: crate::v5::Status::Success,
+                addr: crate::v5::AddressType::from(addr.ip()),
+                port: addr.port(),
+            },
+            Err(e) => {
+                let reply_with = self.failure_reply_address.reply_with(requested);
+                crate::v5::Response {
+                    status: rejection::status_for(e),
+                    addr: reply_with.addr,
+                    port: reply_with.port,
+                }
+            }
+        }
+    }
+
+    /// Authentication method replied to `hello`, `NotAcceptable` if none is supported.
+    async fn select_method(
+        &self,
+        peer: SocketAddr,
+        hello: &crate::v5::Hello,
+    ) -> io::Result<crate::v5::AuthenticationMethod> {
+        use crate::v5::AuthenticationMethod;
+
+        let method = match self.method_selector {
+            Some(ref selector) => selector.select(peer, hello).await?,
+            None if self.authenticator.is_some() => AuthenticationMethod::UsernamePassword,
+            None => AuthenticationMethod::None,
+        };
+        let supported = match method {
+            AuthenticationMethod::None => true,
+            AuthenticationMethod::UsernamePassword => self.authenticator.is_some(),
+            _ => false,
+        };
+        Ok(if supported && hello.offers(method) {
+            method
+        } else {
+            AuthenticationMethod::NotAcceptable
+        })
+    }
+
+    /// Fails in strict mode if the client sent data after its final request, which is left in
+    /// `buffer`, without waiting for the reply.
+    fn check_early_data(&self, buffer: &[u8]) -> io::Result<()> {
+        if self.parse_mode == ParseMode::Strict && !buffer.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes sent before the reply", buffer.len()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Turns away a client speaking a disabled `version` with a reply it understands: SOCKS4
+    /// requests are rejected, SOCKS5 clients are told no authentication method is acceptable.
+    #[cfg_attr(not(feature = "audit"), allow(unused_variables))]
+    async fn reject_version<C: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        peer: SocketAddr,
+        version: Version,
+    ) -> io::Error {
+        let written = match version {
+            Version::Socks4 => {
+                let response = crate::v4::Response {
+                    status: crate::v4::Status::Rejected,
+                    addr: Ipv4Addr::UNSPECIFIED,
+                    port: 0,
+                };
+                write_message(stream, encoder, &response).await
+            }
+            Version::Socks5 => {
+                let response = crate::v5::HelloResponse {
+                    method: crate::v5::AuthenticationMethod::NotAcceptable,
+                };
+                write_message(stream, encoder, &response).await
+            }
+        };
+        if let Err(e) = written {
+            return e;
+        }
+        audit!(self, peer, AuditKind::VersionRejected { version });
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Client requested disabled {version:?}"),
+        )
+    }
+
+    /// Deadline of a handshake starting now, according to the configured timeout.
+    fn handshake_deadline(&self) -> Option<Instant> {
+        self.handshake_timeout
+            .map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Fails with `TimedOut` if `handshake` is still running at `deadline`.
+    async fn timed<T>(
+        deadline: Option<Instant>,
+        handshake: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), handshake)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))?,
+            None => handshake.await,
+        }
+    }
+
+    /// Same as [`Shared::admit`], then checks the policy of the user, auditing the decision.
+    #[cfg_attr(not(feature = "audit"), allow(unused_variables))]
+    async fn authorize(
+        &self,
+        peer: SocketAddr,
+        destination: &Destination,
+        identity: Option<&Identity>,
+    ) -> io::Result<()> {
+        let admitted = self
+            .admit(destination)
+            .and_then(|()| match self.user_policies {
+                Some(ref policies) => policies.admit(identity, destination),
+                None => Ok(()),
+            });
+        match admitted {
+            Ok(()) => {
+                audit!(
+                    self,
+                    peer,
+                    AuditKind::RequestAllowed {
+                        destination: destination.clone(),
+                        username: identity.map(|i| i.username.clone()),
+                    }
+                );
+                Ok(())
+            }
+            Err(e) => {
+                audit!(
+                    self,
+                    peer,
+                    AuditKind::RequestDenied {
+                        destination: destination.clone(),
+                        reason: e.to_string(),
+                    }
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs the layers over `request`, auditing a denial.
+    #[cfg_attr(not(feature = "audit"), allow(unused_variables))]
+    async fn apply_layers(
+        &self,
+        peer: SocketAddr,
+        mut request: ConnectionRequest,
+    ) -> io::Result<ConnectionRequest> {
+        for layer in &self.layers {
+            #[cfg(feature = "audit")]
+            let destination = request.destination.clone();
+            request = match layer.apply(request) {
+                Decision::Continue(request) => request,
+                Decision::Deny(rejection) => {
+                    audit!(
+                        self,
+                        peer,
+                        AuditKind::RequestDenied {
+                            destination,
+                            reason: rejection.to_string(),
+                        }
+                    );
+                    return Err(rejection.into());
+                }
+            };
+        }
+        Ok(request)
+    }
+
+    /// Runs the request handler, unless the destination is denied.
+    async fn handle_request<HC, S, FC>(
+        &self,
+        session: Session,
+        request: ConnectionRequest,
+        handle_request: HC,
+    ) -> io::Result<(S, Destination)>
+    where
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+    {
+        let mut request = self.apply_layers(session.peer, request).await?;
+        self.authorize(
+            session.peer,
+            &request.destination,
+            request.identity.as_ref(),
+        )
+        .await?;
+        request.peer = Some(session.peer);
+        request.deadline = session.deadline;
+        request.allow_private = self.allow_private;
+        request.acl = self.acl.clone();
+        handle_request(request).await
+    }
+
+    #[cfg(feature = "audit")]
+    async fn audit(&self, peer: SocketAddr, kind: AuditKind) {
+        let Some(ref sink) = self.audit else {
+            return;
+        };
+        let event = AuditEvent {
+            time: std::time::SystemTime::now(),
+            peer,
+            kind,
+        };
+        if let Err(e) = sink.record(event).await {
+            connection_log!(warn, "Could not record audit event: {e}");
+        }
+    }
 }
 
 impl Server {
     pub fn new(listener: TcpListener) -> Self {
-        Self { listener }
+        Self {
+            listener: Some(listener),
+            ..Self::unbound()
+        }
     }
 
-    pub async fn run<HC, HS, S, FC, FS>(
-        self,
+    /// Server with no TCP listener, serving clients handed over by other means such as
+    /// [`serve`](Self::serve).
+    pub fn unbound() -> Self {
+        Self {
+            listener: None,
+            stats: Arc::default(),
+            authenticator: None,
+            method_selector: None,
+            acl: None,
+            user_policies: None,
+            limits: DecodeLimits::default(),
+            parse_mode: ParseMode::default(),
+            max_connections: None,
+            allow_link_local: true,
+            allow_private: true,
+            recorder: None,
+            inspector: None,
+            on_session_end: None,
+            versions: vec![Version::Socks4, Version::Socks5],
+            handshake_timeout: None,
+            layers: Vec::new(),
+            failure_reply_address: FailureReplyAddress::default(),
+            listeners: Vec::new(),
+            tcp_options: TcpOptions::default(),
+            #[cfg(all(feature = "transparent", target_os = "linux"))]
+            transparent_listeners: Vec::new(),
+            #[cfg(feature = "tor")]
+            resolver: None,
+            #[cfg(feature = "audit")]
+            audit: None,
+            udp_relay: UdpRelayOptions::default(),
+            bind: None,
+            #[cfg(feature = "config")]
+            config: ConfigHandle::new(),
+        }
+    }
+
+    /// Hands the transcript of every handshake, successful or not, to `on_handshake` along
+    /// with the address of the client.
+    ///
+    /// Passwords sent by clients are masked, see [`Transcript::mask_credentials`].
+    pub fn with_recorder(
+        mut self,
+        on_handshake: impl Fn(SocketAddr, Transcript) + Send + Sync + 'static,
+    ) -> Self {
+        self.recorder = Some(Arc::new(on_handshake));
+        self
+    }
+
+    /// Hands every request to `on_request` as the client sent it, along with its address,
+    /// before it is checked and turned into a [`ConnectionRequest`].
+    pub fn with_inspector(
+        mut self,
+        on_request: impl Fn(SocketAddr, InspectedRequest<'_>) + Send + Sync + 'static,
+    ) -> Self {
+        self.inspector = Some(Arc::new(on_request));
+        self
+    }
+
+    /// Hands a summary of every tunnel to `on_session_end` along with the address of the
+    /// client, once the stream handler returned, for per-tunnel usage accounting.
+    ///
+    /// Byte counters are split between directions if the stream handler returns them, as
+    /// [`handlers::relay`](crate::handlers::relay) does.
+    pub fn with_session_summaries(
+        mut self,
+        on_session_end: impl Fn(SocketAddr, SessionSummary) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_session_end = Some(Arc::new(on_session_end));
+        self
+    }
+
+    /// Whether clients may reach link-local destinations (`169.254.0.0/16`, `fe80::/10`).
+    ///
+    /// Allowed by default.
+    pub fn allow_link_local(mut self, allow: bool) -> Self {
+        self.allow_link_local = allow;
+        self
+    }
+
+    /// Whether clients may reach the loopback, private and link-local destinations denied by
+    /// [`acl::block_private_ranges`], such as cloud metadata services.
+    ///
+    /// Allowed by default. Otherwise, domain names are checked again once resolved, so that a
+    /// name pointing, or rebound, to a private address is denied as well: by the UDP relay, and
+    /// by request handlers honouring [`ConnectionRequest::allow_private`], such as
+    /// [`handlers::connect_with`](crate::handlers::connect_with). Handlers forwarding names to
+    /// another proxy leave that check to it.
+    pub fn allow_private_destinations(mut self, allow: bool) -> Self {
+        self.allow_private = allow;
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on the clients of the TCP listener, sparing interactive traffic the
+    /// delays of Nagle's algorithm.
+    pub fn with_tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_options.nodelay = nodelay;
+        self
+    }
+
+    /// Enables TCP keepalive on the clients of the TCP listener, probing after `time` idle, to
+    /// notice peers which vanished during long-lived tunnels.
+    pub fn with_tcp_keepalive(mut self, time: Duration) -> Self {
+        self.tcp_options.keepalive = Some(time);
+        self
+    }
+
+    /// Stops accepting new clients while `max` connections are being handled.
+    ///
+    /// Pending clients wait in the listen backlog until a connection finishes.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+
+    /// Rejects requests to destinations denied by `acl` before they reach the request handler.
+    pub fn with_acl(mut self, acl: impl Acl + 'static) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Applies the ACL and connection limit of the policy of authenticated users.
+    ///
+    /// A tunnel counts against the limit of its user until the stream handler returns. Bandwidth
+    /// is limited by request handlers wrapped with [`throttle_users`](Self::throttle_users).
+    pub fn with_user_policies(mut self, policies: Arc<UserPolicies>) -> Self {
+        self.user_policies = Some(policies);
+        self
+    }
+
+    /// Policies set with [`with_user_policies`](Self::with_user_policies) or loaded from the
+    /// configuration, to throttle the streams of request handlers with.
+    pub fn user_policies(&self) -> Option<Arc<UserPolicies>> {
+        self.shared().user_policies
+    }
+
+    /// Request handler wrapping `handle_request`, whose streams are limited to the bandwidth of
+    /// the user who asked for them according to the policies of the server.
+    ///
+    /// Unlike [`handlers::throttle_users`](crate::handlers::throttle_users), policies replaced
+    /// through the [configuration handle](Self::config_handle) apply to the following requests.
+    pub fn throttle_users<HC, FC, S>(
+        &self,
+        handle_request: HC,
+    ) -> impl FnOnce(ConnectionRequest) -> BoxFuture<'static, io::Result<(Throttled<S>, Destination)>>
+           + Send
+           + Clone
+           + 'static
+    where
+        HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+        FC: Future<Output = io::Result<(S, Destination)>> + Send + 'static,
+        S: Send + 'static,
+    {
+        let policies = self.user_policies.clone();
+        #[cfg(feature = "config")]
+        let config = self.config.clone();
+        move |req| {
+            #[cfg(feature = "config")]
+            let policies = match config.current() {
+                Some(settings) => settings.user_policies.clone(),
+                None => policies,
+            };
+            Box::pin(async move {
+                let identity = req.identity.clone();
+                let (stream, bound) = handle_request(req).await?;
+                let stream = match policies {
+                    Some(policies) => policies.throttle(identity.as_ref(), stream),
+                    None => Throttled::new(stream, None),
+                };
+                Ok((stream, bound))
+            })
+        }
+    }
+
+    /// Bounds applied when decoding client messages.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Parses SOCKS messages in `mode`, lenient by default.
+    ///
+    /// In strict mode, clients sending data after their final request, before getting its reply,
+    /// are rejected instead of having it forwarded.
+    pub fn with_parse_mode(mut self, mode: ParseMode) -> Self {
+        self.parse_mode = mode;
+        self
+    }
+
+    /// Requires SOCKS5 clients to authenticate with username/password against `authenticator`,
+    /// and HTTP CONNECT clients with a `Proxy-Authorization: Basic` header, others being answered
+    /// with `407 Proxy Authentication Required`.
+    ///
+    /// SOCKS4 clients, which cannot authenticate, are then refused.
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
+    /// Lets `selector` pick the authentication method of SOCKS5 clients, instead of requiring
+    /// username/password exactly when an authenticator is set.
+    pub fn with_method_selector(mut self, selector: impl MethodSelector + 'static) -> Self {
+        self.method_selector = Some(Arc::new(selector));
+        self
+    }
+
+    /// Configuration handed to every connection, as last updated through the
+    /// [configuration handle](Self::config_handle).
+    fn shared(&self) -> Shared {
+        #[cfg_attr(not(feature = "config"), allow(unused_mut))]
+        let mut shared = Shared {
+            stats: Arc::clone(&self.stats),
+            authenticator: self.authenticator.clone(),
+            method_selector: self.method_selector.clone(),
+            acl: self.acl.clone(),
+            user_policies: self.user_policies.clone(),
+            limits: self.limits,
+            parse_mode: self.parse_mode,
+            allow_link_local: self.allow_link_local,
+            allow_private: self.allow_private,
+            recorder: self.recorder.clone(),
+            inspector: self.inspector.clone(),
+            on_session_end: self.on_session_end.clone(),
+            versions: self.versions.clone(),
+            handshake_timeout: self.handshake_timeout,
+            layers: self.layers.clone(),
+            failure_reply_address: self.failure_reply_address,
+            #[cfg(feature = "tor")]
+            resolver: self.resolver.clone(),
+            #[cfg(feature = "audit")]
+            audit: self.audit.clone(),
+            udp_relay: self.udp_relay,
+            bind: self.bind.clone(),
+        };
+        #[cfg(feature = "config")]
+        if let Some(settings) = self.config.current() {
+            settings.apply(&mut shared);
+        }
+        shared
+    }
+
+    /// Handle updating the configuration of this server while it runs, for the connections it
+    /// accepts next. See [`ConfigHandle::update_config`].
+    #[cfg(feature = "config")]
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.config.clone()
+    }
+
+    /// SOCKS versions clients may use, both by default. Clients speaking another one get a
+    /// failure reply, SOCKS4 requests being rejected and SOCKS5 clients offered no acceptable
+    /// authentication method, then their connection closed.
+    pub fn with_versions(mut self, versions: impl IntoIterator<Item = Version>) -> Self {
+        self.versions = versions.into_iter().collect();
+        self
+    }
+
+    /// Closes connections whose handshake, including the request handler, takes longer than
+    /// `timeout`. Request handlers are told when through [`ConnectionRequest::deadline`].
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    /// Hands requests to `layer` before the request handler, after the layers already added.
+    ///
+    /// The server policy (ACL, link-local restriction) applies to the requests as rewritten by
+    /// the layers.
+    pub fn with_layer(mut self, layer: impl Layer + 'static) -> Self {
+        self.layers.push(Arc::new(layer));
+        self
+    }
+
+    /// Answers the Tor RESOLVE and RESOLVE_PTR commands with `resolver`, instead of replying
+    /// they are not supported.
+    #[cfg(feature = "tor")]
+    pub fn with_resolver(mut self, resolver: impl ResolveHandler + 'static) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// How UDP ASSOCIATE requests are served, rejected by default for clients connected over
+    /// TCP. Associations end once the client closes the connection it requested them on.
+    pub fn with_udp_relay_options(mut self, options: UdpRelayOptions) -> Self {
+        self.udp_relay = options;
+        self
+    }
+
+    /// Serves SOCKS5 BIND requests as set in `options`, rejected as not supported by default.
+    ///
+    /// The first reply gives the address listened on, the second one the peer which connected
+    /// to it, other peers being turned away. The tunnel is relayed by the server, the request
+    /// and stream handlers are left out.
+    pub fn with_bind_options(mut self, options: BindOptions) -> Self {
+        self.bind = Some(options);
+        self
+    }
+
+    /// Reports authentications, requests and closed connections to `sink`.
+    #[cfg(feature = "audit")]
+    pub fn with_audit_sink(mut self, sink: impl AuditSink + 'static) -> Self {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Waits for the next client of the TCP listener, for applications running their own accept
+    /// loop instead of [`run`](Self::run), to stop it on shutdown or to spawn connections their
+    /// own way.
+    ///
+    /// The [connection limit](Self::with_max_connections) is left to the caller.
+    pub async fn accept(&self) -> io::Result<Incoming> {
+        let listener = self.listener.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "Server has no TCP listener")
+        })?;
+        let (stream, peer) = listener.accept().await?;
+        self.tcp_options.apply(&stream, peer);
+        let id = ConnectionId::next();
+        log::info!("New connection {id} from {peer}");
+        Ok(Incoming {
+            id,
+            stream,
+            peer,
+            shared: Arc::new(self.shared()),
+        })
+    }
+
+    /// Address reported to clients whose request failed, `0.0.0.0:0` by default.
+    pub fn with_failure_reply_address(mut self, policy: FailureReplyAddress) -> Self {
+        self.failure_reply_address = policy;
+        self
+    }
+
+    /// Handshake-only side of this server, for applications replying to requests themselves.
+    pub fn handshake_only(&self) -> ServerHandshake {
+        ServerHandshake {
+            shared: Arc::new(self.shared()),
+        }
+    }
+
+    /// Also accepts clients from `listener` when served with [`run_all`](Self::run_all), with
+    /// the settings of the server changed by `overrides`.
+    pub fn with_listener(
+        mut self,
+        listener: impl Listener + Sync + 'static,
+        overrides: ListenerOverrides,
+    ) -> Self {
+        self.listeners.push((Box::new(listener), overrides));
+        self
+    }
+
+    /// Also accepts clients redirected to `listener` by the firewall when served with
+    /// [`run_all`](Self::run_all), with the settings of the server changed by `overrides`.
+    ///
+    /// Such clients send no SOCKS handshake: the request handler is given a request for the
+    /// destination they connected to, read from the `SO_ORIGINAL_DST` socket option for
+    /// iptables `REDIRECT` rules, or from their local address for `TPROXY` ones, which need
+    /// `listener` to have the `IP_TRANSPARENT` option set. Clients connecting to `listener`
+    /// directly are dropped.
+    #[cfg(all(feature = "transparent", target_os = "linux"))]
+    pub fn with_transparent_listener(
+        mut self,
+        listener: TcpListener,
+        overrides: ListenerOverrides,
+    ) -> Self {
+        self.transparent_listeners.push((listener, overrides));
+        self
+    }
+
+    /// Address of the TCP listener, such as the port picked when binding to port `0`.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.listener {
+            Some(ref listener) => listener.local_addr(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Server has no TCP listener",
+            )),
+        }
+    }
+
+    /// Counters updated by every connection accepted by this server.
+    pub fn stats(&self) -> Arc<ServerStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Fails right away for servers created without a TCP listener, such as
+    /// [`testing::server`](crate::testing::server), or with other listeners, which
+    /// [`run_all`](Self::run_all) serves.
+    ///
+    /// `handle_request` is only given CONNECT requests, others being replied to as not
+    /// supported. See [`handshake_only`](Self::handshake_only) to serve BIND.
+    pub async fn run<HC, HS, S, FC, FS, R>(
+        mut self,
         handle_request: HC,
         handle_stream: HS,
     ) -> io::Result<()>
@@ -28,154 +872,1120 @@ impl Server {
         HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
         HS: FnOnce(TcpStream, S) -> FS + Send + Clone + 'static,
         FC: Future<Output = io::Result<(S, Destination)>> + Send,
-        FS: Future<Output = io::Result<()>> + Send,
+        FS: Future<Output = io::Result<R>> + Send,
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: Relayed,
+    {
+        if !self.listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Server has several listeners, serve them with run_all",
+            ));
+        }
+        let listener = self.listener.take().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "Server has no TCP listener")
+        })?;
+        let listener = TunedListener {
+            listener,
+            options: self.tcp_options,
+        };
+        self.serve(listener, handle_request, handle_stream).await
+    }
+
+    /// Same as [`run`](Self::run), handing requests to `handler`, shared by every connection.
+    pub async fn run_handler<H, HS, FS, R>(self, handler: H, handle_stream: HS) -> io::Result<()>
+    where
+        H: RequestHandler + 'static,
+        HS: FnOnce(TcpStream, H::Stream) -> FS + Send + Clone + 'static,
+        FS: Future<Output = io::Result<R>> + Send,
+        R: Relayed,
+    {
+        let handler = Arc::new(handler);
+        let handle_request = move |req| async move { handler.handle(req).await };
+        self.run(handle_request, handle_stream).await
+    }
+
+    /// Accepts clients from the TCP listener, if any, and from those added with
+    /// [`with_listener`](Self::with_listener) at once, until one of them fails.
+    ///
+    /// All listeners share the request and stream handlers, the statistics and the
+    /// [connection limit](Self::with_max_connections).
+    pub async fn run_all<HC, HS, S, FC, FS, R>(
+        mut self,
+        handle_request: HC,
+        handle_stream: HS,
+    ) -> io::Result<()>
+    where
+        HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+        HS: FnOnce(DynStream, S) -> FS + Send + Clone + 'static,
+        FC: Future<Output = io::Result<(S, Destination)>> + Send,
+        FS: Future<Output = io::Result<R>> + Send,
         S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: Relayed,
     {
+        let mut listeners = std::mem::take(&mut self.listeners);
+        if let Some(listener) = self.listener.take() {
+            let listener = TunedListener {
+                listener,
+                options: self.tcp_options,
+            };
+            listeners.insert(0, (Box::new(listener), ListenerOverrides::default()));
+        }
+        #[cfg(all(feature = "transparent", target_os = "linux"))]
+        for (listener, overrides) in std::mem::take(&mut self.transparent_listeners) {
+            let listener = TransparentListener {
+                listener,
+                options: self.tcp_options,
+            };
+            listeners.push((Box::new(listener), overrides));
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Server has no listener",
+            ));
+        }
+
+        // Each listener is accepted from by its own task, handing over one client at a time so
+        // that the connection limit still holds clients back.
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(1);
+        let mut acceptors = JoinSet::new();
+        let mut overrides = Vec::with_capacity(listeners.len());
+        for (index, (mut listener, listener_overrides)) in listeners.into_iter().enumerate() {
+            overrides.push(listener_overrides);
+            let accepted_tx = accepted_tx.clone();
+            acceptors.spawn(async move {
+                loop {
+                    let accepted = listener.accept().await;
+                    let failed = accepted.is_err();
+                    let accepted = accepted.map(|accepted| (accepted, index));
+                    if accepted_tx.send(accepted).await.is_err() || failed {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(accepted_tx);
+        let listener_shared = || -> Vec<Arc<Shared>> {
+            overrides
+                .iter()
+                .map(|overrides| {
+                    let mut shared = self.shared();
+                    overrides.clone().apply(&mut shared);
+                    Arc::new(shared)
+                })
+                .collect()
+        };
+        #[cfg_attr(not(feature = "config"), allow(unused_mut))]
+        let mut shared = listener_shared();
+        #[cfg(feature = "config")]
+        let mut updates = self.config.subscribe();
+
+        let permits = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let mut tasks = JoinSet::new();
         loop {
-            let (stream, addr) = self.listener.accept().await?;
-            log::info!("New connection from {addr}");
+            let permit = acquire(permits.as_ref()).await;
+            let accepted = tokio::select! {
+                accepted = accepted_rx.recv() => accepted.unwrap_or_else(|| {
+                    Err(io::Error::other("Every listener stopped"))
+                }),
+                Some(joined) = tasks.join_next(), if !tasks.is_empty() => {
+                    reap(joined, &self.stats);
+                    continue;
+                }
+            };
+            let ((stream, addr, redirected), index) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Connections in flight outlive the accept loop.
+                    tasks.detach_all();
+                    return Err(e);
+                }
+            };
+            #[cfg(feature = "config")]
+            if updates.has_changed().unwrap_or(false) {
+                updates.mark_unchanged();
+                shared = listener_shared();
+            }
+            let id = ConnectionId::next();
+            log::info!("New connection {id} from {addr}");
             let hc = handle_request.clone();
             let hs = handle_stream.clone();
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_client(stream, hc, hs).await {
-                    log::error!("Issue with client {addr}: {e}");
+            let shared = Arc::clone(&shared[index]);
+            tasks.spawn(client_task(id, addr, async move {
+                let _permit = permit;
+                let opening = redirected.map_or(Opening::Handshake, Opening::Redirected);
+                Self::handle_client(stream, addr, hc, hs, &shared, None, opening).await
+            }));
+        }
+    }
+
+    /// Accepts clients from `listener` until it fails, like [`run`](Self::run) does from the
+    /// TCP listener, which is left unused.
+    pub async fn serve<L, HC, HS, S, FC, FS, R>(
+        self,
+        mut listener: L,
+        handle_request: HC,
+        handle_stream: HS,
+    ) -> io::Result<()>
+    where
+        L: Listener,
+        HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+        HS: FnOnce(L::Stream, S) -> FS + Send + Clone + 'static,
+        FC: Future<Output = io::Result<(S, Destination)>> + Send,
+        FS: Future<Output = io::Result<R>> + Send,
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: Relayed,
+    {
+        let permits = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        #[cfg_attr(not(feature = "config"), allow(unused_mut))]
+        let mut shared = Arc::new(self.shared());
+        #[cfg(feature = "config")]
+        let mut updates = self.config.subscribe();
+        let mut tasks = JoinSet::new();
+        loop {
+            let permit = acquire(permits.as_ref()).await;
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(joined) = tasks.join_next(), if !tasks.is_empty() => {
+                    reap(joined, &shared.stats);
+                    continue;
                 }
-            });
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Connections in flight outlive the accept loop.
+                    tasks.detach_all();
+                    return Err(e);
+                }
+            };
+            #[cfg(feature = "config")]
+            if updates.has_changed().unwrap_or(false) {
+                updates.mark_unchanged();
+                shared = Arc::new(self.shared());
+            }
+            let id = ConnectionId::next();
+            log::info!("New connection {id} from {addr}");
+            let hc = handle_request.clone();
+            let hs = handle_stream.clone();
+            let shared = Arc::clone(&shared);
+            tasks.spawn(client_task(id, addr, async move {
+                let _permit = permit;
+                Self::handle_client(stream, addr, hc, hs, &shared, None, Opening::Handshake).await
+            }));
         }
     }
 
-    async fn handle_client<HC, HS, S, FC, FS>(
-        mut stream: TcpStream,
+    async fn handle_client<C, HC, HS, S, FC, FS, R>(
+        mut stream: C,
+        peer: SocketAddr,
         handle_request: HC,
         handle_stream: HS,
+        shared: &Shared,
+        datagrams: Option<&Datagrams>,
+        opening: Opening,
     ) -> io::Result<()>
     where
+        C: AsyncRead + AsyncWrite + Unpin,
         HC: FnOnce(ConnectionRequest) -> FC,
-        HS: FnOnce(TcpStream, S) -> FS,
+        HS: FnOnce(C, S) -> FS,
         FC: Future<Output = io::Result<(S, Destination)>>,
-        FS: Future<Output = io::Result<()>>,
+        FS: Future<Output = io::Result<R>>,
         S: AsyncRead + AsyncWrite + Unpin,
+        R: Relayed,
     {
-        let mut buffer = Vec::with_capacity(512);
+        let stats = &shared.stats;
+        let _active = stats.connection_opened();
+        let accepted = Instant::now();
 
-        let n = stream.read_buf(&mut buffer).await?;
+        let mut destination = None;
+        let mut lease = None;
+        let handle_request = |req: ConnectionRequest| {
+            destination = Some(req.destination.clone());
+            let opened = match shared.user_policies {
+                Some(ref policies) => policies
+                    .open(req.identity.as_ref())
+                    .map(|opened| lease = opened),
+                None => Ok(()),
+            };
+            async move {
+                opened?;
+                handle_request(req).await
+            }
+        };
+        let handshaken = match opening {
+            Opening::Handshake => {
+                Self::negotiate(&mut stream, peer, handle_request, shared, datagrams).await?
+            }
+            Opening::Redirected(original) => {
+                Self::redirect(peer, original, handle_request, shared).await?
+            }
+            #[cfg(feature = "mux")]
+            Opening::Multiplexed(identity) => {
+                Self::negotiate_multiplexed(&mut stream, peer, identity, handle_request, shared)
+                    .await?
+            }
+        };
+        let (remote_stream, destination) = match (handshaken, destination) {
+            (Handshaken::Relay(remote_stream), Some(destination)) => (remote_stream, destination),
+            // Over once the client closes the stream.
+            (Handshaken::Associate(association), _) => {
+                return association.run(&mut stream, shared).await;
+            }
+            (Handshaken::Bind(pending), _) => return pending.run(&mut stream, shared).await,
+            // RESOLVE answered.
+            _ => return Ok(()),
+        };
 
-        let (_, version) = Version::decode(&buffer[..n]).map_err(map_nom_error)?;
+        let ((bytes_up, bytes_down), close_reason, result) =
+            match handle_stream(stream, remote_stream).await {
+                Ok(relayed) => {
+                    stats.record_bytes_relayed(relayed.bytes_relayed());
+                    (relayed.bytes_each_way(), CloseReason::Finished, Ok(()))
+                }
+                Err(e) => ((0, 0), CloseReason::Failed(e.kind()), Err(e)),
+            };
+        drop(lease);
+        let summary = SessionSummary {
+            destination,
+            duration: accepted.elapsed(),
+            bytes_up,
+            bytes_down,
+            close_reason,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(bytes_up, bytes_down, %close_reason, "connection closed");
+        if let Some(ref on_session_end) = shared.on_session_end {
+            on_session_end(peer, summary.clone());
+        }
+        audit!(shared, peer, AuditKind::ConnectionClosed { summary });
+        result
+    }
 
-        let remote_stream = match version {
-            Version::Socks4 => Self::handle_client_v4(&mut stream, buffer, handle_request).await?,
-            Version::Socks5 => Self::handle_client_v5(&mut stream, buffer, handle_request).await?,
+    /// Requests the original destination of a client redirected to a transparent listener,
+    /// within the handshake timeout.
+    async fn redirect<HC, S, FC>(
+        peer: SocketAddr,
+        original: Destination,
+        handle_request: HC,
+        shared: &Shared,
+    ) -> io::Result<Handshaken<S>>
+    where
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+    {
+        let session = Session {
+            peer,
+            deadline: shared.handshake_deadline(),
         };
+        let request = ConnectionRequest::from(original);
+        Shared::timed(
+            session.deadline,
+            shared.handle_request(session, request, handle_request),
+        )
+        .await
+        .map(|(remote_stream, _)| Handshaken::Relay(remote_stream))
+    }
 
-        handle_stream(stream, remote_stream).await
+    /// Runs the handshake with a client within the handshake timeout, recording it if asked to.
+    async fn negotiate<C, HC, S, FC>(
+        stream: &mut C,
+        peer: SocketAddr,
+        handle_request: HC,
+        shared: &Shared,
+        datagrams: Option<&Datagrams>,
+    ) -> io::Result<Handshaken<S>>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let session = Session {
+            peer,
+            deadline: shared.handshake_deadline(),
+        };
+        match shared.recorder {
+            Some(ref on_handshake) => {
+                let mut recorder = Recorder::new(stream);
+                let result = Shared::timed(
+                    session.deadline,
+                    Self::handshake(&mut recorder, session, handle_request, shared, datagrams),
+                )
+                .await;
+                let (_, mut transcript) = recorder.into_parts();
+                transcript.mask_credentials(Direction::Received);
+                on_handshake(peer, transcript);
+                result
+            }
+            None => {
+                Shared::timed(
+                    session.deadline,
+                    Self::handshake(stream, session, handle_request, shared, datagrams),
+                )
+                .await
+            }
+        }
     }
 
-    async fn handle_client_v4<HC, S, FC>(
-        stream: &mut TcpStream,
-        mut buffer: Vec<u8>,
+    /// Runs the handshake with a client, up to what the request it sent calls for.
+    async fn handshake<C, HC, S, FC>(
+        stream: &mut C,
+        session: Session,
         handle_request: HC,
+        shared: &Shared,
+        datagrams: Option<&Datagrams>,
+    ) -> io::Result<Handshaken<S>>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buffer = HandshakeBuffer::new(&shared.limits);
+        let mut encoder = EncodeBuffer::with_capacity(framing::REPLY_BUFFER_LEN);
+
+        buffer.read_from(stream).await?;
+        #[cfg(feature = "http-connect")]
+        while MaybeSocks::detect(&buffer) == MaybeSocks::Incomplete {
+            if buffer.read_from(stream).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        match MaybeSocks::detect(&buffer) {
+            #[cfg(feature = "http-connect")]
+            MaybeSocks::HttpConnect => Self::handle_client_http(
+                stream,
+                &mut encoder,
+                session,
+                buffer.into_inner(),
+                handle_request,
+                shared,
+            )
+            .await
+            .map(Handshaken::Relay),
+            _ => {
+                let (_, version) = Version::decode(&buffer).map_err(invalid_data(&buffer))?;
+                record_span!("version", version as u8);
+                if !shared.accepts(version) {
+                    return Err(shared
+                        .reject_version(stream, &mut encoder, session.peer, version)
+                        .await);
+                }
+                let handshaken = match version {
+                    Version::Socks4 => Handshaken::Relay(
+                        Self::handle_client_v4(
+                            stream,
+                            &mut encoder,
+                            session,
+                            buffer,
+                            handle_request,
+                            shared,
+                        )
+                        .await?,
+                    ),
+                    Version::Socks5 => {
+                        Self::handle_client_v5(
+                            stream,
+                            &mut encoder,
+                            session,
+                            buffer,
+                            handle_request,
+                            shared,
+                            datagrams,
+                        )
+                        .await?
+                    }
+                };
+                shared.stats.record_handshake(version);
+                Ok(handshaken)
+            }
+        }
+    }
+
+    /// Runs the server side of the handshake recorded on a client by `transcript`.
+    ///
+    /// The messages sent by the client are fed back one by one, without any network access
+    /// besides what `handle_request` does. Returns the new transcript, seen from the server,
+    /// along with the outcome of the handshake. Statistics of this server are left untouched.
+    ///
+    /// Transcripts do not record the client address, the method selector sees `0.0.0.0:0`.
+    pub async fn replay<HC, S, FC>(
+        &self,
+        transcript: &Transcript,
+        handle_request: HC,
+    ) -> (Transcript, io::Result<()>)
+    where
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let shared = Shared {
+            stats: Arc::default(),
+            ..self.shared()
+        };
+        let mut recorder = Recorder::new(Playback::new(transcript.sent()));
+        let session = Session {
+            peer: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            deadline: None,
+        };
+        let result = Self::handshake(&mut recorder, session, handle_request, &shared, None)
+            .await
+            .map(drop);
+        (recorder.into_parts().1, result)
+    }
+
+    async fn handle_client_v4<C, HC, S, FC>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        session: Session,
+        mut buffer: HandshakeBuffer,
+        handle_request: HC,
+        shared: &Shared,
     ) -> io::Result<S>
     where
+        C: AsyncRead + AsyncWrite + Unpin,
         HC: FnOnce(ConnectionRequest) -> FC,
         FC: Future<Output = io::Result<(S, Destination)>>,
         S: AsyncRead + AsyncWrite + Unpin,
     {
         use crate::v4::*;
 
-        let (_, req) = Request::decode(&buffer).map_err(map_nom_error)?;
+        let req: Request =
+            read_message(stream, &mut buffer, &shared.limits, shared.parse_mode).await?;
+        shared.check_early_data(&buffer)?;
+        shared.inspect(session.peer, InspectedRequest::V4(&req));
+        record_span!(
+            "destination",
+            Destination::from((req.addr.clone(), req.port))
+        );
 
-        let connection_request = (req.addr.clone(), req.port).into();
-        match handle_request(connection_request).await {
-            Ok((s, destination)) => {
+        let mut connection_request: ConnectionRequest = (req.addr.clone(), req.port).into();
+        connection_request.secret = req.secret;
+        let result = match req.command {
+            Command::Connect => {
+                match shared
+                    .handle_request(session, connection_request, handle_request)
+                    .await
+                {
+                    Ok((mut s, destination)) => forward_early_data(&mut s, &buffer)
+                        .await
+                        .map(|()| (s, destination)),
+                    Err(e) => Err(e),
+                }
+            }
+            // BIND waits for the destination to connect back, which handlers cannot do.
+            Command::Bind => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unsupported command Bind",
+            )),
+        };
+        match result {
+            Ok((mut s, destination)) => {
                 let response = Response {
                     status: Status::Success,
-                    addr: match destination.addr {
-                        crate::common::v5::AddressType::IPv4(ip4) => ip4,
-                        _ => 0u32.into(),
-                    },
+                    addr: destination.addr.to_ipv4().unwrap_or_else(|| {
+                        connection_log!(
+                            debug,
+                            "SOCKS4 cannot report bound address {}",
+                            destination.addr
+                        );
+                        Ipv4Addr::UNSPECIFIED
+                    }),
                     port: destination.port,
                 };
-                buffer.clear();
-                response.encode_into(&mut buffer);
-                stream.write_all(&buffer[..]).await?;
+                write_reply(stream, encoder, &response, &mut s).await?;
                 Ok(s)
             }
             Err(e) => {
+                let reply_with = shared
+                    .failure_reply_address
+                    .reply_with((req.addr, req.port).into());
                 let response = Response {
-                    status: Status::Rejected,
-                    addr: match req.addr {
-                        AddressType::IPv4(ip4) => ip4,
-                        _ => 0u32.into(),
-                    },
-                    port: req.port,
+                    status: rejection::status_for(&e).into(),
+                    addr: reply_with.addr.to_ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED),
+                    port: reply_with.port,
                 };
-                buffer.clear();
-                response.encode_into(&mut buffer);
-                stream.write_all(&buffer[..]).await?;
+                write_message(stream, encoder, &response).await?;
                 Err(e)
             }
         }
     }
 
-    async fn handle_client_v5<HC, S, FC>(
-        stream: &mut TcpStream,
-        mut buffer: Vec<u8>,
+    /// UDP associations are carried over `datagrams` if given, a UDP socket otherwise.
+    #[cfg_attr(not(feature = "quic"), allow(unused_variables))]
+    async fn handle_client_v5<C, HC, S, FC>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        session: Session,
+        mut buffer: HandshakeBuffer,
         handle_request: HC,
-    ) -> io::Result<S>
+        shared: &Shared,
+        datagrams: Option<&Datagrams>,
+    ) -> io::Result<Handshaken<S>>
     where
+        C: AsyncRead + AsyncWrite + Unpin,
         HC: FnOnce(ConnectionRequest) -> FC,
         FC: Future<Output = io::Result<(S, Destination)>>,
         S: AsyncRead + AsyncWrite + Unpin,
     {
         use crate::v5::*;
 
-        let (_, hello) = Hello::decode(&buffer).map_err(map_nom_error)?;
-        let method = if hello.methods.contains(&AuthenticationMethod::None) {
-            AuthenticationMethod::None
-        } else {
-            AuthenticationMethod::NotAcceptable
-        };
+        let (hello, identity) =
+            Self::authenticate_client_v5(stream, encoder, session, &mut buffer, shared).await?;
 
-        let response = HelloResponse { method };
-        buffer.clear();
-        response.encode_into(&mut buffer);
-        stream.write_all(&buffer[..]).await?;
+        let req: Request =
+            read_message(stream, &mut buffer, &shared.limits, shared.parse_mode).await?;
+        shared.check_early_data(&buffer)?;
+        shared.inspect(
+            session.peer,
+            InspectedRequest::V5 {
+                hello: &hello,
+                request: &req,
+            },
+        );
+        record_span!(
+            "destination",
+            Destination::from((req.addr.clone(), req.port))
+        );
 
-        if response.method == AuthenticationMethod::NotAcceptable {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                "Client requested only unsupported authentication methods",
-            ));
+        #[cfg(feature = "tor")]
+        if matches!(req.command, Command::TorResolve | Command::TorResolvePtr) {
+            let destination = Destination::from((req.addr.clone(), req.port));
+            let result = match shared.resolver {
+                Some(ref resolver) => match shared
+                    .authorize(session.peer, &destination, identity.as_ref())
+                    .await
+                {
+                    Ok(()) => resolve(&**resolver, req.command, &req.addr).await,
+                    Err(e) => Err(e),
+                },
+                None => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Tor extensions are not supported",
+                )),
+            };
+            let response = match result {
+                Ok(ref addr) => Response {
+                    status: Status::Success,
+                    addr: addr.clone(),
+                    port: 0,
+                },
+                Err(ref e) => {
+                    let reply_with = shared
+                        .failure_reply_address
+                        .reply_with((req.addr, req.port).into());
+                    Response {
+                        status: match e.kind() {
+                            io::ErrorKind::Unsupported => Status::CommandNotSupported,
+                            _ => rejection::status_for(e),
+                        },
+                        addr: reply_with.addr,
+                        port: reply_with.port,
+                    }
+                }
+            };
+            write_message(stream, encoder, &response).await?;
+            return result.map(|_| Handshaken::Done);
+        }
+
+        #[cfg(feature = "quic")]
+        if let (Command::UdpAssociate, Some(datagrams)) = (req.command, datagrams) {
+            let claimed = datagrams.claim().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "QUIC connection already carries a UDP association",
+                )
+            });
+            let response = shared.bound_response(
+                claimed
+                    .as_ref()
+                    .map(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+                (req.addr, req.port).into(),
+            );
+            write_message(stream, encoder, &response).await?;
+            return Ok(Handshaken::Associate(Association {
+                transport: ClientTransport::Quic {
+                    connection: datagrams.connection.clone(),
+                    _claim: claimed?,
+                },
+                peer: session.peer,
+                identity,
+            }));
+        }
+        if let (Command::UdpAssociate, Some(ip)) = (req.command, shared.udp_relay.bind_address) {
+            let declared = Destination::from((req.addr.clone(), req.port));
+            let bound = UdpSocket::bind((ip, 0))
+                .await
+                .and_then(|socket| Ok((socket.local_addr()?, socket)));
+            let response =
+                shared.bound_response(bound.as_ref().map(|(addr, _)| *addr), declared.clone());
+            write_message(stream, encoder, &response).await?;
+            let (_, socket) = bound?;
+            return Ok(Handshaken::Associate(Association {
+                transport: ClientTransport::Udp {
+                    socket,
+                    source: ClientSource::new(&declared, session.peer.ip()),
+                },
+                peer: session.peer,
+                identity,
+            }));
+        }
+        if let (Command::Bind, Some(ref options)) = (req.command, &shared.bind) {
+            let requested = Destination::from((req.addr.clone(), req.port));
+            let mut bind_request = ConnectionRequest::from(requested.clone());
+            bind_request.identity = identity;
+            // The expected peer is vetted as a CONNECT destination would be.
+            let pending = async {
+                let bind_request = shared.apply_layers(session.peer, bind_request).await?;
+                shared
+                    .authorize(
+                        session.peer,
+                        &bind_request.destination,
+                        bind_request.identity.as_ref(),
+                    )
+                    .await?;
+                let pending = options.listen(&bind_request).await?;
+                Ok((pending.local_addr()?, pending))
+            }
+            .await;
+            let response =
+                shared.bound_response(pending.as_ref().map(|(addr, _)| *addr), requested);
+            write_message(stream, encoder, &response).await?;
+            let (_, pending) = pending?;
+            return Ok(Handshaken::Bind(pending));
         }
 
-        buffer.clear();
-        let n = stream.read_buf(&mut buffer).await?;
-        let (_, req) = Request::decode(&buffer[..n]).map_err(map_nom_error)?;
+        // Request handlers only know how to reach a destination.
+        if req.command != Command::Connect {
+            return Err(Self::reject_command(stream, encoder, req, shared).await);
+        }
 
-        let connection_request = (req.addr.clone(), req.port).into();
-        match handle_request(connection_request).await {
-            Ok((s, destination)) => {
+        let mut connection_request: ConnectionRequest = (req.addr, req.port).into();
+        connection_request.identity = identity;
+        Self::connect_v5(
+            stream,
+            encoder,
+            session,
+            &buffer,
+            connection_request,
+            handle_request,
+            shared,
+        )
+        .await
+        .map(Handshaken::Relay)
+    }
+
+    /// Tells a SOCKS5 client the command of `req` is not supported, returning the error to fail
+    /// with.
+    async fn reject_command<C: AsyncWrite + Unpin>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        req: crate::v5::Request,
+        shared: &Shared,
+    ) -> io::Error {
+        use crate::v5::*;
+
+        let reply_with = shared
+            .failure_reply_address
+            .reply_with((req.addr, req.port).into());
+        let response = Response {
+            status: Status::CommandNotSupported,
+            addr: reply_with.addr,
+            port: reply_with.port,
+        };
+        if let Err(e) = write_message(stream, encoder, &response).await {
+            return e;
+        }
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported command {:?}", req.command),
+        )
+    }
+
+    /// Runs the request handler for a SOCKS5 CONNECT request, then replies with its outcome.
+    /// Data the client sent along with its request is forwarded to the destination first.
+    async fn connect_v5<C, HC, S, FC>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        session: Session,
+        early_data: &[u8],
+        request: ConnectionRequest,
+        handle_request: HC,
+        shared: &Shared,
+    ) -> io::Result<S>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        use crate::v5::*;
+
+        let requested = request.destination.clone();
+        let result = match shared
+            .handle_request(session, request, handle_request)
+            .await
+        {
+            Ok((mut s, destination)) => forward_early_data(&mut s, early_data)
+                .await
+                .map(|()| (s, destination)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((mut s, destination)) => {
                 let response = Response {
                     status: Status::Success,
                     addr: destination.addr,
                     port: destination.port,
                 };
-                buffer.clear();
-                response.encode_into(&mut buffer);
-                stream.write_all(&buffer[..]).await?;
+                write_reply(stream, encoder, &response, &mut s).await?;
                 Ok(s)
             }
             Err(e) => {
+                let reply_with = shared.failure_reply_address.reply_with(requested);
                 let response = Response {
-                    status: Status::GeneralFailure,
-                    addr: req.addr,
-                    port: req.port,
+                    status: rejection::status_for(&e),
+                    addr: reply_with.addr,
+                    port: reply_with.port,
                 };
-                buffer.clear();
-                response.encode_into(&mut buffer);
-                stream.write_all(&buffer[..]).await?;
+                write_message(stream, encoder, &response).await?;
                 Err(e)
             }
         }
     }
+
+    /// Negotiates the authentication method with a SOCKS5 client, then authenticates it.
+    async fn authenticate_client_v5<C: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        session: Session,
+        buffer: &mut HandshakeBuffer,
+        shared: &Shared,
+    ) -> io::Result<(crate::v5::Hello, Option<Identity>)> {
+        use crate::v5::*;
+
+        let hello: Hello =
+            match read_message(stream, buffer, &shared.limits, shared.parse_mode).await {
+                Ok(hello) => hello,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    // Tell the client no method fits rather than just hanging up, as it did say SOCKS5.
+                    let response = HelloResponse {
+                        method: AuthenticationMethod::NotAcceptable,
+                    };
+                    let _ = write_message(stream, encoder, &response).await;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+        let method = shared.select_method(session.peer, &hello).await?;
+
+        let response = HelloResponse { method };
+        write_message(stream, encoder, &response).await?;
+
+        if response.method == AuthenticationMethod::NotAcceptable {
+            shared.stats.record_auth_failure();
+            audit!(
+                shared,
+                session.peer,
+                AuditKind::AuthFailed { username: None }
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Client requested only unsupported authentication methods",
+            ));
+        }
+
+        let identity = match (method, &shared.authenticator) {
+            (AuthenticationMethod::UsernamePassword, Some(authenticator)) => Some(
+                Self::authenticate_v5(
+                    stream,
+                    encoder,
+                    session.peer,
+                    buffer,
+                    &**authenticator,
+                    shared,
+                )
+                .await?,
+            ),
+            _ => None,
+        };
+        Ok((hello, identity))
+    }
+
+    #[cfg_attr(not(feature = "audit"), allow(unused_variables))]
+    async fn authenticate_v5<C: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        peer: SocketAddr,
+        buffer: &mut HandshakeBuffer,
+        authenticator: &dyn Authenticator,
+        shared: &Shared,
+    ) -> io::Result<Identity> {
+        use crate::v5::*;
+
+        let credentials: UsernamePassword =
+            read_message(stream, buffer, &shared.limits, shared.parse_mode).await?;
+
+        let identity = authenticator
+            .verify(&credentials.username, &credentials.password)
+            .await?;
+
+        let response = UsernamePasswordResponse {
+            success: identity.is_some(),
+        };
+        write_message(stream, encoder, &response).await?;
+
+        match identity {
+            Some(identity) => {
+                audit!(
+                    shared,
+                    peer,
+                    AuditKind::AuthSucceeded {
+                        username: identity.username.clone(),
+                    }
+                );
+                Ok(identity)
+            }
+            None => {
+                shared.stats.record_auth_failure();
+                audit!(
+                    shared,
+                    peer,
+                    AuditKind::AuthFailed {
+                        username: Some(credentials.username.clone()),
+                    }
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Invalid credentials for user {:?}", credentials.username),
+                ))
+            }
+        }
+    }
+
+    #[cfg(feature = "http-connect")]
+    async fn handle_client_http<C, HC, S, FC>(
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        session: Session,
+        mut buffer: Vec<u8>,
+        handle_request: HC,
+        shared: &Shared,
+    ) -> io::Result<S>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        use crate::http::*;
+        use tokio::io::AsyncReadExt;
+
+        const MAX_HEADER_SIZE: usize = 8192;
+
+        while !buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+            if buffer.len() > MAX_HEADER_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "HTTP CONNECT request too large",
+                ));
+            }
+            if stream.read_buf(&mut buffer).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        let (consumed, req) = match ConnectRequest::decode(&buffer) {
+            Ok((rest, req)) => {
+                record_span!("version", "http");
+                record_span!("destination", req.destination);
+                (buffer.len() - rest.len(), req)
+            }
+            Err(e) => {
+                let e = invalid_data(&buffer)(e);
+                write_message(stream, encoder, &ConnectResponse::BAD_REQUEST).await?;
+                return Err(e);
+            }
+        };
+        buffer.drain(..consumed);
+
+        let mut request = ConnectionRequest::from(req.destination.clone());
+        if let Some(ref authenticator) = shared.authenticator {
+            match Self::authenticate_http(&req, session.peer, &**authenticator, shared).await {
+                Ok(identity) => request.identity = Some(identity),
+                Err(e) => {
+                    let response = ConnectResponse::PROXY_AUTHENTICATION_REQUIRED;
+                    write_message(stream, encoder, &response).await?;
+                    return Err(e);
+                }
+            }
+        }
+        let result = match shared
+            .handle_request(session, request, handle_request)
+            .await
+        {
+            Ok((mut s, destination)) => forward_early_data(&mut s, &buffer)
+                .await
+                .map(|()| (s, destination)),
+            Err(e) => Err(e),
+        };
+        let (mut s, _) = match result {
+            Ok(connected) => connected,
+            Err(e) => {
+                let response = match e.kind() {
+                    io::ErrorKind::PermissionDenied => ConnectResponse::FORBIDDEN,
+                    _ => ConnectResponse::BAD_GATEWAY,
+                };
+                write_message(stream, encoder, &response).await?;
+                return Err(e);
+            }
+        };
+        write_reply(stream, encoder, &ConnectResponse::ESTABLISHED, &mut s).await?;
+        Ok(s)
+    }
+
+    /// Checks the `Proxy-Authorization: Basic` credentials of an HTTP CONNECT request.
+    #[cfg(feature = "http-connect")]
+    #[cfg_attr(not(feature = "audit"), allow(unused_variables))]
+    async fn authenticate_http(
+        req: &crate::http::ConnectRequest,
+        peer: SocketAddr,
+        authenticator: &dyn Authenticator,
+        shared: &Shared,
+    ) -> io::Result<Identity> {
+        let (username, identity) = match req.basic_credentials() {
+            Some((username, password)) => {
+                let identity = authenticator.verify(&username, &password).await?;
+                (Some(username), identity)
+            }
+            None => (None, None),
+        };
+        match identity {
+            Some(identity) => {
+                audit!(
+                    shared,
+                    peer,
+                    AuditKind::AuthSucceeded {
+                        username: identity.username.clone(),
+                    }
+                );
+                Ok(identity)
+            }
+            None => {
+                shared.stats.record_auth_failure();
+                audit!(
+                    shared,
+                    peer,
+                    AuditKind::AuthFailed {
+                        username: username.clone(),
+                    }
+                );
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    match username {
+                        Some(username) => format!("Invalid credentials for user {username:?}"),
+                        None => "Missing proxy credentials".to_owned(),
+                    },
+                ))
+            }
+        }
+    }
+}
+
+/// Sends to the destination the data a client pipelined after its request.
+async fn forward_early_data<S: AsyncWrite + Unpin>(remote: &mut S, data: &[u8]) -> io::Result<()> {
+    if !data.is_empty() {
+        connection_log!(
+            debug,
+            "Forwarding {} bytes sent along with the request",
+            data.len()
+        );
+        remote.write_all(data).await?;
+    }
+    Ok(())
+}
+
+async fn acquire(permits: Option<&Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    match permits {
+        Some(permits) => Some(
+            Arc::clone(permits)
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed"),
+        ),
+        None => None,
+    }
+}
+
+/// Logs the outcome of a client session, within its `socks_connection` span, as connection
+/// `id`.
+fn client_task(
+    id: ConnectionId,
+    peer: SocketAddr,
+    session: impl Future<Output = io::Result<()>>,
+) -> impl Future<Output = ()> {
+    let connection = async move {
+        if let Err(e) = session.await {
+            connection_log!(error, "Issue with client {peer}: {e}");
+            #[cfg(feature = "tracing")]
+            tracing::warn!(error = %e, "connection failed");
+        }
+    };
+    #[cfg(feature = "tracing")]
+    let connection = tracing::Instrument::instrument(
+        connection,
+        tracing::info_span!(
+            "socks_connection",
+            id = id.get(),
+            peer = %peer,
+            version = tracing::field::Empty,
+            destination = tracing::field::Empty,
+        ),
+    );
+    id.scope(connection)
+}
+
+/// Reports connection tasks which panicked instead of silently dropping them.
+fn reap(joined: Result<(), JoinError>, stats: &ServerStats) {
+    if let Err(e) = joined {
+        if e.is_panic() {
+            stats.record_handler_panic();
+            let payload = e.into_panic();
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("<non-string payload>");
+            log::error!("Connection task panicked: {message}");
+        }
+    }
+}
+
+/// Runs a RESOLVE or RESOLVE_PTR `command` on `addr`, returning the address to reply with.
+#[cfg(feature = "tor")]
+async fn resolve(
+    resolver: &dyn ResolveHandler,
+    command: crate::v5::Command,
+    addr: &crate::v5::AddressType,
+) -> io::Result<crate::v5::AddressType> {
+    use crate::v5::{AddressType, Command};
+
+    match (command, addr) {
+        (Command::TorResolve, AddressType::DomainName(host)) => {
+            Ok(resolver.resolve(host).await?.into())
+        }
+        (Command::TorResolve, addr) => Ok(addr.clone()),
+        (_, addr) => match addr.to_socket_addr(0) {
+            Some(ip) => Ok(AddressType::DomainName(
+                resolver.resolve_ptr(ip.ip()).await?,
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot reverse-resolve {addr}"),
+            )),
+        },
+    }
 }
@@ -0,0 +1,239 @@
+use std::{
+    borrow::Cow,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::UdpSocket,
+};
+
+use super::Shared;
+use crate::{
+    auth::Identity,
+    error::invalid_data,
+    resolver::{self, SystemResolver},
+    udp::{ClientSource, FragmentPolicy, Reassembler},
+    v5::{AddressType, UdpHeader},
+    Destination, Wire,
+};
+
+/// UDP association granted to a client, relayed until its control stream is closed.
+pub(super) struct Association {
+    pub(super) transport: ClientTransport,
+    pub(super) peer: SocketAddr,
+    /// Identity of the client, whose policy applies to every datagram.
+    pub(super) identity: Option<Identity>,
+}
+
+/// How datagrams are exchanged with the client of an association.
+pub(super) enum ClientTransport {
+    /// Datagrams exchanged as QUIC datagrams of the connection carrying the control stream.
+    #[cfg(feature = "quic")]
+    Quic {
+        connection: quinn::Connection,
+        /// Keeps other associations off the connection datagrams.
+        _claim: tokio::sync::OwnedSemaphorePermit,
+    },
+    /// Datagrams exchanged with the client on a UDP socket.
+    Udp {
+        socket: UdpSocket,
+        source: ClientSource,
+    },
+}
+
+impl Association {
+    /// Relays datagrams between the client and the destinations it names, until `control` is
+    /// closed.
+    pub(super) async fn run<C>(self, control: &mut C, shared: &Shared) -> io::Result<()>
+    where
+        C: AsyncRead + Unpin,
+    {
+        let Self {
+            mut transport,
+            peer,
+            identity,
+        } = self;
+        let mut relay = Relay::new(shared).await?;
+        let mut control_buffer = [0; 64];
+        let mut client_buffer = vec![0; u16::MAX as usize];
+
+        loop {
+            tokio::select! {
+                n = control.read(&mut control_buffer) => {
+                    if n? == 0 {
+                        return Ok(());
+                    }
+                }
+                datagram = transport.recv(&mut client_buffer) => {
+                    let datagram = datagram?;
+                    let forwarded = relay.forward(&datagram, peer, identity.as_ref(), shared);
+                    if let Err(e) = forwarded.await {
+                        connection_log!(debug, "Dropping datagram: {e}");
+                    }
+                }
+                datagram = relay.recv() => {
+                    transport.send(datagram?).await?;
+                }
+            }
+        }
+    }
+}
+
+impl ClientTransport {
+    /// Waits for the next datagram of the client.
+    async fn recv<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<Cow<'a, [u8]>> {
+        match self {
+            #[cfg(feature = "quic")]
+            Self::Quic { connection, .. } => {
+                Ok(Cow::Owned(connection.read_datagram().await?.into()))
+            }
+            Self::Udp { socket, source } => loop {
+                let (n, from) = socket.recv_from(buffer).await?;
+                if source.accept(from) {
+                    return Ok(Cow::Borrowed(&buffer[..n]));
+                }
+                connection_log!(
+                    debug,
+                    "Dropping datagram from {from}, not the client of the association"
+                );
+            },
+        }
+    }
+
+    /// Sends a datagram back to the client, dropping it if the client is not known yet or it
+    /// cannot be carried, as a datagram too large for the QUIC connection.
+    async fn send(&self, datagram: Vec<u8>) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "quic")]
+            Self::Quic { connection, .. } => {
+                if let Err(e) = connection.send_datagram(datagram.into()) {
+                    connection_log!(debug, "Dropping datagram for the client: {e}");
+                }
+                Ok(())
+            }
+            Self::Udp { socket, source } => {
+                if let Some(client) = source.client() {
+                    socket.send_to(&datagram, client).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Sockets exchanging the payloads of the datagrams of a client with their destinations.
+struct Relay {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+    reassembler: Option<Reassembler>,
+    v4_buffer: Vec<u8>,
+    v6_buffer: Vec<u8>,
+}
+
+impl Relay {
+    async fn new(shared: &Shared) -> io::Result<Self> {
+        Ok(Self {
+            v4: UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?,
+            v6: None,
+            reassembler: match shared.udp_relay.fragmentation {
+                FragmentPolicy::Drop => None,
+                FragmentPolicy::Reassemble { timeout } => Some(Reassembler::new(timeout)),
+            },
+            v4_buffer: vec![0; u16::MAX as usize],
+            v6_buffer: vec![0; u16::MAX as usize],
+        })
+    }
+
+    /// Sends the payload of a datagram of the client at `peer` to the destination named in its
+    /// header, once authorized and all its fragments are received if reassembling them,
+    /// fragments being dropped otherwise.
+    async fn forward(
+        &mut self,
+        datagram: &[u8],
+        peer: SocketAddr,
+        identity: Option<&Identity>,
+        shared: &Shared,
+    ) -> io::Result<()> {
+        let (payload, header) = UdpHeader::decode_with_limits(datagram, &shared.limits)
+            .map_err(invalid_data(datagram))?;
+        let (destination, payload) = match self.reassembler {
+            Some(ref mut reassembler) => match reassembler.push(header, payload, Instant::now()) {
+                Some((destination, payload)) => (destination, Cow::Owned(payload)),
+                None => return Ok(()),
+            },
+            None if header.frag != 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Fragmented datagrams are not supported",
+                ));
+            }
+            None => {
+                let destination = Destination {
+                    addr: header.addr,
+                    port: header.port,
+                };
+                (destination, Cow::Borrowed(payload))
+            }
+        };
+        shared.authorize(peer, &destination, identity).await?;
+
+        let target = resolver::resolve_and_pin(
+            &SystemResolver,
+            &destination,
+            shared.acl.as_deref(),
+            shared.allow_private,
+        )
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No address found"))?;
+        let socket = match target {
+            SocketAddr::V4(_) => &self.v4,
+            SocketAddr::V6(_) => match self.v6 {
+                Some(ref socket) => socket,
+                None => self
+                    .v6
+                    .insert(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?),
+            },
+        };
+        socket.send_to(&payload, target).await?;
+        Ok(())
+    }
+
+    /// Waits for the next datagram from a destination, returned with its UDP request header to
+    /// send it back to the client.
+    async fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let (payload, from) = tokio::select! {
+            received = self.v4.recv_from(&mut self.v4_buffer) => {
+                let (n, from) = received?;
+                (&self.v4_buffer[..n], from)
+            }
+            received = recv_from(self.v6.as_ref(), &mut self.v6_buffer) => {
+                let (n, from) = received?;
+                (&self.v6_buffer[..n], from)
+            }
+        };
+        let header = UdpHeader {
+            frag: 0,
+            addr: AddressType::from(from.ip()).normalized(),
+            port: from.port(),
+        };
+        let mut datagram = Vec::with_capacity(payload.len() + 22);
+        header.encode_into(&mut datagram);
+        datagram.extend_from_slice(payload);
+        Ok(datagram)
+    }
+}
+
+async fn recv_from(
+    socket: Option<&UdpSocket>,
+    buffer: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buffer).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+use super::Shared;
+use crate::{
+    framing::{self, write_message},
+    ConnectionRequest, Destination, EncodeBuffer,
+};
+
+/// Peer expected to connect for a BIND request, see [`BindOptions::with_expected_peer`].
+type ExpectPeer = dyn Fn(&ConnectionRequest) -> Option<SocketAddr> + Send + Sync;
+
+/// Settings of the SOCKS5 BIND command, see
+/// [`Server::with_bind_options`](super::Server::with_bind_options).
+#[derive(Clone)]
+pub struct BindOptions {
+    address: IpAddr,
+    accept_timeout: Duration,
+    expected_peer: Option<Arc<ExpectPeer>>,
+}
+
+impl BindOptions {
+    /// Listens for the connections of BIND requests on `address`, which must be reachable by
+    /// the peers clients tell about it.
+    pub fn new(address: IpAddr) -> Self {
+        Self {
+            address,
+            accept_timeout: Duration::from_secs(120),
+            expected_peer: None,
+        }
+    }
+
+    /// Fails BIND requests once no expected peer connected within `timeout`, two minutes by
+    /// default.
+    pub fn with_accept_timeout(mut self, timeout: Duration) -> Self {
+        self.accept_timeout = timeout;
+        self
+    }
+
+    /// Lets `expected_peer` name the peer allowed to connect for a request, `None` letting any
+    /// peer in. An unspecified IP address or a zero port in the returned address matches any.
+    ///
+    /// Defaults to the IP address of the request destination, as RFC 1928 suggests, while a
+    /// domain name matches any peer.
+    pub fn with_expected_peer(
+        mut self,
+        expected_peer: impl Fn(&ConnectionRequest) -> Option<SocketAddr> + Send + Sync + 'static,
+    ) -> Self {
+        self.expected_peer = Some(Arc::new(expected_peer));
+        self
+    }
+
+    /// Listens for the peer `req` expects.
+    pub(super) async fn listen(&self, req: &ConnectionRequest) -> io::Result<PendingBind> {
+        let expected = match self.expected_peer {
+            Some(ref expected_peer) => expected_peer(req),
+            None => req
+                .destination
+                .addr
+                .to_socket_addr(0)
+                .filter(|addr| !addr.ip().is_unspecified()),
+        };
+        Ok(PendingBind {
+            listener: TcpListener::bind((self.address, 0)).await?,
+            expected,
+            timeout: self.accept_timeout,
+        })
+    }
+}
+
+impl fmt::Debug for BindOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BindOptions")
+            .field("address", &self.address)
+            .field("accept_timeout", &self.accept_timeout)
+            .field("expected_peer", &self.expected_peer.is_some())
+            .finish()
+    }
+}
+
+/// BIND request whose first reply was sent, waiting for its peer.
+pub(super) struct PendingBind {
+    listener: TcpListener,
+    expected: Option<SocketAddr>,
+    timeout: Duration,
+}
+
+impl PendingBind {
+    pub(super) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Whether `peer` may be relayed to the client.
+    fn expects(&self, peer: SocketAddr) -> bool {
+        self.expected.is_none_or(|expected| {
+            (expected.ip().is_unspecified() || expected.ip() == peer.ip().to_canonical())
+                && (expected.port() == 0 || expected.port() == peer.port())
+        })
+    }
+
+    /// Accepts the expected peer, ignoring any other, sends the second reply to the client, then
+    /// relays between them.
+    pub(super) async fn run<C>(self, stream: &mut C, shared: &Shared) -> io::Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let accepted = tokio::time::timeout(self.timeout, async {
+            loop {
+                let (peer_stream, peer) = self.listener.accept().await?;
+                if self.expects(peer) {
+                    return Ok((peer_stream, peer));
+                }
+                connection_log!(
+                    debug,
+                    "Rejecting connection from {peer}, not the expected BIND peer"
+                );
+            }
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Expected peer did not connect",
+            ))
+        });
+
+        let expected = self
+            .expected
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let response = shared.bound_response(
+            accepted.as_ref().map(|(_, peer)| *peer),
+            Destination::from(expected),
+        );
+        let mut encoder = EncodeBuffer::with_capacity(framing::REPLY_BUFFER_LEN);
+        write_message(stream, &mut encoder, &response).await?;
+        let (mut peer_stream, _) = accepted?;
+        let (up, down) = tokio::io::copy_bidirectional(stream, &mut peer_stream).await?;
+        shared.stats.record_bytes_relayed(up + down);
+        Ok(())
+    }
+}
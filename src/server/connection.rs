@@ -0,0 +1,46 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+tokio::task_local! {
+    static CURRENT: ConnectionId;
+}
+
+/// Identifier of a connection accepted by a server, unique within the process, prefixing the
+/// log lines of the server about it and recorded on its `socks_connection` tracing span.
+///
+/// Request and stream handlers, and the callbacks the server runs for the connection, get it
+/// with [`ConnectionId::current`] to tag their own logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub(super) fn next() -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        Self(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Connection served by the current task, if any.
+    ///
+    /// Tasks spawned by handlers do not inherit it, pass it along instead.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|id| *id).ok()
+    }
+
+    pub fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Runs `future` as part of this connection.
+    pub(super) fn scope<F: Future>(self, future: F) -> impl Future<Output = F::Output> {
+        CURRENT.scope(self, future)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
@@ -0,0 +1,213 @@
+use std::{fmt, io, net::SocketAddr, sync::Arc};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{rejection, InspectedRequest, Server, Session, Shared};
+use crate::{
+    error::invalid_data,
+    framing::{read_message, write_message, HandshakeBuffer},
+    stream::PrefixedStream,
+    v4, v5, ConnectionRequest, ConnectionResponse, Destination, EncodeBuffer, Version, Wire,
+};
+
+/// Request read by [`ServerHandshake::read_request`].
+#[derive(Debug, Clone)]
+pub struct HandshakeRequest {
+    pub version: Version,
+    /// SOCKS4 commands are mapped to their SOCKS5 counterparts.
+    pub command: v5::Command,
+    /// Destination as rewritten by the server layers, along with what the client told about
+    /// itself.
+    pub request: ConnectionRequest,
+}
+
+/// Server side of the handshake, leaving the reply to the request to the caller: to send it
+/// once an upstream connection is truly established, or to implement BIND its own way.
+///
+/// Created by [`Server::handshake_only`], following its settings: authentication, decode
+/// limits, versions, handshake timeout, layers and ACL. Requests denied by the server policy
+/// are replied to and never handed over.
+#[derive(Clone)]
+pub struct ServerHandshake {
+    pub(super) shared: Arc<Shared>,
+}
+
+impl ServerHandshake {
+    /// Negotiates with the client at `peer` until it sent its request.
+    pub async fn read_request<C>(
+        &self,
+        mut stream: C,
+        peer: SocketAddr,
+    ) -> io::Result<(HandshakeRequest, ReplyWriter<C>)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let shared = &*self.shared;
+        let session = Session {
+            peer,
+            deadline: shared.handshake_deadline(),
+        };
+        let mut encoder = EncodeBuffer::new();
+        let mut buffer = HandshakeBuffer::new(&shared.limits);
+        let (version, command, request) = Shared::timed(
+            session.deadline,
+            self.negotiate(&mut stream, &mut encoder, session, &mut buffer),
+        )
+        .await?;
+
+        let reply = ReplyWriter {
+            stream,
+            encoder,
+            version,
+            failure_address: shared
+                .failure_reply_address
+                .reply_with(request.destination.clone()),
+            early_data: buffer.into_inner(),
+        };
+        let admitted = match shared.apply_layers(peer, request).await {
+            Ok(request) => shared
+                .authorize(peer, &request.destination, request.identity.as_ref())
+                .await
+                .map(|()| request),
+            Err(e) => Err(e),
+        };
+        let mut request = match admitted {
+            Ok(request) => request,
+            Err(e) => {
+                reply.failure(rejection::status_for(&e)).await?;
+                return Err(e);
+            }
+        };
+        request.peer = Some(session.peer);
+        request.deadline = session.deadline;
+        request.allow_private = shared.allow_private;
+        request.acl = shared.acl.clone();
+        shared.stats.record_handshake(version);
+        Ok((
+            HandshakeRequest {
+                version,
+                command,
+                request,
+            },
+            reply,
+        ))
+    }
+
+    async fn negotiate<C>(
+        &self,
+        stream: &mut C,
+        encoder: &mut EncodeBuffer,
+        session: Session,
+        buffer: &mut HandshakeBuffer,
+    ) -> io::Result<(Version, v5::Command, ConnectionRequest)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let shared = &*self.shared;
+        buffer.read_from(stream).await?;
+        let (_, version) = Version::decode(buffer).map_err(invalid_data(buffer))?;
+        if !shared.accepts(version) {
+            return Err(shared
+                .reject_version(stream, encoder, session.peer, version)
+                .await);
+        }
+        match version {
+            Version::Socks4 => {
+                let req: v4::Request =
+                    read_message(stream, buffer, &shared.limits, shared.parse_mode).await?;
+                shared.check_early_data(buffer)?;
+                shared.inspect(session.peer, InspectedRequest::V4(&req));
+                let command = match req.command {
+                    v4::Command::Connect => v5::Command::Connect,
+                    v4::Command::Bind => v5::Command::Bind,
+                };
+                let mut request: ConnectionRequest = (req.addr, req.port).into();
+                request.secret = req.secret;
+                Ok((version, command, request))
+            }
+            Version::Socks5 => {
+                let (hello, identity) =
+                    Server::authenticate_client_v5(stream, encoder, session, buffer, shared)
+                        .await?;
+                let req: v5::Request =
+                    read_message(stream, buffer, &shared.limits, shared.parse_mode).await?;
+                shared.check_early_data(buffer)?;
+                shared.inspect(
+                    session.peer,
+                    InspectedRequest::V5 {
+                        hello: &hello,
+                        request: &req,
+                    },
+                );
+                let mut request: ConnectionRequest = (req.addr, req.port).into();
+                request.identity = identity;
+                Ok((version, req.command, request))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ServerHandshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerHandshake").finish_non_exhaustive()
+    }
+}
+
+/// Replies to a request read by [`ServerHandshake::read_request`].
+///
+/// Dropping it closes the connection without a reply.
+pub struct ReplyWriter<C> {
+    stream: C,
+    encoder: EncodeBuffer,
+    version: Version,
+    /// Address reported by [`failure`](Self::failure), following the server policy.
+    failure_address: Destination,
+    early_data: Vec<u8>,
+}
+
+impl<C: AsyncWrite + Unpin> ReplyWriter<C> {
+    /// Sends a reply without ending the handshake, such as the first of the two BIND replies.
+    ///
+    /// SOCKS4 clients only learn whether `status` is a success, and the IPv4 address of `bound`.
+    pub async fn reply(&mut self, status: v5::Status, bound: Destination) -> io::Result<()> {
+        let response = ConnectionResponse {
+            connected_to: bound,
+            status,
+        };
+        match self.version {
+            Version::Socks4 => {
+                let response = v4::Response::from(response);
+                write_message(&mut self.stream, &mut self.encoder, &response).await
+            }
+            Version::Socks5 => {
+                let response = v5::Response::from(response);
+                write_message(&mut self.stream, &mut self.encoder, &response).await
+            }
+        }
+    }
+
+    /// Tells the client the request succeeded with `bound` as the address used to reach the
+    /// destination, handing the stream over to relay it. Data the client sent right after its
+    /// request is read first.
+    pub async fn success(mut self, bound: impl Into<Destination>) -> io::Result<PrefixedStream<C>> {
+        self.reply(v5::Status::Success, bound.into()).await?;
+        Ok(PrefixedStream::new(self.early_data, self.stream))
+    }
+
+    /// Rejects the request with `status`, reporting the address picked by
+    /// [`Server::with_failure_reply_address`].
+    pub async fn failure(mut self, status: v5::Status) -> io::Result<()> {
+        let address = self.failure_address.clone();
+        self.reply(status, address).await
+    }
+}
+
+impl<C> fmt::Debug for ReplyWriter<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplyWriter")
+            .field("version", &self.version)
+            .field("failure_address", &self.failure_address)
+            .field("early_data", &self.early_data.len())
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,102 @@
+use std::{fmt, future::Future, io, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use super::{ConnectionId, Handshaken, Opening, Server, Shared};
+use crate::{stats::Relayed, ConnectionRequest, Destination};
+
+/// Client accepted by [`Server::accept`], the handshake not started yet.
+pub struct Incoming {
+    pub(super) id: ConnectionId,
+    pub(super) stream: TcpStream,
+    pub(super) peer: SocketAddr,
+    pub(super) shared: Arc<Shared>,
+}
+
+impl Incoming {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Identifier of the connection, current while it is negotiated and served.
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Negotiates with the client, returning its stream along with the one opened by
+    /// `handle_request`, or `None` when there is nothing left to relay, once the UDP association
+    /// or BIND tunnel the client requested is over.
+    ///
+    /// The connection only counts as active in the [statistics](Server::stats) during the
+    /// handshake, and bytes relayed afterwards are left out, unlike with [`serve`](Self::serve).
+    pub async fn handshake<HC, S, FC>(
+        mut self,
+        handle_request: HC,
+    ) -> io::Result<Option<(TcpStream, S)>>
+    where
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let id = self.id;
+        id.scope(async move {
+            let shared = &self.shared;
+            let _active = shared.stats.connection_opened();
+            let negotiated =
+                Server::negotiate(&mut self.stream, self.peer, handle_request, shared, None);
+            match negotiated.await? {
+                Handshaken::Relay(remote_stream) => Ok(Some((self.stream, remote_stream))),
+                Handshaken::Associate(association) => {
+                    association.run(&mut self.stream, shared).await?;
+                    Ok(None)
+                }
+                Handshaken::Bind(pending) => {
+                    pending.run(&mut self.stream, shared).await?;
+                    Ok(None)
+                }
+                #[cfg(feature = "tor")]
+                Handshaken::Done => Ok(None),
+            }
+        })
+        .await
+    }
+
+    /// Serves the client as [`Server::run`] does, negotiating then relaying.
+    pub async fn serve<HC, HS, S, FC, FS, R>(
+        self,
+        handle_request: HC,
+        handle_stream: HS,
+    ) -> io::Result<()>
+    where
+        HC: FnOnce(ConnectionRequest) -> FC,
+        HS: FnOnce(TcpStream, S) -> FS,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        FS: Future<Output = io::Result<R>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+        R: Relayed,
+    {
+        let serve = Server::handle_client(
+            self.stream,
+            self.peer,
+            handle_request,
+            handle_stream,
+            &self.shared,
+            None,
+            Opening::Handshake,
+        );
+        self.id.scope(serve).await
+    }
+}
+
+impl fmt::Debug for Incoming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("id", &self.id)
+            .field("stream", &self.stream)
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
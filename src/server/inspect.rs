@@ -0,0 +1,25 @@
+use crate::{v4, v5, Version};
+
+/// Request of a client as decoded, before it is turned into a
+/// [`ConnectionRequest`](crate::ConnectionRequest), see
+/// [`Server::with_inspector`](super::Server::with_inspector).
+///
+/// It keeps what requests of each version carry on their own, such as the user id of SOCKS4
+/// clients or the authentication methods offered by SOCKS5 ones.
+#[derive(Debug, Clone, Copy)]
+pub enum InspectedRequest<'a> {
+    V4(&'a v4::Request),
+    V5 {
+        hello: &'a v5::Hello,
+        request: &'a v5::Request,
+    },
+}
+
+impl InspectedRequest<'_> {
+    pub fn version(&self) -> Version {
+        match self {
+            Self::V4(_) => Version::Socks4,
+            Self::V5 { .. } => Version::Socks5,
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use crate::ConnectionRequest;
+
+use super::Rejection;
+
+/// Outcome of a [`Layer`].
+#[derive(Debug)]
+pub enum Decision {
+    /// Goes on with the request, possibly rewritten or annotated.
+    Continue(ConnectionRequest),
+    /// Replies to the client without running the next layers or the request handler.
+    Deny(Rejection),
+}
+
+/// Inspects requests before the request handler, for policies shared by every handler such as
+/// split-horizon routing: answering names with specific addresses, redirecting ports...
+///
+/// Implemented by closures taking a [`ConnectionRequest`] and returning a [`Decision`].
+pub trait Layer: Send + Sync {
+    fn apply(&self, request: ConnectionRequest) -> Decision;
+}
+
+impl<F> Layer for F
+where
+    F: Fn(ConnectionRequest) -> Decision + Send + Sync,
+{
+    fn apply(&self, request: ConnectionRequest) -> Decision {
+        self(request)
+    }
+}
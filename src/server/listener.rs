@@ -0,0 +1,205 @@
+use std::{fmt, future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+use super::Shared;
+use crate::{
+    acl::Acl,
+    auth::{Authenticator, BoxFuture, MethodSelector},
+    stream::DynStream,
+    Destination,
+};
+
+/// Source of client connections for [`Server::serve`](super::Server::serve).
+///
+/// Local transports such as Unix sockets and named pipes have no peer address: their clients
+/// are reported as coming from `127.0.0.1:0`, so that ACLs treat them as local ones.
+pub trait Listener: Send {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Stream, SocketAddr)>> + Send;
+}
+
+/// Peer address reported for clients of local transports.
+#[cfg(any(unix, windows))]
+const LOCAL_PEER: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(
+    std::net::Ipv4Addr::LOCALHOST,
+    0,
+));
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Stream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)> {
+        let (stream, _) = tokio::net::UnixListener::accept(self).await?;
+        Ok((stream, LOCAL_PEER))
+    }
+}
+
+/// Listener accepting clients on a Windows named pipe, such as `\\.\pipe\socks`.
+#[cfg(windows)]
+#[derive(Debug)]
+pub struct NamedPipeListener {
+    name: std::ffi::OsString,
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+#[cfg(windows)]
+impl NamedPipeListener {
+    /// Creates the first instance of the pipe `name`, failing if it already exists.
+    pub fn bind(name: impl Into<std::ffi::OsString>) -> io::Result<Self> {
+        let name = name.into();
+        let next = tokio::net::windows::named_pipe::ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)?;
+        Ok(Self { name, next })
+    }
+}
+
+#[cfg(windows)]
+impl Listener for NamedPipeListener {
+    type Stream = tokio::net::windows::named_pipe::NamedPipeServer;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)> {
+        self.next.connect().await?;
+        // A new instance waits for the next client while this one serves the connected client.
+        let next = tokio::net::windows::named_pipe::ServerOptions::new().create(&self.name)?;
+        Ok((std::mem::replace(&mut self.next, next), LOCAL_PEER))
+    }
+}
+
+/// Socket options of the clients of the TCP listener of a server.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct TcpOptions {
+    pub(super) nodelay: bool,
+    pub(super) keepalive: Option<Duration>,
+}
+
+impl TcpOptions {
+    /// Sets the options on `stream`, only logging failures so as to serve the client anyway.
+    pub(super) fn apply(&self, stream: &TcpStream, peer: SocketAddr) {
+        use socket2::{SockRef, TcpKeepalive};
+
+        let result = stream
+            .set_nodelay(self.nodelay)
+            .and_then(|()| match self.keepalive {
+                Some(time) => {
+                    SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))
+                }
+                None => Ok(()),
+            });
+        if let Err(e) = result {
+            log::warn!("Cannot set socket options of the connection from {peer}: {e}");
+        }
+    }
+}
+
+/// TCP listener of a server, setting its socket options on every client.
+pub(super) struct TunedListener {
+    pub(super) listener: TcpListener,
+    pub(super) options: TcpOptions,
+}
+
+impl Listener for TunedListener {
+    type Stream = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(Self::Stream, SocketAddr)> {
+        let (stream, peer) = self.listener.accept().await?;
+        self.options.apply(&stream, peer);
+        Ok((stream, peer))
+    }
+}
+
+/// Client accepted by an [`ErasedListener`], with the destination it was redirected from if
+/// it sends no SOCKS handshake.
+pub(super) type Accepted = (DynStream, SocketAddr, Option<Destination>);
+
+/// [`Listener`] with its stream type erased, so that listeners of different kinds are served
+/// together.
+pub(super) trait ErasedListener: Send + Sync {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>>;
+}
+
+impl<L: Listener + Sync> ErasedListener for L {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>> {
+        Box::pin(async move {
+            let (stream, peer) = Listener::accept(self).await?;
+            Ok((Box::new(stream) as DynStream, peer, None))
+        })
+    }
+}
+
+/// Settings of a listener added with [`Server::with_listener`](super::Server::with_listener)
+/// overriding those of the server, such as no authentication on a Unix socket only local
+/// users reach.
+#[derive(Clone, Default)]
+pub struct ListenerOverrides {
+    authenticator: Option<Option<Arc<dyn Authenticator>>>,
+    method_selector: Option<Option<Arc<dyn MethodSelector>>>,
+    acl: Option<Arc<dyn Acl>>,
+}
+
+impl ListenerOverrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets clients in without authentication.
+    pub fn without_authentication(mut self) -> Self {
+        self.authenticator = Some(None);
+        self.method_selector = Some(None);
+        self
+    }
+
+    /// Authenticates clients with `authenticator` instead of the server one.
+    pub fn with_authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticator = Some(Some(Arc::new(authenticator)));
+        self
+    }
+
+    /// Checks requests against `acl` instead of the server one.
+    pub fn with_acl(mut self, acl: impl Acl + 'static) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    pub(super) fn apply(self, shared: &mut Shared) {
+        if let Some(authenticator) = self.authenticator {
+            shared.authenticator = authenticator;
+        }
+        if let Some(method_selector) = self.method_selector {
+            shared.method_selector = method_selector;
+        }
+        if let Some(acl) = self.acl {
+            shared.acl = Some(acl);
+        }
+    }
+}
+
+impl fmt::Debug for ListenerOverrides {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ListenerOverrides")
+            .field(
+                "authenticator",
+                &self.authenticator.as_ref().map(Option::is_some),
+            )
+            .field(
+                "method_selector",
+                &self.method_selector.as_ref().map(Option::is_some),
+            )
+            .field("acl", &self.acl.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,258 @@
+//! Multiplexed connections: once granted with a `MUX` request, every yamux stream of the
+//! connection carries a tunnel opened with a lone SOCKS5 request.
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Semaphore,
+    task::JoinSet,
+};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+use super::{
+    acquire, client_task, reap, ConnectionId, Handshaken, InspectedRequest, Listener, Opening,
+    Server, Session, Shared,
+};
+use crate::{
+    auth::Identity,
+    error::invalid_data,
+    framing::{self, read_message, write_message, HandshakeBuffer},
+    mux::MuxStream,
+    stats::Relayed,
+    stream::PrefixedStream,
+    v5, ConnectionRequest, Destination, EncodeBuffer, Wire,
+};
+
+impl Server {
+    /// Accepts clients from `listener` which multiplex their tunnels over a single connection,
+    /// see [`mux`](crate::mux). Other requests are answered as not supported.
+    ///
+    /// This server's configuration applies to every tunnel, its TCP listener is left unused.
+    /// Authentication happens once per connection, its identity applying to every tunnel. The
+    /// connection limit counts tunnels, not connections.
+    pub async fn serve_mux<L, HC, HS, S, FC, FS, R>(
+        self,
+        mut listener: L,
+        handle_request: HC,
+        handle_stream: HS,
+    ) -> io::Result<()>
+    where
+        L: Listener,
+        HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+        HS: FnOnce(MuxStream, S) -> FS + Send + Clone + 'static,
+        FC: Future<Output = io::Result<(S, Destination)>> + Send,
+        FS: Future<Output = io::Result<R>> + Send,
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: Relayed,
+    {
+        let permits = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let shared = Arc::new(self.shared());
+        let mut tasks = JoinSet::new();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                Some(joined) = tasks.join_next(), if !tasks.is_empty() => {
+                    reap(joined, &shared.stats);
+                    continue;
+                }
+            };
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    // Connections in flight outlive the accept loop.
+                    tasks.detach_all();
+                    return Err(e);
+                }
+            };
+            let id = ConnectionId::next();
+            log::info!("New connection {id} from {peer}");
+            let hc = handle_request.clone();
+            let hs = handle_stream.clone();
+            let shared = Arc::clone(&shared);
+            let permits = permits.clone();
+            tasks.spawn(client_task(id, peer, async move {
+                Self::serve_multiplexed(stream, peer, hc, hs, shared, permits).await
+            }));
+        }
+    }
+
+    /// Grants the `MUX` request of the client at `peer`, then serves the tunnels it opens until
+    /// the connection is closed.
+    async fn serve_multiplexed<C, HC, HS, S, FC, FS, R>(
+        mut stream: C,
+        peer: SocketAddr,
+        handle_request: HC,
+        handle_stream: HS,
+        shared: Arc<Shared>,
+        permits: Option<Arc<Semaphore>>,
+    ) -> io::Result<()>
+    where
+        C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+        HS: FnOnce(MuxStream, S) -> FS + Send + Clone + 'static,
+        FC: Future<Output = io::Result<(S, Destination)>> + Send,
+        FS: Future<Output = io::Result<R>> + Send,
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: Relayed,
+    {
+        let session = Session {
+            peer,
+            deadline: shared.handshake_deadline(),
+        };
+        let (identity, early_data) = Shared::timed(
+            session.deadline,
+            Self::grant_mux(&mut stream, session, &shared),
+        )
+        .await?;
+        let mut connection = yamux::Connection::new(
+            PrefixedStream::new(early_data, stream).compat(),
+            yamux::Config::default(),
+            yamux::Mode::Server,
+        );
+
+        let mut tunnels = JoinSet::new();
+        loop {
+            let inbound = tokio::select! {
+                inbound = poll_fn(|cx| connection.poll_next_inbound(cx)) => inbound,
+                Some(joined) = tunnels.join_next(), if !tunnels.is_empty() => {
+                    reap(joined, &shared.stats);
+                    continue;
+                }
+            };
+            let tunnel = match inbound {
+                Some(Ok(tunnel)) => tunnel,
+                Some(Err(e)) => {
+                    connection_log!(debug, "Multiplexed connection failed: {e}");
+                    break;
+                }
+                None => break,
+            };
+            let hc = handle_request.clone();
+            let hs = handle_stream.clone();
+            let shared = Arc::clone(&shared);
+            let permits = permits.clone();
+            let identity = identity.clone();
+            let id = ConnectionId::next();
+            connection_log!(debug, "New tunnel {id}");
+            tunnels.spawn(client_task(id, peer, async move {
+                // Waiting here rather than in the loop keeps the other tunnels going.
+                let _permit = acquire(permits.as_ref()).await;
+                Server::handle_client(
+                    MuxStream::accepted(tunnel),
+                    peer,
+                    hc,
+                    hs,
+                    &shared,
+                    None,
+                    Opening::Multiplexed(identity),
+                )
+                .await
+            }));
+        }
+        while let Some(joined) = tunnels.join_next().await {
+            reap(joined, &shared.stats);
+        }
+        Ok(())
+    }
+
+    /// Negotiates with the client up to its `MUX` request, returning its identity along with
+    /// the data it sent past the request.
+    async fn grant_mux<C>(
+        stream: &mut C,
+        session: Session,
+        shared: &Shared,
+    ) -> io::Result<(Option<Identity>, Vec<u8>)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        use crate::v5::*;
+
+        let mut buffer = HandshakeBuffer::new(&shared.limits);
+        let mut encoder = EncodeBuffer::with_capacity(framing::REPLY_BUFFER_LEN);
+        buffer.read_from(stream).await?;
+        let (_, version) = Version::decode(&buffer).map_err(invalid_data(&buffer))?;
+        if version != Version::Socks5 || !shared.accepts(version) {
+            return Err(shared
+                .reject_version(stream, &mut encoder, session.peer, version)
+                .await);
+        }
+        let (hello, identity) =
+            Self::authenticate_client_v5(stream, &mut encoder, session, &mut buffer, shared)
+                .await?;
+        let req: Request =
+            read_message(stream, &mut buffer, &shared.limits, shared.parse_mode).await?;
+        shared.inspect(
+            session.peer,
+            InspectedRequest::V5 {
+                hello: &hello,
+                request: &req,
+            },
+        );
+        if req.command != Command::Mux {
+            return Err(Self::reject_command(stream, &mut encoder, req, shared).await);
+        }
+        let response = Response {
+            status: Status::Success,
+            addr: AddressType::IPv4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+        };
+        write_message(stream, &mut encoder, &response).await?;
+        shared.stats.record_handshake(version);
+        Ok((identity, buffer.into_inner()))
+    }
+
+    /// Reads the request opening a tunnel of a multiplexed connection, on behalf of the client
+    /// authenticated as `identity`, then connects it within the handshake timeout.
+    pub(super) async fn negotiate_multiplexed<C, HC, S, FC>(
+        stream: &mut C,
+        peer: SocketAddr,
+        identity: Option<Identity>,
+        handle_request: HC,
+        shared: &Shared,
+    ) -> io::Result<Handshaken<S>>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        HC: FnOnce(ConnectionRequest) -> FC,
+        FC: Future<Output = io::Result<(S, Destination)>>,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let session = Session {
+            peer,
+            deadline: shared.handshake_deadline(),
+        };
+        Shared::timed(session.deadline, async {
+            let mut buffer = HandshakeBuffer::new(&shared.limits);
+            let mut encoder = EncodeBuffer::with_capacity(framing::REPLY_BUFFER_LEN);
+            let req: v5::Request =
+                read_message(stream, &mut buffer, &shared.limits, shared.parse_mode).await?;
+            shared.check_early_data(&buffer)?;
+            record_span!(
+                "destination",
+                Destination::from((req.addr.clone(), req.port))
+            );
+            if req.command != v5::Command::Connect {
+                return Err(Self::reject_command(stream, &mut encoder, req, shared).await);
+            }
+            let mut request: ConnectionRequest = (req.addr, req.port).into();
+            request.identity = identity;
+            Self::connect_v5(
+                stream,
+                &mut encoder,
+                session,
+                &buffer,
+                request,
+                handle_request,
+                shared,
+            )
+            .await
+            .map(Handshaken::Relay)
+        })
+        .await
+    }
+}
@@ -0,0 +1,128 @@
+//! SOCKS over QUIC: every bidirectional stream carries a SOCKS session, UDP associations are
+//! relayed over the connection datagrams.
+
+use std::{future::Future, io, sync::Arc};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+
+use super::{acquire, client_task, reap, ConnectionId, Opening, Server};
+use crate::{quic::QuicStream, stats::Relayed, ConnectionRequest, Destination};
+
+/// Datagrams of a QUIC connection, carrying a single UDP association at a time as they name no
+/// stream.
+#[derive(Clone)]
+pub(super) struct Datagrams {
+    pub(super) connection: quinn::Connection,
+    association: Arc<Semaphore>,
+}
+
+impl Datagrams {
+    fn new(connection: quinn::Connection) -> Self {
+        Self {
+            connection,
+            association: Arc::new(Semaphore::new(1)),
+        }
+    }
+
+    /// Reserves the datagrams for an association until the returned permit is dropped, if no
+    /// other association uses them.
+    pub(super) fn claim(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.association).try_acquire_owned().ok()
+    }
+}
+
+impl Server {
+    /// Serves SOCKS sessions opened on the QUIC connections accepted by `endpoint`.
+    ///
+    /// This server's configuration applies to every session, its TCP listener is left unused.
+    /// SOCKS5 UDP ASSOCIATE requests are answered with an unspecified address: datagrams, along
+    /// with their UDP request header, are exchanged as QUIC datagrams while the stream which
+    /// requested the association stays open. As datagrams name no stream, a connection carries
+    /// a single association at a time, other requests being refused meanwhile.
+    pub async fn serve_quic<HC, HS, S, FC, FS, R>(
+        self,
+        endpoint: quinn::Endpoint,
+        handle_request: HC,
+        handle_stream: HS,
+    ) -> io::Result<()>
+    where
+        HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+        HS: FnOnce(QuicStream, S) -> FS + Send + Clone + 'static,
+        FC: Future<Output = io::Result<(S, Destination)>> + Send,
+        FS: Future<Output = io::Result<R>> + Send,
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+        R: Relayed,
+    {
+        let permits = self.max_connections.map(|n| Arc::new(Semaphore::new(n)));
+        let shared = Arc::new(self.shared());
+        let mut tasks = JoinSet::new();
+        while let Some(incoming) = endpoint.accept().await {
+            while let Some(joined) = tasks.try_join_next() {
+                reap(joined, &shared.stats);
+            }
+            let handle_request = handle_request.clone();
+            let handle_stream = handle_stream.clone();
+            let shared = Arc::clone(&shared);
+            let permits = permits.clone();
+            tasks.spawn(async move {
+                let peer = incoming.remote_address();
+                let connection = match incoming.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        log::error!("QUIC handshake with {peer} failed: {e}");
+                        return;
+                    }
+                };
+                log::info!("New QUIC connection from {peer}");
+                let datagrams = Datagrams::new(connection.clone());
+                let mut sessions = JoinSet::new();
+                loop {
+                    let (send, recv) = tokio::select! {
+                        accepted = connection.accept_bi() => match accepted {
+                            Ok(streams) => streams,
+                            Err(e) => {
+                                log::debug!("QUIC connection from {peer} closed: {e}");
+                                break;
+                            }
+                        },
+                        Some(joined) = sessions.join_next(), if !sessions.is_empty() => {
+                            reap(joined, &shared.stats);
+                            continue;
+                        }
+                    };
+                    let hc = handle_request.clone();
+                    let hs = handle_stream.clone();
+                    let shared = Arc::clone(&shared);
+                    let datagrams = datagrams.clone();
+                    let permits = permits.clone();
+                    let id = ConnectionId::next();
+                    log::debug!("New stream {id} on the QUIC connection from {peer}");
+                    sessions.spawn(client_task(id, peer, async move {
+                        // Only streams opened by the client count, not idle connections.
+                        let _permit = acquire(permits.as_ref()).await;
+                        let stream = tokio::io::join(recv, send);
+                        Server::handle_client(
+                            stream,
+                            peer,
+                            hc,
+                            hs,
+                            &shared,
+                            Some(&datagrams),
+                            Opening::Handshake,
+                        )
+                        .await
+                    }));
+                }
+                while let Some(joined) = sessions.join_next().await {
+                    reap(joined, &shared.stats);
+                }
+            });
+        }
+        tasks.detach_all();
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    error::Error,
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use crate::{v5::Status, Destination};
+
+/// Error returned by request handlers to pick the reply sent to the client.
+///
+/// Convert it into an [`io::Error`] with `?` or `.into()`; the server looks for it before falling
+/// back to a status derived from the error kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Rejection {
+    /// SOCKS5 reply code, SOCKS4 clients only getting "rejected".
+    Status(Status),
+}
+
+impl Rejection {
+    fn status(self) -> Status {
+        match self {
+            Self::Status(status) => status,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Request rejected ({:?})", self.status())
+    }
+}
+
+impl Error for Rejection {}
+
+impl From<Rejection> for io::Error {
+    fn from(value: Rejection) -> Self {
+        io::Error::new(value.status().error_kind(), value)
+    }
+}
+
+/// SOCKS5 reply code for a failed request.
+pub(super) fn status_for(error: &io::Error) -> Status {
+    if let Some(rejection) = error.get_ref().and_then(|e| e.downcast_ref::<Rejection>()) {
+        return rejection.status();
+    }
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => Status::ConnectionNotAllowed,
+        io::ErrorKind::NetworkUnreachable => Status::NetworkUnreachable,
+        io::ErrorKind::HostUnreachable => Status::HostUnreachalble,
+        io::ErrorKind::ConnectionRefused => Status::ConnectionRefused,
+        io::ErrorKind::TimedOut => Status::TTLExpired,
+        _ => Status::GeneralFailure,
+    }
+}
+
+/// Address reported to clients whose request failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailureReplyAddress {
+    /// `0.0.0.0:0`, as most servers do.
+    #[default]
+    Zeroed,
+    /// The destination the client asked for, which shows up in traffic captures.
+    Requested,
+}
+
+impl FailureReplyAddress {
+    /// Address to reply with for a request to `requested`.
+    pub(super) fn reply_with(self, requested: Destination) -> Destination {
+        match self {
+            Self::Zeroed => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into(),
+            Self::Requested => requested,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use super::Shared;
+use crate::{
+    acl::Acl, auth::Authenticator, policy::UserPolicies, DecodeLimits, ParseMode, Version,
+};
+
+/// Settings of a [`ServerConfig`](crate::config::ServerConfig) which a running server picks up
+/// for its next connections.
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub(crate) versions: Vec<Version>,
+    pub(crate) authenticator: Option<Arc<dyn Authenticator>>,
+    pub(crate) acl: Option<Arc<dyn Acl>>,
+    pub(crate) user_policies: Option<Arc<UserPolicies>>,
+    pub(crate) allow_link_local: bool,
+    pub(crate) allow_private: bool,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) limits: DecodeLimits,
+    pub(crate) parse_mode: ParseMode,
+}
+
+impl Settings {
+    pub(super) fn apply(&self, shared: &mut Shared) {
+        shared.versions = self.versions.clone();
+        shared.authenticator = self.authenticator.clone();
+        shared.acl = self.acl.clone();
+        shared.user_policies = self.user_policies.clone();
+        shared.allow_link_local = self.allow_link_local;
+        shared.allow_private = self.allow_private;
+        shared.handshake_timeout = self.handshake_timeout;
+        shared.limits = self.limits;
+        shared.parse_mode = self.parse_mode;
+    }
+}
+
+/// Handle updating the configuration of a [`Server`](super::Server) once it runs, see
+/// [`Server::config_handle`](super::Server::config_handle).
+#[derive(Clone)]
+pub struct ConfigHandle {
+    updates: Arc<watch::Sender<Option<Arc<Settings>>>>,
+}
+
+impl ConfigHandle {
+    pub(super) fn new() -> Self {
+        Self {
+            updates: Arc::new(watch::Sender::new(None)),
+        }
+    }
+
+    pub(crate) fn replace(&self, settings: Settings) {
+        self.updates.send_replace(Some(Arc::new(settings)));
+    }
+
+    /// Settings of the last update, if any.
+    pub(super) fn current(&self) -> Option<Arc<Settings>> {
+        self.updates.borrow().clone()
+    }
+
+    pub(super) fn subscribe(&self) -> watch::Receiver<Option<Arc<Settings>>> {
+        self.updates.subscribe()
+    }
+}
+
+impl fmt::Debug for ConfigHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigHandle")
+            .field("updated", &self.updates.borrow().is_some())
+            .finish()
+    }
+}
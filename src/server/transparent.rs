@@ -0,0 +1,63 @@
+use std::{io, net::SocketAddr};
+
+use rustix::net::sockopt;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::listener::{Accepted, ErasedListener, TcpOptions};
+use crate::{auth::BoxFuture, stream::DynStream, Destination};
+
+/// Listener of clients redirected to the server by the firewall, which send no SOCKS handshake,
+/// see [`Server::with_transparent_listener`](super::Server::with_transparent_listener).
+pub(super) struct TransparentListener {
+    pub(super) listener: TcpListener,
+    pub(super) options: TcpOptions,
+}
+
+impl ErasedListener for TransparentListener {
+    fn accept(&mut self) -> BoxFuture<'_, io::Result<Accepted>> {
+        Box::pin(async move {
+            loop {
+                let (stream, peer) = self.listener.accept().await?;
+                match self.original_destination(&stream) {
+                    Ok(original) => {
+                        self.options.apply(&stream, peer);
+                        let destination = Some(Destination::from(original));
+                        return Ok((Box::new(stream) as DynStream, peer, destination));
+                    }
+                    Err(e) => log::warn!("Dropping connection from {peer}: {e}"),
+                }
+            }
+        })
+    }
+}
+
+impl TransparentListener {
+    /// Destination `stream` was connected to before being redirected to this listener.
+    ///
+    /// Connections redirected with `REDIRECT` have their original destination recorded by
+    /// connection tracking, while `TPROXY` ones keep it as their local address, as do clients
+    /// connecting to the listener directly.
+    fn original_destination(&self, stream: &TcpStream) -> io::Result<SocketAddr> {
+        let local = stream.local_addr()?;
+        let original = match local {
+            SocketAddr::V4(_) => sockopt::ip_original_dst(stream).map(SocketAddr::V4),
+            SocketAddr::V6(_) => sockopt::ipv6_original_dst(stream).map(SocketAddr::V6),
+        };
+        let original = match original {
+            Ok(original) => original,
+            Err(rustix::io::Errno::NOENT | rustix::io::Errno::NOPROTOOPT) => local,
+            Err(e) => return Err(e.into()),
+        };
+        // Relaying clients which reached the listener itself would loop back to it.
+        let listening = self.listener.local_addr()?;
+        if original.port() == listening.port()
+            && (listening.ip().is_unspecified() || original.ip() == listening.ip())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Connection was not redirected",
+            ));
+        }
+        Ok(original)
+    }
+}
@@ -0,0 +1,40 @@
+use crate::Version;
+
+const HTTP_CONNECT: &[u8] = b"CONNECT ";
+
+/// Protocol guessed from the first bytes sent by a client.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MaybeSocks {
+    Socks(Version),
+    HttpConnect,
+    /// Not enough bytes to tell yet.
+    Incomplete,
+    NotSocks,
+}
+
+impl MaybeSocks {
+    /// Inspects the beginning of a stream without consuming it.
+    ///
+    /// This never fails: traffic which is neither SOCKS nor an HTTP `CONNECT` request is
+    /// reported as [`MaybeSocks::NotSocks`].
+    pub fn detect(buffer: &[u8]) -> Self {
+        match buffer {
+            [] => Self::Incomplete,
+            // VN, CD (connect or bind)
+            [4] | [4, 1 | 2, ..] => Self::Socks(Version::Socks4),
+            // VER, NMETHODS (at least one)
+            [5] | [5, 1..=0xff, ..] => Self::Socks(Version::Socks5),
+            _ if buffer.starts_with(HTTP_CONNECT) => Self::HttpConnect,
+            _ if HTTP_CONNECT.starts_with(buffer) => Self::Incomplete,
+            _ => Self::NotSocks,
+        }
+    }
+}
+
+/// Returns the SOCKS version `buffer` looks like, if any.
+pub fn sniff(buffer: &[u8]) -> Option<Version> {
+    match MaybeSocks::detect(buffer) {
+        MaybeSocks::Socks(version) => Some(version),
+        _ => None,
+    }
+}
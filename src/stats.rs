@@ -0,0 +1,213 @@
+use std::{
+    fmt, io,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{Destination, Version};
+
+/// Connection counters maintained by the server.
+///
+/// Every counter is a relaxed atomic, so the registry can be shared behind an `Arc` and read at
+/// any time without synchronizing with the connection tasks.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    handshakes_v4: AtomicU64,
+    handshakes_v5: AtomicU64,
+    auth_failures: AtomicU64,
+    bytes_relayed: AtomicU64,
+    handler_panics: AtomicU64,
+}
+
+/// Point-in-time copy of [`ServerStats`].
+///
+/// Its `Display` implementation renders the Prometheus text exposition format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub active_connections: u64,
+    pub total_connections: u64,
+    pub handshakes_v4: u64,
+    pub handshakes_v5: u64,
+    pub auth_failures: u64,
+    pub bytes_relayed: u64,
+    pub handler_panics: u64,
+}
+
+/// Values returned by stream handlers which know how many bytes they relayed.
+pub trait Relayed {
+    fn bytes_relayed(&self) -> u64;
+
+    /// Bytes sent to the destination, then back to the client.
+    ///
+    /// Handlers only knowing the total count it all as sent to the destination.
+    fn bytes_each_way(&self) -> (u64, u64) {
+        (self.bytes_relayed(), 0)
+    }
+}
+
+impl Relayed for () {
+    fn bytes_relayed(&self) -> u64 {
+        0
+    }
+}
+
+/// Matches the output of `tokio::io::copy_bidirectional`.
+impl Relayed for (u64, u64) {
+    fn bytes_relayed(&self) -> u64 {
+        self.0 + self.1
+    }
+
+    fn bytes_each_way(&self) -> (u64, u64) {
+        *self
+    }
+}
+
+/// Usage of a tunnel, once the stream handler relaying it returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSummary {
+    /// Destination handed to the request handler, after the server layers.
+    pub destination: Destination,
+    /// Time since the connection was accepted.
+    pub duration: Duration,
+    /// Bytes sent by the client to the destination.
+    pub bytes_up: u64,
+    /// Bytes sent by the destination to the client.
+    pub bytes_down: u64,
+    pub close_reason: CloseReason,
+}
+
+/// How a tunnel ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The stream handler returned successfully, typically once both sides closed.
+    Finished,
+    /// The stream handler failed, byte counters being unknown and left to zero.
+    Failed(io::ErrorKind),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Finished => f.write_str("finished"),
+            Self::Failed(kind) => write!(f, "{kind}"),
+        }
+    }
+}
+
+/// Keeps a connection accounted as active until dropped.
+#[cfg(feature = "async")]
+pub(crate) struct ActiveConnection<'s> {
+    stats: &'s ServerStats,
+}
+
+#[cfg(feature = "async")]
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.stats
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn handshakes(&self, version: Version) -> u64 {
+        match version {
+            Version::Socks4 => self.handshakes_v4.load(Ordering::Relaxed),
+            Version::Socks5 => self.handshakes_v5.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_relayed(&self) -> u64 {
+        self.bytes_relayed.load(Ordering::Relaxed)
+    }
+
+    /// Connection tasks which panicked, usually in a user provided handler.
+    pub fn handler_panics(&self) -> u64 {
+        self.handler_panics.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            active_connections: self.active_connections(),
+            total_connections: self.total_connections(),
+            handshakes_v4: self.handshakes(Version::Socks4),
+            handshakes_v5: self.handshakes(Version::Socks5),
+            auth_failures: self.auth_failures(),
+            bytes_relayed: self.bytes_relayed(),
+            handler_panics: self.handler_panics(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl ServerStats {
+    pub(crate) fn connection_opened(&self) -> ActiveConnection<'_> {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection { stats: self }
+    }
+
+    pub(crate) fn record_handshake(&self, version: Version) {
+        let counter = match version {
+            Version::Socks4 => &self.handshakes_v4,
+            Version::Socks5 => &self.handshakes_v5,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_auth_failure(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_bytes_relayed(&self, n: u64) {
+        self.bytes_relayed.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_handler_panic(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# TYPE socks_active_connections gauge")?;
+        writeln!(f, "socks_active_connections {}", self.active_connections)?;
+        writeln!(f, "# TYPE socks_connections_total counter")?;
+        writeln!(f, "socks_connections_total {}", self.total_connections)?;
+        writeln!(f, "# TYPE socks_handshakes_total counter")?;
+        writeln!(
+            f,
+            "socks_handshakes_total{{version=\"4\"}} {}",
+            self.handshakes_v4
+        )?;
+        writeln!(
+            f,
+            "socks_handshakes_total{{version=\"5\"}} {}",
+            self.handshakes_v5
+        )?;
+        writeln!(f, "# TYPE socks_auth_failures_total counter")?;
+        writeln!(f, "socks_auth_failures_total {}", self.auth_failures)?;
+        writeln!(f, "# TYPE socks_relayed_bytes_total counter")?;
+        writeln!(f, "socks_relayed_bytes_total {}", self.bytes_relayed)?;
+        writeln!(f, "# TYPE socks_handler_panics_total counter")?;
+        writeln!(f, "socks_handler_panics_total {}", self.handler_panics)
+    }
+}
@@ -0,0 +1,241 @@
+//! Stream types letting a single handler return different kinds of streams.
+//!
+//! [`Server::run`](crate::Server::run) expects `handle_request` to always yield the same stream
+//! type. A handler connecting either directly or through TLS can return an [`EitherStream`], and
+//! one with more alternatives a [`DynStream`], such as the handlers wrapped by
+//! [`handlers::boxed`](crate::handlers::boxed).
+//!
+//! A [`StreamTransform`] wraps the tunnel once the SOCKS handshake is done, to encrypt or
+//! compress what is relayed over it.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::auth::BoxFuture;
+
+/// Any stream usable by the server and its handlers.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + ?Sized> AsyncStream for T {}
+
+/// Type-erased stream, at the cost of an allocation and dynamic dispatch.
+pub type DynStream = Box<dyn AsyncStream>;
+
+/// Wraps a stream once its SOCKS handshake is done, such as with TLS or compression, see
+/// [`handlers::transform_client`](crate::handlers::transform_client) and
+/// [`Client::connect_transformed`](crate::Client::connect_transformed).
+///
+/// Implemented by closures taking the stream and returning the future of the wrapped one, and
+/// by `Option`s of transforms, leaving the stream as is when `None`.
+pub trait StreamTransform<S>: Send + Sync {
+    type Output: AsyncStream;
+
+    fn transform(&self, stream: S) -> BoxFuture<'_, io::Result<Self::Output>>;
+}
+
+impl<S, F, Fut, T> StreamTransform<S> for F
+where
+    F: Fn(S) -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: AsyncStream,
+{
+    type Output = T;
+
+    fn transform(&self, stream: S) -> BoxFuture<'_, io::Result<T>> {
+        Box::pin(self(stream))
+    }
+}
+
+impl<S, T: StreamTransform<S> + ?Sized> StreamTransform<S> for Arc<T> {
+    type Output = T::Output;
+
+    fn transform(&self, stream: S) -> BoxFuture<'_, io::Result<T::Output>> {
+        (**self).transform(stream)
+    }
+}
+
+impl<S, T> StreamTransform<S> for Option<T>
+where
+    S: AsyncStream + 'static,
+    T: StreamTransform<S>,
+{
+    type Output = EitherStream<T::Output, S>;
+
+    fn transform(&self, stream: S) -> BoxFuture<'_, io::Result<Self::Output>> {
+        match self {
+            Some(transform) => {
+                Box::pin(async move { transform.transform(stream).await.map(EitherStream::Left) })
+            }
+            None => Box::pin(async move { Ok(EitherStream::Right(stream)) }),
+        }
+    }
+}
+
+/// One of two stream types.
+#[derive(Debug)]
+pub enum EitherStream<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A, B> AsyncRead for EitherStream<A, B>
+where
+    A: AsyncRead + Unpin,
+    B: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Left(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Right(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<A, B> AsyncWrite for EitherStream<A, B>
+where
+    A: AsyncWrite + Unpin,
+    B: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Left(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Right(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Left(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            Self::Right(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Left(s) => s.is_write_vectored(),
+            Self::Right(s) => s.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Left(s) => Pin::new(s).poll_flush(cx),
+            Self::Right(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Left(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Right(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Stream yielding `prefix` before the data of the inner stream, writes going straight to it.
+#[derive(Debug)]
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    consumed: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: impl Into<Vec<u8>>, inner: S) -> Self {
+        Self {
+            prefix: prefix.into(),
+            consumed: 0,
+            inner,
+        }
+    }
+
+    /// Part of the prefix not read yet.
+    pub fn prefix(&self) -> &[u8] {
+        &self.prefix[self.consumed..]
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the unread part of the prefix along with the inner stream.
+    pub fn into_parts(mut self) -> (Vec<u8>, S) {
+        self.prefix.drain(..self.consumed);
+        (self.prefix, self.inner)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let prefix = &this.prefix[this.consumed..];
+        if prefix.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = prefix.len().min(buf.remaining());
+        buf.put_slice(&prefix[..n]);
+        this.consumed += n;
+        if this.consumed == this.prefix.len() {
+            this.prefix = Vec::new();
+            this.consumed = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
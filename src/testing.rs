@@ -0,0 +1,115 @@
+//! In-memory transport to test SOCKS handshakes without binding sockets.
+//!
+//! ```no_run
+//! # async fn test() -> std::io::Result<()> {
+//! use socks_parser::{handlers, testing, Client, ConnectionRequest, Destination};
+//!
+//! async fn handle_request(
+//!     req: ConnectionRequest,
+//! ) -> std::io::Result<(tokio::io::DuplexStream, Destination)> {
+//!     let (stream, _) = tokio::io::duplex(64);
+//!     Ok((stream, req.destination))
+//! }
+//!
+//! let transport = testing::serve(testing::server(), handle_request, handlers::relay);
+//! let stream = transport.connect().await?;
+//! Client::new(stream).connect(("example.com", 80)).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream},
+    sync::mpsc,
+};
+
+use crate::{server::Listener, stats::Relayed, ConnectionRequest, Destination, Server};
+
+/// Capacity of each direction of the in-memory streams.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Client side of an in-memory transport, connected to a [`Server`] by [`serve`].
+#[derive(Debug)]
+pub struct MemoryTransport {
+    connections: mpsc::UnboundedSender<(DuplexStream, SocketAddr)>,
+    next_port: AtomicU16,
+}
+
+/// Server side of a [`MemoryTransport`].
+#[derive(Debug)]
+pub(crate) struct MemoryListener {
+    connections: mpsc::UnboundedReceiver<(DuplexStream, SocketAddr)>,
+}
+
+impl MemoryTransport {
+    pub(crate) fn new() -> (Self, MemoryListener) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let transport = Self {
+            connections: tx,
+            next_port: AtomicU16::new(1),
+        };
+        (transport, MemoryListener { connections: rx })
+    }
+
+    /// Opens a connection, seen by the server as coming from a distinct loopback port.
+    pub async fn connect(&self) -> io::Result<DuplexStream> {
+        let port = self.next_port.fetch_add(1, Ordering::Relaxed);
+        self.connect_from((Ipv4Addr::LOCALHOST, port).into()).await
+    }
+
+    /// Opens a connection, seen by the server as coming from `peer`.
+    pub async fn connect_from(&self, peer: SocketAddr) -> io::Result<DuplexStream> {
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        self.connections.send((server, peer)).map_err(|_| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "Server no longer running")
+        })?;
+        Ok(client)
+    }
+}
+
+impl Listener for MemoryListener {
+    type Stream = DuplexStream;
+
+    async fn accept(&mut self) -> io::Result<(DuplexStream, SocketAddr)> {
+        self.connections
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Memory transport closed"))
+    }
+}
+
+/// [`Server`] without a TCP listener, to configure before handing it to [`serve`].
+pub fn server() -> Server {
+    Server::unbound()
+}
+
+/// Spawns `server` on an in-memory transport, its TCP listener being left unused.
+///
+/// The server stops once the returned transport is dropped.
+pub fn serve<HC, HS, S, FC, FS, R>(
+    server: Server,
+    handle_request: HC,
+    handle_stream: HS,
+) -> MemoryTransport
+where
+    HC: FnOnce(ConnectionRequest) -> FC + Send + Clone + 'static,
+    HS: FnOnce(DuplexStream, S) -> FS + Send + Clone + 'static,
+    FC: Future<Output = io::Result<(S, Destination)>> + Send,
+    FS: Future<Output = io::Result<R>> + Send,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    R: Relayed + 'static,
+{
+    let (transport, listener) = MemoryTransport::new();
+    tokio::spawn(async move {
+        let result = server.serve(listener, handle_request, handle_stream).await;
+        log::debug!("In-memory server stopped: {result:?}");
+    });
+    transport
+}
@@ -0,0 +1,180 @@
+//! Token-bucket bandwidth shaping for relayed streams.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Rates, in bytes per second, enforced by [`handlers::relay_throttled`](crate::handlers::relay_throttled).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(default, deny_unknown_fields)
+)]
+pub struct BandwidthLimit {
+    /// Data sent by the client.
+    pub upload: Option<u64>,
+    /// Data sent back to the client.
+    pub download: Option<u64>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Bytes allowance refilled at a constant rate, up to one second worth of data.
+///
+/// A bucket can be shared between streams to cap their total throughput.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second.max(1) as f64;
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bytes which may be transferred right away, or how long to wait for some.
+    fn available(&self) -> Result<usize, Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let refill = now.duration_since(state.refilled).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.rate);
+        state.refilled = now;
+        if state.tokens >= 1. {
+            Ok(state.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64((1. - state.tokens) / self.rate))
+        }
+    }
+
+    /// Concurrent users of a shared bucket may overdraw it, delaying the next transfers.
+    fn consume(&self, n: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.tokens -= n as f64;
+    }
+}
+
+/// Stream whose reads are limited by a [`TokenBucket`], writes being left alone unless given
+/// a bucket of their own with [`Throttled::with_write_bucket`].
+pub struct Throttled<S> {
+    inner: S,
+    bucket: Option<Arc<TokenBucket>>,
+    delay: Option<Pin<Box<Sleep>>>,
+    scratch: Vec<u8>,
+    write_bucket: Option<Arc<TokenBucket>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    /// No limit applies without `bucket`.
+    pub fn new(inner: S, bucket: Option<Arc<TokenBucket>>) -> Self {
+        Self {
+            inner,
+            bucket,
+            delay: None,
+            scratch: Vec::new(),
+            write_bucket: None,
+            write_delay: None,
+        }
+    }
+
+    /// Also limits writes, with `bucket` if any.
+    pub fn with_write_bucket(mut self, bucket: Option<Arc<TokenBucket>>) -> Self {
+        self.write_bucket = bucket;
+        self
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(ref bucket) = this.bucket else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        loop {
+            if let Some(ref mut delay) = this.delay {
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+            match bucket.available() {
+                Ok(n) => {
+                    let n = n.min(buf.remaining());
+                    this.scratch.resize(n, 0);
+                    let mut limited = ReadBuf::new(&mut this.scratch[..n]);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+                    let read = limited.filled();
+                    bucket.consume(read.len());
+                    buf.put_slice(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Err(wait) => this.delay = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(ref bucket) = this.write_bucket else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        loop {
+            if let Some(ref mut delay) = this.write_delay {
+                ready!(delay.as_mut().poll(cx));
+                this.write_delay = None;
+            }
+            match bucket.available() {
+                Ok(n) => {
+                    let n = n.min(buf.len());
+                    let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..n]))?;
+                    bucket.consume(written);
+                    return Poll::Ready(Ok(written));
+                }
+                Err(wait) => this.write_delay = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,200 @@
+//! Handling of the UDP datagrams relayed for SOCKS5 UDP associations.
+//!
+//! Clients may split a datagram into fragments, numbered by the `frag` field of their
+//! [`UdpHeader`] from 1, the last one having its high-order bit set (RFC 1928, section 7).
+//! Few clients do, and most servers drop them: see [`FragmentPolicy`].
+//!
+//! The relay only accepts datagrams from the client which requested the association, see
+//! [`ClientSource`].
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use crate::{v5::UdpHeader, Destination};
+
+/// Bit of the `frag` field marking the last fragment of a datagram.
+const END_OF_SEQUENCE: u8 = 0x80;
+
+/// What the UDP relay does with fragmented datagrams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FragmentPolicy {
+    /// Drops them, as most servers do.
+    #[default]
+    Drop,
+    /// Reassembles them with a [`Reassembler`], abandoning datagrams whose fragments did not
+    /// all arrive within `timeout`, which RFC 1928 requires to be at least 5 seconds.
+    Reassemble { timeout: Duration },
+}
+
+/// Settings of the UDP relay, see
+/// [`Server::with_udp_relay_options`](crate::Server::with_udp_relay_options).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UdpRelayOptions {
+    pub(crate) fragmentation: FragmentPolicy,
+    pub(crate) bind_address: Option<IpAddr>,
+}
+
+impl UdpRelayOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fragmented datagrams are dropped by default.
+    pub fn fragmentation(mut self, policy: FragmentPolicy) -> Self {
+        self.fragmentation = policy;
+        self
+    }
+
+    /// Relays the datagrams of clients connected over TCP on a UDP socket bound to `ip`, which
+    /// they are told to send them to.
+    ///
+    /// `ip` must be reachable by clients. Without it, UDP ASSOCIATE requests of TCP clients are
+    /// rejected as not supported.
+    pub fn bind_address(mut self, ip: IpAddr) -> Self {
+        self.bind_address = Some(ip);
+        self
+    }
+}
+
+#[derive(Debug)]
+struct Sequence {
+    destination: Destination,
+    /// Position of the last fragment received.
+    position: u8,
+    payload: Vec<u8>,
+    started: Instant,
+}
+
+/// Reassembly queue of the fragments sent by a client over a UDP association.
+///
+/// There is a single queue per association: a fragment out of order, a standalone datagram or
+/// the expiry of the timeout abandons the fragments received so far.
+#[derive(Debug)]
+pub struct Reassembler {
+    timeout: Duration,
+    sequence: Option<Sequence>,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sequence: None,
+        }
+    }
+
+    /// Feeds the datagram of a client received at `now`, returning a whole datagram once
+    /// complete, along with its destination.
+    ///
+    /// Standalone datagrams are returned right away.
+    pub fn push(
+        &mut self,
+        header: UdpHeader,
+        payload: &[u8],
+        now: Instant,
+    ) -> Option<(Destination, Vec<u8>)> {
+        let destination = Destination {
+            addr: header.addr,
+            port: header.port,
+        };
+        if header.frag == 0 {
+            self.abandon();
+            return Some((destination, payload.to_vec()));
+        }
+
+        let position = header.frag & !END_OF_SEQUENCE;
+        let sequence = match self.sequence.take() {
+            _ if position == 1 => self.sequence.insert(Sequence {
+                destination,
+                position,
+                payload: Vec::new(),
+                started: now,
+            }),
+            Some(sequence)
+                if position == sequence.position + 1
+                    && sequence.destination == destination
+                    && now.duration_since(sequence.started) < self.timeout =>
+            {
+                self.sequence.insert(sequence)
+            }
+            abandoned => {
+                if abandoned.is_some() {
+                    log::debug!("Abandoning fragments, got fragment {position} out of sequence");
+                }
+                return None;
+            }
+        };
+        sequence.position = position;
+        sequence.payload.extend_from_slice(payload);
+        if sequence.payload.len() > u16::MAX as usize {
+            log::debug!("Abandoning fragments of an oversized datagram");
+            self.abandon();
+            return None;
+        }
+        if header.frag & END_OF_SEQUENCE == 0 {
+            return None;
+        }
+        self.sequence
+            .take()
+            .map(|sequence| (sequence.destination, sequence.payload))
+    }
+
+    /// Drops the fragments received so far.
+    pub fn abandon(&mut self) {
+        self.sequence = None;
+    }
+}
+
+/// Address of the client of a UDP association, as far as the relay knows it.
+///
+/// Datagrams are only accepted from the host of the client's control connection. Clients
+/// declare the port they send datagrams from in their UDP ASSOCIATE request, zeroing it if they
+/// do not know it yet (typically behind a NAT), in which case it is learnt from the first
+/// datagram. A port learnt this way follows the client to its new port if its NAT rebinds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientSource {
+    ip: IpAddr,
+    port: Option<u16>,
+    learnt: Option<SocketAddr>,
+}
+
+impl ClientSource {
+    /// Source of the client connected from `peer` which declared `declared`, only the port of
+    /// the declaration being kept: an address would let any host take over the association.
+    pub fn new(declared: &Destination, peer: IpAddr) -> Self {
+        Self {
+            ip: peer.to_canonical(),
+            port: (declared.port != 0).then_some(declared.port),
+            learnt: None,
+        }
+    }
+
+    /// Whether to relay a datagram received from `from`, learning the address of the client if
+    /// it is the first one.
+    pub fn accept(&mut self, from: SocketAddr) -> bool {
+        let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+        if self.ip != from.ip() || self.port.is_some_and(|p| p != from.port()) {
+            return false;
+        }
+        match self.learnt {
+            Some(client) => {
+                if client != from {
+                    log::debug!("Client of UDP association moved from {client} to {from}");
+                    self.learnt = Some(from);
+                }
+                true
+            }
+            None => {
+                self.learnt = Some(from);
+                true
+            }
+        }
+    }
+
+    /// Address to relay replies to, once the client sent a datagram.
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.learnt
+    }
+}
@@ -0,0 +1,166 @@
+//! JavaScript bindings to the wire format.
+//!
+//! The crate is only built as a `cdylib` on request, for instance with
+//! `cargo rustc --lib --crate-type cdylib --target wasm32-unknown-unknown --release
+//! --no-default-features --features wasm`, the module then going through `wasm-bindgen`.
+
+use wasm_bindgen::prelude::*;
+
+use crate::{parse_request, v4, v5, AnyRequest, ParseError, Wire};
+
+type Error<'i> = nom::error::VerboseError<&'i [u8]>;
+
+/// Client message decoded by [`decode_request`].
+#[wasm_bindgen]
+pub struct DecodedRequest {
+    version: u8,
+    command: Option<u8>,
+    host: Option<String>,
+    port: Option<u16>,
+    methods: Vec<u8>,
+    consumed: usize,
+}
+
+#[wasm_bindgen]
+impl DecodedRequest {
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Unset for SOCKS5 hellos.
+    #[wasm_bindgen(getter)]
+    pub fn command(&self) -> Option<u8> {
+        self.command
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn host(&self) -> Option<String> {
+        self.host.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn port(&self) -> Option<u16> {
+        self.port
+    }
+
+    /// Authentication methods offered by a SOCKS5 hello.
+    #[wasm_bindgen(getter)]
+    pub fn methods(&self) -> Vec<u8> {
+        self.methods.clone()
+    }
+
+    /// Number of input bytes making up the message.
+    #[wasm_bindgen(getter)]
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+}
+
+fn command_v4(command: u8) -> Result<v4::Command, JsError> {
+    match command {
+        1 => Ok(v4::Command::Connect),
+        2 => Ok(v4::Command::Bind),
+        _ => Err(JsError::new("Invalid SOCKS4 command")),
+    }
+}
+
+fn command_v5(command: u8) -> Result<v5::Command, JsError> {
+    match command {
+        1 => Ok(v5::Command::Connect),
+        2 => Ok(v5::Command::Bind),
+        3 => Ok(v5::Command::UdpAssociate),
+        #[cfg(feature = "tor")]
+        0xf0 => Ok(v5::Command::TorResolve),
+        #[cfg(feature = "tor")]
+        0xf1 => Ok(v5::Command::TorResolvePtr),
+        #[cfg(feature = "mux")]
+        0x80 => Ok(v5::Command::Mux),
+        _ => Err(JsError::new("Invalid SOCKS5 command")),
+    }
+}
+
+fn address(host: &str) -> Result<v5::AddressType, JsError> {
+    match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => Ok(ip.into()),
+        Err(_) => v5::AddressType::domain(host).map_err(|e| JsError::new(&e.to_string())),
+    }
+}
+
+/// Decodes the first message sent by a SOCKS client.
+#[wasm_bindgen(js_name = decodeRequest)]
+pub fn decode_request(bytes: &[u8]) -> Result<DecodedRequest, JsError> {
+    let (rest, req) = parse_request::<Error>(bytes)
+        .map_err(|e| JsError::new(&ParseError::new(bytes, e).to_string()))?;
+    let mut decoded = DecodedRequest {
+        version: req.version() as u8,
+        command: None,
+        host: None,
+        port: None,
+        methods: Vec::new(),
+        consumed: bytes.len() - rest.len(),
+    };
+    match req {
+        AnyRequest::V4(req) => {
+            decoded.command = Some(req.command as u8);
+            decoded.host = Some(crate::v5::AddressType::from(req.addr).to_string());
+            decoded.port = Some(req.port);
+        }
+        AnyRequest::V5Hello(hello) => {
+            decoded.methods = hello.methods.iter().map(|m| m.as_u8()).collect();
+        }
+        AnyRequest::V5(req) => {
+            decoded.command = Some(req.command.as_u8());
+            decoded.host = Some(req.addr.to_string());
+            decoded.port = Some(req.port);
+        }
+    }
+    Ok(decoded)
+}
+
+/// Encodes a SOCKS5 request, `host` being an IP address or a domain name.
+#[wasm_bindgen(js_name = encodeRequestV5)]
+pub fn encode_request_v5(command: u8, host: &str, port: u16) -> Result<Vec<u8>, JsError> {
+    let mut buffer = Vec::new();
+    v5::Request {
+        command: command_v5(command)?,
+        rsv: 0,
+        addr: address(host)?,
+        port,
+    }
+    .encode_into(&mut buffer);
+    Ok(buffer)
+}
+
+/// Encodes a SOCKS4 request, using SOCKS4a when `host` is a domain name.
+#[wasm_bindgen(js_name = encodeRequestV4)]
+pub fn encode_request_v4(
+    command: u8,
+    host: &str,
+    port: u16,
+    user_id: Option<String>,
+) -> Result<Vec<u8>, JsError> {
+    let addr =
+        v4::AddressType::try_from(address(host)?).map_err(|e| JsError::new(&e.to_string()))?;
+    let mut buffer = Vec::new();
+    v4::Request {
+        command: command_v4(command)?,
+        addr,
+        port,
+        secret: user_id,
+    }
+    .try_encode_into(&mut buffer, v4::Request::DEFAULT_MARKER)
+    .map_err(|e| JsError::new(&e.to_string()))?;
+    Ok(buffer)
+}
+
+/// Encodes a SOCKS5 hello offering `methods`.
+#[wasm_bindgen(js_name = encodeHello)]
+pub fn encode_hello(methods: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    v5::Hello {
+        methods: methods.iter().copied().map(Into::into).collect(),
+    }
+    .encode_into(&mut buffer);
+    buffer
+}
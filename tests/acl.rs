@@ -0,0 +1,134 @@
+use std::{fs, time::Duration};
+
+use socks_parser::{
+    acl::{Acl, AclRules, Action, FileWatcherAcl},
+    v5::AddressType,
+    Destination,
+};
+
+fn dest(host: &str, port: u16) -> Destination {
+    let addr = match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => ip.into(),
+        Err(_) => AddressType::DomainName(host.into()),
+    };
+    Destination { addr, port }
+}
+
+#[test]
+fn first_matching_rule_wins() {
+    let acl = AclRules::parse(
+        "# local services\n\
+         allow 10.0.0.1:22\n\
+         deny 10.0.0.0/8\n\
+         deny [fd00::]/8\n\
+         allow *.example.com:443\n\
+         deny example.com:1-1024 # trailing comment\n\
+         default allow\n",
+    )
+    .unwrap();
+
+    assert_eq!(acl.check(&dest("10.0.0.1", 22)), Action::Allow);
+    assert_eq!(acl.check(&dest("10.0.0.1", 23)), Action::Deny);
+    assert_eq!(acl.check(&dest("10.255.0.1", 80)), Action::Deny);
+    assert_eq!(acl.check(&dest("11.0.0.1", 80)), Action::Allow);
+    assert_eq!(acl.check(&dest("fd12::1", 80)), Action::Deny);
+    assert_eq!(acl.check(&dest("WWW.Example.com", 443)), Action::Allow);
+    assert_eq!(acl.check(&dest("example.com", 443)), Action::Deny);
+    assert_eq!(acl.check(&dest("badexample.com", 443)), Action::Allow);
+    assert_eq!(acl.check(&dest("example.com", 8080)), Action::Allow);
+}
+
+#[test]
+fn networks_match_every_spelling_of_an_address() {
+    let acl = AclRules::parse("deny 10.0.0.0/8\ndeny [fd00::]/8\n").unwrap();
+    for addr in [
+        AddressType::IPv6("::ffff:10.0.0.1".parse().unwrap()),
+        AddressType::DomainName("10.0.0.1".into()),
+        AddressType::DomainName("[::ffff:10.0.0.1]".into()),
+        AddressType::DomainName("[fd00::1]".into()),
+    ] {
+        let destination = Destination { addr, port: 80 };
+        assert_eq!(acl.check(&destination), Action::Deny, "{destination}");
+    }
+    let destination = Destination {
+        addr: AddressType::DomainName("10.0.0.1.example.com".into()),
+        port: 80,
+    };
+    assert_eq!(acl.check(&destination), Action::Allow);
+}
+
+#[test]
+fn default_deny() {
+    assert!(AclRules::parse("allow *:http\n").is_err());
+    assert!(AclRules::parse("default maybe\n").is_err());
+
+    let acl = AclRules::parse("allow *:80\ndefault deny\n").unwrap();
+    assert_eq!(acl.check(&dest("example.com", 80)), Action::Allow);
+    assert_eq!(acl.check(&dest("example.com", 81)), Action::Deny);
+}
+
+#[test]
+fn file_watcher_reloads_rules() {
+    let path = std::env::temp_dir().join(format!("socks-acl-{}.txt", std::process::id()));
+    fs::write(&path, "deny example.com\n").unwrap();
+    let acl = FileWatcherAcl::with_interval(&path, Duration::ZERO).unwrap();
+    assert_eq!(acl.check(&dest("example.com", 80)), Action::Deny);
+
+    fs::write(&path, "allow example.com\ndefault deny\n").unwrap();
+    acl.reload().unwrap();
+    assert_eq!(acl.check(&dest("example.com", 80)), Action::Allow);
+    assert_eq!(acl.check(&dest("example.org", 80)), Action::Deny);
+
+    // Broken files keep the previous rules.
+    fs::write(&path, "maybe example.com\n").unwrap();
+    assert!(acl.reload().is_err());
+    assert_eq!(acl.check(&dest("example.com", 80)), Action::Allow);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn file_watcher_polls_for_changes() {
+    let path = std::env::temp_dir().join(format!("socks-acl-poll-{}.txt", std::process::id()));
+    fs::write(&path, "deny example.com\n").unwrap();
+    let acl = FileWatcherAcl::with_interval(&path, Duration::from_millis(10)).unwrap();
+    assert_eq!(acl.check(&dest("example.com", 80)), Action::Deny);
+
+    fs::write(&path, "allow example.com\ndefault deny\n").unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while acl.check(&dest("example.com", 80)) == Action::Deny {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Rules reloaded by polling");
+    assert_eq!(acl.check(&dest("example.org", 80)), Action::Deny);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn private_ranges_preset_denies_internal_destinations() {
+    let acl = socks_parser::acl::block_private_ranges();
+    for host in [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.31.255.255",
+        "192.168.0.1",
+        "100.64.0.1",
+        "169.254.169.254",
+        "0.0.0.0",
+        "::1",
+        "fe80::1",
+        "fd00:ec2::254",
+        "::ffff:10.0.0.1",
+        "localhost",
+        "api.localhost",
+        "metadata.google.internal",
+    ] {
+        assert_eq!(acl.check(&dest(host, 80)), Action::Deny, "{host}");
+    }
+    for host in ["192.0.2.1", "172.32.0.1", "2001:db8::1", "example.com"] {
+        assert_eq!(acl.check(&dest(host, 80)), Action::Allow, "{host}");
+    }
+}
@@ -0,0 +1,189 @@
+#![cfg(feature = "audit")]
+
+use std::{io, net::SocketAddr, time::SystemTime};
+
+use socks_parser::{
+    acl::AclRules,
+    audit::{AuditEvent, AuditKind, AuditSink, JsonlSink},
+    auth::{BoxFuture, StaticUserDb},
+    handlers,
+    v5::AddressType,
+    Client, ConnectionRequest, Destination, Version,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+};
+
+struct Collector(mpsc::UnboundedSender<AuditEvent>);
+
+impl AuditSink for Collector {
+    fn record(&self, event: AuditEvent) -> BoxFuture<'_, io::Result<()>> {
+        let result = self
+            .0
+            .send(event)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Test finished"));
+        Box::pin(async move { result })
+    }
+}
+
+async fn handle_request(req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+    let (stream, mut remote) = tokio::io::duplex(64);
+    tokio::spawn(async move { tokio::io::copy(&mut remote, &mut tokio::io::sink()).await });
+    Ok((stream, req.destination))
+}
+
+async fn next(events: &mut mpsc::UnboundedReceiver<AuditEvent>) -> AuditKind {
+    events.recv().await.unwrap().kind
+}
+
+#[tokio::test]
+async fn records_client_activity() {
+    let (tx, mut events) = mpsc::unbounded_channel();
+    let server = socks_parser::testing::server()
+        .with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+        .with_acl(AclRules::parse("deny denied.test").unwrap())
+        .with_audit_sink(Collector(tx));
+    let transport = socks_parser::testing::serve(server, handle_request, handlers::relay);
+    let stream = transport.connect().await.unwrap();
+    assert!(Client::new(stream)
+        .with_username_password("alice", "wrong")
+        .connect(("example.com", 80))
+        .await
+        .is_err());
+
+    let stream = transport.connect().await.unwrap();
+    assert!(Client::new(stream)
+        .with_username_password("alice", "secret")
+        .connect(("denied.test", 80))
+        .await
+        .is_err());
+
+    let stream = transport.connect().await.unwrap();
+    let mut stream = Client::new(stream)
+        .with_username_password("alice", "secret")
+        .connect(("example.com", 80))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    drop(stream);
+
+    assert_eq!(
+        next(&mut events).await,
+        AuditKind::AuthFailed {
+            username: Some("alice".into())
+        }
+    );
+    let alice = AuditKind::AuthSucceeded {
+        username: "alice".into(),
+    };
+    assert_eq!(next(&mut events).await, alice);
+    assert!(matches!(
+        next(&mut events).await,
+        AuditKind::RequestDenied { destination, .. }
+            if destination.addr == AddressType::DomainName("denied.test".into())
+    ));
+    assert_eq!(next(&mut events).await, alice);
+    assert_eq!(
+        next(&mut events).await,
+        AuditKind::RequestAllowed {
+            destination: Destination {
+                addr: AddressType::DomainName("example.com".into()),
+                port: 80,
+            },
+            username: Some("alice".into()),
+        }
+    );
+    assert!(matches!(
+        next(&mut events).await,
+        AuditKind::ConnectionClosed { summary } if summary.bytes_up + summary.bytes_down == 4
+    ));
+}
+
+#[tokio::test]
+async fn jsonl_sink_writes_one_line_per_event() {
+    let sink = JsonlSink::new(Vec::new());
+    let peer: SocketAddr = "192.0.2.1:41000".parse().unwrap();
+    for username in [Some("al\"ice"), None] {
+        sink.record(AuditEvent {
+            time: SystemTime::UNIX_EPOCH,
+            peer,
+            kind: AuditKind::AuthFailed {
+                username: username.map(Into::into),
+            },
+        })
+        .await
+        .unwrap();
+    }
+    assert_eq!(
+        String::from_utf8(sink.into_inner()).unwrap(),
+        concat!(
+            r#"{"timestamp_ms":0,"peer":"192.0.2.1:41000","event":"auth_failed","username":"al\"ice"}"#,
+            "\n",
+            r#"{"timestamp_ms":0,"peer":"192.0.2.1:41000","event":"auth_failed","username":null}"#,
+            "\n",
+        )
+    );
+}
+
+#[tokio::test]
+async fn disabled_versions_are_rejected() {
+    let (tx, mut events) = mpsc::unbounded_channel();
+    let server = socks_parser::testing::server()
+        .with_versions([Version::Socks4])
+        .with_audit_sink(Collector(tx));
+    let transport = socks_parser::testing::serve(server, handle_request, handlers::relay);
+    let mut stream = transport.connect().await.unwrap();
+    stream.write_all(&[5, 1, 0]).await.unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [5, 0xff]);
+    assert_eq!(
+        next(&mut events).await,
+        AuditKind::VersionRejected {
+            version: Version::Socks5
+        }
+    );
+}
+
+#[tokio::test]
+async fn honeypot_records_what_clients_send() {
+    use std::time::Duration;
+
+    use socks_parser::honeypot::{Honeypot, Tarpit};
+
+    let (tx, mut events) = mpsc::unbounded_channel();
+    let bound = SocketAddr::from(([203, 0, 113, 7], 40000));
+    let honeypot = Honeypot::new(bound)
+        .with_tarpit(Tarpit::Drip {
+            banner: b"220 ".to_vec(),
+            interval: Duration::from_millis(1),
+        })
+        .with_audit_sink(Collector(tx), 8);
+    let transport = socks_parser::testing::serve(
+        socks_parser::testing::server(),
+        handlers::honeypot(honeypot),
+        handlers::relay,
+    );
+    let stream = transport.connect().await.unwrap();
+    let mut negotiated = Client::new(stream).handshake_only().await.unwrap();
+    let reported = negotiated
+        .request(socks_parser::v5::Command::Connect, ("mail.example", 25))
+        .await
+        .unwrap();
+    assert_eq!(reported, Destination::from(bound));
+    let mut stream = negotiated.into_inner();
+    stream.write_all(b"EHLO spammer.example").await.unwrap();
+    let mut banner = [0; 6];
+    stream.read_exact(&mut banner).await.unwrap();
+    assert_eq!(&banner, b"220 22");
+    drop(stream);
+
+    assert_eq!(
+        next(&mut events).await,
+        AuditKind::HoneypotPayload {
+            destination: Destination::from((AddressType::DomainName("mail.example".into()), 25)),
+            payload: b"EHLO spa".to_vec(),
+        }
+    );
+}
@@ -0,0 +1,29 @@
+#![cfg(all(feature = "async", feature = "bcrypt"))]
+
+use std::time::Duration;
+
+use socks_parser::auth::{Authenticator, HtpasswdFile};
+
+#[tokio::test(flavor = "current_thread")]
+async fn htpasswd_hashes_are_checked_off_the_runtime() {
+    let hash = bcrypt::hash("secret", 10).unwrap();
+    let users = HtpasswdFile::parse(&format!("alice:{hash}\n")).unwrap();
+
+    // The runtime keeps serving other tasks while a hash is checked.
+    let ticks = tokio::spawn(async {
+        let mut ticks = 0;
+        loop {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            ticks += 1;
+            if ticks == 5 {
+                return ticks;
+            }
+        }
+    });
+    let identity = users.verify("alice", "secret").await.unwrap().unwrap();
+    assert_eq!(identity.username, "alice");
+    assert!(ticks.is_finished(), "Runtime blocked by the hash check");
+
+    assert_eq!(users.verify("alice", "wrong").await.unwrap(), None);
+    assert_eq!(users.verify("bob", "secret").await.unwrap(), None);
+}
@@ -0,0 +1,62 @@
+#![cfg(feature = "cli")]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    thread,
+};
+
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn connects_through_reference_server() {
+    let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = echo.accept().unwrap();
+        let mut reader = stream.try_clone().unwrap();
+        std::io::copy(&mut reader, &mut stream).unwrap();
+    });
+
+    let mut server = KillOnDrop(
+        Command::new(env!("CARGO_BIN_EXE_socks-server"))
+            .args(["--listen", "127.0.0.1:0", "--user", "alice:secret"])
+            .env_remove("RUST_LOG")
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let mut stderr = BufReader::new(server.0.stderr.take().unwrap());
+    let proxy = loop {
+        let mut line = String::new();
+        assert_ne!(stderr.read_line(&mut line).unwrap(), 0, "server exited");
+        if let Some((_, addr)) = line.trim().split_once("Listening on ") {
+            break addr.to_owned();
+        }
+    };
+    TcpStream::connect(&proxy).unwrap();
+
+    let mut client = KillOnDrop(
+        Command::new(env!("CARGO_BIN_EXE_socks-connect"))
+            .args(["--proxy", &format!("socks5://alice:secret@{proxy}")])
+            .args([&echo_addr.ip().to_string(), &echo_addr.port().to_string()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let mut stdin = client.0.stdin.take().unwrap();
+    stdin.write_all(b"ping\n").unwrap();
+    let mut stdout = BufReader::new(client.0.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert_eq!(line, "ping\n");
+}
@@ -0,0 +1,468 @@
+#![cfg(feature = "async")]
+
+use socks_parser::{v4, Client, Wire};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+type Error<'i> = nom::error::VerboseError<&'i [u8]>;
+
+#[tokio::test]
+async fn downgrades_to_socks4a() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        // SOCKS4-only proxy, rejecting what it does not understand.
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0; 64];
+        // Hello offering no authentication.
+        stream.read_exact(&mut buffer[..3]).await.unwrap();
+        stream
+            .write_all(&[0, 0x5b, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        drop(stream);
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let n = stream.read(&mut buffer).await.unwrap();
+        let (_, request) = v4::Request::decode::<Error>(&buffer[..n]).unwrap();
+        stream
+            .write_all(&[0, 0x5a, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        request
+    });
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    Client::new(stream)
+        .connect_or_downgrade(("example.com", 80), || TcpStream::connect(proxy))
+        .await
+        .unwrap();
+
+    let request = server.await.unwrap();
+    assert_eq!(
+        request.addr,
+        v4::AddressType::DomainName("example.com".into())
+    );
+    assert_eq!(request.port, 80);
+}
+
+/// Answers a Tor-style handshake authenticated with `token`, then replies with `reply` to the
+/// request, which is returned.
+#[cfg(feature = "tor")]
+async fn fake_tor(
+    mut stream: tokio::io::DuplexStream,
+    token: &str,
+    reply: socks_parser::v5::Response,
+) -> socks_parser::v5::Request {
+    use socks_parser::v5;
+
+    let mut buffer = [0; 512];
+    let n = stream.read(&mut buffer).await.unwrap();
+    let (_, hello) = v5::Hello::decode::<Error>(&buffer[..n]).unwrap();
+    assert!(hello
+        .methods
+        .contains(&v5::AuthenticationMethod::UsernamePassword));
+    stream.write_all(&[5, 2]).await.unwrap();
+    let n = stream.read(&mut buffer).await.unwrap();
+    let (_, auth) = v5::UsernamePassword::decode::<Error>(&buffer[..n]).unwrap();
+    assert_eq!((&auth.username[..], &auth.password[..]), (token, token));
+    stream.write_all(&[1, 0]).await.unwrap();
+    let n = stream.read(&mut buffer).await.unwrap();
+    let (_, request) = v5::Request::decode::<Error>(&buffer[..n]).unwrap();
+    let mut response = Vec::new();
+    reply.encode_into(&mut response);
+    stream.write_all(&response).await.unwrap();
+    request
+}
+
+#[cfg(feature = "tor")]
+#[tokio::test]
+async fn tor_extensions() {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use socks_parser::v5::{self, AddressType, Command, Status};
+
+    let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7));
+    let (client, server) = tokio::io::duplex(512);
+    let server = tokio::spawn(fake_tor(
+        server,
+        "circuit-1",
+        v5::Response {
+            status: Status::Success,
+            addr: ip.into(),
+            port: 0,
+        },
+    ));
+    let resolved = Client::new(client)
+        .with_isolation("circuit-1")
+        .resolve("example.com")
+        .await
+        .unwrap();
+    assert_eq!(resolved, ip);
+    let request = server.await.unwrap();
+    assert_eq!(request.command, Command::TorResolve);
+    assert_eq!(request.addr, AddressType::DomainName("example.com".into()));
+
+    let (client, server) = tokio::io::duplex(512);
+    let server = tokio::spawn(fake_tor(
+        server,
+        "circuit-2",
+        v5::Response {
+            status: Status::Success,
+            addr: AddressType::DomainName("example.com".into()),
+            port: 0,
+        },
+    ));
+    let name = Client::new(client)
+        .with_isolation("circuit-2")
+        .resolve_ptr(ip)
+        .await
+        .unwrap();
+    assert_eq!(name, "example.com");
+    assert_eq!(server.await.unwrap().command, Command::TorResolvePtr);
+
+    // Onion service descriptor cannot be found.
+    let (client, server) = tokio::io::duplex(512);
+    tokio::spawn(fake_tor(
+        server,
+        "circuit-3",
+        v5::Response {
+            status: Status::TorExtended(0xf0),
+            addr: AddressType::IPv4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+        },
+    ));
+    let error = Client::new(client)
+        .with_isolation("circuit-3")
+        .connect(("example.onion", 80))
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "TorExtended(240)");
+
+    let mut reply = Vec::new();
+    Status::TorExtended(0xf6).encode_into(&mut reply);
+    assert_eq!(reply, [0xf6]);
+}
+
+#[tokio::test]
+async fn leaves_data_following_the_reply() {
+    let (client, mut server) = tokio::io::duplex(512);
+    tokio::spawn(async move {
+        // Hello offering no authentication, then the request.
+        let mut buffer = [0; 18];
+        server.read_exact(&mut buffer[..3]).await.unwrap();
+        server.write_all(&[5, 0]).await.unwrap();
+        server.read_exact(&mut buffer).await.unwrap();
+        // Reply, then the destination banner, in a single write.
+        let mut reply = vec![5, 0, 0, 1, 192, 0, 2, 1, 0, 80];
+        reply.extend_from_slice(b"220 ready\r\n");
+        server.write_all(&reply).await.unwrap();
+    });
+
+    let mut stream = Client::new(client)
+        .connect(("example.com", 25))
+        .await
+        .unwrap();
+    let mut banner = [0; 11];
+    stream.read_exact(&mut banner).await.unwrap();
+    assert_eq!(&banner, b"220 ready\r\n");
+}
+
+#[tokio::test]
+async fn leaves_data_following_the_socks4_reply() {
+    let (client, mut server) = tokio::io::duplex(512);
+    tokio::spawn(async move {
+        let mut buffer = [0; 9];
+        server.read_exact(&mut buffer).await.unwrap();
+        server
+            .write_all(b"\x00\x5a\x00\x50\xc0\x00\x02\x01SSH-2.0-test\r\n")
+            .await
+            .unwrap();
+    });
+
+    let mut stream = Client::new_with_version(client, socks_parser::Version::Socks4)
+        .connect(std::net::SocketAddr::from(([192, 0, 2, 1], 22)))
+        .await
+        .unwrap();
+    let mut banner = [0; 14];
+    stream.read_exact(&mut banner).await.unwrap();
+    assert_eq!(&banner, b"SSH-2.0-test\r\n");
+}
+
+#[tokio::test]
+async fn falls_through_to_the_next_proxy_without_waiting() {
+    use std::time::Duration;
+
+    use socks_parser::{
+        handlers,
+        proxy::{ProxyUrl, RetryPolicy},
+        ConnectionRequest, Server,
+    };
+
+    let dead = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let dead_addr = dead.local_addr().unwrap();
+    drop(dead);
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let live_addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run(
+        |req: ConnectionRequest| async move {
+            let (stream, _) = tokio::io::duplex(64);
+            Ok((stream, req.destination))
+        },
+        handlers::relay,
+    ));
+
+    let proxies: Vec<ProxyUrl> = [dead_addr, live_addr]
+        .iter()
+        .map(|addr| format!("socks5://{addr}").parse().unwrap())
+        .collect();
+    let policy = RetryPolicy {
+        max_rounds: 2,
+        initial_backoff: Duration::from_secs(60),
+        max_backoff: Duration::from_secs(60),
+        jitter: false,
+    };
+    let (_, proxy) = tokio::time::timeout(
+        Duration::from_secs(5),
+        Client::connect_with_fallback(&proxies, ("example.com", 80), policy),
+    )
+    .await
+    .expect("No backoff before the next proxy")
+    .unwrap();
+    assert_eq!(proxy, &proxies[1]);
+}
+
+#[tokio::test]
+async fn pools_negotiated_connections() {
+    use std::{sync::Arc, time::Duration};
+
+    use socks_parser::{auth::StaticUserDb, handlers, pool::ClientPool, ConnectionRequest, Server};
+
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server =
+        Server::new(listener).with_authenticator(StaticUserDb::new().with_user("alice", "secret"));
+    let stats = server.stats();
+    tokio::spawn(server.run(
+        |req: ConnectionRequest| async move {
+            let stream = TcpStream::connect(("127.0.0.1", req.destination.port)).await?;
+            let bound = stream.local_addr()?.into();
+            Ok((stream, bound))
+        },
+        handlers::relay,
+    ));
+
+    let pool = Arc::new(
+        ClientPool::new(format!("socks5://alice:secret@{proxy}").parse().unwrap(), 2)
+            .with_pre_negotiation(),
+    );
+    let warmed_up = |pool: Arc<ClientPool>| async move {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while pool.idle_connections() < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap()
+    };
+    pool.fill();
+    warmed_up(Arc::clone(&pool)).await;
+    assert_eq!(stats.total_connections(), 2);
+
+    for _ in 0..3 {
+        let mut stream = pool.connect(echo_addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+    }
+    warmed_up(Arc::clone(&pool)).await;
+    assert_eq!(stats.total_connections(), 5);
+    assert_eq!(stats.auth_failures(), 0);
+}
+
+#[tokio::test]
+async fn rejects_destinations_it_cannot_send() {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use socks_parser::{v5::AddressType, TryIntoSocksAddr};
+
+    let name = String::from("example.com");
+    assert_eq!(
+        (&name, 80).try_into_socks_addr().unwrap(),
+        (AddressType::DomainName(name.clone()), 80)
+    );
+    assert_eq!(
+        (Ipv4Addr::LOCALHOST, 80).try_into_socks_addr().unwrap(),
+        (AddressType::IPv4(Ipv4Addr::LOCALHOST), 80)
+    );
+    assert_eq!(
+        (Ipv6Addr::LOCALHOST, 80).try_into_socks_addr().unwrap(),
+        (AddressType::IPv6(Ipv6Addr::LOCALHOST), 80)
+    );
+    for name in [String::new(), "a".repeat(256), String::from("evil\0.com")] {
+        let e = (name, 80).try_into_socks_addr().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+    #[cfg(feature = "url")]
+    {
+        let url = url::Url::parse("unix:/run/app.sock").unwrap();
+        assert!(url.try_into_socks_addr().is_err());
+        let url = url::Url::parse("foo://example.com").unwrap();
+        assert!((&url).try_into_socks_addr().is_err());
+
+        let (stream, _proxy) = tokio::io::duplex(64);
+        let e = Client::new(stream).connect(url).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    // Rejected before anything is sent to the proxy.
+    let (stream, mut proxy) = tokio::io::duplex(64);
+    let e = Client::new(stream)
+        .connect(("a".repeat(300), 80))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    let mut buffer = [0; 8];
+    assert_eq!(proxy.read(&mut buffer).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn tells_handshake_failures_apart() {
+    use socks_parser::{
+        v5::{AuthenticationMethod, Status},
+        ClientError,
+    };
+
+    async fn fail(replies: &'static [u8]) -> std::io::Error {
+        let (client, mut server) = tokio::io::duplex(512);
+        tokio::spawn(async move {
+            let mut buffer = [0; 64];
+            let _ = server.read(&mut buffer).await;
+            let _ = server.write_all(replies).await;
+            // Request, after a successful hello.
+            let _ = server.read(&mut buffer).await;
+        });
+        Client::new(client)
+            .connect(("example.com", 80))
+            .await
+            .unwrap_err()
+    }
+
+    let e = fail(&[5, 0xff]).await;
+    assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+    match ClientError::of(&e) {
+        Some(ClientError::MethodNegotiationFailed { offered, selected }) => {
+            assert_eq!(offered, &[AuthenticationMethod::None]);
+            assert_eq!(*selected, AuthenticationMethod::NotAcceptable);
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+
+    let e = fail(&[5, 0, 5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+    assert_eq!(e.kind(), std::io::ErrorKind::HostUnreachable);
+    let failure = ClientError::of(&e).unwrap();
+    assert!(matches!(
+        failure,
+        ClientError::RequestDenied(Status::HostUnreachalble)
+    ));
+    assert!(failure.is_retryable());
+
+    let e = fail(&[5, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0]).await;
+    assert!(!ClientError::of(&e).unwrap().is_retryable());
+
+    // Unknown address type.
+    let e = fail(&[5, 0, 5, 0, 0, 9, 0, 0, 0, 0, 0, 0]).await;
+    assert!(matches!(
+        ClientError::of(&e),
+        Some(ClientError::Protocol(_))
+    ));
+
+    let e = Client::connect_tcp(
+        ("127.0.0.1", 1),
+        ("example.com", 80),
+        &socks_parser::ConnectOptions::default(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        ClientError::of(&e),
+        Some(ClientError::ProxyUnreachable(_))
+    ));
+}
+
+#[tokio::test]
+async fn pipelines_the_request_when_optimistic() {
+    let (client, mut server) = tokio::io::duplex(512);
+    let server = tokio::spawn(async move {
+        // Hello offering no authentication and request, before any reply.
+        let mut buffer = [0; 64];
+        let n = server.read(&mut buffer).await.unwrap();
+        server
+            .write_all(&[5, 0, 5, 0, 0, 1, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        buffer[..n].to_vec()
+    });
+
+    Client::new(client)
+        .with_optimistic_handshake(true)
+        .connect(std::net::SocketAddr::from(([192, 0, 2, 1], 80)))
+        .await
+        .unwrap();
+    assert_eq!(
+        server.await.unwrap(),
+        [5, 1, 0, 5, 1, 0, 1, 192, 0, 2, 1, 0, 80]
+    );
+}
+
+#[tokio::test]
+async fn retries_strictly_when_the_proxy_objects() {
+    use socks_parser::ConnectOptions;
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut attempts = 0;
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            attempts += 1;
+            let mut buffer = [0; 64];
+            let n = stream.read(&mut buffer).await.unwrap();
+            if n > 3 {
+                // Expecting the hello alone.
+                continue;
+            }
+            stream.write_all(&[5, 0]).await.unwrap();
+            stream.read_exact(&mut buffer[..18]).await.unwrap();
+            stream
+                .write_all(&[5, 0, 0, 1, 192, 0, 2, 1, 0, 80])
+                .await
+                .unwrap();
+            return attempts;
+        }
+    });
+
+    let options = ConnectOptions {
+        optimistic_handshake: true,
+        ..ConnectOptions::default()
+    };
+    Client::connect_tcp(proxy, ("example.com", 80), &options)
+        .await
+        .unwrap();
+    assert_eq!(server.await.unwrap(), 2);
+}
@@ -0,0 +1,204 @@
+#![cfg(feature = "codec")]
+
+use std::net::Ipv4Addr;
+
+use futures_util::{SinkExt, StreamExt};
+use socks_parser::{
+    codec::{ClientMessage, ServerMessage, SocksClientCodec, SocksServerCodec},
+    v4,
+    v5::{self, AddressType, AuthenticationMethod, Command, Status},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+#[tokio::test]
+async fn pipelined_socks5_handshake() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = Framed::new(client, SocksClientCodec::new());
+    let mut server = Framed::new(server, SocksServerCodec::new());
+
+    let messages: [ClientMessage; 3] = [
+        v5::Hello {
+            methods: vec![AuthenticationMethod::UsernamePassword],
+        }
+        .into(),
+        v5::UsernamePassword {
+            username: "alice".into(),
+            password: "secret".into(),
+        }
+        .into(),
+        v5::Request {
+            command: Command::Connect,
+            rsv: 0,
+            addr: AddressType::DomainName("example.com".into()),
+            port: 80,
+        }
+        .into(),
+    ];
+    for message in messages {
+        client.feed(message).await.unwrap();
+    }
+    client.flush().await.unwrap();
+    client.get_mut().write_all(b"ping").await.unwrap();
+
+    assert!(matches!(
+        server.next().await.unwrap().unwrap(),
+        ClientMessage::Hello(hello) if hello.offers(AuthenticationMethod::UsernamePassword)
+    ));
+    assert!(matches!(
+        server.next().await.unwrap().unwrap(),
+        ClientMessage::UsernamePassword(creds) if creds.username == "alice"
+    ));
+    assert!(matches!(
+        server.next().await.unwrap().unwrap(),
+        ClientMessage::Request(req) if req.port == 80
+    ));
+
+    let replies: [ServerMessage; 3] = [
+        v5::HelloResponse {
+            method: AuthenticationMethod::UsernamePassword,
+        }
+        .into(),
+        v5::UsernamePasswordResponse { success: true }.into(),
+        v5::Response {
+            status: Status::Success,
+            addr: AddressType::IPv4(Ipv4Addr::LOCALHOST),
+            port: 4242,
+        }
+        .into(),
+    ];
+    for reply in replies {
+        server.send(reply).await.unwrap();
+    }
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        ServerMessage::Hello(_)
+    ));
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        ServerMessage::UsernamePassword(resp) if resp.success
+    ));
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        ServerMessage::Response(resp) if resp.port == 4242
+    ));
+
+    // Relayed data is left to the caller.
+    let mut parts = server.into_parts();
+    let mut data = parts.read_buf.to_vec();
+    while data.len() < 4 {
+        let mut chunk = [0; 4];
+        let n = parts.io.read(&mut chunk).await.unwrap();
+        data.extend_from_slice(&chunk[..n]);
+    }
+    assert_eq!(data, b"ping");
+}
+
+#[tokio::test]
+async fn socks4_handshake() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = Framed::new(client, SocksClientCodec::new());
+    let mut server = Framed::new(server, SocksServerCodec::new());
+
+    client
+        .send(
+            v4::Request {
+                command: v4::Command::Connect,
+                port: 80,
+                addr: v4::AddressType::IPv4(Ipv4Addr::new(192, 0, 2, 1)),
+                secret: None,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        server.next().await.unwrap().unwrap(),
+        ClientMessage::V4(_)
+    ));
+    server
+        .send(
+            v4::Response {
+                status: v4::Status::Success,
+                addr: Ipv4Addr::UNSPECIFIED,
+                port: 0,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        ServerMessage::V4(resp) if resp.status == v4::Status::Success
+    ));
+}
+
+#[tokio::test]
+async fn credentials_ignored_by_the_server_get_no_reply() {
+    let (client, server) = tokio::io::duplex(1024);
+    let mut client = Framed::new(client, SocksClientCodec::new());
+    let mut server = Framed::new(server, SocksServerCodec::new());
+    client
+        .feed(
+            v5::Hello {
+                methods: vec![
+                    AuthenticationMethod::None,
+                    AuthenticationMethod::UsernamePassword,
+                ],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    client
+        .send(
+            v5::UsernamePassword {
+                username: "alice".into(),
+                password: "secret".into(),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    server
+        .send(
+            v5::HelloResponse {
+                method: AuthenticationMethod::None,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        ServerMessage::Hello(_)
+    ));
+
+    client
+        .send(
+            v5::Request {
+                command: Command::Connect,
+                rsv: 0,
+                addr: AddressType::IPv4(Ipv4Addr::new(192, 0, 2, 1)),
+                port: 80,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    server
+        .send(
+            v5::Response {
+                status: Status::ConnectionRefused,
+                addr: AddressType::IPv4(Ipv4Addr::UNSPECIFIED),
+                port: 0,
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.next().await.unwrap().unwrap(),
+        ServerMessage::Response(resp) if resp.status == Status::ConnectionRefused
+    ));
+}
@@ -0,0 +1,179 @@
+#![cfg(feature = "config")]
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use socks_parser::{
+    config::{AuthConfig, ServerConfig},
+    handlers, Client, ConnectionRequest, Destination, ParseMode, Server, Version,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+};
+
+async fn handle_request(req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+    let (stream, _) = tokio::io::duplex(64);
+    Ok((stream, req.destination))
+}
+
+async fn handle_stream(_local: TcpStream, _remote: DuplexStream) -> io::Result<()> {
+    Ok(())
+}
+
+#[test]
+fn parses_toml() {
+    let config = ServerConfig::from_toml(
+        r#"
+        listen = "0.0.0.0:1081"
+        versions = ["socks5"]
+        handshake_timeout_secs = 10
+        parse_mode = "strict"
+
+        [auth]
+        type = "users"
+        users = { alice = "secret" }
+
+        [limits]
+        max_message_size = 512
+
+        [policies.alice]
+        max_connections = 2
+        bandwidth = { download = 1000 }
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.listen, ([0, 0, 0, 0], 1081).into());
+    assert_eq!(config.versions, [Version::Socks5]);
+    assert_eq!(config.handshake_timeout_secs, Some(10));
+    assert_eq!(config.limits.max_message_size, 512);
+    assert_eq!(config.limits.max_methods, 255);
+    assert_eq!(config.parse_mode, ParseMode::Strict);
+    assert!(matches!(config.auth, AuthConfig::Users { ref users } if users["alice"] == "secret"));
+    assert!(!format!("{config:?}").contains("secret"));
+    let policy = &config.policies["alice"];
+    assert_eq!(policy.max_connections, Some(2));
+    assert_eq!(policy.bandwidth.download, Some(1000));
+    assert_eq!(policy.bandwidth.upload, None);
+
+    assert!(ServerConfig::from_toml("listen = \"0.0.0.0:1081\"\nport = 1").is_err());
+    assert_eq!(
+        ServerConfig::from_toml("").unwrap(),
+        ServerConfig::default()
+    );
+}
+
+#[tokio::test]
+async fn builds_server_from_config() {
+    std::env::set_var("SOCKS_LISTEN", "127.0.0.1:0");
+    std::env::set_var("SOCKS_VERSIONS", "socks5");
+    std::env::set_var("SOCKS_HANDSHAKE_TIMEOUT", "1");
+    let config = ServerConfig::from_env().unwrap();
+    assert_eq!(config.versions, [Version::Socks5]);
+
+    let server = Server::from_config(&config).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run(handle_request, handle_stream));
+
+    // SOCKS4 is disabled.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(&[4, 1, 0, 80, 192, 0, 2, 1, 0])
+        .await
+        .unwrap();
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+
+    // Silent clients are dropped once the handshake times out.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut [0; 8])).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+}
+
+#[tokio::test]
+async fn updates_config_of_running_server() {
+    let users = |username: &str| AuthConfig::Users {
+        users: [(username.to_owned(), "secret".to_owned())].into(),
+    };
+    let mut config = ServerConfig {
+        listen: ([127, 0, 0, 1], 0).into(),
+        auth: users("alice"),
+        ..Default::default()
+    };
+    let server = Server::from_config(&config).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.config_handle();
+    tokio::spawn(server.run(handle_request, handlers::relay));
+    let connect = |username: &'static str| async move {
+        Client::new(TcpStream::connect(addr).await?)
+            .with_username_password(username, "secret")
+            .connect(("example.com", 80))
+            .await
+    };
+
+    let _tunnel = connect("alice").await.unwrap();
+    assert!(connect("bob").await.is_err());
+
+    // SOCKS4 cannot authenticate, it is disabled along with authentication.
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(&[4, 1, 0, 80, 192, 0, 2, 1, 0])
+        .await
+        .unwrap();
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+
+    config.auth = users("bob");
+    handle.update_config(&config).unwrap();
+    assert!(connect("alice").await.is_err());
+    connect("bob").await.unwrap();
+
+    // Nothing changes if the new configuration cannot be loaded.
+    config.auth = users("alice");
+    config.acl = Some("/nonexistent/acl".into());
+    assert!(handle.update_config(&config).is_err());
+    connect("bob").await.unwrap();
+}
+
+#[tokio::test]
+async fn throttles_users_of_config_built_server() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = target.local_addr().unwrap().port();
+    let config = ServerConfig::from_toml(
+        r#"
+        listen = "127.0.0.1:0"
+
+        [auth]
+        type = "users"
+        users = { alice = "secret" }
+
+        [policies.alice]
+        bandwidth = { upload = 1000 }
+        "#,
+    )
+    .unwrap();
+    let server = Server::from_config(&config).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    let connect = server.throttle_users(|req: ConnectionRequest| async move {
+        let stream = TcpStream::connect(req.destination.to_string()).await?;
+        Ok((stream, req.destination))
+    });
+    tokio::spawn(server.run(connect, handlers::relay));
+
+    let mut tunnel = Client::new(TcpStream::connect(addr).await.unwrap())
+        .with_username_password("alice", "secret")
+        .connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let (mut remote, _) = target.accept().await.unwrap();
+    let start = Instant::now();
+    tunnel.write_all(&[0; 2000]).await.unwrap();
+    remote.read_exact(&mut [0; 2000]).await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+}
@@ -0,0 +1,173 @@
+//! Runs the client against the server over every version, authentication and command, checking
+//! both sides exchanged the bytes laid out by the specifications (SOCKS4, SOCKS4a, RFC 1928 and
+//! RFC 1929).
+
+#![cfg(feature = "async")]
+
+use std::{io, net::SocketAddr};
+
+use socks_parser::{
+    auth::StaticUserDb,
+    handlers,
+    recorder::{Direction, Recorder, Transcript},
+    testing,
+    v5::{AddressType, Command},
+    Client, ConnectionRequest, Destination, Version,
+};
+use tokio::{io::DuplexStream, sync::mpsc};
+
+/// Address every request handler claims to be bound to.
+const BOUND: ([u8; 4], u16) = ([198, 51, 100, 7], 0xa028);
+
+async fn handle_request(_: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+    let (stream, _) = tokio::io::duplex(64);
+    Ok((stream, SocketAddr::from(BOUND).into()))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Auth {
+    None,
+    UsernamePassword,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Ip,
+    Domain,
+}
+
+impl Target {
+    fn addr(self) -> AddressType {
+        match self {
+            Self::Ip => AddressType::IPv4([192, 0, 2, 1].into()),
+            Self::Domain => AddressType::DomainName("example.com".into()),
+        }
+    }
+}
+
+/// Client and server transcripts of one handshake, along with its outcome.
+async fn exchange(
+    version: Version,
+    auth: Auth,
+    command: Command,
+    target: Target,
+) -> (Transcript, Transcript, io::Result<Destination>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut server = testing::server().with_recorder(move |_, transcript| {
+        let _ = tx.send(transcript);
+    });
+    if let Auth::UsernamePassword = auth {
+        server = server.with_authenticator(StaticUserDb::new().with_user("alice", "secret"));
+    }
+    let transport = testing::serve(server, handle_request, handlers::relay);
+
+    let stream = Recorder::new(transport.connect().await.unwrap());
+    let mut client = Client::new_with_version(stream, version);
+    if let Auth::UsernamePassword = auth {
+        client = client.with_username_password("alice", "secret");
+    }
+    let mut negotiated = client.handshake_only().await.unwrap();
+    let outcome = negotiated.request(command, (target.addr(), 80)).await;
+    let (_, client_transcript) = negotiated.into_inner().into_parts();
+    (client_transcript, rx.recv().await.unwrap(), outcome)
+}
+
+/// Bytes of the SOCKS4 request for `command` to port 80 of `target`.
+fn v4_request(command: u8, target: Target) -> Vec<u8> {
+    let mut request = vec![4, command, 0, 80];
+    match target {
+        Target::Ip => request.extend_from_slice(&[192, 0, 2, 1, 0]),
+        Target::Domain => {
+            request.extend_from_slice(&[0, 0, 0, 1, 0]);
+            request.extend_from_slice(b"example.com\0");
+        }
+    }
+    request
+}
+
+/// Bytes of the SOCKS5 request for `command` to port 80 of `target`.
+fn v5_request(command: u8, target: Target) -> Vec<u8> {
+    let mut request = vec![5, command, 0];
+    match target {
+        Target::Ip => request.extend_from_slice(&[1, 192, 0, 2, 1]),
+        Target::Domain => {
+            request.extend_from_slice(&[3, 11]);
+            request.extend_from_slice(b"example.com");
+        }
+    }
+    request.extend_from_slice(&[0, 80]);
+    request
+}
+
+#[tokio::test]
+async fn socks4_and_socks4a() {
+    for target in [Target::Ip, Target::Domain] {
+        for (command, code) in [(Command::Connect, 1), (Command::Bind, 2)] {
+            let case = format!("{command:?} to {target:?}");
+            let (client, server, outcome) =
+                exchange(Version::Socks4, Auth::None, command, target).await;
+            assert_eq!(client.sent_bytes(), server.received_bytes(), "{case}");
+            assert_eq!(client.received_bytes(), server.sent_bytes(), "{case}");
+
+            assert_eq!(client.sent_bytes(), v4_request(code, target), "{case}");
+            let reply = match command {
+                Command::Connect => {
+                    assert_eq!(outcome.unwrap(), SocketAddr::from(BOUND).into(), "{case}");
+                    vec![0, 90, 0xa0, 0x28, 198, 51, 100, 7]
+                }
+                _ => {
+                    assert!(outcome.is_err(), "{case}");
+                    vec![0, 91, 0, 0, 0, 0, 0, 0]
+                }
+            };
+            assert_eq!(client.received_bytes(), reply, "{case}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn socks5() {
+    for auth in [Auth::None, Auth::UsernamePassword] {
+        for target in [Target::Ip, Target::Domain] {
+            for (command, code) in [
+                (Command::Connect, 1),
+                (Command::Bind, 2),
+                (Command::UdpAssociate, 3),
+            ] {
+                let case = format!("{command:?} to {target:?} with {auth:?}");
+                let (client, server, outcome) =
+                    exchange(Version::Socks5, auth, command, target).await;
+                // Servers mask the passwords they record.
+                let mut masked = client.clone();
+                masked.mask_credentials(Direction::Sent);
+                assert_eq!(masked.sent_bytes(), server.received_bytes(), "{case}");
+                assert_eq!(client.received_bytes(), server.sent_bytes(), "{case}");
+
+                let (mut sent, mut received) = match auth {
+                    Auth::None => (vec![5, 1, 0], vec![5, 0]),
+                    Auth::UsernamePassword => {
+                        let mut sent = vec![5, 2, 2, 0, 1, 5];
+                        sent.extend_from_slice(b"alice\x06secret");
+                        let mut recorded = server.received_bytes();
+                        recorded.truncate(sent.len());
+                        assert!(recorded.ends_with(b"alice\x06******"), "{case}");
+                        (sent, vec![5, 2, 1, 0])
+                    }
+                };
+                sent.extend(v5_request(code, target));
+                match command {
+                    Command::Connect => {
+                        assert_eq!(outcome.unwrap(), SocketAddr::from(BOUND).into(), "{case}");
+                        received.extend_from_slice(&[5, 0, 0, 1, 198, 51, 100, 7, 0xa0, 0x28]);
+                    }
+                    _ => {
+                        assert!(outcome.is_err(), "{case}");
+                        received.extend_from_slice(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+                    }
+                }
+                assert_eq!(client.sent_bytes(), sent, "{case}");
+                assert_eq!(client.received_bytes(), received, "{case}");
+            }
+        }
+    }
+}
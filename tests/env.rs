@@ -0,0 +1,103 @@
+#![cfg(feature = "async")]
+
+use std::env;
+
+use socks_parser::{
+    handlers,
+    proxy::{NoProxy, ProxyUrl},
+    v5::AddressType,
+    Client, ConnectionRequest, Destination, Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+fn dest(host: &str, port: u16) -> Destination {
+    Destination {
+        addr: AddressType::DomainName(host.into()),
+        port,
+    }
+}
+
+#[test]
+fn no_proxy_lists() {
+    let no_proxy = NoProxy::parse("localhost, .corp.example,*.internal.example,10.0.0.0/8,[::1],");
+    for host in [
+        "localhost",
+        "corp.example",
+        "wiki.corp.example",
+        "git.internal.example",
+        "10.1.2.3",
+        "::1",
+    ] {
+        assert!(no_proxy.matches(&dest(host, 80)), "{host}");
+    }
+    let ip = Destination::from(std::net::SocketAddr::from(([10, 0, 0, 1], 443)));
+    assert!(no_proxy.matches(&ip));
+    for host in ["example.com", "notcorp.example", "11.0.0.1", "::2"] {
+        assert!(!no_proxy.matches(&dest(host, 80)), "{host}");
+    }
+
+    assert!(NoProxy::parse("*").matches(&dest("example.com", 80)));
+    assert!(!NoProxy::default().matches(&dest("localhost", 80)));
+
+    // Entries other tools understand are skipped.
+    let lenient = NoProxy::parse("localhost:8080,<local>,10.0.0.0/40,example.com");
+    assert!(lenient.matches(&dest("example.com", 80)));
+    assert!(!lenient.matches(&dest("localhost", 80)));
+    assert_eq!(lenient, NoProxy::parse("example.com"));
+}
+
+// The only test touching the environment of this process.
+#[tokio::test]
+async fn connects_through_the_environment_proxy() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server = Server::new(listener);
+    let stats = server.stats();
+    tokio::spawn(server.run(
+        |req: ConnectionRequest| async move {
+            let stream = TcpStream::connect(("127.0.0.1", req.destination.port)).await?;
+            let bound = stream.local_addr()?.into();
+            Ok((stream, bound))
+        },
+        handlers::relay,
+    ));
+
+    env::remove_var("ALL_PROXY");
+    env::set_var("all_proxy", format!("socks5://{proxy}"));
+    env::set_var("NO_PROXY", "localhost");
+    assert_eq!(
+        ProxyUrl::from_env().unwrap(),
+        Some(format!("socks5://{proxy}").parse().unwrap())
+    );
+
+    for (host, proxied) in [("localhost", 0), ("127.0.0.1", 1)] {
+        let mut stream = Client::connect_via_env((host, echo_port)).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        assert_eq!(stats.total_connections(), proxied);
+    }
+
+    env::set_var("ALL_PROXY", "http://proxy.example:3128");
+    assert_eq!(ProxyUrl::from_env().unwrap(), None);
+    env::set_var("ALL_PROXY", "socks5://proxy.example:port");
+    assert!(ProxyUrl::from_env().is_err());
+    env::set_var("ALL_PROXY", " ");
+    assert!(ProxyUrl::from_env().unwrap().is_some());
+}
@@ -0,0 +1,77 @@
+#![cfg(feature = "extensions")]
+
+use std::path::PathBuf;
+
+use socks_parser::{extensions, v5, ParseMode, Wire};
+
+type Error<'i> = nom::error::VerboseError<&'i [u8]>;
+
+#[test]
+fn unix_path_address_type() {
+    let request = v5::Request {
+        command: v5::Command::Connect,
+        rsv: 0,
+        addr: v5::AddressType::UnixPath(PathBuf::from("/run/app.sock")),
+        port: 0,
+    };
+    let mut buffer = Vec::new();
+    request.encode_into(&mut buffer);
+    assert_eq!(&buffer[3..5], [extensions::DEFAULT_UNIX_PATH_CODE, 13]);
+    assert_eq!(&buffer[5..18], b"/run/app.sock");
+    let (_, decoded) = v5::Request::decode::<Error>(&buffer).unwrap();
+    assert_eq!(decoded.addr, request.addr);
+    assert!(v5::Request::decode_with::<Error>(&buffer, ParseMode::Strict).is_err());
+    assert_eq!(request.addr.to_string(), "unix:/run/app.sock");
+
+    assert!(extensions::set_unix_path_code(3).is_err());
+    extensions::set_unix_path_code(0x85).unwrap();
+    assert!(v5::Request::decode::<Error>(&buffer).is_err());
+    buffer.clear();
+    request.encode_into(&mut buffer);
+    assert_eq!(buffer[3], 0x85);
+    assert_eq!(
+        v5::Request::decode::<Error>(&buffer).unwrap().1.addr,
+        request.addr
+    );
+    extensions::set_unix_path_code(extensions::DEFAULT_UNIX_PATH_CODE).unwrap();
+}
+
+#[test]
+fn registered_extensions() {
+    use extensions::AddressFormat;
+
+    let request = [0x05, 0x82, 0x00, 0x81, 0xde, 0xad, 0xbe, 0xef, 0x00, 0x50];
+    assert!(v5::Request::decode::<Error>(&request).is_err());
+
+    extensions::register_address_type(0x81, AddressFormat::Fixed(4)).unwrap();
+    extensions::register_address_type(0x83, AddressFormat::LengthPrefixed).unwrap();
+    extensions::register_command(0x82).unwrap();
+    assert!(extensions::register_address_type(4, AddressFormat::Fixed(16)).is_err());
+    assert!(extensions::register_command(1).is_err());
+
+    let (_, decoded) = v5::Request::decode::<Error>(&request).unwrap();
+    assert_eq!(decoded.command, v5::Command::Other(0x82));
+    assert_eq!(
+        decoded.addr,
+        v5::AddressType::Other {
+            code: 0x81,
+            payload: vec![0xde, 0xad, 0xbe, 0xef],
+        }
+    );
+    assert_eq!(decoded.port, 80);
+    assert_eq!(decoded.addr.to_string(), "ext81:deadbeef");
+    let mut buffer = Vec::new();
+    decoded.encode_into(&mut buffer);
+    assert_eq!(buffer, request);
+    assert!(v5::Request::decode_with::<Error>(&request, ParseMode::Strict).is_err());
+
+    let (rest, addr) = v5::AddressType::decode::<Error>(&[0x83, 0x02, 0x01, 0x02, 0xff]).unwrap();
+    assert_eq!(
+        addr,
+        v5::AddressType::Other {
+            code: 0x83,
+            payload: vec![0x02, 0x01, 0x02],
+        }
+    );
+    assert_eq!(rest, [0xff]);
+}
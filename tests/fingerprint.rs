@@ -0,0 +1,69 @@
+use socks_parser::fingerprint::Fingerprint;
+
+fn fingerprint(data: &[u8]) -> Fingerprint {
+    Fingerprint::from_client_bytes(data).expect("not a SOCKS handshake")
+}
+
+#[test]
+fn fingerprints_known_clients() {
+    // curl --socks5-hostname with a proxy user: hello, credentials and request.
+    let curl =
+        fingerprint(b"\x05\x02\x00\x02\x01\x04user\x04pass\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+    assert_eq!(curl.to_string(), "5:00,02:00:03");
+    assert_eq!(curl.known_clients(), ["curl", "proxychains"]);
+
+    let firefox = fingerprint(b"\x05\x01\x00\x05\x01\x00\x01\x5d\xb8\xd8\x22\x00\x50");
+    assert_eq!(firefox.to_string(), "5:00:00:01");
+    assert!(firefox.known_clients().contains(&"Firefox"));
+
+    let proxychains = fingerprint(&[0x04, 0x01, 0x00, 0x16, 10, 0, 0, 1, 0x00]);
+    assert_eq!(proxychains.to_string(), "4:-:-");
+    assert_eq!(proxychains.known_clients(), ["proxychains"]);
+
+    let curl = fingerprint(b"\x04\x01\x00\x50\x00\x00\x00\x01user\x00example.com\x00");
+    assert_eq!(curl.to_string(), "4a:01:uid");
+    assert_eq!(curl.known_clients(), ["curl"]);
+
+    // Scanners filling the reserved byte match nothing.
+    let scanner = fingerprint(b"\x05\x01\x00\x05\x01\xff\x01\x7f\x00\x00\x01\x00\x50");
+    assert_eq!(scanner.to_string(), "5:00:ff:01");
+    assert!(scanner.known_clients().is_empty());
+
+    assert_eq!(Fingerprint::from_client_bytes(b"\x05\x01\x00"), None);
+    assert_eq!(Fingerprint::from_client_bytes(b"GET / HTTP/1.1\r\n"), None);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn detects_pipelining() {
+    use std::time::Duration;
+
+    use socks_parser::recorder::{Direction, Record, Transcript};
+
+    let record = |direction, data: &[u8]| Record {
+        elapsed: Duration::ZERO,
+        direction,
+        data: data.to_vec(),
+    };
+    let hello = b"\x05\x01\x00";
+    let request = b"\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+
+    let waiting = Transcript {
+        records: vec![
+            record(Direction::Received, hello),
+            record(Direction::Sent, b"\x05\x00"),
+            record(Direction::Received, request),
+        ],
+    };
+    let fingerprint = Fingerprint::from_transcript(&waiting).unwrap();
+    assert_eq!(fingerprint.to_string(), "5:00:00:01:w");
+
+    let pipelined = Transcript {
+        records: vec![
+            record(Direction::Received, &[&hello[..], &request[..]].concat()),
+            record(Direction::Sent, b"\x05\x00"),
+        ],
+    };
+    let fingerprint = Fingerprint::from_transcript(&pipelined).unwrap();
+    assert_eq!(fingerprint.to_string(), "5:00:00:01:p");
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "mux")]
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+use socks_parser::{
+    auth::StaticUserDb, handlers, v5::Status, Client, ClientError, ConnectionRequest, Destination,
+    Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+async fn echo_server() -> std::net::SocketAddr {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+    echo_addr
+}
+
+#[tokio::test]
+async fn tunnels_share_one_authenticated_connection() {
+    let echo_addr = echo_server().await;
+    let users = Arc::new(Mutex::new(Vec::new()));
+    let server =
+        Server::unbound().with_authenticator(StaticUserDb::new().with_user("alice", "secret"));
+    let stats = server.stats();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let seen = Arc::clone(&users);
+    tokio::spawn(server.serve_mux(
+        listener,
+        move |req: ConnectionRequest| async move {
+            seen.lock().unwrap().push(req.identity.map(|i| i.username));
+            let addr = req.destination.addr.to_socket_addr(req.destination.port);
+            let stream = TcpStream::connect(addr.expect("IP destination")).await?;
+            let local = stream.local_addr()?;
+            Ok::<_, io::Error>((stream, Destination::from(local)))
+        },
+        handlers::relay,
+    ));
+
+    let session = Client::new(TcpStream::connect(proxy).await.unwrap())
+        .with_username_password("alice", "secret")
+        .open_mux()
+        .await
+        .unwrap();
+    let mut tunnels = Vec::new();
+    for _ in 0..3 {
+        tunnels.push(session.connect(echo_addr).await.unwrap());
+    }
+    drop(session);
+    for (i, tunnel) in tunnels.iter_mut().enumerate() {
+        let message = format!("ping {i}");
+        tunnel.write_all(message.as_bytes()).await.unwrap();
+        let mut reply = vec![0; message.len()];
+        tunnel.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, message.as_bytes());
+    }
+
+    assert_eq!(stats.handshakes(socks_parser::Version::Socks5), 1);
+    assert_eq!(stats.total_connections(), 3);
+    assert_eq!(*users.lock().unwrap(), vec![Some("alice".to_owned()); 3]);
+}
+
+#[tokio::test]
+async fn mux_servers_only_grant_mux_requests() {
+    let echo_addr = echo_server().await;
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(Server::unbound().serve_mux(
+        listener,
+        |_: ConnectionRequest| async {
+            Err::<(TcpStream, Destination), _>(io::Error::other("unreachable"))
+        },
+        handlers::relay,
+    ));
+
+    let e = Client::new(TcpStream::connect(proxy).await.unwrap())
+        .connect(echo_addr)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        ClientError::of(&e),
+        Some(ClientError::RequestDenied(Status::CommandNotSupported))
+    ));
+
+    // A tunnel failing to connect leaves the others be.
+    let session = Client::new(TcpStream::connect(proxy).await.unwrap())
+        .open_mux()
+        .await
+        .unwrap();
+    for _ in 0..2 {
+        let e = session.connect(echo_addr).await.unwrap_err();
+        assert!(matches!(
+            ClientError::of(&e),
+            Some(ClientError::RequestDenied(Status::GeneralFailure))
+        ));
+    }
+}
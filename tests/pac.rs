@@ -0,0 +1,123 @@
+#![cfg(feature = "pac")]
+
+use socks_parser::{
+    pac::{ProxyChoice, ProxySelector},
+    proxy::ProxyUrl,
+    v5::AddressType,
+    Destination,
+};
+
+fn dest(host: &str, port: u16) -> Destination {
+    let addr = match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => ip.into(),
+        Err(_) => AddressType::DomainName(host.into()),
+    };
+    Destination { addr, port }
+}
+
+fn proxy(url: &str) -> ProxyChoice {
+    ProxyChoice::Proxy(url.parse::<ProxyUrl>().unwrap())
+}
+
+#[test]
+fn first_matching_rule_wins() {
+    let selector = ProxySelector::parse(
+        "# corporate network\n\
+         *.corp.example DIRECT\n\
+         10.0.0.0/8     direct # trailing comment\n\
+         *.example.com:443 SOCKS5 gw.example.com:1080; DIRECT\n\
+         *              SOCKS proxy.example.com\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        selector.select(&dest("wiki.corp.example", 80)),
+        [ProxyChoice::Direct]
+    );
+    assert_eq!(
+        selector.select(&dest("10.1.2.3", 22)),
+        [ProxyChoice::Direct]
+    );
+    assert_eq!(
+        selector.select(&dest("www.example.com", 443)),
+        [proxy("socks5://gw.example.com:1080"), ProxyChoice::Direct]
+    );
+    assert_eq!(
+        selector.select(&dest("www.example.com", 80)),
+        [proxy("socks4://proxy.example.com:1080")]
+    );
+    assert_eq!(
+        ProxySelector::default().select(&dest("example.org", 80)),
+        [ProxyChoice::Direct]
+    );
+}
+
+#[test]
+fn rejects_invalid_rules() {
+    for content in [
+        "*.example.com\n",
+        "*.example.com ;\n",
+        "*.example.com PROXY proxy.example.com:3128\n",
+        "*.example.com SOCKS5\n",
+        "*.example.com DIRECT now\n",
+        "10.0.0.0/33 DIRECT\n",
+    ] {
+        assert!(ProxySelector::parse(content).is_err(), "{content:?}");
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn connects_as_selected() {
+    use socks_parser::{handlers, Client, Server};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server = Server::new(listener);
+    let stats = server.stats();
+    tokio::spawn(server.run(
+        |req: socks_parser::ConnectionRequest| async move {
+            let stream = TcpStream::connect(("127.0.0.1", req.destination.port)).await?;
+            let bound = stream.local_addr()?.into();
+            Ok((stream, bound))
+        },
+        handlers::relay,
+    ));
+    let dead = TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let selector = ProxySelector::parse(&format!(
+        "localhost DIRECT\n\
+         * SOCKS5 {dead}; SOCKS5 {proxy}\n"
+    ))
+    .unwrap();
+    for (host, proxied) in [("localhost", 0), ("echo.test", 1)] {
+        let mut stream = Client::connect_selected(&selector, (host, echo_port))
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        assert_eq!(stats.total_connections(), proxied);
+    }
+}
@@ -0,0 +1,74 @@
+#![cfg(feature = "pcap")]
+
+use socks_parser::pcap::{Direction, Message, SessionEvent, SessionReassembler};
+
+#[test]
+fn reassembles_split_socks5_session() {
+    let mut session = SessionReassembler::new();
+
+    assert!(session
+        .feed(Direction::ClientToServer, &[0x05, 0x01])
+        .is_empty());
+    // Pipelined request, before the server answered the hello.
+    let events = session.feed(
+        Direction::ClientToServer,
+        &[0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50],
+    );
+    assert!(matches!(
+        events[..],
+        [SessionEvent::Message {
+            offset: 0,
+            message: Message::Hello(_),
+            ..
+        }]
+    ));
+
+    let events = session.feed(Direction::ServerToClient, &[0x05, 0x00]);
+    assert!(matches!(
+        events[..],
+        [
+            SessionEvent::Message {
+                message: Message::HelloResponse(_),
+                ..
+            },
+            SessionEvent::Message {
+                offset: 3,
+                message: Message::V5Request(_),
+                ..
+            }
+        ]
+    ));
+
+    let events = session.feed(
+        Direction::ServerToClient,
+        &[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50, b'H', b'i'],
+    );
+    assert!(session.is_tunneling());
+    assert!(matches!(
+        events[..],
+        [
+            SessionEvent::Message {
+                message: Message::V5Response(_),
+                ..
+            },
+            SessionEvent::Payload {
+                direction: Direction::ServerToClient,
+                offset: 12,
+                len: 2,
+            }
+        ]
+    ));
+}
+
+#[test]
+fn stops_on_garbage() {
+    let mut session = SessionReassembler::new();
+    let events = session.feed(Direction::ClientToServer, b"GET / HTTP/1.1\r\n");
+    assert!(matches!(
+        events[..],
+        [SessionEvent::Invalid {
+            direction: Direction::ClientToServer,
+            offset: 0
+        }]
+    ));
+}
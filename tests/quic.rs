@@ -0,0 +1,181 @@
+#![cfg(feature = "quic")]
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use quinn::rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    RootCertStore,
+};
+use socks_parser::{
+    handlers,
+    udp::{FragmentPolicy, UdpRelayOptions},
+    v5::UdpHeader,
+    Client, ConnectionRequest, Destination, Server, Wire,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+async fn connect_direct(req: ConnectionRequest) -> io::Result<(TcpStream, Destination)> {
+    let addr = req.destination.addr.to_socket_addr(req.destination.port);
+    let stream = TcpStream::connect(addr.expect("IP destination")).await?;
+    let local = stream.local_addr()?;
+    Ok((stream, local.into()))
+}
+
+/// Starts `server` over QUIC, returning its address and certificate.
+fn quic_server(server: Server) -> (SocketAddr, CertificateDer<'static>) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_der = CertificateDer::from(cert.cert);
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der()));
+
+    let server_config = quinn::ServerConfig::with_single_cert(vec![cert_der.clone()], key).unwrap();
+    let endpoint = quinn::Endpoint::server(server_config, ([127, 0, 0, 1], 0).into()).unwrap();
+    let server_addr = endpoint.local_addr().unwrap();
+    tokio::spawn(server.serve_quic(endpoint, connect_direct, handlers::relay));
+    (server_addr, cert_der)
+}
+
+/// Opens a connection to the QUIC server at `addr` presenting `cert`.
+async fn quic_connect(addr: SocketAddr, cert: CertificateDer<'static>) -> quinn::Connection {
+    let mut roots = RootCertStore::empty();
+    roots.add(cert).unwrap();
+    let mut client = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+    client.set_default_client_config(
+        quinn::ClientConfig::with_root_certificates(Arc::new(roots)).unwrap(),
+    );
+    client.connect(addr, "localhost").unwrap().await.unwrap()
+}
+
+/// Starts `server` over QUIC and returns a client connection to it.
+async fn quic_pair(server: Server) -> quinn::Connection {
+    let (addr, cert) = quic_server(server);
+    quic_connect(addr, cert).await
+}
+
+#[tokio::test]
+async fn connect_over_quic_stream() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await.unwrap();
+    });
+
+    let connection = quic_pair(Server::unbound()).await;
+    let mut stream = Client::connect_quic(&connection, echo_addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+}
+
+#[tokio::test]
+async fn idle_connections_hold_no_permit() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await.unwrap();
+    });
+
+    let (addr, cert) = quic_server(Server::unbound().with_max_connections(1));
+    let _idle = quic_connect(addr, cert.clone()).await;
+    let connection = quic_connect(addr, cert).await;
+    let mut stream = tokio::time::timeout(
+        Duration::from_secs(5),
+        Client::connect_quic(&connection, echo_addr),
+    )
+    .await
+    .expect("Stream served despite the idle connection")
+    .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+}
+
+#[tokio::test]
+async fn udp_associate_over_datagrams() {
+    let echo = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0; 64];
+        let (n, from) = echo.recv_from(&mut buffer).await.unwrap();
+        echo.send_to(&buffer[..n], from).await.unwrap();
+    });
+
+    let connection = quic_pair(Server::unbound()).await;
+    let association = Client::open_quic(&connection)
+        .await
+        .unwrap()
+        .associate_quic(connection.clone())
+        .await
+        .unwrap();
+    association.send_to(echo_addr, b"ping").unwrap();
+    let (from, payload) = association.recv_from().await.unwrap();
+    assert_eq!(from, Destination::from(echo_addr));
+    assert_eq!(payload, b"ping");
+}
+
+#[tokio::test]
+async fn one_udp_association_per_connection() {
+    let connection = quic_pair(Server::unbound()).await;
+    let associate = || async {
+        Client::open_quic(&connection)
+            .await?
+            .associate_quic(connection.clone())
+            .await
+    };
+    let association = associate().await.unwrap();
+    assert!(associate().await.is_err());
+
+    drop(association);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while associate().await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Datagrams released with the association");
+}
+
+#[tokio::test]
+async fn udp_associate_reassembles_fragments() {
+    let echo = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0; 64];
+        let (n, from) = echo.recv_from(&mut buffer).await.unwrap();
+        echo.send_to(&buffer[..n], from).await.unwrap();
+    });
+
+    let options = UdpRelayOptions::new().fragmentation(FragmentPolicy::Reassemble {
+        timeout: Duration::from_secs(5),
+    });
+    let connection = quic_pair(Server::unbound().with_udp_relay_options(options)).await;
+    let association = Client::open_quic(&connection)
+        .await
+        .unwrap()
+        .associate_quic(connection.clone())
+        .await
+        .unwrap();
+    let destination = Destination::from(echo_addr);
+    for (frag, payload) in [(1, &b"pi"[..]), (0x82, b"ng")] {
+        let mut datagram = Vec::new();
+        UdpHeader {
+            frag,
+            addr: destination.addr.clone(),
+            port: destination.port,
+        }
+        .encode_into(&mut datagram);
+        datagram.extend_from_slice(payload);
+        connection.send_datagram(datagram.into()).unwrap();
+    }
+    let (from, payload) = association.recv_from().await.unwrap();
+    assert_eq!(from, destination);
+    assert_eq!(payload, b"ping");
+}
@@ -0,0 +1,197 @@
+#![cfg(feature = "async")]
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use socks_parser::{
+    acl::AclRules,
+    auth::BoxFuture,
+    handlers,
+    resolver::{self, CachingResolver, Lookup, Resolver},
+    v5::AddressType,
+    Client, ConnectOptions, ConnectionRequest, Destination, Server,
+};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Resolves every name to the loopback address, counting lookups.
+#[derive(Default)]
+struct Loopback {
+    lookups: AtomicUsize,
+    ttl: Option<Duration>,
+}
+
+impl Resolver for Loopback {
+    fn lookup<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        Box::pin(async move {
+            Ok(Lookup {
+                addrs: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                ttl: self.ttl,
+            })
+        })
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn caching_resolver_follows_ttl_and_evicts_least_recently_used() {
+    let inner = Arc::new(Loopback {
+        ttl: Some(Duration::from_secs(30)),
+        ..Default::default()
+    });
+    let resolver = CachingResolver::new(Arc::clone(&inner), 2);
+    let lookups = || inner.lookups.load(Ordering::Relaxed);
+
+    resolver.lookup("a.test").await.unwrap();
+    let cached = resolver.lookup("A.test").await.unwrap();
+    assert_eq!(lookups(), 1);
+    assert_eq!(cached.ttl, Some(Duration::from_secs(30)));
+
+    tokio::time::advance(Duration::from_secs(31)).await;
+    resolver.lookup("a.test").await.unwrap();
+    assert_eq!(lookups(), 2);
+
+    resolver.lookup("b.test").await.unwrap();
+    resolver.lookup("a.test").await.unwrap();
+    resolver.lookup("c.test").await.unwrap();
+    assert_eq!((lookups(), resolver.len()), (4, 2));
+    resolver.lookup("a.test").await.unwrap();
+    assert_eq!(lookups(), 4);
+    resolver.lookup("b.test").await.unwrap();
+    assert_eq!(lookups(), 5);
+
+    let resolver = CachingResolver::new(Loopback::default(), 16)
+        .with_default_ttl(Duration::from_secs(5))
+        .with_max_ttl(Duration::from_secs(1));
+    assert_eq!(
+        resolver.lookup("a.test").await.unwrap().ttl,
+        None,
+        "Unknown TTL reported as such on a miss"
+    );
+    assert_eq!(
+        resolver.lookup("a.test").await.unwrap().ttl,
+        Some(Duration::from_secs(1))
+    );
+}
+
+#[tokio::test]
+async fn direct_handler_shares_its_resolver() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = target.local_addr().unwrap().port();
+    let inner = Arc::new(Loopback::default());
+    let connect = handlers::connect_with(
+        CachingResolver::new(Arc::clone(&inner), 16),
+        ConnectOptions::default(),
+    );
+
+    for _ in 0..3 {
+        let request = ConnectionRequest::from(Destination {
+            addr: AddressType::DomainName("service.test".into()),
+            port,
+        });
+        let (stream, bound) = connect.clone()(request).await.unwrap();
+        assert_eq!(bound, stream.local_addr().unwrap().into());
+        target.accept().await.unwrap();
+    }
+    assert_eq!(inner.lookups.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn direct_handler_checks_resolved_addresses() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = target.local_addr().unwrap().port();
+    let connect = handlers::connect_with(Loopback::default(), ConnectOptions::default());
+
+    // Name rebound to the loopback address after passing the server checks.
+    let mut request = ConnectionRequest::from(Destination {
+        addr: AddressType::DomainName("rebound.test".into()),
+        port,
+    });
+    request.allow_private = false;
+    let e = connect.clone()(request.clone()).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    request.allow_private = true;
+    connect(request).await.unwrap();
+    target.accept().await.unwrap();
+}
+
+#[tokio::test]
+async fn direct_handler_checks_resolved_addresses_against_the_server_acl() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = target.local_addr().unwrap().port();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let acl = AclRules::parse("deny 127.0.0.0/8\ndefault allow\n").unwrap();
+    tokio::spawn(Server::new(listener).with_acl(acl).run(
+        handlers::connect_with(Loopback::default(), ConnectOptions::default()),
+        handlers::relay,
+    ));
+
+    // The name passes the ACL, the loopback address it resolves to does not.
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let e = Client::new(stream)
+        .connect(("service.test", port))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), target.accept())
+            .await
+            .is_err(),
+        "Connected to a denied address"
+    );
+}
+
+/// Resolves every name to a private and a public address.
+struct Rebinding;
+
+impl Resolver for Rebinding {
+    fn lookup<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async {
+            Ok(Lookup {
+                addrs: vec![[10, 0, 0, 1].into(), [192, 0, 2, 1].into()],
+                ttl: None,
+            })
+        })
+    }
+}
+
+#[tokio::test]
+async fn resolve_and_pin_keeps_the_vetted_addresses() {
+    let destination = Destination {
+        addr: AddressType::DomainName("rebound.test".into()),
+        port: 80,
+    };
+    let public = SocketAddr::from(([192, 0, 2, 1], 80));
+
+    let pinned = resolver::resolve_and_pin(&Rebinding, &destination, None, false)
+        .await
+        .unwrap();
+    assert_eq!(pinned, [public]);
+    let pinned = resolver::resolve_and_pin(&Rebinding, &destination, None, true)
+        .await
+        .unwrap();
+    assert_eq!(pinned, [SocketAddr::from(([10, 0, 0, 1], 80)), public]);
+
+    let acl = AclRules::parse("deny 192.0.2.0/24\ndefault allow\n").unwrap();
+    let e = resolver::resolve_and_pin(&Rebinding, &destination, Some(&acl), false)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    // Literal addresses are checked as well, without any lookup.
+    let literal = Destination::from(public);
+    let loopback = Loopback::default();
+    let pinned = resolver::resolve_and_pin(&loopback, &literal, None, false)
+        .await
+        .unwrap();
+    assert_eq!(pinned, [public]);
+    assert_eq!(loopback.lookups.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,2063 @@
+#![cfg(feature = "async")]
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use socks_parser::{
+    acl::AclRules,
+    auth::{BoxFuture, Identity, StaticUserDb},
+    handlers::{self, DynRequestHandler, LimitScope, RequestHandler},
+    policy::{UserPolicies, UserPolicy},
+    proxy::ProxyUrl,
+    recorder::{Direction, Record, Recorder, Transcript},
+    relay,
+    stats::CloseReason,
+    stream::EitherStream,
+    testing,
+    throttle::BandwidthLimit,
+    udp::UdpRelayOptions,
+    v4,
+    v5::{self, AddressType},
+    BindOptions, Client, ClientError, ConnectOptions, ConnectionRequest, DecodeLimits, Destination,
+    FailureReplyAddress, HandshakeTooLarge, ListenerOverrides, ParseMode, Rejection, Server, Wire,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpSocket, TcpStream, UdpSocket},
+};
+
+async fn handle_request(req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+    if req.destination.addr == AddressType::DomainName("panic.test".into()) {
+        panic!("handler bug");
+    }
+    let (stream, _) = tokio::io::duplex(64);
+    Ok((stream, req.destination))
+}
+
+async fn handle_stream(_local: TcpStream, _remote: DuplexStream) -> io::Result<()> {
+    Ok(())
+}
+
+#[tokio::test]
+async fn panicking_handler_releases_its_slot() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(listener).with_max_connections(1);
+    let stats = server.stats();
+    tokio::spawn(server.run(handle_request, handle_stream));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    assert!(Client::new(stream)
+        .connect(("panic.test", 80))
+        .await
+        .is_err());
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while stats.handler_panics() == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        Client::new(stream).connect(("ok.test", 80)),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(stats.handler_panics(), 1);
+}
+
+/// Request handler counting the requests it served.
+#[derive(Default)]
+struct Counting {
+    requests: AtomicUsize,
+}
+
+impl RequestHandler for Counting {
+    type Stream = DuplexStream;
+
+    async fn handle(&self, req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        handle_request(req).await
+    }
+}
+
+#[tokio::test]
+async fn serves_request_handler_traits() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let counting = Arc::new(Counting::default());
+    tokio::spawn(Server::new(listener).run_handler(Arc::clone(&counting), handle_stream));
+    for _ in 0..2 {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        Client::new(stream).connect(("ok.test", 80)).await.unwrap();
+    }
+    assert_eq!(counting.requests.load(Ordering::Relaxed), 2);
+
+    // Boxed handlers are picked at runtime.
+    let counting = Arc::new(Counting::default());
+    let handler: Box<dyn DynRequestHandler<DuplexStream>> = Box::new(Arc::clone(&counting));
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run_handler(handler, handle_stream));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    Client::new(stream).connect(("ok.test", 80)).await.unwrap();
+    assert_eq!(counting.requests.load(Ordering::Relaxed), 1);
+}
+
+async fn connect_direct(req: ConnectionRequest) -> io::Result<(TcpStream, Destination)> {
+    let addr = req.destination.addr.to_socket_addr(req.destination.port);
+    let stream = TcpStream::connect(addr.expect("IP destination")).await?;
+    let local = stream.local_addr()?;
+    Ok((stream, local.into()))
+}
+
+async fn spawn_server(server: impl FnOnce(TcpListener) -> Server) -> SocketAddr {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server(listener).run(connect_direct, handlers::relay));
+    addr
+}
+
+#[tokio::test]
+async fn sets_socket_options_of_clients() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let server = Server::new(listener)
+        .with_tcp_nodelay(true)
+        .with_tcp_keepalive(Duration::from_secs(60));
+    tokio::spawn(server.run(handle_request, move |local: TcpStream, _| {
+        let _ = tx.send(local.nodelay().unwrap());
+        async { Ok(()) }
+    }));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    Client::new(stream)
+        .connect(("example.com", 80))
+        .await
+        .unwrap();
+    assert!(rx.recv().await.unwrap());
+}
+
+#[tokio::test]
+async fn chains_to_upstream_proxy() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await.unwrap();
+    });
+
+    let upstream = spawn_server(Server::new).await;
+    let relay_listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let relay = relay_listener.local_addr().unwrap();
+    let proxy: ProxyUrl = format!("socks5://{upstream}").parse().unwrap();
+    tokio::spawn(Server::new(relay_listener).run(handlers::chain_to(proxy), handlers::relay));
+
+    let stream = TcpStream::connect(relay).await.unwrap();
+    let mut stream = Client::new(stream).connect(echo_addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+}
+
+#[tokio::test]
+async fn spreads_connections_over_upstream_pool() {
+    use std::sync::Arc;
+
+    use socks_parser::pool::{Strategy, UpstreamPool};
+
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+
+    let dead = TcpListener::bind(("127.0.0.1", 0))
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut proxies = vec![format!("socks5://{dead}").parse::<ProxyUrl>().unwrap()];
+    for _ in 0..2 {
+        let upstream = spawn_server(Server::new).await;
+        proxies.push(format!("socks5://{upstream}").parse().unwrap());
+    }
+    let pool = Arc::new(UpstreamPool::new(proxies, Strategy::RoundRobin));
+    pool.check_health(Duration::from_secs(1)).await;
+    let healthy: Vec<_> = pool.status().iter().map(|s| s.healthy).collect();
+    assert_eq!(healthy, [false, true, true]);
+
+    let relay_listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let relay = relay_listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::new(relay_listener)
+            .run(handlers::chain_to_pool(Arc::clone(&pool)), handlers::relay),
+    );
+
+    let mut streams = Vec::new();
+    for _ in 0..2 {
+        let stream = TcpStream::connect(relay).await.unwrap();
+        let mut stream = Client::new(stream).connect(echo_addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        streams.push(stream);
+    }
+    let active: Vec<_> = pool.status().iter().map(|s| s.active_connections).collect();
+    assert_eq!(active, [0, 1, 1]);
+}
+
+#[tokio::test]
+async fn dials_proxies_with_custom_dialers() {
+    use socks_parser::pool::{Strategy, UpstreamPool};
+
+    let transport = Arc::new(testing::serve(
+        testing::server(),
+        handle_request,
+        handlers::relay,
+    ));
+    let proxy: ProxyUrl = "socks5://192.0.2.1:1080".parse().unwrap();
+    let dials = Arc::new(AtomicUsize::new(0));
+    let dialer = {
+        let dials = Arc::clone(&dials);
+        move |addr: SocketAddr| {
+            assert_eq!(addr, SocketAddr::from(([192, 0, 2, 1], 1080)));
+            dials.fetch_add(1, Ordering::Relaxed);
+            let transport = Arc::clone(&transport);
+            async move { transport.connect().await }
+        }
+    };
+    let destination = Destination {
+        addr: AddressType::DomainName("example.test".into()),
+        port: 80,
+    };
+
+    Client::connect_dialed("192.0.2.1:1080", destination.clone(), &dialer)
+        .await
+        .unwrap();
+
+    let pool = UpstreamPool::new([proxy.clone()], Strategy::RoundRobin).with_dialer(dialer.clone());
+    let pool = Arc::new(pool);
+    let (stream, _) = pool.connect(destination.clone()).await.unwrap();
+    assert_eq!(stream.proxy(), &proxy);
+    pool.check_health(Duration::from_secs(1)).await;
+    assert!(pool.status()[0].healthy);
+
+    let chain = handlers::chain_through(proxy, dialer);
+    chain(ConnectionRequest::from(destination)).await.unwrap();
+    // One connection each, except the health check opening one per method it probes.
+    assert_eq!(dials.load(Ordering::Relaxed), 1 + 1 + 3 + 1);
+}
+
+#[tokio::test]
+async fn probes_proxies() {
+    use v5::AuthenticationMethod;
+
+    let timeout = Duration::from_secs(1);
+    let open = spawn_server(Server::new).await;
+    let proxy = format!("socks5://{open}").parse().unwrap();
+    let report = Client::probe(&proxy, timeout).await.unwrap();
+    assert_eq!(report.version, socks_parser::Version::Socks5);
+    assert_eq!(report.methods, [AuthenticationMethod::None]);
+    assert!(report.usable_by(&proxy));
+
+    let guarded = spawn_server(|listener| {
+        Server::new(listener).with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+    })
+    .await;
+    let anonymous = format!("socks5://{guarded}").parse().unwrap();
+    let report = Client::probe(&anonymous, timeout).await.unwrap();
+    assert_eq!(report.methods, [AuthenticationMethod::UsernamePassword]);
+    assert!(!report.usable_by(&anonymous));
+    let alice = format!("socks5://alice:secret@{guarded}").parse().unwrap();
+    assert!(Client::probe(&alice, timeout)
+        .await
+        .unwrap()
+        .usable_by(&alice));
+    let mallory = format!("socks5://alice:guess@{guarded}").parse().unwrap();
+    let error = Client::probe(&mallory, timeout).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+}
+
+#[cfg(all(feature = "extensions", unix))]
+#[tokio::test]
+async fn connects_to_allowed_unix_sockets() {
+    use std::path::PathBuf;
+
+    let path = std::env::temp_dir().join(format!("socks-unix-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await
+    });
+    let transport = testing::serve(
+        testing::server(),
+        handlers::unix_sockets([path.clone()]),
+        handlers::relay,
+    );
+
+    let stream = transport.connect().await.unwrap();
+    let mut stream = Client::new(stream)
+        .connect((AddressType::UnixPath(path.clone()), 0))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+
+    let stream = transport.connect().await.unwrap();
+    assert!(Client::new(stream)
+        .connect((AddressType::UnixPath(PathBuf::from("/run/docker.sock")), 0))
+        .await
+        .is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_clients_on_a_unix_socket() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await
+    });
+    let path = std::env::temp_dir().join(format!("socks-server-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    let server = Server::unbound();
+    let stats = server.stats();
+    tokio::spawn(server.serve(listener, connect_direct, handlers::relay));
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let mut stream = Client::new(stream).connect(echo_addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+    assert_eq!(stats.total_connections(), 1);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serves_several_listeners_with_overrides() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await
+    });
+    let path = std::env::temp_dir().join(format!("socks-listeners-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let tcp = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    let server = Server::new(tcp)
+        .with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+        .with_listener(
+            tokio::net::UnixListener::bind(&path).unwrap(),
+            ListenerOverrides::new().without_authentication(),
+        );
+    let stats = server.stats();
+    tokio::spawn(server.run_all(connect_direct, handlers::relay));
+
+    let stream = TcpStream::connect(tcp_addr).await.unwrap();
+    assert!(Client::new(stream).connect(echo_addr).await.is_err());
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let mut stream = Client::new(stream).connect(echo_addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+    assert_eq!(stats.total_connections(), 2);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(all(feature = "transparent", target_os = "linux"))]
+#[tokio::test]
+async fn transparent_listener_drops_direct_clients() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await
+    });
+    let tcp = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let tcp_addr = tcp.local_addr().unwrap();
+    let transparent = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let transparent_addr = transparent.local_addr().unwrap();
+    let server = Server::new(tcp).with_transparent_listener(transparent, ListenerOverrides::new());
+    let stats = server.stats();
+    tokio::spawn(server.run_all(connect_direct, handlers::relay));
+
+    // Not redirected, so it would be relayed to the listener itself.
+    let mut stream = TcpStream::connect(transparent_addr).await.unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(stream.read(&mut buffer).await.unwrap_or(0), 0);
+
+    let stream = TcpStream::connect(tcp_addr).await.unwrap();
+    let mut stream = Client::new(stream).connect(echo_addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+    assert_eq!(stats.total_connections(), 1);
+}
+
+#[tokio::test]
+async fn applications_own_the_accept_loop() {
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut r, mut w) = stream.split();
+        tokio::io::copy(&mut r, &mut w).await
+    });
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(listener);
+    let (shutdown, mut stopped) = tokio::sync::oneshot::channel::<()>();
+    let accept_loop = tokio::spawn(async move {
+        loop {
+            let incoming = tokio::select! {
+                incoming = server.accept() => incoming?,
+                _ = &mut stopped => return io::Result::Ok(()),
+            };
+            tokio::spawn(async move {
+                if let Some((local, remote)) = incoming.handshake(connect_direct).await? {
+                    handlers::relay(local, remote).await?;
+                }
+                io::Result::Ok(())
+            });
+        }
+    });
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = Client::new(stream).connect(echo_addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+
+    shutdown.send(()).unwrap();
+    accept_loop.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn bounds_the_handshake_size() {
+    let limits = DecodeLimits {
+        max_handshake_size: 64,
+        ..Default::default()
+    };
+    let handshake = Server::unbound()
+        .with_decode_limits(limits)
+        .handshake_only();
+    let peer: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+    let handshake_to = |name_len: usize| {
+        let mut message = vec![5, 1, 0];
+        v5::Request::new(v5::Command::Connect, &"a".repeat(name_len), 80)
+            .unwrap()
+            .encode_into(&mut message);
+        message
+    };
+
+    let (mut client, server) = tokio::io::duplex(1024);
+    client.write_all(&handshake_to(20)).await.unwrap();
+    handshake.read_request(server, peer).await.unwrap();
+
+    let (mut client, server) = tokio::io::duplex(1024);
+    client.write_all(&handshake_to(100)).await.unwrap();
+    let e = handshake.read_request(server, peer).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        e.get_ref().and_then(|e| e.downcast_ref()),
+        Some(&HandshakeTooLarge { limit: 64 })
+    );
+}
+
+#[tokio::test]
+async fn default_limits_fit_the_largest_handshake() {
+    let (username, password) = ("u".repeat(255), "p".repeat(255));
+    let handshake = Server::unbound()
+        .with_authenticator(StaticUserDb::new().with_user(&username, &password))
+        .handshake_only();
+    let peer: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+
+    let mut message = vec![5, 255];
+    message.extend(0..255);
+    message.extend([1, 255]);
+    message.extend_from_slice(username.as_bytes());
+    message.push(255);
+    message.extend_from_slice(password.as_bytes());
+    v5::Request::new(v5::Command::Connect, &"a".repeat(255), 80)
+        .unwrap()
+        .encode_into(&mut message);
+    assert_eq!(message.len(), 1032);
+
+    let (mut client, server) = tokio::io::duplex(4096);
+    client.write_all(&message).await.unwrap();
+    let (request, _) = handshake.read_request(server, peer).await.unwrap();
+    assert_eq!(request.request.identity.unwrap().username, username);
+}
+
+#[tokio::test]
+async fn handshake_only_leaves_the_reply_to_the_caller() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handshake = Server::unbound()
+        .with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+        .handshake_only();
+    tokio::spawn(async move {
+        let (stream, peer) = listener.accept().await.unwrap();
+        let (request, reply) = handshake.read_request(stream, peer).await.unwrap();
+        assert_eq!(request.command, v5::Command::Connect);
+        assert_eq!(request.request.identity.unwrap().username, "alice");
+        let bound: SocketAddr = "192.0.2.1:4242".parse().unwrap();
+        let mut stream = reply.success(bound).await.unwrap();
+        let (mut r, mut w) = tokio::io::split(&mut stream);
+        tokio::io::copy(&mut r, &mut w).await.unwrap();
+
+        let (stream, peer) = listener.accept().await.unwrap();
+        let (_, reply) = handshake.read_request(stream, peer).await.unwrap();
+        reply.failure(v5::Status::HostUnreachalble).await.unwrap();
+    });
+
+    let credentials = || -> ProxyUrl { format!("socks5://alice:secret@{addr}").parse().unwrap() };
+    let mut stream = Client::connect_via(&credentials(), ("example.test", 80))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+    drop(stream);
+
+    let error = Client::connect_via(&credentials(), ("example.test", 80))
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "HostUnreachalble");
+}
+
+#[tokio::test]
+async fn routes_socks4_requests_by_userid() {
+    use std::collections::HashMap;
+
+    use handlers::Route;
+
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let upstream = spawn_server(Server::new).await;
+    let routes = HashMap::from([
+        (
+            "local".to_owned(),
+            Route::Direct(ConnectOptions {
+                local_addr: Some(([127, 0, 0, 1], 0).into()),
+                ..Default::default()
+            }),
+        ),
+        (
+            "chained".to_owned(),
+            Route::Proxy(format!("socks5://{upstream}").parse().unwrap()),
+        ),
+    ]);
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run(handlers::route_by_userid(routes), handlers::relay));
+
+    for (user_id, expected) in [
+        ("local", v4::Status::Success),
+        ("chained", v4::Status::Success),
+        ("unknown", v4::Status::Rejected),
+    ] {
+        let request = v4::Request {
+            command: v4::Command::Connect,
+            addr: v4::AddressType::IPv4(std::net::Ipv4Addr::LOCALHOST),
+            port: target_addr.port(),
+            secret: Some(user_id.into()),
+        };
+        let mut buffer = Vec::new();
+        request.encode_into(&mut buffer);
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&buffer).await.unwrap();
+        let mut reply = [0; 8];
+        stream.read_exact(&mut reply).await.unwrap();
+        let (_, response) = v4::Response::decode::<()>(&reply).unwrap();
+        assert_eq!(response.status, expected, "{user_id}");
+    }
+}
+
+#[tokio::test]
+async fn inspector_sees_requests_as_sent() {
+    use std::sync::Mutex;
+
+    use socks_parser::InspectedRequest;
+
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let proxy = spawn_server(|listener| {
+        let seen = Arc::clone(&seen);
+        Server::new(listener).with_inspector(move |_, request| {
+            let seen_request = match request {
+                InspectedRequest::V4(req) => format!("v4 {:?}", req.secret),
+                InspectedRequest::V5 { hello, request } => format!(
+                    "v5 {:?} {}",
+                    hello.methods,
+                    request.addr.to_socket_addr(request.port).unwrap()
+                ),
+            };
+            seen.lock().unwrap().push(seen_request);
+        })
+    })
+    .await;
+
+    let request = v4::Request {
+        command: v4::Command::Connect,
+        addr: v4::AddressType::IPv4(std::net::Ipv4Addr::LOCALHOST),
+        port: target_addr.port(),
+        secret: Some("analytics".into()),
+    };
+    let mut buffer = Vec::new();
+    request.encode_into(&mut buffer);
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&buffer).await.unwrap();
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    Client::new(stream)
+        .with_username_password("alice", "secret")
+        .connect(target_addr)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            String::from("v4 Some(\"analytics\")"),
+            format!("v5 [UsernamePassword, None] {target_addr}"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn connect_tcp_applies_socket_options() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy = spawn_server(Server::new).await;
+
+    let options = ConnectOptions {
+        nodelay: true,
+        keepalive: Some(Duration::from_secs(30)),
+        local_addr: Some(([127, 0, 0, 1], 0).into()),
+        connect_timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let stream = Client::connect_tcp(proxy, target_addr, &options)
+        .await
+        .unwrap();
+    assert!(stream.nodelay().unwrap());
+    let (_, peer) = target.accept().await.unwrap();
+    assert_eq!(peer.ip(), proxy.ip());
+}
+
+#[tokio::test]
+async fn accepts_requests_pipelined_with_the_hello() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let proxy = spawn_server(Server::new).await;
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let mut stream = Client::new(stream)
+        .with_optimistic_handshake(true)
+        .connect(target_addr)
+        .await
+        .unwrap();
+    let (mut peer, _) = target.accept().await.unwrap();
+    peer.write_all(b"hi").await.unwrap();
+    let mut greeting = [0; 2];
+    stream.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(&greeting, b"hi");
+}
+
+#[tokio::test]
+async fn transforms_tunnels_after_the_handshake() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    // Stands for a TLS handshake: the server greets the client, which checks the greeting.
+    let greet = |mut stream: TcpStream| async move {
+        stream.write_all(b"wrapped").await?;
+        Ok(stream)
+    };
+    tokio::spawn(Server::new(listener).run(
+        connect_direct,
+        handlers::transform_client(Some(greet), handlers::relay),
+    ));
+
+    let expect_greeting = |mut stream: TcpStream| async move {
+        let mut greeting = [0; 7];
+        stream.read_exact(&mut greeting).await?;
+        assert_eq!(&greeting, b"wrapped");
+        Ok(stream)
+    };
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let mut stream = Client::new(stream)
+        .connect_transformed(target_addr, &expect_greeting)
+        .await
+        .unwrap();
+    let (mut peer, _) = target.accept().await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut ping = [0; 4];
+    peer.read_exact(&mut ping).await.unwrap();
+    assert_eq!(&ping, b"ping");
+}
+
+#[tokio::test]
+async fn handlers_see_the_connection_id() {
+    use socks_parser::ConnectionId;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let request_tx = tx.clone();
+    tokio::spawn(Server::new(listener).run(
+        move |req| {
+            request_tx.send(ConnectionId::current()).unwrap();
+            handle_request(req)
+        },
+        move |local, remote| {
+            tx.send(ConnectionId::current()).unwrap();
+            handle_stream(local, remote)
+        },
+    ));
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        Client::new(stream).connect(("ok.test", 80)).await.unwrap();
+        let in_request = rx.recv().await.unwrap().unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Some(in_request));
+        ids.push(in_request);
+    }
+    assert_ne!(ids[0], ids[1]);
+    assert_eq!(ConnectionId::current(), None);
+}
+
+#[tokio::test]
+async fn authenticator_turns_socks4_clients_away() {
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let port = target.local_addr().unwrap().port();
+    let proxy = spawn_server(|listener| {
+        Server::new(listener).with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let [hi, lo] = port.to_be_bytes();
+    stream
+        .write_all(&[4, 1, hi, lo, 127, 0, 0, 1, 0])
+        .await
+        .unwrap();
+    let mut reply = [0; 8];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(stream.read(&mut [0; 8]).await.unwrap(), 0);
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), target.accept())
+            .await
+            .is_err(),
+        "SOCKS4 client bypassed the authenticator"
+    );
+}
+
+#[tokio::test]
+async fn denies_private_destinations() {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server = Server::new(listener).allow_private_destinations(false);
+    tokio::spawn(server.run(
+        move |req: ConnectionRequest| {
+            tx.send(req.allow_private).unwrap();
+            handle_request(req)
+        },
+        handle_stream,
+    ));
+
+    for destination in ["127.0.0.1", "169.254.169.254", "localhost"] {
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let e = Client::new(stream)
+            .connect((destination, 80))
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied, "{destination}");
+    }
+    // Names are left to the request handler to check once resolved.
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    Client::new(stream).connect(("ok.test", 80)).await.unwrap();
+    assert_eq!(rx.recv().await, Some(false));
+}
+
+#[tokio::test]
+async fn records_and_replays_handshakes() {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    let server =
+        Server::new(listener).with_recorder(move |_, transcript| tx.send(transcript).unwrap());
+    tokio::spawn(server.run(handle_request, handle_stream));
+    let target_addr: SocketAddr = ([192, 0, 2, 1], 80).into();
+
+    let stream = Recorder::new(TcpStream::connect(proxy).await.unwrap());
+    let stream = Client::new(stream).connect(target_addr).await.unwrap();
+    let (_, client) = stream.into_parts();
+    let server = rx.recv().await.unwrap();
+
+    let concat = |chunks: Vec<&[u8]>| chunks.concat();
+    assert_eq!(
+        concat(client.sent().collect()),
+        concat(server.received().collect())
+    );
+    assert_eq!(
+        concat(client.received().collect()),
+        concat(server.sent().collect())
+    );
+
+    let mut jsonl = Vec::new();
+    client.write_jsonl(&mut jsonl).unwrap();
+    assert_eq!(Transcript::read_jsonl(&jsonl[..]).unwrap(), client);
+    let mut binary = Vec::new();
+    client.write_binary(&mut binary).unwrap();
+    assert_eq!(Transcript::read_binary(&binary[..]).unwrap(), client);
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let (replayed, result) = Server::new(listener).replay(&client, handle_request).await;
+    result.unwrap();
+    assert_eq!(
+        concat(replayed.sent().collect()),
+        concat(client.received().collect())
+    );
+}
+
+#[test]
+fn masks_recorded_credentials() {
+    use Direction::{Received, Sent};
+
+    let transcript = |chunks: &[(Direction, &[u8])]| Transcript {
+        records: chunks
+            .iter()
+            .map(|&(direction, data)| Record {
+                elapsed: Duration::ZERO,
+                direction,
+                data: data.to_vec(),
+            })
+            .collect(),
+    };
+
+    // Password split across reads.
+    let mut socks = transcript(&[
+        (Received, b"\x05\x01\x02\x01\x05alice\x06sec"),
+        (Sent, b"\x05\x02"),
+        (Received, b"ret\x05\x01\x00\x01"),
+    ]);
+    socks.mask_credentials(Received);
+    assert_eq!(
+        socks.received_bytes(),
+        b"\x05\x01\x02\x01\x05alice\x06******\x05\x01\x00\x01"
+    );
+
+    let request =
+        b"CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n";
+    let mut http = transcript(&[(Sent, request)]);
+    http.mask_credentials(Sent);
+    let masked = String::from_utf8(http.sent_bytes()).unwrap();
+    assert_eq!(
+        masked,
+        "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization:***********************\r\n\r\n"
+    );
+
+    // Nothing to mask without username/password authentication.
+    let mut anonymous = transcript(&[
+        (Received, b"\x05\x01\x00\x05\x01\x00\x01"),
+        (Sent, b"\x05\x00"),
+    ]);
+    let unchanged = anonymous.clone();
+    anonymous.mask_credentials(Received);
+    assert_eq!(anonymous, unchanged);
+}
+
+#[tokio::test]
+async fn replies_carry_what_the_destination_already_sent() {
+    async fn greeting_destination(
+        req: ConnectionRequest,
+    ) -> io::Result<(DuplexStream, Destination)> {
+        let (remote, mut target) = tokio::io::duplex(1024);
+        target.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await?;
+        Ok((remote, req.destination))
+    }
+
+    let request = [5, 1, 0, 1, 192, 0, 2, 1, 0, 22];
+    let client = Transcript {
+        records: [&[5, 1, 0][..], &request]
+            .into_iter()
+            .map(|data| Record {
+                elapsed: Duration::ZERO,
+                direction: Direction::Sent,
+                data: data.to_vec(),
+            })
+            .collect(),
+    };
+    let (replayed, result) = testing::server()
+        .replay(&client, greeting_destination)
+        .await;
+    result.unwrap();
+    let sent: Vec<_> = replayed.sent().collect();
+    assert_eq!(sent.len(), 2, "{sent:?}");
+    assert_eq!(
+        sent[1],
+        [
+            &[5, 0, 0, 1, 192, 0, 2, 1, 0, 22][..],
+            b"SSH-2.0-OpenSSH_9.6\r\n"
+        ]
+        .concat()
+    );
+}
+
+#[tokio::test]
+async fn session_summaries_account_for_each_tunnel() {
+    async fn echo(req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+        let (remote, target) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(target);
+            tokio::io::copy(&mut reader, &mut writer).await
+        });
+        Ok((remote, req.destination))
+    }
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let server = testing::server().with_session_summaries(move |_, summary| {
+        tx.send(summary).unwrap();
+    });
+    let transport = testing::serve(server, echo, handlers::relay);
+    let mut stream = Client::new(transport.connect().await.unwrap())
+        .connect(("echo.test", 80))
+        .await
+        .unwrap();
+    stream.write_all(b"ping!").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, b"ping!");
+
+    let summary = rx.recv().await.unwrap();
+    assert_eq!(
+        summary.destination,
+        Destination {
+            addr: AddressType::DomainName("echo.test".into()),
+            port: 80,
+        }
+    );
+    assert_eq!((summary.bytes_up, summary.bytes_down), (5, 5));
+    assert_eq!(summary.close_reason, CloseReason::Finished);
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let server = testing::server().with_session_summaries(move |_, summary| {
+        tx.send(summary).unwrap();
+    });
+    let transport = testing::serve(server, echo, |_, _| async {
+        Err::<(), _>(io::Error::from(io::ErrorKind::BrokenPipe))
+    });
+    Client::new(transport.connect().await.unwrap())
+        .connect(("echo.test", 80))
+        .await
+        .unwrap();
+    let summary = rx.recv().await.unwrap();
+    assert_eq!(
+        summary.close_reason,
+        CloseReason::Failed(io::ErrorKind::BrokenPipe)
+    );
+}
+
+/// Both ends of a loopback TCP connection.
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let connecting = TcpStream::connect(listener.local_addr().unwrap());
+    let (connected, accepted) = tokio::join!(connecting, listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+#[tokio::test]
+async fn zero_copy_relay_moves_data_both_ways() {
+    let (mut client, local) = tcp_pair().await;
+    let (remote, mut target) = tcp_pair().await;
+    let relay = tokio::spawn(relay::pipe_zero_copy(local, remote));
+
+    let upload = vec![7; 200_000];
+    client.write_all(&upload).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    target.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, upload);
+
+    target.write_all(b"bye").await.unwrap();
+    drop(target);
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"bye");
+    assert_eq!(relay.await.unwrap().unwrap(), (200_000, 3));
+
+    // Streams other than TCP ones are copied.
+    let (local, mut client) = tokio::io::duplex(1024);
+    let (remote, mut target) = tcp_pair().await;
+    let relay = tokio::spawn(relay::pipe_zero_copy(local, remote));
+    client.write_all(b"hello").await.unwrap();
+    drop(client);
+    let mut received = Vec::new();
+    target.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"hello");
+    drop(target);
+    assert_eq!(relay.await.unwrap().unwrap(), (5, 0));
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+#[tokio::test]
+async fn uring_relay_moves_data_both_ways() {
+    let relay = Arc::new(relay::UringRelay::new(2).unwrap());
+    let transport = testing::serve(
+        testing::server(),
+        |req: ConnectionRequest| async move {
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+            let connecting = TcpStream::connect(listener.local_addr()?);
+            let (connected, accepted) = tokio::join!(connecting, listener.accept());
+            let (mut target, _) = accepted?;
+            tokio::spawn(async move {
+                let (mut r, mut w) = target.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+            Ok((connected?, req.destination))
+        },
+        handlers::relay_uring(Arc::clone(&relay)),
+    );
+
+    // The client stream of the memory transport is not a TCP one, so it is copied as usual.
+    let mut stream = Client::new(transport.connect().await.unwrap())
+        .connect(("echo.test", 80))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+
+    let (mut client, local) = tcp_pair().await;
+    let (remote, mut target) = tcp_pair().await;
+    let piped = tokio::spawn({
+        let relay = Arc::clone(&relay);
+        async move { relay.pipe(local, remote).await }
+    });
+    let upload = vec![7; 200_000];
+    client.write_all(&upload).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    target.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, upload);
+
+    target.write_all(b"bye").await.unwrap();
+    drop(target);
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"bye");
+    assert_eq!(piped.await.unwrap().unwrap(), (200_000, 3));
+}
+
+#[tokio::test(start_paused = true)]
+async fn throttled_relay_caps_download_rate() {
+    let (local, mut client) = tokio::io::duplex(64 * 1024);
+    let (remote, mut target) = tokio::io::duplex(64 * 1024);
+    let limit = BandwidthLimit {
+        upload: None,
+        download: Some(1000),
+    };
+    let relay = handlers::relay_throttled(limit, LimitScope::PerConnection);
+    tokio::spawn(relay(local, remote));
+
+    let start = tokio::time::Instant::now();
+    target.write_all(&[0; 3000]).await.unwrap();
+    let mut received = [0; 3000];
+    client.read_exact(&mut received).await.unwrap();
+    // One second of burst, then 1000 bytes per second.
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(1900), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(3), "{elapsed:?}");
+
+    let start = tokio::time::Instant::now();
+    client.write_all(&[0; 3000]).await.unwrap();
+    target.read_exact(&mut received).await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn user_policies_restrict_each_user() {
+    let policy = UserPolicy::new()
+        .with_acl(AclRules::parse("deny *:22").unwrap())
+        .with_max_connections(1);
+    let policies = Arc::new(UserPolicies::new().with_user("alice", policy));
+    let server = testing::server()
+        .with_authenticator(
+            StaticUserDb::new()
+                .with_user("alice", "secret")
+                .with_user("bob", "secret"),
+        )
+        .with_user_policies(Arc::clone(&policies));
+    let transport = testing::serve(server, handle_request, handlers::relay);
+    let connect = |username: &'static str, port| {
+        let transport = &transport;
+        async move {
+            Client::new(transport.connect().await?)
+                .with_username_password(username, "secret")
+                .connect(("example.com", port))
+                .await
+        }
+    };
+
+    let tunnel = connect("alice", 80).await.unwrap();
+    assert_eq!(policies.active_connections("alice"), 1);
+    assert!(connect("alice", 80).await.is_err());
+    let _other = connect("bob", 80).await.unwrap();
+    assert_eq!(policies.active_connections("bob"), 0);
+
+    drop(tunnel);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while policies.active_connections("alice") > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(connect("alice", 22).await.is_err());
+    assert!(connect("bob", 22).await.is_ok());
+    connect("alice", 80).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn user_bandwidth_is_shared_by_their_tunnels() {
+    let limit = BandwidthLimit {
+        upload: Some(1000),
+        download: None,
+    };
+    let policies = UserPolicies::new().with_user("alice", UserPolicy::new().with_bandwidth(limit));
+    let alice = Identity {
+        username: "alice".into(),
+    };
+    let bob = Identity {
+        username: "bob".into(),
+    };
+
+    let (first, _peer) = tokio::io::duplex(64 * 1024);
+    let mut first = policies.throttle(Some(&alice), first);
+    let (second, _peer) = tokio::io::duplex(64 * 1024);
+    let mut second = policies.throttle(Some(&alice), second);
+    let start = tokio::time::Instant::now();
+    first.write_all(&[0; 1000]).await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+    second.write_all(&[0; 1000]).await.unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(900), "{elapsed:?}");
+
+    let (other, _peer) = tokio::io::duplex(64 * 1024);
+    let mut other = policies.throttle(Some(&bob), other);
+    let start = tokio::time::Instant::now();
+    other.write_all(&[0; 3000]).await.unwrap();
+    assert!(start.elapsed() < Duration::from_millis(100));
+}
+
+async fn connect_or_loopback(
+    req: ConnectionRequest,
+) -> io::Result<(EitherStream<DuplexStream, TcpStream>, Destination)> {
+    if req.destination.addr == AddressType::DomainName("loopback.test".into()) {
+        let (stream, mut peer) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            let (mut r, mut w) = tokio::io::split(&mut peer);
+            tokio::io::copy(&mut r, &mut w).await
+        });
+        return Ok((EitherStream::Left(stream), req.destination));
+    }
+    let (stream, bound) = connect_direct(req).await?;
+    Ok((EitherStream::Right(stream), bound))
+}
+
+#[tokio::test]
+async fn handlers_return_heterogeneous_streams() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run(connect_or_loopback, handlers::relay));
+
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    Client::new(stream).connect(target_addr).await.unwrap();
+    target.accept().await.unwrap();
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let mut stream = Client::new(stream)
+        .connect(("loopback.test", 80))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+}
+
+#[tokio::test]
+async fn boxed_handlers_share_a_stream_type() {
+    let loopback = handlers::boxed(handle_request);
+    let direct = handlers::boxed(connect_direct);
+    let handle_request = move |req: ConnectionRequest| match req.destination.addr {
+        AddressType::DomainName(_) => loopback(req),
+        _ => direct(req),
+    };
+    let transport = testing::serve(testing::server(), handle_request, handlers::relay);
+
+    let target = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let target_addr = target.local_addr().unwrap();
+    let stream = transport.connect().await.unwrap();
+    Client::new(stream).connect(target_addr).await.unwrap();
+    target.accept().await.unwrap();
+
+    let stream = transport.connect().await.unwrap();
+    Client::new(stream)
+        .connect(("example.com", 80))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn rejections_reach_the_client() {
+    async fn reject(req: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+        match req.destination.port {
+            1 => Err(Rejection::Status(v5::Status::ConnectionRefused).into()),
+            2 => Err(io::ErrorKind::HostUnreachable.into()),
+            _ => Err(io::Error::other("handler failure")),
+        }
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run(reject, handle_stream));
+
+    for (port, expected) in [
+        (1, v5::Status::ConnectionRefused),
+        (2, v5::Status::HostUnreachalble),
+        (3, v5::Status::GeneralFailure),
+    ] {
+        let mut stream = TcpStream::connect(proxy).await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut hello = [0; 2];
+        stream.read_exact(&mut hello).await.unwrap();
+        let request = v5::Request {
+            command: v5::Command::Connect,
+            rsv: 0,
+            addr: AddressType::IPv4([192, 0, 2, 1].into()),
+            port,
+        };
+        let mut buffer = Vec::new();
+        request.encode_into(&mut buffer);
+        stream.write_all(&buffer).await.unwrap();
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        let (_, response) = v5::Response::decode::<()>(&reply).unwrap();
+        assert_eq!(response.status, expected);
+    }
+}
+
+#[tokio::test]
+async fn commands_other_than_connect_are_not_supported() {
+    async fn unreachable(_: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+        panic!("BIND and UDP ASSOCIATE are not handed to request handlers")
+    }
+
+    let transport = testing::serve(testing::server(), unreachable, handlers::relay);
+    for command in [2, 3] {
+        let mut stream = transport.connect().await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.read_exact(&mut [0; 2]).await.unwrap();
+        stream
+            .write_all(&[5, command, 0, 1, 192, 0, 2, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    let mut stream = transport.connect().await.unwrap();
+    stream
+        .write_all(&[4, 2, 0, 80, 192, 0, 2, 1, 0])
+        .await
+        .unwrap();
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.unwrap();
+    assert_eq!(reply, [0, 91, 0, 0, 0, 0, 0, 0]);
+}
+
+/// Sends `payload` to `to` through the UDP relay at `relay`, returning the reply.
+async fn udp_exchange(
+    socket: &UdpSocket,
+    relay: SocketAddr,
+    to: SocketAddr,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut datagram = Vec::new();
+    v5::UdpHeader {
+        frag: 0,
+        addr: AddressType::from(to.ip()),
+        port: to.port(),
+    }
+    .encode_into(&mut datagram);
+    datagram.extend_from_slice(payload);
+    socket.send_to(&datagram, relay).await.unwrap();
+
+    let mut buffer = [0; 64];
+    let n = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buffer))
+        .await
+        .unwrap()
+        .unwrap();
+    let (payload, header) = v5::UdpHeader::decode::<()>(&buffer[..n]).unwrap();
+    assert_eq!(
+        (header.addr, header.port),
+        (AddressType::from(to.ip()), to.port())
+    );
+    payload.to_vec()
+}
+
+/// Spawns a UDP server sending datagrams back to their sender, returning its address.
+async fn udp_echo() -> SocketAddr {
+    let echo = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0; 64];
+        loop {
+            let (n, from) = echo.recv_from(&mut buffer).await.unwrap();
+            echo.send_to(&buffer[..n], from).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn udp_association_follows_its_client() {
+    let echo_addr = udp_echo().await;
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(listener)
+        .with_udp_relay_options(UdpRelayOptions::new().bind_address([127, 0, 0, 1].into()));
+    let stats = server.stats();
+    tokio::spawn(server.run(connect_direct, handlers::relay));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut control = Client::new(stream).handshake_only().await.unwrap();
+    let relay = control
+        .request(
+            v5::Command::UdpAssociate,
+            SocketAddr::from(([0, 0, 0, 0], 0)),
+        )
+        .await
+        .unwrap();
+    let relay = relay.addr.to_socket_addr(relay.port).unwrap();
+
+    // Another host cannot claim the association before its client.
+    let intruder = UdpSocket::bind(("127.0.0.2", 0)).await.unwrap();
+    let mut datagram = Vec::new();
+    v5::UdpHeader {
+        frag: 0,
+        addr: AddressType::from(echo_addr.ip()),
+        port: echo_addr.port(),
+    }
+    .encode_into(&mut datagram);
+    datagram.extend_from_slice(b"hijack");
+    intruder.send_to(&datagram, relay).await.unwrap();
+    let dropped =
+        tokio::time::timeout(Duration::from_millis(200), intruder.recv(&mut [0; 64])).await;
+    assert!(dropped.is_err(), "Datagram of another host relayed");
+
+    let client = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    assert_eq!(
+        udp_exchange(&client, relay, echo_addr, b"ping").await,
+        b"ping"
+    );
+    // The NAT of the client maps it to another port.
+    let rebound = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    assert_eq!(
+        udp_exchange(&rebound, relay, echo_addr, b"pong").await,
+        b"pong"
+    );
+
+    drop(control);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while stats.active_connections() != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Association torn down with its control connection");
+}
+
+#[tokio::test]
+async fn udp_association_applies_user_policies() {
+    let allowed = udp_echo().await;
+    let denied = udp_echo().await;
+    let rule = format!("deny *:{}", denied.port());
+    let policies = UserPolicies::new().with_user(
+        "alice",
+        UserPolicy::new().with_acl(AclRules::parse(&rule).unwrap()),
+    );
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(listener)
+        .with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+        .with_user_policies(Arc::new(policies))
+        .with_udp_relay_options(UdpRelayOptions::new().bind_address([127, 0, 0, 1].into()));
+    tokio::spawn(server.run(connect_direct, handlers::relay));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut control = Client::new(stream)
+        .with_username_password("alice", "secret")
+        .handshake_only()
+        .await
+        .unwrap();
+    let relay = control
+        .request(
+            v5::Command::UdpAssociate,
+            SocketAddr::from(([0, 0, 0, 0], 0)),
+        )
+        .await
+        .unwrap();
+    let relay = relay.addr.to_socket_addr(relay.port).unwrap();
+
+    let client = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let mut datagram = Vec::new();
+    v5::UdpHeader {
+        frag: 0,
+        addr: AddressType::from(denied.ip()),
+        port: denied.port(),
+    }
+    .encode_into(&mut datagram);
+    datagram.extend_from_slice(b"denied");
+    client.send_to(&datagram, relay).await.unwrap();
+    assert_eq!(
+        udp_exchange(&client, relay, allowed, b"allowed").await,
+        b"allowed",
+        "Only the allowed destination answers"
+    );
+}
+
+/// Reads the second reply to a BIND request, naming an IPv4 peer.
+async fn second_reply(control: &mut TcpStream) -> v5::Response {
+    let mut reply = [0; 10];
+    control.read_exact(&mut reply).await.unwrap();
+    v5::Response::decode::<()>(&reply).unwrap().1
+}
+
+#[tokio::test]
+async fn bind_relays_only_the_expected_peer() {
+    let peer = TcpSocket::new_v4().unwrap();
+    peer.bind(([127, 0, 0, 1], 0).into()).unwrap();
+    let peer_addr = peer.local_addr().unwrap();
+    let addr = spawn_server(|listener| {
+        Server::new(listener).with_bind_options(
+            BindOptions::new([127, 0, 0, 1].into())
+                .with_accept_timeout(Duration::from_millis(200))
+                .with_expected_peer(move |_| Some(peer_addr)),
+        )
+    })
+    .await;
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut control = Client::new(stream).handshake_only().await.unwrap();
+    let bound = control
+        .request(v5::Command::Bind, SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let bound = bound.addr.to_socket_addr(bound.port).unwrap();
+    let mut intruder = TcpStream::connect(bound).await.unwrap();
+    assert_eq!(intruder.read(&mut [0; 1]).await.unwrap(), 0);
+    let mut peer = peer.connect(bound).await.unwrap();
+    let reply = second_reply(control.get_mut()).await;
+    assert_eq!(reply.status, v5::Status::Success);
+    assert_eq!(reply.addr.to_socket_addr(reply.port), Some(peer_addr));
+    peer.write_all(b"ping").await.unwrap();
+    let mut received = [0; 4];
+    control.get_mut().read_exact(&mut received).await.unwrap();
+    assert_eq!(&received, b"ping");
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut control = Client::new(stream).handshake_only().await.unwrap();
+    control
+        .request(v5::Command::Bind, SocketAddr::from(([127, 0, 0, 1], 0)))
+        .await
+        .unwrap();
+    let reply = second_reply(control.get_mut()).await;
+    assert_eq!(reply.status, v5::Status::TTLExpired);
+}
+
+#[tokio::test]
+async fn bind_destination_is_authorized() {
+    let addr = spawn_server(|listener| {
+        Server::new(listener)
+            .with_acl(AclRules::parse("deny 192.0.2.0/24").unwrap())
+            .with_bind_options(BindOptions::new([127, 0, 0, 1].into()))
+    })
+    .await;
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut control = Client::new(stream).handshake_only().await.unwrap();
+    let e = control
+        .request(v5::Command::Bind, SocketAddr::from(([192, 0, 2, 1], 80)))
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        ClientError::of(&e),
+        Some(ClientError::RequestDenied(v5::Status::ConnectionNotAllowed))
+    ));
+}
+
+#[tokio::test]
+async fn failure_replies_follow_the_address_policy() {
+    async fn refuse(_: ConnectionRequest) -> io::Result<(DuplexStream, Destination)> {
+        Err(io::ErrorKind::ConnectionRefused.into())
+    }
+
+    let v5_request = [5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+    let v4_request = [4, 1, 0, 80, 192, 0, 2, 1, 0];
+    for (policy, v5_reply, v4_reply) in [
+        (
+            FailureReplyAddress::Zeroed,
+            [5, 5, 0, 1, 0, 0, 0, 0, 0, 0],
+            [0, 91, 0, 0, 0, 0, 0, 0],
+        ),
+        (
+            FailureReplyAddress::Requested,
+            [5, 5, 0, 1, 192, 0, 2, 1, 0, 80],
+            [0, 91, 0, 80, 192, 0, 2, 1],
+        ),
+    ] {
+        let server = testing::server().with_failure_reply_address(policy);
+        let transport = testing::serve(server, refuse, handlers::relay);
+
+        let mut stream = transport.connect().await.unwrap();
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        stream.read_exact(&mut [0; 2]).await.unwrap();
+        stream.write_all(&v5_request).await.unwrap();
+        let mut reply = [0; 10];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, v5_reply, "{policy:?}");
+
+        let mut stream = transport.connect().await.unwrap();
+        stream.write_all(&v4_request).await.unwrap();
+        let mut reply = [0; 8];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, v4_reply, "{policy:?}");
+    }
+}
+
+#[tokio::test]
+async fn serves_in_memory_clients() {
+    let server =
+        testing::server().with_authenticator(StaticUserDb::new().with_user("alice", "secret"));
+    let stats = server.stats();
+    let transport = testing::serve(server, handle_request, handlers::relay);
+
+    let stream = transport.connect().await.unwrap();
+    let mut stream = Client::new(stream)
+        .with_username_password("alice", "secret")
+        .connect(("example.com", 80))
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+
+    let stream = transport.connect().await.unwrap();
+    assert!(Client::new(stream)
+        .with_username_password("alice", "wrong")
+        .connect(("example.com", 80))
+        .await
+        .is_err());
+    assert_eq!(stats.auth_failures(), 1);
+
+    assert!(testing::server()
+        .run(handle_request, handle_stream)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn handlers_see_the_handshake_deadline() {
+    let timeout = Duration::from_millis(200);
+    let handle_request = move |req: ConnectionRequest| async move {
+        let time_left = req.time_left().expect("deadline");
+        assert!(time_left <= timeout);
+        if req.destination.port == 80 {
+            tokio::time::sleep(time_left * 2).await;
+        }
+        let (stream, _) = tokio::io::duplex(64);
+        Ok((stream, req.destination))
+    };
+    let transport = testing::serve(
+        testing::server().with_handshake_timeout(timeout),
+        handle_request,
+        handlers::relay,
+    );
+
+    let stream = transport.connect().await.unwrap();
+    Client::new(stream)
+        .connect(("example.com", 443))
+        .await
+        .unwrap();
+    let stream = transport.connect().await.unwrap();
+    assert!(Client::new(stream)
+        .connect(("example.com", 80))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn method_selector_picks_authentication() {
+    use socks_parser::auth::{BoxFuture, MethodSelector};
+    use v5::{AuthenticationMethod, Hello};
+
+    /// Lets local clients in without credentials.
+    struct LocalTrusted;
+
+    impl MethodSelector for LocalTrusted {
+        fn select<'a>(
+            &'a self,
+            peer: SocketAddr,
+            hello: &'a Hello,
+        ) -> BoxFuture<'a, io::Result<AuthenticationMethod>> {
+            let priority: &[_] = if peer.ip().is_loopback() {
+                &[
+                    AuthenticationMethod::None,
+                    AuthenticationMethod::UsernamePassword,
+                ]
+            } else {
+                &[AuthenticationMethod::UsernamePassword]
+            };
+            let method = hello
+                .preferred(priority)
+                .unwrap_or(AuthenticationMethod::NotAcceptable);
+            Box::pin(async move { Ok(method) })
+        }
+    }
+
+    let server = testing::server()
+        .with_authenticator(StaticUserDb::new().with_user("alice", "secret"))
+        .with_method_selector(LocalTrusted);
+    let transport = testing::serve(server, handle_request, handlers::relay);
+    let external = "203.0.113.7:4242".parse().unwrap();
+
+    let stream = transport.connect().await.unwrap();
+    Client::new(stream)
+        .connect(("example.com", 80))
+        .await
+        .unwrap();
+
+    let stream = transport.connect_from(external).await.unwrap();
+    assert!(Client::new(stream)
+        .connect(("example.com", 80))
+        .await
+        .is_err());
+
+    let stream = transport.connect_from(external).await.unwrap();
+    Client::new(stream)
+        .with_username_password("alice", "secret")
+        .connect(("example.com", 80))
+        .await
+        .unwrap();
+}
+
+#[cfg(feature = "tor")]
+#[tokio::test]
+async fn answers_resolve_requests() {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use socks_parser::{auth::BoxFuture, resolve::ResolveHandler};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7));
+
+    struct Zone;
+
+    impl ResolveHandler for Zone {
+        fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<IpAddr>> {
+            Box::pin(async move {
+                match host {
+                    "example.com" => Ok(IP),
+                    _ => Err(io::ErrorKind::HostUnreachable.into()),
+                }
+            })
+        }
+
+        fn resolve_ptr(&self, ip: IpAddr) -> BoxFuture<'_, io::Result<String>> {
+            Box::pin(async move {
+                assert_eq!(ip, IP);
+                Ok("example.com".into())
+            })
+        }
+    }
+
+    let transport = testing::serve(
+        testing::server().with_resolver(Zone),
+        handle_request,
+        handlers::relay,
+    );
+    let stream = transport.connect().await.unwrap();
+    let ip = Client::new(stream).resolve("example.com").await.unwrap();
+    assert_eq!(ip, IP);
+    let stream = transport.connect().await.unwrap();
+    let name = Client::new(stream).resolve_ptr(IP).await.unwrap();
+    assert_eq!(name, "example.com");
+    let stream = transport.connect().await.unwrap();
+    let error = Client::new(stream).resolve("nx.test").await.unwrap_err();
+    assert_eq!(error.to_string(), "HostUnreachalble");
+
+    let transport = testing::serve(testing::server(), handle_request, handlers::relay);
+    let stream = transport.connect().await.unwrap();
+    let error = Client::new(stream)
+        .resolve("example.com")
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "CommandNotSupported");
+}
+
+#[tokio::test]
+async fn accepts_pipelined_handshakes() {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let handle_request = move |req: ConnectionRequest| {
+        let tx = tx.clone();
+        async move {
+            let (stream, peer) = tokio::io::duplex(64);
+            tx.send(peer).unwrap();
+            Ok((stream, req.destination))
+        }
+    };
+    let server =
+        testing::server().with_authenticator(StaticUserDb::new().with_user("alice", "secret"));
+    let transport = testing::serve(server, handle_request, handlers::relay);
+
+    // Hello, credentials, request and the first bytes for the destination in one go.
+    let mut message = vec![5, 1, 2];
+    v5::UsernamePassword {
+        username: "alice".into(),
+        password: "secret".into(),
+    }
+    .encode_into(&mut message);
+    v5::Request {
+        command: v5::Command::Connect,
+        rsv: 0,
+        addr: AddressType::DomainName("example.com".into()),
+        port: 80,
+    }
+    .encode_into(&mut message);
+    message.extend_from_slice(b"GET / HTTP/1.0\r\n\r\n");
+
+    let mut stream = transport.connect().await.unwrap();
+    stream.write_all(&message).await.unwrap();
+    let mut replies = [0; 2 + 2 + 18];
+    stream.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies[..4], [5, 2, 1, 0]);
+    let (_, response) = v5::Response::decode::<()>(&replies[4..]).unwrap();
+    assert_eq!(response.status, v5::Status::Success);
+
+    let mut remote = rx.recv().await.unwrap();
+    let mut early = [0; 18];
+    remote.read_exact(&mut early).await.unwrap();
+    assert_eq!(&early, b"GET / HTTP/1.0\r\n\r\n");
+}
+
+#[tokio::test]
+async fn strict_server_rejects_sloppy_clients() {
+    let server = testing::server().with_parse_mode(ParseMode::Strict);
+    let transport = testing::serve(server, handle_request, handlers::relay);
+
+    let request = |rsv: u8| {
+        let mut message = vec![5, 1, 0];
+        v5::Request {
+            command: v5::Command::Connect,
+            rsv,
+            addr: AddressType::DomainName("example.com".into()),
+            port: 80,
+        }
+        .encode_into(&mut message);
+        message
+    };
+
+    // Pipelining the hello and the request is fine.
+    let mut stream = transport.connect().await.unwrap();
+    stream.write_all(&request(0)).await.unwrap();
+    let mut replies = [0; 2 + 10];
+    stream.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies[..3], [5, 0, 5]);
+
+    let mut early_data = request(0);
+    early_data.extend_from_slice(b"GET / HTTP/1.0\r\n\r\n");
+    for message in [request(1), early_data] {
+        let mut stream = transport.connect().await.unwrap();
+        stream.write_all(&message).await.unwrap();
+        let mut replies = Vec::new();
+        stream.read_to_end(&mut replies).await.unwrap();
+        assert_eq!(replies, [5, 0]);
+    }
+
+    // Hellos repeating methods are answered with no acceptable method.
+    let mut stream = transport.connect().await.unwrap();
+    stream.write_all(&[5, 2, 0, 0]).await.unwrap();
+    let mut replies = Vec::new();
+    stream.read_to_end(&mut replies).await.unwrap();
+    assert_eq!(replies, [5, 0xff]);
+}
+
+#[tokio::test]
+async fn layers_rewrite_and_deny_requests() {
+    use socks_parser::Decision;
+
+    let split_horizon = |mut req: ConnectionRequest| {
+        match req.destination.addr {
+            AddressType::DomainName(ref name) if name == "blocked.test" => {
+                return Decision::Deny(Rejection::Status(v5::Status::HostUnreachalble));
+            }
+            AddressType::DomainName(ref name) if name.ends_with(".intranet.test") => {
+                req.destination.addr = AddressType::IPv4([10, 0, 0, 1].into());
+                req.annotations.insert("view".into(), "internal".into());
+            }
+            _ => {}
+        }
+        Decision::Continue(req)
+    };
+    let redirect_http = |mut req: ConnectionRequest| {
+        if req.destination.port == 80 {
+            req.destination.port = 8080;
+        }
+        Decision::Continue(req)
+    };
+    let handle_request = |req: ConnectionRequest| async move {
+        let internal = req.annotations.get("view").map(String::as_str) == Some("internal");
+        assert_eq!(
+            internal,
+            req.destination.addr == AddressType::IPv4([10, 0, 0, 1].into())
+        );
+        let (stream, _) = tokio::io::duplex(64);
+        Ok((stream, req.destination))
+    };
+    let transport = testing::serve(
+        testing::server()
+            .with_layer(split_horizon)
+            .with_layer(redirect_http),
+        handle_request,
+        handlers::relay,
+    );
+
+    for (name, expected) in [
+        (
+            "wiki.intranet.test",
+            Destination::from((AddressType::IPv4([10, 0, 0, 1].into()), 8080)),
+        ),
+        (
+            "example.com",
+            Destination::from((AddressType::DomainName("example.com".into()), 8080)),
+        ),
+    ] {
+        let stream = transport.connect().await.unwrap();
+        let mut negotiated = Client::new(stream).handshake_only().await.unwrap();
+        let bound = negotiated
+            .request(v5::Command::Connect, (name, 80))
+            .await
+            .unwrap();
+        assert_eq!(bound, expected);
+    }
+
+    let stream = transport.connect().await.unwrap();
+    let error = Client::new(stream)
+        .connect(("blocked.test", 80))
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "HostUnreachalble");
+}
+
+#[tokio::test]
+async fn follows_rewrite_rules() {
+    use socks_parser::rewrite::RewriteRules;
+
+    let echo = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await
+            });
+        }
+    });
+
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    let upstream_server = Server::new(listener);
+    let upstream_stats = upstream_server.stats();
+    tokio::spawn(upstream_server.run(connect_direct, handlers::relay));
+
+    let rules = RewriteRules::parse(&format!(
+        "proxy up socks5://{upstream}\n\
+         port *.test {echo_port}\n\
+         via proxied.test up\n\
+         host *.test 127.0.0.1\n\
+         block 127.0.0.1:1-1024\n"
+    ))
+    .unwrap();
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).with_layer(rules.clone()).run(
+        handlers::follow_rewrites(rules, ConnectOptions::default()),
+        handlers::relay,
+    ));
+
+    for (name, via_upstream) in [("direct.test", 0), ("proxied.test", 1)] {
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let mut stream = Client::new(stream).connect((name, 80)).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+        assert_eq!(upstream_stats.total_connections(), via_upstream);
+    }
+
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let error = Client::new(stream)
+        .connect(SocketAddr::from(([127, 0, 0, 1], 80)))
+        .await
+        .unwrap_err();
+    assert_eq!(error.to_string(), "ConnectionNotAllowed");
+}
+
+/// Dialer opening in-memory channels, whose remote end names the destination and echoes.
+struct ChannelDialer;
+
+impl handlers::Dialer for ChannelDialer {
+    type Stream = DuplexStream;
+
+    fn dial<'a>(&'a self, destination: &'a Destination) -> BoxFuture<'a, io::Result<DuplexStream>> {
+        let greeting = format!("{destination}\n");
+        Box::pin(async move {
+            let (local, mut remote) = tokio::io::duplex(64);
+            tokio::spawn(async move {
+                remote.write_all(greeting.as_bytes()).await?;
+                let (mut r, mut w) = tokio::io::split(remote);
+                tokio::io::copy(&mut r, &mut w).await
+            });
+            Ok(local)
+        })
+    }
+}
+
+#[tokio::test]
+async fn forwards_dynamically_through_a_dialer() {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(Server::new(listener).run(
+        handlers::local_dynamic_forward(ChannelDialer),
+        handlers::relay,
+    ));
+
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let mut stream = Client::new(stream)
+        .connect(("example.test", 22))
+        .await
+        .unwrap();
+    let mut greeting = [0; 16];
+    stream.read_exact(&mut greeting).await.unwrap();
+    assert_eq!(&greeting, b"example.test:22\n");
+    stream.write_all(b"ping").await.unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"ping");
+}
+
+#[cfg(feature = "http-connect")]
+#[tokio::test]
+async fn http_connect_requires_credentials_when_authenticating() {
+    let identities = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = Arc::clone(&identities);
+    let server =
+        testing::server().with_authenticator(StaticUserDb::new().with_user("alice", "secret"));
+    let transport = testing::serve(
+        server,
+        move |req: ConnectionRequest| {
+            seen.lock().unwrap().push(req.identity.clone());
+            handle_request(req)
+        },
+        |_, _| async { Ok::<_, io::Error>((0, 0)) },
+    );
+
+    async fn send(transport: &testing::MemoryTransport, request: &str) -> String {
+        let mut stream = transport.connect().await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![0; 12];
+        stream.read_exact(&mut response).await.unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    let connect = "CONNECT echo.test:80 HTTP/1.1\r\nHost: echo.test:80\r\n";
+    let refused = send(&transport, &format!("{connect}\r\n")).await;
+    assert_eq!(refused, "HTTP/1.1 407");
+    // "alice:wrong"
+    let refused = send(
+        &transport,
+        &format!("{connect}Proxy-Authorization: Basic YWxpY2U6d3Jvbmc=\r\n\r\n"),
+    )
+    .await;
+    assert_eq!(refused, "HTTP/1.1 407");
+    assert!(identities.lock().unwrap().is_empty());
+
+    // "alice:secret"
+    let accepted = send(
+        &transport,
+        &format!("{connect}proxy-authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n"),
+    )
+    .await;
+    assert_eq!(accepted, "HTTP/1.1 200");
+    assert_eq!(
+        *identities.lock().unwrap(),
+        [Some(Identity {
+            username: "alice".into()
+        })]
+    );
+}
@@ -0,0 +1,38 @@
+#![cfg(feature = "async")]
+
+use socks_parser::{sniff, stream::PrefixedStream, v5, Client, Version, Wire};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn prefixed_stream_puts_back_sniffed_bytes() {
+    let (client, mut server) = tokio::io::duplex(512);
+    let client = tokio::spawn(Client::new(client).connect(("example.com", 80)));
+
+    let mut peeked = [0; 2];
+    server.read_exact(&mut peeked).await.unwrap();
+    assert_eq!(sniff(&peeked), Some(Version::Socks5));
+
+    let mut server = PrefixedStream::new(&peeked[..], server);
+    assert_eq!(server.prefix(), [5, 1]);
+    let mut hello = [0; 3];
+    server.read_exact(&mut hello).await.unwrap();
+    assert_eq!(hello, [5, 1, 0]);
+    assert!(server.prefix().is_empty());
+    server.write_all(&[5, 0]).await.unwrap();
+
+    let mut request = [0; 18];
+    server.read_exact(&mut request).await.unwrap();
+    let (_, request) = v5::Request::decode::<()>(&request).unwrap();
+    assert_eq!(
+        request.addr,
+        v5::AddressType::DomainName("example.com".into())
+    );
+    server
+        .write_all(&[5, 0, 0, 1, 192, 0, 2, 1, 0, 80])
+        .await
+        .unwrap();
+    client.await.unwrap().unwrap();
+
+    let (prefix, _) = PrefixedStream::new(b"abc".to_vec(), tokio::io::empty()).into_parts();
+    assert_eq!(prefix, b"abc");
+}
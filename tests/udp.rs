@@ -0,0 +1,105 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use socks_parser::{
+    udp::{ClientSource, Reassembler},
+    v5::UdpHeader,
+    Destination,
+};
+
+fn header(frag: u8, port: u16) -> UdpHeader {
+    let destination = Destination::from(SocketAddr::from(([192, 0, 2, 1], port)));
+    UdpHeader {
+        frag,
+        addr: destination.addr,
+        port: destination.port,
+    }
+}
+
+#[test]
+fn reassembles_fragments_in_sequence() {
+    let now = Instant::now();
+    let mut reassembler = Reassembler::new(Duration::from_secs(5));
+    assert_eq!(reassembler.push(header(1, 53), b"a", now), None);
+    assert_eq!(reassembler.push(header(2, 53), b"b", now), None);
+    let (destination, payload) = reassembler.push(header(0x83, 53), b"c", now).unwrap();
+    assert_eq!(destination.port, 53);
+    assert_eq!(payload, b"abc");
+
+    let (_, payload) = reassembler.push(header(0, 53), b"whole", now).unwrap();
+    assert_eq!(payload, b"whole", "Standalone datagrams pass through");
+}
+
+#[test]
+fn abandons_incomplete_datagrams() {
+    let now = Instant::now();
+    let mut reassembler = Reassembler::new(Duration::from_secs(5));
+
+    reassembler.push(header(1, 53), b"a", now);
+    assert_eq!(reassembler.push(header(0x83, 53), b"c", now), None);
+    assert_eq!(
+        reassembler.push(header(0x82, 53), b"b", now),
+        None,
+        "Gap abandoned the sequence"
+    );
+
+    reassembler.push(header(1, 53), b"a", now);
+    assert_eq!(reassembler.push(header(0x82, 54), b"b", now), None);
+
+    reassembler.push(header(1, 53), b"a", now);
+    let late = now + Duration::from_secs(5);
+    assert_eq!(reassembler.push(header(0x82, 53), b"b", late), None);
+
+    reassembler.push(header(1, 53), b"a", now);
+    reassembler.push(header(0, 53), b"whole", now);
+    assert_eq!(reassembler.push(header(0x82, 53), b"b", now), None);
+
+    reassembler.push(header(1, 53), &[0; 40_000], now);
+    assert_eq!(reassembler.push(header(0x82, 53), &[0; 40_000], now), None);
+}
+
+#[test]
+fn client_source_learns_unknown_parts() {
+    let peer = IpAddr::from([198, 51, 100, 7]);
+    let client = SocketAddr::from((peer, 41000));
+    let other = SocketAddr::from(([203, 0, 113, 9], 41000));
+
+    let mut declared = ClientSource::new(&Destination::from(client), peer);
+    assert!(!declared.accept(other));
+    assert!(!declared.accept(SocketAddr::from((peer, 41001))));
+    assert!(declared.accept(client));
+    assert_eq!(declared.client(), Some(client));
+
+    let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+    let mut unknown = ClientSource::new(&Destination::from(unspecified), peer);
+    assert_eq!(unknown.client(), None);
+    assert!(
+        !unknown.accept(other),
+        "Only the peer can claim the association"
+    );
+    assert_eq!(unknown.client(), None);
+    assert!(unknown.accept(client));
+    assert!(!unknown.accept(other), "Another host cannot take over");
+    let rebound = SocketAddr::from((peer, 52000));
+    assert!(unknown.accept(rebound), "NAT rebinding followed");
+    assert_eq!(unknown.client(), Some(rebound));
+
+    let mut port_only = ClientSource::new(
+        &Destination::from(SocketAddr::from(([0, 0, 0, 0], 41000))),
+        peer,
+    );
+    assert!(!port_only.accept(rebound));
+    assert!(!port_only.accept(other));
+    assert!(port_only.accept(client));
+
+    // Declaring another host does not hand the association over to it.
+    let mut elsewhere = ClientSource::new(&Destination::from(other), peer);
+    assert!(!elsewhere.accept(other));
+    assert!(elsewhere.accept(client));
+
+    let mapped = IpAddr::from(Ipv4Addr::from([198, 51, 100, 7]).to_ipv6_mapped());
+    let mut dual_stack = ClientSource::new(&Destination::from(unspecified), mapped);
+    assert!(dual_stack.accept(client));
+}
@@ -0,0 +1,29 @@
+#![cfg(feature = "wasm")]
+
+//! Success paths only: errors are JavaScript values, which cannot be created natively.
+
+use socks_parser::wasm::{decode_request, encode_hello, encode_request_v4, encode_request_v5};
+
+#[test]
+fn encoded_messages_decode_back() {
+    let hello = encode_hello(&[0, 2]);
+    assert_eq!(hello, [5, 2, 0, 2]);
+    let decoded = decode_request(&hello).unwrap_or_else(|_| panic!("hello"));
+    assert_eq!(decoded.version(), 5);
+    assert_eq!(decoded.command(), None);
+    assert_eq!(decoded.methods(), [0, 2]);
+    assert_eq!(decoded.consumed(), 4);
+
+    let request = encode_request_v5(1, "example.com", 443).unwrap_or_else(|_| panic!("v5"));
+    assert_eq!(request, b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+
+    let request = encode_request_v4(1, "192.0.2.1", 80, Some("alice".into()))
+        .unwrap_or_else(|_| panic!("v4"));
+    assert_eq!(request, b"\x04\x01\x00\x50\xc0\x00\x02\x01alice\x00");
+    let decoded = decode_request(&request).unwrap_or_else(|_| panic!("v4 request"));
+    assert_eq!(decoded.version(), 4);
+    assert_eq!(decoded.command(), Some(1));
+    assert_eq!(decoded.host().as_deref(), Some("192.0.2.1"));
+    assert_eq!(decoded.port(), Some(80));
+    assert_eq!(decoded.consumed(), request.len());
+}
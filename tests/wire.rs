@@ -0,0 +1,700 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+
+use proptest::prelude::*;
+use socks_parser::{
+    v4, v5, DecodeLimits, EncodeBuffer, HelloError, ParseError, ParseMode, Version, Wire,
+};
+
+type Error<'i> = nom::error::VerboseError<&'i [u8]>;
+
+fn encode<T: Wire>(value: &T) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    value.encode_into(&mut buffer);
+    buffer
+}
+
+fn decode<T: Wire>(buffer: &[u8]) -> T {
+    let (rest, value) = T::decode::<Error>(buffer).expect("decoding failed");
+    assert!(rest.is_empty(), "{} trailing bytes", rest.len());
+    value
+}
+
+/// Checks that `value` survives an encode/decode cycle byte for byte.
+fn assert_roundtrip<T: Wire>(value: &T) {
+    let encoded = encode(value);
+    let decoded: T = decode(&encoded);
+    assert_eq!(encoded, encode(&decoded));
+}
+
+// Golden vectors: handshakes as emitted by common clients.
+
+#[test]
+fn golden_curl_socks5h() {
+    // curl --socks5-hostname with a proxy user set offers both methods.
+    let hello: v5::Hello = decode(&[0x05, 0x02, 0x00, 0x02]);
+    assert_eq!(
+        hello.methods,
+        [
+            v5::AuthenticationMethod::None,
+            v5::AuthenticationMethod::UsernamePassword
+        ]
+    );
+
+    let request: v5::Request = decode(b"\x05\x01\x00\x03\x0bexample.com\x01\xbb");
+    assert_eq!(request.command, v5::Command::Connect);
+    assert_eq!(
+        request.addr,
+        v5::AddressType::DomainName("example.com".into())
+    );
+    assert_eq!(request.port, 443);
+}
+
+#[test]
+fn golden_firefox_socks5_ipv6() {
+    let hello: v5::Hello = decode(&[0x05, 0x01, 0x00]);
+    assert_eq!(hello.methods, [v5::AuthenticationMethod::None]);
+
+    let mut bytes = vec![0x05, 0x01, 0x00, 0x04];
+    bytes.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    bytes.extend_from_slice(&[0x00, 0x50]);
+    let request: v5::Request = decode(&bytes);
+    assert_eq!(request.addr, v5::AddressType::IPv6(Ipv6Addr::LOCALHOST));
+    assert_eq!(request.port, 80);
+}
+
+#[test]
+fn golden_proxychains_socks4() {
+    // proxychains sends an empty user ID.
+    let request: v4::Request = decode(&[0x04, 0x01, 0x00, 0x16, 10, 0, 0, 1, 0x00]);
+    assert_eq!(request.command, v4::Command::Connect);
+    assert_eq!(
+        request.addr,
+        v4::AddressType::IPv4(Ipv4Addr::new(10, 0, 0, 1))
+    );
+    assert_eq!(request.port, 22);
+    assert_eq!(request.secret, None);
+}
+
+#[test]
+fn golden_curl_socks4a() {
+    let request: v4::Request = decode(b"\x04\x01\x00\x50\x00\x00\x00\x01user\x00example.com\x00");
+    assert_eq!(
+        request.addr,
+        v4::AddressType::DomainName("example.com".into())
+    );
+    assert_eq!(request.port, 80);
+    assert_eq!(request.secret.as_deref(), Some("user"));
+}
+
+#[test]
+fn socks4a_encoding() {
+    use std::num::NonZeroU8;
+
+    let request = |addr: &str, secret: Option<&str>| v4::Request {
+        command: v4::Command::Connect,
+        addr: v4::AddressType::DomainName(addr.into()),
+        port: 80,
+        secret: secret.map(Into::into),
+    };
+
+    let mut buffer = Vec::new();
+    request("example.com", Some("user"))
+        .try_encode_into(&mut buffer, v4::Request::DEFAULT_MARKER)
+        .unwrap();
+    assert_eq!(
+        buffer,
+        b"\x04\x01\x00\x50\x00\x00\x00\x01user\x00example.com\x00"
+    );
+
+    let mut buffer = Vec::new();
+    request("example.com", None)
+        .try_encode_into(&mut buffer, NonZeroU8::MAX)
+        .unwrap();
+    assert_eq!(buffer[4..8], [0, 0, 0, 0xff]);
+    let decoded: v4::Request = decode(&buffer);
+    assert_eq!(decoded.addr, request("example.com", None).addr);
+
+    for invalid in [
+        request("", None),
+        request("exa\0mple.com", None),
+        request("bücher.example", None),
+        request("example.com", Some("us\0er")),
+    ] {
+        let err = invalid
+            .try_encode_into(&mut Vec::new(), v4::Request::DEFAULT_MARKER)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
+
+#[test]
+fn golden_server_replies() {
+    let response: v4::Response = decode(&[0x00, 0x5a, 0x00, 0x50, 93, 184, 216, 34]);
+    assert_eq!(response.status, v4::Status::Success);
+
+    let hello: v5::HelloResponse = decode(&[0x05, 0x00]);
+    assert_eq!(hello.method, v5::AuthenticationMethod::None);
+
+    let response: v5::Response = decode(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(response.status, v5::Status::ConnectionRefused);
+}
+
+#[test]
+fn golden_udp_header() {
+    let datagram = b"\x00\x00\x00\x01\x7f\x00\x00\x01\x00\x35DNS";
+    let (payload, header) = v5::UdpHeader::decode::<Error>(datagram).expect("decoding failed");
+    assert_eq!(
+        header,
+        v5::UdpHeader {
+            frag: 0,
+            addr: v5::AddressType::IPv4(Ipv4Addr::LOCALHOST),
+            port: 53,
+        }
+    );
+    assert_eq!(payload, b"DNS");
+    assert_eq!(encode(&header), datagram[..datagram.len() - 3]);
+}
+
+#[test]
+fn socks4_ip_request_ignores_trailing_data() {
+    // Bytes following a plain SOCKS4 request belong to the tunnel, not to a domain name.
+    let (rest, request) = v4::Request::decode::<Error>(b"\x04\x01\x00\x50\x01\x02\x03\x04\x00GET")
+        .expect("decoding failed");
+    assert_eq!(
+        request.addr,
+        v4::AddressType::IPv4(Ipv4Addr::new(1, 2, 3, 4))
+    );
+    assert_eq!(rest, b"GET");
+}
+
+fn v5_address() -> impl Strategy<Value = v5::AddressType> {
+    prop_oneof![
+        any::<[u8; 4]>().prop_map(|o| v5::AddressType::IPv4(o.into())),
+        any::<[u8; 16]>().prop_map(|o| v5::AddressType::IPv6(o.into())),
+        "[a-z0-9.-]{1,253}".prop_map(v5::AddressType::DomainName),
+    ]
+}
+
+fn v4_address() -> impl Strategy<Value = v4::AddressType> {
+    prop_oneof![
+        any::<[u8; 4]>()
+            .prop_filter(
+                "0.0.0.x marks SOCKS4a",
+                |o| !matches!(o, [0, 0, 0, x] if *x != 0)
+            )
+            .prop_map(|o| v4::AddressType::IPv4(o.into())),
+        "[a-z0-9.-]{1,64}".prop_map(v4::AddressType::DomainName),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn roundtrip_version(v in prop_oneof![Just(Version::Socks4), Just(Version::Socks5)]) {
+        assert_roundtrip(&v);
+    }
+
+    #[test]
+    fn roundtrip_ip(ip4 in any::<[u8; 4]>(), ip6 in any::<[u8; 16]>()) {
+        assert_roundtrip(&Ipv4Addr::from(ip4));
+        assert_roundtrip(&Ipv6Addr::from(ip6));
+    }
+
+    #[test]
+    fn roundtrip_v5_address(addr in v5_address()) {
+        assert_roundtrip(&addr);
+    }
+
+    #[test]
+    fn roundtrip_v4_request(
+        command in prop_oneof![Just(v4::Command::Connect), Just(v4::Command::Bind)],
+        addr in v4_address(),
+        port in any::<u16>(),
+        secret in proptest::option::of("[a-zA-Z0-9]{1,32}"),
+    ) {
+        assert_roundtrip(&v4::Request { command, addr, port, secret });
+    }
+
+    #[test]
+    fn roundtrip_v4_response(
+        status in prop_oneof![
+            Just(v4::Status::Success),
+            Just(v4::Status::Rejected),
+            Just(v4::Status::InetdNotAccessible),
+            Just(v4::Status::InetdNotIdentified),
+        ],
+        addr in any::<[u8; 4]>(),
+        port in any::<u16>(),
+    ) {
+        assert_roundtrip(&v4::Response { status, addr: addr.into(), port });
+    }
+
+    #[test]
+    fn roundtrip_v5_hello(methods in proptest::collection::btree_set(any::<u8>(), 1..=255)) {
+        let methods = methods.into_iter().map(v5::AuthenticationMethod::from).collect();
+        assert_roundtrip(&v5::Hello { methods });
+    }
+
+    #[test]
+    fn roundtrip_v5_hello_response(method in any::<u8>()) {
+        assert_roundtrip(&v5::HelloResponse { method: method.into() });
+    }
+
+    #[test]
+    fn roundtrip_v5_request(
+        command in prop_oneof![
+            Just(v5::Command::Connect),
+            Just(v5::Command::Bind),
+            Just(v5::Command::UdpAssociate),
+        ],
+        rsv in any::<u8>(),
+        addr in v5_address(),
+        port in any::<u16>(),
+    ) {
+        assert_roundtrip(&v5::Request { command, rsv, addr, port });
+    }
+
+    #[test]
+    fn roundtrip_v5_response(status in any::<u8>(), addr in v5_address(), port in any::<u16>()) {
+        assert_roundtrip(&v5::Response { status: status.into(), addr, port });
+    }
+
+    #[test]
+    fn roundtrip_username_password(username in "\\PC{0,64}", password in "\\PC{0,64}") {
+        prop_assume!(username.len() <= 255 && password.len() <= 255);
+        assert_roundtrip(&v5::UsernamePassword { username, password });
+    }
+
+    #[test]
+    fn roundtrip_username_password_response(success in any::<bool>()) {
+        assert_roundtrip(&v5::UsernamePasswordResponse { success });
+    }
+
+    #[test]
+    fn decode_never_panics(bytes in proptest::collection::vec(any::<u8>(), 0..512)) {
+        let _ = v4::Request::decode::<Error>(&bytes);
+        let _ = v5::Hello::decode::<Error>(&bytes);
+        let _ = v5::Request::decode::<Error>(&bytes);
+        let _ = v5::Response::decode::<Error>(&bytes);
+        let _ = v5::UsernamePassword::decode::<Error>(&bytes);
+    }
+}
+
+#[test]
+fn debug_output_hides_secrets() {
+    let request = v4::Request::connect_to("example.com", 80)
+        .unwrap()
+        .with_user_id("s3cr3t-id")
+        .unwrap();
+    let debug = format!("{request:?}");
+    assert!(!debug.contains("s3cr3t-id"), "{debug}");
+    assert!(debug.contains("secret: Some(***)"), "{debug}");
+    assert!(request.debug_unredacted().contains("\"s3cr3t-id\""));
+
+    let credentials = v5::UsernamePassword {
+        username: "alice".into(),
+        password: "hunter2".into(),
+    };
+    let debug = format!("{credentials:?}");
+    assert!(!debug.contains("hunter2"), "{debug}");
+    assert!(debug.contains("alice"), "{debug}");
+    assert_eq!(
+        credentials.debug_unredacted(),
+        r#"UsernamePassword { username: "alice", password: "hunter2" }"#
+    );
+}
+
+#[test]
+fn parse_request_dispatches_on_version() {
+    use socks_parser::{parse_request, AnyRequest};
+
+    let (_, req) = parse_request::<Error>(&[0x04, 0x01, 0x00, 0x16, 10, 0, 0, 1, 0x00]).unwrap();
+    assert!(matches!(req, AnyRequest::V4(_)));
+
+    let (_, req) = parse_request::<Error>(&[0x05, 0x01, 0x00]).unwrap();
+    assert!(matches!(req, AnyRequest::V5Hello(_)));
+
+    let (_, req) = parse_request::<Error>(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80]).unwrap();
+    assert!(matches!(req, AnyRequest::V5(_)));
+
+    assert!(parse_request::<Error>(b"GET / HTTP/1.1\r\n").is_err());
+}
+
+#[test]
+fn address_normalization() {
+    let mapped = v5::AddressType::IPv6("::ffff:192.0.2.1".parse().unwrap());
+    assert_eq!(mapped.to_ipv4(), Some(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(
+        mapped.normalized(),
+        v5::AddressType::IPv4(Ipv4Addr::new(192, 0, 2, 1))
+    );
+    assert_eq!(
+        v4::Response::from(socks_parser::ConnectionResponse {
+            connected_to: (
+                v5::AddressType::IPv6("::ffff:192.0.2.1".parse().unwrap()),
+                80
+            )
+                .into(),
+            status: v5::Status::Success,
+        })
+        .addr,
+        Ipv4Addr::new(192, 0, 2, 1)
+    );
+
+    assert!(v5::AddressType::IPv4(Ipv4Addr::new(169, 254, 1, 1)).is_link_local());
+    assert!(v5::AddressType::IPv6("fe80::1".parse().unwrap()).is_link_local());
+    assert!(v5::AddressType::DomainName("fe80::1%2".into()).is_link_local());
+    assert!(!v5::AddressType::IPv6("2001:db8::1".parse().unwrap()).is_link_local());
+
+    let scoped = v5::AddressType::DomainName("[fe80::1%2]".into());
+    assert_eq!(
+        scoped.to_socket_addr(22),
+        Some(SocketAddr::V6(SocketAddrV6::new(
+            "fe80::1".parse().unwrap(),
+            22,
+            0,
+            2
+        )))
+    );
+    assert_eq!(
+        v5::AddressType::DomainName("example.com".into()).to_socket_addr(22),
+        None
+    );
+}
+
+#[test]
+fn socks4_replies_to_socks5_statuses() {
+    assert_eq!(v4::Status::from(v5::Status::Success), v4::Status::Success);
+    for code in 1..=0xff {
+        assert_eq!(
+            v4::Status::from(v5::Status::from(code)),
+            v4::Status::Rejected,
+            "{code:#04x}"
+        );
+    }
+
+    let response = v4::Response::from(socks_parser::ConnectionResponse {
+        connected_to: (v5::AddressType::IPv6("2001:db8::1".parse().unwrap()), 80).into(),
+        status: v5::Status::CommandNotSupported,
+    });
+    assert_eq!(
+        encode(&response),
+        [0, 0x5b, 0, 80, 0, 0, 0, 0],
+        "IPv6 addresses cannot be told to SOCKS4 clients"
+    );
+}
+
+#[test]
+fn hello_method_selection() {
+    use v5::AuthenticationMethod::*;
+
+    let hello: v5::Hello = decode(&[0x05, 0x04, 0x02, 0x00, 0x02, 0x80]);
+    assert_eq!(hello.methods, [UsernamePassword, None, PrivateMethod(0x80)]);
+    assert!(hello.offers(None));
+    assert!(!hello.offers(Gssapi));
+    assert_eq!(
+        hello.preferred(&[Gssapi, None, UsernamePassword]),
+        Some(None)
+    );
+    assert_eq!(hello.preferred(&[Gssapi]), Option::None);
+
+    assert!(v5::Hello::decode::<Error>(&[0x05, 0x00]).is_err());
+}
+
+#[test]
+fn hello_validation() {
+    use v5::AuthenticationMethod::*;
+
+    let hello = |methods: Vec<v5::AuthenticationMethod>| v5::Hello { methods };
+    assert_eq!(hello(vec![None, UsernamePassword]).validate(), Ok(()));
+    assert_eq!(hello(vec![]).validate(), Err(HelloError::NoMethods));
+    assert_eq!(
+        hello(vec![None, UsernamePassword, None]).validate(),
+        Err(HelloError::RepeatedMethod(None))
+    );
+    assert_eq!(
+        hello(vec![None; 256]).validate(),
+        Err(HelloError::TooManyMethods(256))
+    );
+
+    // Decoded hellos are valid, repeated methods being dropped.
+    let decoded: v5::Hello = decode(&[0x05, 0x03, 0x00, 0x02, 0x00]);
+    assert_eq!(decoded.validate(), Ok(()));
+
+    // An oversized method count is rejected before the methods it announces arrive.
+    let limits = DecodeLimits {
+        max_methods: 4,
+        ..Default::default()
+    };
+    assert!(matches!(
+        v5::Hello::decode_with_limits::<Error>(&[0x05, 0x10, 0x00], &limits),
+        Err(nom::Err::Failure(_))
+    ));
+    assert!(matches!(
+        v5::Hello::decode_with_limits::<Error>(&[0x05, 0x04, 0x00], &limits),
+        Err(nom::Err::Error(_))
+    ));
+}
+
+#[test]
+fn typestate_client_handshake() {
+    use socks_parser::sansio::{Client, Negotiated, NegotiatedMethod};
+    use v5::AuthenticationMethod::*;
+
+    let client = Client::new().with_username_password("alice", "secret");
+    let hello = client.hello();
+    assert_eq!(hello.methods, [UsernamePassword]);
+    let method = NegotiatedMethod::select(&hello, &[None, UsernamePassword]).unwrap();
+    assert!(NegotiatedMethod::select(&hello, &[None, Gssapi]).is_none());
+    let reply = v5::HelloResponse::accept(method);
+    assert_eq!(encode(&reply), [0x05, 0x02]);
+
+    let Ok(Negotiated::NeedsAuth(client)) = client.on_hello_response(&reply) else {
+        panic!("credentials not asked for");
+    };
+    assert_eq!(client.credentials().username, "alice");
+    let client = client
+        .on_auth_response(&v5::UsernamePasswordResponse { success: true })
+        .unwrap();
+    assert_eq!(client.method(), UsernamePassword);
+    let destination = SocketAddr::from(([192, 0, 2, 1], 80)).into();
+    let (request, pending) = client.request(v5::Command::Connect, destination);
+    assert_eq!(request.port, 80);
+    let error = pending
+        .on_response(v5::Response {
+            status: v5::Status::ConnectionRefused,
+            addr: v5::AddressType::IPv4(Ipv4Addr::UNSPECIFIED),
+            port: 0,
+        })
+        .unwrap_err();
+    assert_eq!(error.to_string(), "ConnectionRefused");
+
+    let client = Client::new();
+    let error = client
+        .on_hello_response(&v5::HelloResponse::not_acceptable())
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+
+    let Ok(Negotiated::NeedsAuth(client)) = Client::new()
+        .with_username_password("alice", "wrong")
+        .on_hello_response(&v5::HelloResponse::accept(method))
+    else {
+        panic!("credentials not asked for");
+    };
+    let error = client
+        .on_auth_response(&v5::UsernamePasswordResponse { success: false })
+        .unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn domain_names_from_user_input() {
+    assert_eq!(
+        v5::AddressType::domain("example.com").unwrap(),
+        v5::AddressType::DomainName("example.com".into())
+    );
+    assert!(v5::AddressType::domain("").is_err());
+    assert!(v5::AddressType::domain(&"a".repeat(256)).is_err());
+    assert!(v5::AddressType::domain("example.com\r\n").is_err());
+
+    #[cfg(feature = "idna")]
+    {
+        let addr = v5::AddressType::domain("Bücher.example").unwrap();
+        assert_eq!(
+            addr,
+            v5::AddressType::DomainName("xn--bcher-kva.example".into())
+        );
+        assert_eq!(addr.to_string(), "xn--bcher-kva.example");
+        assert_eq!(format!("{addr:#}"), "bücher.example");
+    }
+    #[cfg(not(feature = "idna"))]
+    assert!(v5::AddressType::domain("bücher.example").is_err());
+}
+
+#[test]
+fn validated_request_constructors() {
+    let req = v5::Request::connect_to("example.com", 443).unwrap();
+    assert_eq!(req.addr, v5::AddressType::DomainName("example.com".into()));
+    assert_eq!(req.command, v5::Command::Connect);
+    let req = v5::Request::connect_to("[::1]", 22).unwrap();
+    assert_eq!(req.addr, v5::AddressType::IPv6(Ipv6Addr::LOCALHOST));
+    assert!(v5::Request::connect_to("example.com", 0).is_err());
+    assert!(v5::Request::connect_to(&"a".repeat(300), 443).is_err());
+    assert!(v5::Request::connect_to("exa mple.com", 443).is_err());
+    assert!(v5::Request::new(v5::Command::UdpAssociate, "0.0.0.0", 0).is_ok());
+
+    let oversized = v5::Request {
+        command: v5::Command::Connect,
+        rsv: 0,
+        addr: v5::AddressType::DomainName("a".repeat(300)),
+        port: 443,
+    };
+    assert!(oversized.try_encode_into(&mut Vec::new()).is_err());
+
+    let req = v4::Request::connect_to("192.0.2.1", 80)
+        .unwrap()
+        .with_user_id("bob")
+        .unwrap();
+    assert_eq!(req.addr, v4::AddressType::IPv4(Ipv4Addr::new(192, 0, 2, 1)));
+    assert_eq!(req.secret.as_deref(), Some("bob"));
+    assert!(v4::Request::connect_to("::1", 80).is_err());
+    assert!(v4::Request::connect_to("example.com", 80)
+        .unwrap()
+        .with_user_id("b\0b")
+        .is_err());
+}
+
+#[test]
+fn strict_parse_mode() {
+    /// Checks `input` is only rejected in strict mode.
+    fn strict_only<T: Wire>(input: &[u8]) {
+        assert!(T::decode_with::<Error>(input, ParseMode::Lenient).is_ok());
+        assert!(T::decode_with::<Error>(input, ParseMode::Strict).is_err());
+    }
+
+    let request = [0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50];
+    assert!(v5::Request::decode_with::<Error>(&request, ParseMode::Strict).is_ok());
+
+    strict_only::<v5::Request>(&[0x05, 0x01, 0x01, 0x01, 127, 0, 0, 1, 0x00, 0x50]);
+    let sloppy: v5::Request = decode(&[0x05, 0x01, 0x01, 0x01, 127, 0, 0, 1, 0x00, 0x50]);
+    assert_eq!(sloppy.rsv, 1);
+    assert_eq!(encode(&sloppy)[2], 1);
+    strict_only::<v5::Request>(&[0x05, 0x01, 0x00, 0x03, 0x00, 0x00, 0x50]);
+    strict_only::<v5::Request>(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x50, 0xff]);
+    strict_only::<v5::Hello>(&[0x05, 0x02, 0x00, 0x00]);
+    strict_only::<v5::UsernamePassword>(&[0x01, 0x00, 0x00]);
+    strict_only::<v5::UdpHeader>(&[0x00, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x35]);
+
+    let input = [0x05, 0x01, 0x02, 0x01, 1, 1, 1, 1, 0, 0];
+    let err = v5::Request::decode_with::<Error>(&input, ParseMode::Strict).unwrap_err();
+    assert_eq!(
+        ParseError::new(&input, err).contexts,
+        ["Reserved byte must be 0"]
+    );
+}
+
+#[test]
+fn parse_error_locates_offending_byte() {
+    let input = [0x05, 0x01, 0x00, 0x07, 0x7f, 0x00, 0x00, 0x01, 0x00, 0x50];
+    let err = v5::Request::decode::<Error>(&input).unwrap_err();
+    let err = ParseError::new(&input, err);
+    assert_eq!(err.offset, 3);
+    assert_eq!(err.contexts, ["Request", "Invalid address type"]);
+    assert_eq!(
+        err.to_string(),
+        "Invalid data at offset 3 (NoneOf) in Request > Invalid address type\n\
+         0000: 05 01 00 [07] 7f 00 00 01 00 50"
+    );
+
+    let err = v5::Request::decode::<Error>(&input[..2]).unwrap_err();
+    let err = ParseError::new(&input[..2], err);
+    assert_eq!(err.offset, 2);
+    assert!(err.to_string().ends_with("0000: 05 01 []"));
+}
+
+#[test]
+fn encode_buffer_reuse() {
+    let mut encoder = EncodeBuffer::new();
+    let hello = v5::Hello {
+        methods: vec![v5::AuthenticationMethod::None],
+    };
+    assert_eq!(encoder.encode(&hello), [0x05, 0x01, 0x00]);
+    let response = v5::HelloResponse {
+        method: v5::AuthenticationMethod::UsernamePassword,
+    };
+    assert_eq!(encoder.encode(&response), [0x05, 0x02]);
+    assert_eq!(encoder.as_slice(), [0x05, 0x02]);
+
+    #[cfg(feature = "bytes")]
+    {
+        let mut buffer = bytes::BytesMut::from(&b"\x01"[..]);
+        hello.encode_into_bytes(&mut buffer);
+        response.encode_into_bytes(&mut buffer);
+        assert_eq!(&buffer[..], [0x01, 0x05, 0x01, 0x00, 0x05, 0x02]);
+    }
+}
+
+#[cfg(feature = "bytes")]
+#[test]
+fn decode_from_bytes_buffers() {
+    let request = v5::Request {
+        command: v5::Command::Connect,
+        rsv: 0,
+        addr: v5::AddressType::DomainName("example.com".into()),
+        port: 443,
+    };
+    let mut encoded = encode(&request);
+    encoded.extend_from_slice(b"GET");
+
+    let mut truncated = bytes::Bytes::copy_from_slice(&encoded[..6]);
+    assert!(v5::Request::decode_buf(&mut truncated).unwrap().is_none());
+    assert_eq!(truncated.len(), 6);
+
+    let mut input = bytes::BytesMut::from(&encoded[..]);
+    let decoded = v5::Request::decode_buf(&mut input).unwrap().unwrap();
+    assert_eq!(decoded.addr, request.addr);
+    assert_eq!(&input[..], b"GET");
+
+    let mut invalid = bytes::Bytes::from_static(b"\x05\x09\x00\x01");
+    assert!(v5::Request::decode_buf(&mut invalid).is_err());
+}
+
+#[test]
+fn destinations_convert_to_addresses() {
+    use socks_parser::{ConnectionRequest, Destination};
+
+    let request = ConnectionRequest::from(SocketAddr::from(([192, 0, 2, 1], 443)));
+    assert_eq!(
+        SocketAddr::try_from(&request).unwrap(),
+        SocketAddr::from(([192, 0, 2, 1], 443))
+    );
+    let named = Destination::from((v5::AddressType::DomainName("example.com".into()), 8080));
+    assert_eq!(
+        SocketAddr::try_from(&named).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+
+    #[cfg(feature = "url")]
+    {
+        assert_eq!(
+            url::Url::try_from(&request).unwrap().as_str(),
+            "https://192.0.2.1/"
+        );
+        assert_eq!(
+            url::Url::try_from(&named).unwrap().as_str(),
+            "http://example.com:8080/"
+        );
+    }
+    #[cfg(feature = "http")]
+    {
+        let v6 = Destination::from(SocketAddr::from((Ipv6Addr::LOCALHOST, 80)));
+        let uri = http::Uri::try_from(&v6).unwrap();
+        assert_eq!((uri.host(), uri.port_u16()), (Some("[::1]"), Some(80)));
+    }
+}
+
+#[test]
+fn statuses_convert_across_versions() {
+    for code in 0..=u8::MAX {
+        let status = v5::Status::from(code);
+        assert_eq!(u8::from(status), code);
+        if let Ok(status) = v4::Status::try_from(code) {
+            assert_eq!(u8::from(status), code);
+        }
+    }
+    assert!(v4::Status::try_from(0).is_err());
+
+    assert_eq!(
+        v4::Status::from(v5::Status::HostUnreachalble),
+        v4::Status::Rejected
+    );
+    assert_eq!(
+        v5::Status::from(v4::Status::InetdNotIdentified),
+        v5::Status::ConnectionNotAllowed
+    );
+    assert!(v5::Status::from(v4::Status::Success).is_success());
+    assert!(v5::Status::from(v4::Status::Rejected).is_retryable());
+    assert!(v5::Status::TTLExpired.is_retryable());
+    assert!(!v5::Status::ConnectionNotAllowed.is_retryable());
+    assert!(!v5::Status::Success.is_retryable());
+    assert!(!v4::Status::InetdNotAccessible.is_retryable());
+}